@@ -0,0 +1,21 @@
+#[macro_use]
+extern crate criterion;
+extern crate rapidus;
+
+use criterion::Criterion;
+use rapidus::bench::PROGRAMS;
+use rapidus::Engine;
+
+fn programs(c: &mut Criterion) {
+    for &(mode, jit) in &[("jit", true), ("interpreter", false)] {
+        let engine = Engine::builder().jit(jit).build();
+        for program in PROGRAMS {
+            c.bench_function(&format!("{}/{}", program.name, mode), |b| {
+                b.iter(|| program.run(&engine).unwrap())
+            });
+        }
+    }
+}
+
+criterion_group!(benches, programs);
+criterion_main!(benches);
@@ -0,0 +1,58 @@
+var PI = 3.141592653589793
+var SOLAR_MASS = 4 * PI * PI
+var DAYS_PER_YEAR = 365.24
+
+function body(x, y, z, vx, vy, vz, mass) {
+  return {
+    x: x, y: y, z: z,
+    vx: vx * DAYS_PER_YEAR, vy: vy * DAYS_PER_YEAR, vz: vz * DAYS_PER_YEAR,
+    mass: mass * SOLAR_MASS
+  }
+}
+
+var bodies = [
+  body(0, 0, 0, 0, 0, 0, 1),
+  body(4.84143144246472090e+00, -1.16032004402742839e+00, -1.03622044471123109e-01,
+       1.66007664274403694e-03, 7.69901118419740425e-03, -6.90460016972063023e-05,
+       9.54791938424326609e-04),
+  body(8.34336671824457987e+00, 4.12479856412430479e+00, -4.03523417114321381e-01,
+       -2.76742510726862411e-03, 4.99852801234917238e-03, 2.30417297573763929e-05,
+       2.85885980666130812e-04),
+  body(1.28943695621391310e+01, -1.51111514016986312e+01, -2.23307578892655734e-01,
+       2.96460137564761618e-03, 2.37847173959480950e-03, -2.96589568540237556e-05,
+       4.36624404335156298e-05),
+  body(1.53796971148509165e+01, -2.59193146099879641e+01, 1.79258772950371181e-01,
+       2.68067772490389322e-03, 1.62824170038242295e-03, -9.51592254519715870e-05,
+       5.15138902046611451e-05)
+]
+
+function advance(n, dt) {
+  for (var i = 0; i < n; i += 1) {
+    var bi = bodies[i]
+    for (var j = i + 1; j < n; j += 1) {
+      var bj = bodies[j]
+      var dx = bi.x - bj.x
+      var dy = bi.y - bj.y
+      var dz = bi.z - bj.z
+      var d2 = dx * dx + dy * dy + dz * dz
+      var mag = dt / (d2 * Math.pow(d2, 0.5))
+      bi.vx = bi.vx - dx * bj.mass * mag
+      bi.vy = bi.vy - dy * bj.mass * mag
+      bi.vz = bi.vz - dz * bj.mass * mag
+      bj.vx = bj.vx + dx * bi.mass * mag
+      bj.vy = bj.vy + dy * bi.mass * mag
+      bj.vz = bj.vz + dz * bi.mass * mag
+    }
+  }
+  for (var i = 0; i < n; i += 1) {
+    var b = bodies[i]
+    b.x = b.x + dt * b.vx
+    b.y = b.y + dt * b.vy
+    b.z = b.z + dt * b.vz
+  }
+}
+
+for (var step = 0; step < 1000; step += 1) {
+  advance(5, 0.01)
+}
+bodies[0].x
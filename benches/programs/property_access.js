@@ -0,0 +1,9 @@
+var point = { x: 1, y: 2, z: 3 }
+var sum = 0
+for (var i = 0; i < 20000; i += 1) {
+  point.x = point.y + 1
+  point.y = point.z + 1
+  point.z = point.x - 2
+  sum = sum + point.x + point.y + point.z
+}
+sum
@@ -0,0 +1,5 @@
+var s = ""
+for (var i = 0; i < 2000; i += 1) {
+  s = s + "item " + i + ", "
+}
+s.length
@@ -0,0 +1,6 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate rapidus;
+
+fuzz_target!(|data: &[u8]| rapidus::fuzz_check(data));
@@ -0,0 +1,80 @@
+use engine::Engine;
+use error::Error;
+
+use std::time::{Duration, Instant};
+
+/// A program for measuring the speed of the engine. The sources are in benches/programs.
+#[derive(Debug, Clone, Copy)]
+pub struct Program {
+    pub name: &'static str,
+    pub source: &'static str,
+}
+
+pub const PROGRAMS: &[Program] = &[
+    Program {
+        name: "fib",
+        source: include_str!("../benches/programs/fib.js"),
+    },
+    Program {
+        name: "nbody",
+        source: include_str!("../benches/programs/nbody.js"),
+    },
+    Program {
+        name: "string_building",
+        source: include_str!("../benches/programs/string_building.js"),
+    },
+    Program {
+        name: "property_access",
+        source: include_str!("../benches/programs/property_access.js"),
+    },
+];
+
+#[derive(Debug, Clone)]
+pub struct Measurement {
+    pub name: &'static str,
+    pub runs: usize,
+    pub min: Duration,
+    pub mean: Duration,
+}
+
+pub fn find(name: &str) -> Option<&'static Program> {
+    PROGRAMS.iter().find(|program| program.name == name)
+}
+
+impl Program {
+    /// Compiles and runs the program once with the options of `engine`, and returns the time
+    /// it took.
+    pub fn run(&self, engine: &Engine) -> Result<Duration, Error> {
+        let start = Instant::now();
+        engine.eval(self.source)?;
+        Ok(start.elapsed())
+    }
+
+    /// Runs the program `runs` times (at least once), for tools that track the speed of the
+    /// engine without criterion.
+    pub fn measure(&self, engine: &Engine, runs: usize) -> Result<Measurement, Error> {
+        let runs = runs.max(1);
+        let mut times = vec![];
+        for _ in 0..runs {
+            times.push(self.run(engine)?);
+        }
+        Ok(Measurement {
+            name: self.name,
+            runs,
+            min: *times.iter().min().unwrap(),
+            mean: times.iter().sum::<Duration>() / runs as u32,
+        })
+    }
+}
+
+#[test]
+fn programs_run() {
+    let engine = Engine::builder().jit(false).build();
+    for program in PROGRAMS {
+        assert!(program.run(&engine).is_ok(), "{} failed", program.name);
+    }
+    assert_eq!(
+        engine.eval(find("fib").unwrap().source).ok(),
+        Some(::vm::Value::Number(75025.0))
+    );
+}
@@ -1,9 +1,23 @@
-use vm::{RawStringPtr, Value, VM};
+use error::ErrorKind;
+use jit;
+use promise::Reaction;
+use vm::{
+    describe_value, number_to_string, own_keys, own_object_keys, own_property, quote_string,
+    to_boolean, iterable_elems, to_primitive, type_of, ArrayValue, Hint, IntegrityLevel,
+    PropertyMap, RawStringPtr, Value, VM, MAX_QUOTED_STRING_LEN,
+};
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::rc::Rc;
 
 use libc;
-use rand::random;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
 
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::io::Write;
 
 pub const CONSOLE_LOG: usize = 0;
 pub const PROCESS_STDOUT_WRITE: usize = 1;
@@ -12,102 +26,234 @@ pub const MATH_FLOOR: usize = 3;
 pub const MATH_RANDOM: usize = 4;
 pub const MATH_POW: usize = 5;
 pub const FUNCTION_PROTOTYPE_CALL: usize = 6;
+pub const TEST: usize = 7;
+pub const ASSERT: usize = 8;
+pub const EXPECT: usize = 9;
+pub const EXPECT_TO_BE: usize = 10;
+pub const EXPECT_TO_EQUAL: usize = 11;
+pub const HOST_GC: usize = 12;
+pub const HOST_CREATE_REALM: usize = 13;
+pub const HOST_EVAL_SCRIPT: usize = 14;
+pub const PROCESS_EXIT: usize = 15;
+pub const OBJECT_KEYS: usize = 16;
+pub const STRING_CHAR_AT: usize = 17;
+pub const STRING_INDEX_OF: usize = 18;
+pub const STRING_SLICE: usize = 19;
+pub const STRING_TO_UPPER_CASE: usize = 20;
+pub const STRING_TO_LOWER_CASE: usize = 21;
+pub const NUMBER_TO_FIXED: usize = 22;
+pub const PRIMITIVE_TO_STRING: usize = 23;
+pub const FUNCTION_PROTOTYPE_TO_STRING: usize = 24;
+pub const STRING: usize = 25;
+pub const ARRAY_SLICE: usize = 26;
+pub const OBJECT_PROTOTYPE_HAS_OWN_PROPERTY: usize = 27;
+pub const OBJECT_PROTOTYPE_PROPERTY_IS_ENUMERABLE: usize = 28;
+pub const ARRAY_FROM: usize = 29;
+pub const OBJECT_FREEZE: usize = 30;
+pub const OBJECT_SEAL: usize = 31;
+pub const OBJECT_IS_FROZEN: usize = 32;
+pub const OBJECT_IS_SEALED: usize = 33;
+pub const OBJECT_PROTOTYPE_TO_STRING: usize = 34;
+pub const PROMISE: usize = 35;
+pub const PROMISE_RESOLVE: usize = 36;
+pub const PROMISE_REJECT: usize = 37;
+pub const PROMISE_PROTOTYPE_THEN: usize = 38;
+pub const PROMISE_PROTOTYPE_CATCH: usize = 39;
+pub const PROMISE_PROTOTYPE_FINALLY: usize = 40;
+pub const PROMISE_RESOLVE_FUNCTION: usize = 41;
+pub const PROMISE_REJECT_FUNCTION: usize = 42;
+pub const STRING_NORMALIZE: usize = 43;
+pub const STRING_LOCALE_COMPARE: usize = 44;
+pub const ARRAY_MAP: usize = 45;
+
+/// The names of the builtin functions above, which their toString() shows.
+pub const NAMES: [&str; 46] = [
+    "log",
+    "write",
+    "push",
+    "floor",
+    "random",
+    "pow",
+    "call",
+    "test",
+    "assert",
+    "expect",
+    "toBe",
+    "toEqual",
+    "gc",
+    "createRealm",
+    "evalScript",
+    "exit",
+    "keys",
+    "charAt",
+    "indexOf",
+    "slice",
+    "toUpperCase",
+    "toLowerCase",
+    "toFixed",
+    "toString",
+    "toString",
+    "String",
+    "slice",
+    "hasOwnProperty",
+    "propertyIsEnumerable",
+    "from",
+    "freeze",
+    "seal",
+    "isFrozen",
+    "isSealed",
+    "toString",
+    "Promise",
+    "resolve",
+    "reject",
+    "then",
+    "catch",
+    "finally",
+    "resolve",
+    "reject",
+    "normalize",
+    "localeCompare",
+    "map",
+];
 
 // BuiltinFunction(0)
-pub unsafe fn console_log(args: Vec<Value>, _: &mut VM) {
+pub unsafe fn console_log(args: Vec<Value>, self_: &mut VM) {
+    let mut out = String::new();
     let args_len = args.len();
     for i in 0..args_len {
         match args[i] {
-            Value::String(ref s) => {
-                libc::printf(b"%s\0".as_ptr() as RawStringPtr, s.as_ptr());
-            }
-            Value::Number(ref n) => {
-                libc::printf(b"%.15g\0".as_ptr() as RawStringPtr, *n);
-            }
-            Value::Bool(true) => {
-                libc::printf(b"true\0".as_ptr() as RawStringPtr);
-            }
-            Value::Bool(false) => {
-                libc::printf(b"false\0".as_ptr() as RawStringPtr);
-            }
-            Value::Object(_) | Value::Array(_) | Value::Function(_, _) => debug_print(&args[i]),
-            Value::Undefined => {
-                libc::printf(b"undefined\0".as_ptr() as RawStringPtr);
-            }
+            Value::String(ref s) => out += s.to_str().unwrap(),
+            Value::Number(n) => out += format_number(n).as_str(),
+            Value::Bool(true) => out += "true",
+            Value::Bool(false) => out += "false",
+            Value::Object(_) | Value::Array(_) => out += debug_string(&args[i]).as_str(),
+            Value::Function(_, _)
+            | Value::NeedThis(_)
+            | Value::WithThis(_)
+            | Value::BuiltinFunction(_) => out += self_.value_to_string(&args[i]).as_str(),
+            Value::Undefined => out += "undefined",
+            Value::Null => out += "null",
             _ => {}
         }
         if args_len - 1 != i {
-            libc::printf(b" \0".as_ptr() as RawStringPtr);
+            out += " ";
         }
     }
-    libc::puts(b"\0".as_ptr() as RawStringPtr);
+    out += "\n";
+    write_stdout(self_, out.as_str());
+    self_.state.stack.push(Value::Undefined)
 }
 
 // BuiltinFunction(1)
-pub unsafe fn process_stdout_write(args: Vec<Value>, _: &mut VM) {
+pub unsafe fn process_stdout_write(args: Vec<Value>, self_: &mut VM) {
+    let mut out = String::new();
     let args_len = args.len();
     for i in 0..args_len {
         match args[i] {
-            Value::String(ref s) => {
-                libc::printf(b"%s\0".as_ptr() as RawStringPtr, s.as_ptr());
-            }
-            Value::Number(ref n) => {
-                libc::printf(b"%.15g\0".as_ptr() as RawStringPtr, *n);
-            }
-            Value::Undefined => {
-                libc::printf(b"undefined\0".as_ptr() as RawStringPtr);
-            }
+            Value::String(ref s) => out += s.to_str().unwrap(),
+            Value::Number(n) => out += format_number(n).as_str(),
+            Value::Undefined => out += "undefined",
+            Value::Null => out += "null",
             _ => {}
         }
         if args_len - 1 != i {
-            libc::printf(b" \0".as_ptr() as RawStringPtr);
+            out += " ";
         }
     }
+    write_stdout(self_, out.as_str());
+    self_.state.stack.push(Value::Undefined)
+}
+
+pub fn debug_string(val: &Value) -> String {
+    debug_string_within(val, &mut vec![])
 }
 
-pub unsafe fn debug_print(val: &Value) {
+/// `outer` holds the objects and arrays being printed, so that cycles print as [Circular].
+fn debug_string_within(val: &Value, outer: &mut Vec<*const ()>) -> String {
     match val {
-        &Value::String(ref s) => {
-            libc::printf("'%s'\0".as_ptr() as RawStringPtr, s.as_ptr());
-        }
-        &Value::Number(ref n) => {
-            libc::printf("%.15g\0".as_ptr() as RawStringPtr, *n);
-        }
+        &Value::String(ref s) => quote_string(s.to_str().unwrap(), MAX_QUOTED_STRING_LEN),
+        &Value::Number(n) => format_number(n),
         &Value::Object(ref values) => {
-            libc::printf("{ \0".as_ptr() as RawStringPtr);
-            for (key, val) in &*(*values).borrow() {
-                libc::printf(
-                    "'%s'\0".as_ptr() as RawStringPtr,
-                    CString::new(key.as_str()).unwrap().into_raw(),
-                );
-                libc::printf(": \0".as_ptr() as RawStringPtr);
-                debug_print(&val);
-                libc::printf(", \0".as_ptr() as RawStringPtr);
+            let ptr = values.as_ptr() as *const ();
+            if outer.contains(&ptr) {
+                return "[Circular]".to_string();
+            }
+            outer.push(ptr);
+            let mut out = "{ ".to_string();
+            let values = &*(*values).borrow();
+            for key in own_keys(values) {
+                let val = debug_string_within(&values[&key], outer);
+                let key = quote_string(key.as_str(), MAX_QUOTED_STRING_LEN);
+                out += format!("{}: {}, ", key, val).as_str();
             }
-            libc::printf("}\0".as_ptr() as RawStringPtr);
+            outer.pop();
+            out + "}"
         }
         &Value::Array(ref values) => {
-            libc::printf("[ \0".as_ptr() as RawStringPtr);
+            let ptr = values.as_ptr() as *const ();
+            if outer.contains(&ptr) {
+                return "[Circular]".to_string();
+            }
+            outer.push(ptr);
+            let mut out = "[ ".to_string();
             let arr = &*(*values).borrow();
-            let elems = &arr.elems;
-            for i in 0..arr.length {
-                debug_print(&elems[i]);
-                libc::printf(", \0".as_ptr() as RawStringPtr);
+            for elem in &arr.elems[..arr.length] {
+                out += format!("{}, ", debug_string_within(elem, outer)).as_str();
             }
-            libc::printf("]\0".as_ptr() as RawStringPtr);
-        }
-        &Value::Function(_, _) => {
-            libc::printf("[Function]\0".as_ptr() as RawStringPtr);
+            outer.pop();
+            out + "]"
         }
-        &Value::Undefined => {
-            libc::printf(b"undefined\0".as_ptr() as RawStringPtr);
+        &Value::Function(_, _) => "[Function]".to_string(),
+        &Value::Accessor(_) => val.inspect(),
+        &Value::Undefined => "undefined".to_string(),
+        &Value::Null => "null".to_string(),
+        _ => "".to_string(),
+    }
+}
+
+/// Formats a number as printf's %.15g does.
+fn format_number(n: f64) -> String {
+    if n.is_nan() || n.is_infinite() {
+        return number_to_string(n);
+    }
+    let mut buf = [0u8; 32];
+    unsafe {
+        libc::snprintf(
+            buf.as_mut_ptr() as *mut libc::c_char,
+            buf.len(),
+            b"%.15g\0".as_ptr() as RawStringPtr,
+            n,
+        );
+        CStr::from_ptr(buf.as_ptr() as RawStringPtr)
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+/// Writes `s` to the standard output, or passes it to the stdout hook of the VM if it has one.
+unsafe fn write_stdout(self_: &VM, s: &str) {
+    match self_.stdout {
+        Some(ref hook) => hook(s),
+        None => {
+            libc::printf(
+                b"%s\0".as_ptr() as RawStringPtr,
+                CString::new(s).unwrap().as_ptr(),
+            );
         }
-        _ => {}
     }
 }
 
 // BuiltinFunction(2)
-pub unsafe fn array_push(args: Vec<Value>, _: &mut VM) {
+pub unsafe fn array_push(args: Vec<Value>, self_: &mut VM) {
+    if self_.integrity_level(&args[0]).is_some() && args.len() > 1 {
+        let length = match args[0] {
+            Value::Array(ref map) => map.borrow().length,
+            _ => unreachable!(),
+        };
+        // push() fails in sloppy mode too.
+        let msg = format!("cannot add property {}, object is not extensible", length);
+        self_.fatal_error(ErrorKind::TypeError, msg.as_str())
+    }
     if let Value::Array(ref map) = args[0] {
         let mut map = map.borrow_mut();
         // let mut elems = &mut map.elems;
@@ -115,6 +261,7 @@ pub unsafe fn array_push(args: Vec<Value>, _: &mut VM) {
             map.elems.push(val.clone());
         }
         map.length += args[1..].len();
+        self_.state.stack.push(Value::Number(map.length as f64))
     } else {
         unreachable!()
     };
@@ -122,36 +269,41 @@ pub unsafe fn array_push(args: Vec<Value>, _: &mut VM) {
 
 // BuiltinFunction(3)
 pub unsafe fn math_floor(args: Vec<Value>, self_: &mut VM) {
-    if let Value::Number(f) = args[0] {
+    if let Some(&Value::Number(f)) = args.get(0) {
         self_.state.stack.push(Value::Number(f.floor()))
+    } else {
+        self_.state.stack.push(Value::Number(::std::f64::NAN))
     }
 }
 
 // BuiltinFunction(4)
 pub unsafe fn math_random(_args: Vec<Value>, self_: &mut VM) {
-    self_.state.stack.push(Value::Number(random::<f64>()))
+    // Shares the generator with JIT-compiled code, so that it can be seeded.
+    self_.state.stack.push(Value::Number(jit::math_random()))
 }
 
 // BuiltinFunction(5)
 pub unsafe fn math_pow(args: Vec<Value>, self_: &mut VM) {
-    if let Value::Number(f1) = args[0] {
-        if let Value::Number(f2) = args[1] {
-            self_.state.stack.push(Value::Number(f1.powf(f2)))
-        }
+    if let (Some(&Value::Number(f1)), Some(&Value::Number(f2))) = (args.get(0), args.get(1)) {
+        self_.state.stack.push(Value::Number(f1.powf(f2)))
+    } else {
+        self_.state.stack.push(Value::Number(::std::f64::NAN))
     }
 }
 
 // BuiltinFunction(6)
 pub unsafe fn function_prototype_call(args: Vec<Value>, self_: &mut VM) {
     let mut callee = args[0].clone();
+    let this = args.get(1).cloned().unwrap_or(Value::Undefined);
     loop {
         match callee {
-            Value::Function(dst, _obj) => {
-                self_.state.history.push((0, 0, 0, self_.state.pc));
+            Value::Function(dst, obj) => {
+                let env = self_.closure_env(&obj);
+                self_.push_frame(dst, None, env);
 
-                self_.state.stack.push(args[1].clone());
+                self_.state.stack.push(this.clone());
 
-                for arg in args[2..].iter() {
+                for arg in args.iter().skip(2) {
                     self_.state.stack.push(arg.clone());
                 }
 
@@ -159,17 +311,22 @@ pub unsafe fn function_prototype_call(args: Vec<Value>, self_: &mut VM) {
                 self_
                     .state
                     .stack
-                    .push(Value::Number(args.len() as f64 - 1.0 /*callee*/));
+                    .push(Value::Number(args.len().max(2) as f64 - 2.0 /*callee, this*/));
 
                 self_.do_run();
-
-                match self_.state.stack.last_mut().unwrap() {
-                    &mut Value::Object(_)
-                    | &mut Value::Array(_)
-                    | &mut Value::Function(_, _)
-                    | &mut Value::BuiltinFunction(_) => {}
-                    others => *others = args[1].clone(),
-                };
+                break;
+            }
+            // Builtin methods get `this` as their first argument.
+            Value::NeedThis(box Value::BuiltinFunction(id))
+            | Value::WithThis(box (Value::BuiltinFunction(id), _)) => {
+                let method = Value::WithThis(Box::new((Value::BuiltinFunction(id), this)));
+                let val = self_.call_value(method, args.into_iter().skip(2).collect());
+                self_.state.stack.push(val);
+                break;
+            }
+            Value::BuiltinFunction(_) => {
+                let val = self_.call_value(callee, args.into_iter().skip(2).collect());
+                self_.state.stack.push(val);
                 break;
             }
             Value::NeedThis(callee_) => {
@@ -179,12 +336,646 @@ pub unsafe fn function_prototype_call(args: Vec<Value>, self_: &mut VM) {
                 callee = callee_;
             }
             c => {
-                println!(
-                    "Function.prototype.call: err: {:?}, pc = {}",
-                    c, self_.state.pc
-                );
-                break;
+                let msg = format!("{} is not a function", describe_value(&c));
+                self_.fatal_error(ErrorKind::TypeError, msg.as_str())
+            }
+        }
+    }
+}
+
+unsafe fn print_line(self_: &VM, s: &str) {
+    write_stdout(self_, format!("{}\n", s).as_str());
+}
+
+unsafe fn test_failed(self_: &mut VM, msg: String) {
+    let msg = match self_.current_location() {
+        Some(location) => format!("{} ({})", msg, location),
+        None => msg,
+    };
+    match self_.tests.current_failures {
+        Some(ref mut failures) => failures.push(msg),
+        // A failed assertion outside of test() counts as a failed test by itself.
+        None => {
+            self_.tests.failed += 1;
+            print_line(self_, format!("  FAILED (top level)\n    {}", msg).as_str());
+        }
+    }
+}
+
+// BuiltinFunction(7)
+pub unsafe fn test(args: Vec<Value>, self_: &mut VM) {
+    let name = match args.get(0) {
+        Some(&Value::String(ref name)) => name.to_str().unwrap().to_string(),
+        Some(name) => describe_value(name),
+        None => "(anonymous)".to_string(),
+    };
+
+    let outer_failures = ::std::mem::replace(&mut self_.tests.current_failures, Some(vec![]));
+    if let Some(body) = args.get(1) {
+        self_.call_value(body.clone(), vec![]);
+    }
+    let failures = ::std::mem::replace(&mut self_.tests.current_failures, outer_failures);
+
+    match failures {
+        Some(ref failures) if failures.is_empty() => {
+            self_.tests.passed += 1;
+            print_line(self_, format!("  ok {}", name).as_str());
+        }
+        Some(failures) => {
+            self_.tests.failed += 1;
+            print_line(self_, format!("  FAILED {}", name).as_str());
+            for failure in failures {
+                print_line(self_, format!("    {}", failure).as_str());
             }
         }
+        None => unreachable!(),
+    }
+    self_.state.stack.push(Value::Undefined)
+}
+
+// BuiltinFunction(8)
+pub unsafe fn assert(args: Vec<Value>, self_: &mut VM) {
+    let ok = args.get(0).map_or(false, to_boolean);
+    if !ok {
+        let msg = match args.get(1) {
+            Some(&Value::String(ref msg)) => msg.to_str().unwrap().to_string(),
+            _ => "assertion failed".to_string(),
+        };
+        test_failed(self_, msg);
+    }
+    self_.state.stack.push(Value::Undefined)
+}
+
+// BuiltinFunction(9)
+pub unsafe fn expect(args: Vec<Value>, self_: &mut VM) {
+    let mut map = PropertyMap::new();
+    map.insert(
+        "actual".to_string(),
+        args.get(0).cloned().unwrap_or(Value::Undefined),
+    );
+    map.insert(
+        "toBe".to_string(),
+        Value::NeedThis(Box::new(Value::BuiltinFunction(EXPECT_TO_BE))),
+    );
+    map.insert(
+        "toEqual".to_string(),
+        Value::NeedThis(Box::new(Value::BuiltinFunction(EXPECT_TO_EQUAL))),
+    );
+    self_
+        .state
+        .stack
+        .push(Value::Object(Rc::new(RefCell::new(map))))
+}
+
+// BuiltinFunction(10)
+pub unsafe fn expect_to_be(args: Vec<Value>, self_: &mut VM) {
+    expect_with(args, self_, "to be", strict_equal)
+}
+
+// BuiltinFunction(11)
+pub unsafe fn expect_to_equal(args: Vec<Value>, self_: &mut VM) {
+    expect_with(args, self_, "to equal", deep_equal)
+}
+
+unsafe fn expect_with(
+    args: Vec<Value>,
+    self_: &mut VM,
+    relation: &str,
+    matches: fn(&Value, &Value) -> bool,
+) {
+    let actual = match args.get(0) {
+        Some(&Value::Object(ref obj)) => obj.borrow().get("actual").cloned(),
+        _ => None,
+    }.unwrap_or(Value::Undefined);
+    let expected = args.get(1).cloned().unwrap_or(Value::Undefined);
+    if !matches(&actual, &expected) {
+        let msg = format!(
+            "expected {} {} {}",
+            describe_value(&actual),
+            relation,
+            describe_value(&expected)
+        );
+        test_failed(self_, msg);
+    }
+    self_.state.stack.push(Value::Undefined)
+}
+
+fn strict_equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (&Value::Undefined, &Value::Undefined) | (&Value::Null, &Value::Null) => true,
+        (&Value::Bool(a), &Value::Bool(b)) => a == b,
+        (&Value::Number(a), &Value::Number(b)) => a == b,
+        (&Value::String(ref a), &Value::String(ref b)) => a == b,
+        (&Value::Function(_, ref a), &Value::Function(_, ref b)) => Rc::ptr_eq(a, b),
+        (&Value::BuiltinFunction(a), &Value::BuiltinFunction(b)) => a == b,
+        (&Value::Object(ref a), &Value::Object(ref b)) => Rc::ptr_eq(a, b),
+        (&Value::Array(ref a), &Value::Array(ref b)) => Rc::ptr_eq(a, b),
+        _ => false,
     }
 }
+
+/// Compares objects and arrays by their contents, and everything else as strict_equal does.
+fn deep_equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (&Value::Object(ref a), &Value::Object(ref b)) => {
+            let (a, b) = (a.borrow(), b.borrow());
+            a.len() == b.len()
+                && a.iter()
+                    .all(|(key, val)| b.get(&key).map_or(false, |val2| deep_equal(val, val2)))
+        }
+        (&Value::Array(ref a), &Value::Array(ref b)) => {
+            let (a, b) = (a.borrow(), b.borrow());
+            a.length == b.length
+                && a.elems
+                    .iter()
+                    .zip(b.elems.iter())
+                    .all(|(val, val2)| deep_equal(val, val2))
+        }
+        _ => strict_equal(a, b),
+    }
+}
+
+// BuiltinFunction(12)
+pub unsafe fn host_gc(_args: Vec<Value>, self_: &mut VM) {
+    // Values are reference counted, so there is nothing to collect.
+    // TODO: Once there is a tracing GC, collect here, and add --trace-gc (log the reason, pause
+    // time and freed bytes of each collection) and --gc-stress (collect at every allocation).
+    self_.state.stack.push(Value::Undefined)
+}
+
+// BuiltinFunction(13)
+pub unsafe fn host_create_realm(_args: Vec<Value>, self_: &mut VM) {
+    // Values can't be shared between VMs, and a VM is a realm.
+    self_.report_error("$262.createRealm() is not supported");
+    self_.state.stack.push(Value::Undefined)
+}
+
+// BuiltinFunction(14)
+pub unsafe fn host_eval_script(_args: Vec<Value>, self_: &mut VM) {
+    self_.report_error("$262.evalScript() is not supported");
+    self_.state.stack.push(Value::Undefined)
+}
+
+// BuiltinFunction(15)
+pub unsafe fn process_exit(args: Vec<Value>, _self_: &mut VM) {
+    let code = match args.get(0) {
+        Some(&Value::Number(n)) => n as i32,
+        _ => 0,
+    };
+    io::stdout().flush().unwrap();
+    // exit() also flushes what was printed by printf.
+    ::std::process::exit(code)
+}
+
+// BuiltinFunction(16)
+pub unsafe fn object_keys(args: Vec<Value>, self_: &mut VM) {
+    let keys = match args.get(0) {
+        Some(&Value::Object(ref map)) => own_object_keys(self_, map),
+        Some(&Value::Array(ref array)) => {
+            let array = array.borrow();
+            (0..array.length)
+                .map(|i| i.to_string())
+                .chain(own_keys(&array.obj))
+                .collect()
+        }
+        _ => vec![],
+    };
+    let keys = keys
+        .into_iter()
+        .map(|key| Value::String(CString::new(key).unwrap()))
+        .collect();
+    self_
+        .state
+        .stack
+        .push(Value::Array(Rc::new(RefCell::new(ArrayValue::new(keys)))))
+}
+
+// The methods of strings, numbers and booleans are called with the primitive as `this`, in
+// args[0]. See vm::primitive_method.
+
+fn this_string(args: &[Value]) -> String {
+    match args.get(0) {
+        Some(&Value::String(ref s)) => s.to_str().unwrap().to_string(),
+        Some(val) => val.to_string(),
+        None => String::new(),
+    }
+}
+
+/// Converts the argument `i` to an integer as the position arguments of string methods are.
+fn integer_arg(args: &[Value], i: usize, default: f64) -> f64 {
+    match args.get(i) {
+        Some(&Value::Number(n)) if n.is_nan() => 0.0,
+        Some(&Value::Number(n)) => n.trunc(),
+        _ => default,
+    }
+}
+
+pub fn new_string(s: String) -> Value {
+    Value::String(CString::new(s).unwrap())
+}
+
+// BuiltinFunction(17)
+pub unsafe fn string_char_at(args: Vec<Value>, self_: &mut VM) {
+    let s = this_string(&args);
+    let pos = integer_arg(&args, 1, 0.0);
+    let c = if pos < 0.0 {
+        None
+    } else {
+        s.chars().nth(pos as usize)
+    };
+    self_
+        .state
+        .stack
+        .push(new_string(c.map_or(String::new(), |c| c.to_string())))
+}
+
+// BuiltinFunction(18)
+pub unsafe fn string_index_of(args: Vec<Value>, self_: &mut VM) {
+    let s = this_string(&args);
+    let search = args.get(1).unwrap_or(&Value::Undefined).to_string();
+    let start = integer_arg(&args, 2, 0.0).max(0.0) as usize;
+    let start_byte = s.char_indices().nth(start).map_or(s.len(), |(i, _)| i);
+    let index = match s[start_byte..].find(search.as_str()) {
+        Some(i) => (start + s[start_byte..start_byte + i].chars().count()) as f64,
+        None => -1.0,
+    };
+    self_.state.stack.push(Value::Number(index))
+}
+
+// BuiltinFunction(19)
+pub unsafe fn string_slice(args: Vec<Value>, self_: &mut VM) {
+    let chars = this_string(&args).chars().collect::<Vec<char>>();
+    let len = chars.len() as f64;
+    // Negative positions count from the end.
+    let position = |n: f64| if n < 0.0 { (len + n).max(0.0) } else { n.min(len) } as usize;
+    let begin = position(integer_arg(&args, 1, 0.0));
+    let end = position(integer_arg(&args, 2, len)).max(begin);
+    self_
+        .state
+        .stack
+        .push(new_string(chars[begin..end].iter().collect()))
+}
+
+// BuiltinFunction(20)
+pub unsafe fn string_to_upper_case(args: Vec<Value>, self_: &mut VM) {
+    self_
+        .state
+        .stack
+        .push(new_string(this_string(&args).to_uppercase()))
+}
+
+// BuiltinFunction(21)
+pub unsafe fn string_to_lower_case(args: Vec<Value>, self_: &mut VM) {
+    self_
+        .state
+        .stack
+        .push(new_string(this_string(&args).to_lowercase()))
+}
+
+// BuiltinFunction(22)
+pub unsafe fn number_to_fixed(args: Vec<Value>, self_: &mut VM) {
+    let n = match args.get(0) {
+        Some(&Value::Number(n)) => n,
+        _ => ::std::f64::NAN,
+    };
+    let digits = integer_arg(&args, 1, 0.0);
+    if digits < 0.0 || digits > 100.0 {
+        self_.report_error("toFixed() digits argument must be between 0 and 100");
+        self_.state.stack.push(Value::Undefined);
+        return;
+    }
+    let s = if !n.is_finite() || n.abs() >= 1e21 {
+        Value::Number(n).to_string()
+    } else if n == 0.0 {
+        // -0 is shown as 0.
+        format!("{:.*}", digits as usize, 0.0)
+    } else {
+        format!("{:.*}", digits as usize, n)
+    };
+    self_.state.stack.push(new_string(s))
+}
+
+// BuiltinFunction(23)
+pub unsafe fn primitive_to_string(args: Vec<Value>, self_: &mut VM) {
+    let s = self_.value_to_string(args.get(0).unwrap_or(&Value::Undefined));
+    self_.state.stack.push(new_string(s))
+}
+
+// BuiltinFunction(24)
+pub unsafe fn function_prototype_to_string(args: Vec<Value>, self_: &mut VM) {
+    match args.get(0) {
+        Some(&Value::Function(_, _))
+        | Some(&Value::NeedThis(_))
+        | Some(&Value::WithThis(_))
+        | Some(&Value::BuiltinFunction(_)) => {
+            let s = self_.value_to_string(&args[0]);
+            self_.state.stack.push(new_string(s))
+        }
+        _ => {
+            self_.fatal_error(
+                ErrorKind::TypeError,
+                "Function.prototype.toString requires that 'this' be a Function"
+            )
+        }
+    }
+}
+
+// BuiltinFunction(25)
+pub unsafe fn string(args: Vec<Value>, self_: &mut VM) {
+    let s = match args.get(0) {
+        Some(val) => {
+            let val = to_primitive(self_, val, Hint::String);
+            self_.value_to_string(&val)
+        }
+        None => "".to_string(),
+    };
+    self_.state.stack.push(new_string(s))
+}
+
+// BuiltinFunction(26)
+pub unsafe fn array_slice(args: Vec<Value>, self_: &mut VM) {
+    let elems = match args.get(0) {
+        Some(&Value::Array(ref map)) => map.borrow().elems.clone(),
+        _ => unreachable!(),
+    };
+    let len = elems.len() as f64;
+    // Negative positions count from the end, as they do for strings.
+    let position = |n: f64| if n < 0.0 { (len + n).max(0.0) } else { n.min(len) } as usize;
+    let begin = position(integer_arg(&args, 1, 0.0));
+    let end = position(integer_arg(&args, 2, len)).max(begin);
+    self_.stats.allocations += 1;
+    self_.state.stack.push(Value::Array(Rc::new(RefCell::new(ArrayValue::new(
+        elems[begin..end].to_vec(),
+    )))))
+}
+
+// BuiltinFunction(27)
+pub unsafe fn object_prototype_has_own_property(args: Vec<Value>, self_: &mut VM) {
+    let this = args.get(0).cloned().unwrap_or(Value::Undefined);
+    let key = args.get(1).unwrap_or(&Value::Undefined).to_string();
+    let found = own_property(self_, &this, key.as_str()).is_some();
+    self_.state.stack.push(Value::Bool(found))
+}
+
+// BuiltinFunction(28)
+pub unsafe fn object_prototype_property_is_enumerable(args: Vec<Value>, self_: &mut VM) {
+    let this = args.get(0).cloned().unwrap_or(Value::Undefined);
+    let key = args.get(1).unwrap_or(&Value::Undefined).to_string();
+    let enumerable = own_property(self_, &this, key.as_str()) == Some(true);
+    self_.state.stack.push(Value::Bool(enumerable))
+}
+
+// BuiltinFunction(29)
+pub unsafe fn array_from(args: Vec<Value>, self_: &mut VM) {
+    let src = args.get(0).cloned().unwrap_or(Value::Undefined);
+    let elems = match iterable_elems(self_, &src) {
+        Some(elems) => elems,
+        None => match src {
+            // An object with a length is taken as array-like.
+            Value::Object(ref map) => {
+                let map = map.borrow();
+                let len = match map.get("length") {
+                    Some(&Value::Number(len)) if len > 0.0 => len as usize,
+                    _ => 0,
+                };
+                (0..len)
+                    .map(|i| map.get(&i.to_string()).cloned().unwrap_or(Value::Undefined))
+                    .collect()
+            }
+            Value::Undefined | Value::Null => {
+                let msg = format!("{} is not iterable", describe_value(&src));
+                self_.fatal_error(ErrorKind::TypeError, msg.as_str())
+            }
+            _ => vec![],
+        },
+    };
+    let elems = match args.get(1) {
+        Some(&Value::Undefined) | None => elems,
+        Some(f) => elems
+            .into_iter()
+            .enumerate()
+            .map(|(i, elem)| self_.call_value(f.clone(), vec![elem, Value::Number(i as f64)]))
+            .collect(),
+    };
+    self_.stats.allocations += 1;
+    self_
+        .state
+        .stack
+        .push(Value::Array(Rc::new(RefCell::new(ArrayValue::new(elems)))))
+}
+
+// BuiltinFunction(30)
+pub unsafe fn object_freeze(args: Vec<Value>, self_: &mut VM) {
+    let obj = args.get(0).cloned().unwrap_or(Value::Undefined);
+    self_.set_integrity_level(&obj, IntegrityLevel::Frozen);
+    self_.state.stack.push(obj)
+}
+
+// BuiltinFunction(31)
+pub unsafe fn object_seal(args: Vec<Value>, self_: &mut VM) {
+    let obj = args.get(0).cloned().unwrap_or(Value::Undefined);
+    self_.set_integrity_level(&obj, IntegrityLevel::Sealed);
+    self_.state.stack.push(obj)
+}
+
+// BuiltinFunction(32)
+pub unsafe fn object_is_frozen(args: Vec<Value>, self_: &mut VM) {
+    let frozen = is_locked(&args, IntegrityLevel::Frozen, self_);
+    self_.state.stack.push(Value::Bool(frozen))
+}
+
+// BuiltinFunction(33)
+pub unsafe fn object_is_sealed(args: Vec<Value>, self_: &mut VM) {
+    let sealed = is_locked(&args, IntegrityLevel::Sealed, self_);
+    self_.state.stack.push(Value::Bool(sealed))
+}
+
+/// Returns whether args[0] is locked to `level` or more. Primitives cannot be changed, so they
+/// count as frozen.
+fn is_locked(args: &[Value], level: IntegrityLevel, self_: &VM) -> bool {
+    match args.get(0) {
+        Some(&Value::Object(_))
+        | Some(&Value::Function(_, _))
+        | Some(&Value::NeedThis(_))
+        | Some(&Value::Array(_)) => self_.integrity_level(&args[0]) >= Some(level),
+        _ => true,
+    }
+}
+
+// BuiltinFunction(34)
+/// Returns "[object Tag]", where Tag tells what kind of value `this` is.
+///
+/// TODO: Use the Symbol.toStringTag property of objects once there are symbols.
+pub unsafe fn object_prototype_to_string(args: Vec<Value>, self_: &mut VM) {
+    let tag = match args.get(0) {
+        None | Some(&Value::Undefined) => "Undefined",
+        Some(&Value::Null) => "Null",
+        Some(&Value::Bool(_)) => "Boolean",
+        Some(&Value::Number(_)) => "Number",
+        Some(&Value::String(_)) => "String",
+        Some(&Value::Function(_, _))
+        | Some(&Value::NeedThis(_))
+        | Some(&Value::WithThis(_))
+        | Some(&Value::BuiltinFunction(_)) => "Function",
+        Some(&Value::Array(_)) => "Array",
+        Some(&Value::Arguments) => "Arguments",
+        Some(&Value::Object(_)) => "Object",
+        Some(&Value::Accessor(_)) => unreachable!(),
+    };
+    self_.state.stack.push(new_string(format!("[object {}]", tag)))
+}
+
+// BuiltinFunction(35)
+/// new Promise(executor) calls `executor` with the functions that resolve and reject the promise.
+pub unsafe fn promise(args: Vec<Value>, self_: &mut VM) {
+    let executor = args.get(0).cloned().unwrap_or(Value::Undefined);
+    if type_of(&executor) != "function" {
+        let msg = format!("Promise resolver {} is not a function", describe_value(&executor));
+        self_.fatal_error(ErrorKind::TypeError, msg.as_str())
+    }
+    let promise = self_.new_promise();
+    let bind = |id| Value::WithThis(Box::new((Value::BuiltinFunction(id), promise.clone())));
+    let resolving_functions = vec![bind(PROMISE_RESOLVE_FUNCTION), bind(PROMISE_REJECT_FUNCTION)];
+    self_.call_value(executor, resolving_functions);
+    self_.state.stack.push(promise)
+}
+
+// BuiltinFunction(36)
+/// Promise.resolve(val) returns `val` if it is a promise, and else a promise fulfilled with it.
+pub unsafe fn promise_resolve(args: Vec<Value>, self_: &mut VM) {
+    let val = args.get(1).cloned().unwrap_or(Value::Undefined);
+    if self_.is_promise(&val) {
+        return self_.state.stack.push(val);
+    }
+    let promise = self_.new_promise();
+    self_.resolve_promise(&promise, val);
+    self_.state.stack.push(promise)
+}
+
+// BuiltinFunction(37)
+pub unsafe fn promise_reject(args: Vec<Value>, self_: &mut VM) {
+    let promise = self_.new_promise();
+    self_.reject_promise(&promise, args.get(1).cloned().unwrap_or(Value::Undefined));
+    self_.state.stack.push(promise)
+}
+
+// BuiltinFunction(38)
+pub unsafe fn promise_prototype_then(args: Vec<Value>, self_: &mut VM) {
+    let on_fulfilled = args.get(1).cloned().unwrap_or(Value::Undefined);
+    let on_rejected = args.get(2).cloned().unwrap_or(Value::Undefined);
+    then(args, self_, |promise| Reaction::Then(on_fulfilled, on_rejected, promise))
+}
+
+// BuiltinFunction(39)
+pub unsafe fn promise_prototype_catch(args: Vec<Value>, self_: &mut VM) {
+    let on_rejected = args.get(1).cloned().unwrap_or(Value::Undefined);
+    then(args, self_, |promise| Reaction::Then(Value::Undefined, on_rejected, promise))
+}
+
+// BuiltinFunction(40)
+/// Calls the callback once `this` is settled either way. The promise returned settles as `this`
+/// does, and does not wait for a promise that the callback returns.
+pub unsafe fn promise_prototype_finally(args: Vec<Value>, self_: &mut VM) {
+    let callback = args.get(1).cloned().unwrap_or(Value::Undefined);
+    then(args, self_, |promise| Reaction::Finally(callback, promise))
+}
+
+/// Adds the reaction that `make_reaction` makes from a new promise to the promise args[0], and
+/// returns the new promise.
+fn then<F: FnOnce(Value) -> Reaction>(args: Vec<Value>, self_: &mut VM, make_reaction: F) {
+    let this = args.get(0).cloned().unwrap_or(Value::Undefined);
+    if !self_.is_promise(&this) {
+        let msg = format!("{} is not a promise", describe_value(&this));
+        self_.fatal_error(ErrorKind::TypeError, msg.as_str())
+    }
+    let promise = self_.new_promise();
+    self_.add_reaction(&this, make_reaction(promise.clone()));
+    self_.state.stack.push(promise)
+}
+
+// BuiltinFunction(41)
+/// The function that an executor is passed to resolve its promise, which is `this`.
+pub unsafe fn promise_resolve_function(args: Vec<Value>, self_: &mut VM) {
+    let val = args.get(1).cloned().unwrap_or(Value::Undefined);
+    self_.resolve_promise(&args[0], val);
+    self_.state.stack.push(Value::Undefined)
+}
+
+// BuiltinFunction(42)
+pub unsafe fn promise_reject_function(args: Vec<Value>, self_: &mut VM) {
+    let reason = args.get(1).cloned().unwrap_or(Value::Undefined);
+    self_.reject_promise(&args[0], reason);
+    self_.state.stack.push(Value::Undefined)
+}
+
+// BuiltinFunction(43)
+pub unsafe fn string_normalize(args: Vec<Value>, self_: &mut VM) {
+    let s = this_string(&args);
+    let form = match args.get(1) {
+        None | Some(&Value::Undefined) => "NFC".to_string(),
+        Some(form) => form.to_string(),
+    };
+    let normalized = match form.as_str() {
+        "NFC" => s.nfc().collect(),
+        "NFD" => s.nfd().collect(),
+        "NFKC" => s.nfkc().collect(),
+        "NFKD" => s.nfkd().collect(),
+        _ => {
+            self_.fatal_error(
+                ErrorKind::RangeError,
+                "the normalization form should be one of NFC, NFD, NFKC, NFKD"
+            )
+        }
+    };
+    self_.state.stack.push(new_string(normalized))
+}
+
+// BuiltinFunction(44)
+pub unsafe fn string_locale_compare(args: Vec<Value>, self_: &mut VM) {
+    let that = args.get(1).unwrap_or(&Value::Undefined).to_string();
+    let order = match locale_compare(this_string(&args).as_str(), that.as_str()) {
+        Ordering::Less => -1.0,
+        Ordering::Equal => 0.0,
+        Ordering::Greater => 1.0,
+    };
+    self_.state.stack.push(Value::Number(order))
+}
+
+/// Compares strings as a collation for no particular locale would: by their letters first, then
+/// by their accents and then by their case, lower case first. Canonically equivalent strings are
+/// equal.
+fn locale_compare(a: &str, b: &str) -> Ordering {
+    let levels = |s: &str| {
+        let chars = s.nfd().collect::<Vec<char>>();
+        let letters = chars
+            .iter()
+            .filter(|&&c| !is_combining_mark(c))
+            .flat_map(|c| c.to_lowercase())
+            .collect::<String>();
+        let accents = chars.iter().flat_map(|c| c.to_lowercase()).collect::<String>();
+        let cases = chars.iter().map(|c| !c.is_lowercase()).collect::<Vec<bool>>();
+        (letters, accents, cases, chars)
+    };
+    levels(a).cmp(&levels(b))
+}
+
+// BuiltinFunction(45)
+pub unsafe fn array_map(args: Vec<Value>, self_: &mut VM) {
+    let elems = match args.get(0) {
+        Some(&Value::Array(ref map)) => map.borrow().elems.clone(),
+        _ => unreachable!(),
+    };
+    let f = args.get(1).cloned().unwrap_or(Value::Undefined);
+    let elems = elems
+        .into_iter()
+        .enumerate()
+        .map(|(i, elem)| {
+            self_.call_value(f.clone(), vec![elem, Value::Number(i as f64), args[0].clone()])
+        })
+        .collect();
+    self_.stats.allocations += 1;
+    self_
+        .state
+        .stack
+        .push(Value::Array(Rc::new(RefCell::new(ArrayValue::new(elems)))))
+}
@@ -0,0 +1,833 @@
+use bytecode_gen::ByteCode;
+use diagnostic::SourceInfo;
+use error::Span;
+use vm::{
+    ArrayValue, CallFrame, ConstantTable, ObjectTemplate, PropertyKind, PropertyMap, Value, VM,
+};
+use vm_codegen::{FunctionSpans, Scope, SourceMap};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::rc::Rc;
+
+const MAGIC: &'static [u8; 4] = b"RBC\0";
+const FORMAT_VERSION: u32 = 6;
+
+const SNAPSHOT_MAGIC: &'static [u8; 4] = b"RSS\0";
+const SNAPSHOT_VERSION: u32 = 8;
+
+const VALUE_UNDEFINED: u8 = 0;
+const VALUE_BOOL: u8 = 1;
+const VALUE_NUMBER: u8 = 2;
+const VALUE_STRING: u8 = 3;
+const VALUE_FUNCTION: u8 = 4;
+const VALUE_NEED_THIS: u8 = 5;
+const VALUE_WITH_THIS: u8 = 6;
+const VALUE_BUILTIN_FUNCTION: u8 = 7;
+const VALUE_OBJECT: u8 = 8;
+const VALUE_ARRAY: u8 = 9;
+const VALUE_ARGUMENTS: u8 = 10;
+const VALUE_NULL: u8 = 11;
+const VALUE_ACCESSOR: u8 = 12;
+
+const CELL_MAP: u8 = 0;
+const CELL_ARRAY: u8 = 1;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    InvalidMagic,
+    UnsupportedVersion(u32),
+    UnexpectedEOF,
+    InvalidTag(u8),
+    InvalidString,
+    InvalidCell(usize),
+    InvalidIndex(usize), // Past the end of the code or the stack
+}
+
+/// A script compiled ahead of time, stored in a .rbc file.
+#[derive(Debug)]
+pub struct CompiledScript {
+    pub file_name: String,
+    pub source: String, // Kept for diagnostics and coverage reports
+    pub insts: ByteCode,
+    pub const_table: ConstantTable,
+    pub global_varmap: PropertyMap,
+    pub source_map: SourceMap,
+    pub function_spans: FunctionSpans, // For Function.prototype.toString
+    pub scopes: Vec<Scope>,            // For the debugger
+    pub strict: bool,
+}
+
+pub fn is_compiled_script(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+impl CompiledScript {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.bytes(MAGIC);
+        w.u32(FORMAT_VERSION);
+        w.str(self.file_name.as_str());
+        w.str(self.source.as_str());
+        w.u8(self.strict as u8);
+        w.u32(self.insts.len() as u32);
+        w.bytes(&self.insts);
+
+        w.const_table(&self.const_table);
+        w.map(&self.global_varmap);
+        w.source_map(&self.source_map);
+        w.function_spans(&self.function_spans);
+
+        w.u32(self.scopes.len() as u32);
+        for scope in &self.scopes {
+            w.str(scope.name.as_str());
+            w.u32(scope.start as u32);
+            w.u32(scope.end as u32);
+            w.u32(scope.vars.len() as u32);
+            for &(ref name, is_arg, id) in &scope.vars {
+                w.str(name.as_str());
+                w.u8(is_arg as u8);
+                w.u32(id as u32);
+            }
+        }
+
+        w.finish()
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<CompiledScript, Error> {
+        if !is_compiled_script(bytes) {
+            return Err(Error::InvalidMagic);
+        }
+        let mut r = Reader::new(&bytes[MAGIC.len()..]);
+        let version = r.u32()?;
+        if version != FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let file_name = r.string()?;
+        let source = r.string()?;
+        let strict = r.u8()? != 0;
+        let len = r.u32()? as usize;
+        let insts = r.bytes(len)?.to_vec();
+
+        let const_table = r.const_table()?;
+        let global_varmap = r.map()?;
+        let source_map = r.source_map()?;
+        let function_spans = r.function_spans()?;
+
+        let mut scopes = vec![];
+        for _ in 0..r.u32()? {
+            let name = r.string()?;
+            let start = r.u32()? as usize;
+            let end = r.u32()? as usize;
+            let mut vars = vec![];
+            for _ in 0..r.u32()? {
+                let name = r.string()?;
+                let is_arg = r.u8()? != 0;
+                vars.push((name, is_arg, r.u32()? as usize));
+            }
+            scopes.push(Scope::new(name, start, end, vars));
+        }
+
+        r.cells()?;
+
+        Ok(CompiledScript {
+            file_name: file_name,
+            source: source,
+            insts: insts,
+            const_table: const_table,
+            global_varmap: global_varmap,
+            source_map: source_map,
+            function_spans: function_spans,
+            scopes: scopes,
+            strict: strict,
+        })
+    }
+}
+
+/// Returns the state of `vm` as bytes: its code, constant table, global variables and the
+/// objects they refer to. Restoring the snapshot makes VMs in that state without running the
+/// code that built it, e.g. to set up the globals of a script once and reuse them.
+///
+/// The VM must not be running. Code compiled by the JIT is not saved.
+pub fn snapshot(vm: &VM) -> Vec<u8> {
+    let mut w = Writer::new();
+    w.bytes(SNAPSHOT_MAGIC);
+    w.u32(SNAPSHOT_VERSION);
+    w.u8(vm.strict as u8);
+    w.u32(vm.insts.len() as u32);
+    w.bytes(&vm.insts);
+    w.const_table(&vm.const_table);
+
+    // The global object is on the stack too, so it is written as a shared cell.
+    w.value(&Value::Object(vm.global_objects.clone()));
+
+    w.u32(vm.state.stack.len() as u32);
+    for val in &vm.state.stack {
+        w.value(val);
+    }
+    w.u32(vm.state.bp as u32);
+    w.u32(vm.state.lp as u32);
+    w.u32(vm.state.argc as u32);
+    w.u32(vm.state.pc as u32);
+    w.u32(vm.state.history.len() as u32);
+    for frame in &vm.state.history {
+        w.u32(frame.bp as u32);
+        w.u32(frame.lp as u32);
+        w.u32(frame.argc as u32);
+        w.u32(frame.sp as u32);
+        w.u32(frame.return_pc as u32);
+        w.u32(frame.func_pos as u32);
+        match frame.new_this {
+            Some(ref new_this) => {
+                w.u8(1);
+                w.value(new_this);
+            }
+            None => w.u8(0),
+        }
+        w.value(&frame.env);
+    }
+    // The closures made in loops, with the environments they capture.
+    let closures = vm
+        .closure_envs
+        .values()
+        .filter_map(|&(ref env, ref weak)| weak.upgrade().map(|map| (Value::Object(map), env)))
+        .collect::<Vec<(Value, &Value)>>();
+    w.u32(closures.len() as u32);
+    for (closure, env) in closures {
+        w.value(&closure);
+        w.value(env);
+    }
+
+    match vm.source {
+        Some(ref info) => {
+            w.u8(1);
+            w.str(info.file_name.as_str());
+            w.str(info.source.as_str());
+            w.source_map(&info.source_map);
+            w.function_spans(&info.function_spans);
+            w.u32(info.files.len() as u32);
+            for &(start, ref file_name) in &info.files {
+                w.u32(start as u32);
+                w.str(file_name.as_str());
+            }
+        }
+        None => w.u8(0),
+    }
+
+    w.finish()
+}
+
+pub fn is_snapshot(bytes: &[u8]) -> bool {
+    bytes.starts_with(SNAPSHOT_MAGIC)
+}
+
+/// Puts `vm` in the state saved by snapshot(). Options of the VM, such as the JIT and stdout,
+/// are kept. Nothing is changed if the snapshot is invalid.
+pub fn restore_snapshot(bytes: &[u8], vm: &mut VM) -> Result<(), Error> {
+    if !is_snapshot(bytes) {
+        return Err(Error::InvalidMagic);
+    }
+    let mut r = Reader::new(&bytes[SNAPSHOT_MAGIC.len()..]);
+    let version = r.u32()?;
+    if version != SNAPSHOT_VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    let strict = r.u8()? != 0;
+    let len = r.u32()? as usize;
+    let insts = r.bytes(len)?.to_vec();
+    let const_table = r.const_table()?;
+
+    let global_objects = match r.u8()? {
+        VALUE_OBJECT => match r.cell(CELL_MAP)? {
+            Cell::Map(map) => map,
+            _ => unreachable!(),
+        },
+        tag => return Err(Error::InvalidTag(tag)),
+    };
+
+    let mut stack = vec![];
+    for _ in 0..r.u32()? {
+        stack.push(r.value()?);
+    }
+    let bp = r.u32()? as usize;
+    let lp = r.u32()? as usize;
+    let argc = r.u32()? as usize;
+    let pc = r.u32()? as isize;
+    let mut history = vec![];
+    for _ in 0..r.u32()? {
+        let bp = r.u32()? as usize;
+        let lp = r.u32()? as usize;
+        let frame_argc = r.u32()? as usize;
+        let sp = r.u32()? as usize;
+        let return_pc = r.u32()? as isize;
+        let func_pos = r.u32()? as usize;
+        let new_this = match r.u8()? {
+            0 => None,
+            _ => Some(r.value()?),
+        };
+        let env = r.env()?;
+        let mut frame = CallFrame::new(return_pc, func_pos, new_this, env);
+        frame.bp = bp;
+        frame.lp = lp;
+        frame.argc = frame_argc;
+        frame.sp = sp;
+        history.push(frame);
+    }
+    let mut closure_envs = HashMap::new();
+    for _ in 0..r.u32()? {
+        let closure = match r.u8()? {
+            VALUE_OBJECT => match r.cell(CELL_MAP)? {
+                Cell::Map(map) => map,
+                _ => unreachable!(),
+            },
+            tag => return Err(Error::InvalidTag(tag)),
+        };
+        let addr = &*closure as *const RefCell<PropertyMap> as usize;
+        closure_envs.insert(addr, (r.env()?, Rc::downgrade(&closure)));
+    }
+
+    // A corrupted snapshot fails here rather than when the VM indexes the code or the stack with
+    // what it has restored.
+    check_index(pc as usize, insts.len())?;
+    for &n in &[bp, lp, bp + argc] {
+        check_index(n, stack.len() + 1)?;
+    }
+    for frame in &history {
+        check_index(frame.return_pc as usize, insts.len())?;
+        check_index(frame.func_pos, insts.len())?;
+        for &n in &[frame.bp, frame.lp, frame.bp + frame.argc, frame.sp] {
+            check_index(n, stack.len() + 1)?;
+        }
+    }
+
+    let source = match r.u8()? {
+        0 => None,
+        _ => {
+            let file_name = r.string()?;
+            let source = r.string()?;
+            let source_map = r.source_map()?;
+            let function_spans = r.function_spans()?;
+            let mut files = vec![];
+            for _ in 0..r.u32()? {
+                let start = r.u32()? as usize;
+                files.push((start, r.string()?));
+            }
+            Some(SourceInfo {
+                file_name: file_name,
+                source: source,
+                source_map: source_map,
+                function_spans: function_spans,
+                files: files,
+            })
+        }
+    };
+
+    r.cells()?;
+
+    vm.strict = strict;
+    vm.insts = insts;
+    vm.const_table = const_table;
+    vm.global_objects = global_objects;
+    vm.closure_envs = closure_envs;
+    vm.global_slot_cache.clear();
+    vm.loop_bgn_end.clear();
+    vm.state.stack = stack;
+    vm.state.bp = bp;
+    vm.state.lp = lp;
+    vm.state.argc = argc;
+    vm.state.pc = pc;
+    vm.state.history = history;
+    vm.source = source;
+    Ok(())
+}
+
+/// Fails unless `index` is below `len`.
+fn check_index(index: usize, len: usize) -> Result<(), Error> {
+    if index < len {
+        Ok(())
+    } else {
+        Err(Error::InvalidIndex(index))
+    }
+}
+
+// Objects, functions and arrays may be shared or cyclic (e.g. prototype.constructor), so each of
+// them is written once into a table of cells and values refer to it by index.
+#[derive(Clone, Debug)]
+enum Cell {
+    Map(Rc<RefCell<PropertyMap>>),
+    Array(Rc<RefCell<ArrayValue>>),
+}
+
+struct Writer {
+    buf: Vec<u8>,
+    cells: Vec<Cell>,
+    cell_ids: HashMap<usize, usize>, // address of the cell -> index
+}
+
+impl Writer {
+    fn new() -> Writer {
+        Writer {
+            buf: vec![],
+            cells: vec![],
+            cell_ids: HashMap::new(),
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let body = ::std::mem::replace(&mut self.buf, vec![]);
+
+        // Writing a cell may find more cells.
+        let mut cells = vec![];
+        let mut i = 0;
+        while i < self.cells.len() {
+            match self.cells[i].clone() {
+                Cell::Map(map) => {
+                    self.u8(CELL_MAP);
+                    self.map(&*map.borrow());
+                }
+                Cell::Array(arr) => {
+                    let arr = arr.borrow();
+                    self.u8(CELL_ARRAY);
+                    self.u32(arr.elems.len() as u32);
+                    for elem in &arr.elems {
+                        self.value(elem);
+                    }
+                    self.u32(arr.length as u32);
+                    self.map(&arr.obj);
+                }
+            }
+            cells.append(&mut self.buf);
+            i += 1;
+        }
+
+        let mut bytes = body;
+        let cells_len = self.cells.len() as u32;
+        self.u32(cells_len);
+        bytes.append(&mut self.buf);
+        bytes.append(&mut cells);
+        bytes
+    }
+
+    fn u8(&mut self, n: u8) {
+        self.buf.push(n);
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.buf.push((n >> 0) as u8);
+        self.buf.push((n >> 8) as u8);
+        self.buf.push((n >> 16) as u8);
+        self.buf.push((n >> 24) as u8);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn str(&mut self, s: &str) {
+        self.u32(s.len() as u32);
+        self.bytes(s.as_bytes());
+    }
+
+    fn map(&mut self, map: &PropertyMap) {
+        self.u32(map.len() as u32);
+        for (key, val) in map.iter() {
+            self.str(key.as_str());
+            self.value(val);
+        }
+    }
+
+    fn const_table(&mut self, const_table: &ConstantTable) {
+        self.u32(const_table.value.len() as u32);
+        for val in &const_table.value {
+            self.value(val);
+        }
+        self.u32(const_table.string.len() as u32);
+        for s in &const_table.string {
+            self.str(s.as_str());
+        }
+        self.u32(const_table.object_templates.len() as u32);
+        for template in &const_table.object_templates {
+            self.map(&template.props);
+            self.u32(template.dynamic_keys.len() as u32);
+            for &(ref key, kind) in &template.dynamic_keys {
+                self.str(key.as_str());
+                self.u8(kind as u8);
+            }
+        }
+    }
+
+    fn source_map(&mut self, source_map: &SourceMap) {
+        self.u32(source_map.len() as u32);
+        for &(pc, pos) in source_map {
+            self.u32(pc as u32);
+            self.u32(pos as u32);
+        }
+    }
+
+    fn function_spans(&mut self, function_spans: &FunctionSpans) {
+        self.u32(function_spans.len() as u32);
+        for &(pc, span) in function_spans {
+            self.u32(pc as u32);
+            self.u32(span.start as u32);
+            self.u32(span.end as u32);
+        }
+    }
+
+    fn cell(&mut self, addr: usize, cell: Cell) {
+        let id = match self.cell_ids.get(&addr) {
+            Some(id) => *id,
+            None => {
+                let id = self.cells.len();
+                self.cells.push(cell);
+                self.cell_ids.insert(addr, id);
+                id
+            }
+        };
+        self.u32(id as u32);
+    }
+
+    fn value(&mut self, val: &Value) {
+        match val {
+            &Value::Undefined => self.u8(VALUE_UNDEFINED),
+            &Value::Null => self.u8(VALUE_NULL),
+            &Value::Bool(b) => {
+                self.u8(VALUE_BOOL);
+                self.u8(b as u8);
+            }
+            &Value::Number(n) => {
+                let bits = n.to_bits();
+                self.u8(VALUE_NUMBER);
+                self.u32(bits as u32);
+                self.u32((bits >> 32) as u32);
+            }
+            &Value::String(ref s) => {
+                self.u8(VALUE_STRING);
+                self.u32(s.as_bytes().len() as u32);
+                self.bytes(s.as_bytes());
+            }
+            &Value::Function(pos, ref map) => {
+                self.u8(VALUE_FUNCTION);
+                self.u32(pos as u32);
+                self.cell(&**map as *const _ as usize, Cell::Map(map.clone()));
+            }
+            &Value::NeedThis(box ref callee) => {
+                self.u8(VALUE_NEED_THIS);
+                self.value(callee);
+            }
+            &Value::WithThis(box (ref callee, ref this)) => {
+                self.u8(VALUE_WITH_THIS);
+                self.value(callee);
+                self.value(this);
+            }
+            &Value::BuiltinFunction(n) => {
+                self.u8(VALUE_BUILTIN_FUNCTION);
+                self.u32(n as u32);
+            }
+            &Value::Object(ref map) => {
+                self.u8(VALUE_OBJECT);
+                self.cell(&**map as *const _ as usize, Cell::Map(map.clone()));
+            }
+            &Value::Array(ref arr) => {
+                self.u8(VALUE_ARRAY);
+                self.cell(&**arr as *const _ as usize, Cell::Array(arr.clone()));
+            }
+            &Value::Arguments => self.u8(VALUE_ARGUMENTS),
+            &Value::Accessor(box (ref getter, ref setter)) => {
+                self.u8(VALUE_ACCESSOR);
+                self.value(getter);
+                self.value(setter);
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    cells: Vec<Option<Cell>>, // Created when first referred to and filled by cells()
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader {
+            bytes: bytes,
+            pos: 0,
+            cells: vec![],
+        }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.pos + len > self.bytes.len() {
+            return Err(Error::UnexpectedEOF);
+        }
+        let bytes = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let b = self.bytes(4)?;
+        Ok(b[0] as u32 + ((b[1] as u32) << 8) + ((b[2] as u32) << 16) + ((b[3] as u32) << 24))
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec()).map_err(|_| Error::InvalidString)
+    }
+
+    fn map(&mut self) -> Result<PropertyMap, Error> {
+        let mut map = PropertyMap::new();
+        for _ in 0..self.u32()? {
+            let key = self.string()?;
+            let val = self.value()?;
+            map.insert(key, val);
+        }
+        Ok(map)
+    }
+
+    fn const_table(&mut self) -> Result<ConstantTable, Error> {
+        let mut const_table = ConstantTable::new();
+        for _ in 0..self.u32()? {
+            let val = self.value()?;
+            const_table.value.push(val);
+        }
+        for _ in 0..self.u32()? {
+            let s = self.string()?;
+            const_table.string.push(s);
+        }
+        for _ in 0..self.u32()? {
+            let props = self.map()?;
+            let mut dynamic_keys = vec![];
+            for _ in 0..self.u32()? {
+                let key = self.string()?;
+                let kind = match self.u8()? {
+                    0 => PropertyKind::Value,
+                    1 => PropertyKind::Getter,
+                    2 => PropertyKind::Setter,
+                    tag => return Err(Error::InvalidTag(tag)),
+                };
+                dynamic_keys.push((key, kind));
+            }
+            const_table.object_templates.push(ObjectTemplate {
+                props: props,
+                dynamic_keys: dynamic_keys,
+            });
+        }
+        Ok(const_table)
+    }
+
+    fn source_map(&mut self) -> Result<SourceMap, Error> {
+        let mut source_map = vec![];
+        for _ in 0..self.u32()? {
+            let pc = self.u32()? as usize;
+            let pos = self.u32()? as usize;
+            source_map.push((pc, pos));
+        }
+        Ok(source_map)
+    }
+
+    fn function_spans(&mut self) -> Result<FunctionSpans, Error> {
+        let mut function_spans = vec![];
+        for _ in 0..self.u32()? {
+            let pc = self.u32()? as usize;
+            let start = self.u32()? as usize;
+            function_spans.push((pc, Span::new(start, self.u32()? as usize)));
+        }
+        Ok(function_spans)
+    }
+
+    fn cell(&mut self, tag: u8) -> Result<Cell, Error> {
+        let id = self.u32()? as usize;
+        // Each cell not read yet takes a byte at least, so ids past the bytes left are invalid
+        // rather than making a table that large.
+        if id >= self.cells.len() + (self.bytes.len() - self.pos) {
+            return Err(Error::InvalidCell(id));
+        }
+        if id >= self.cells.len() {
+            self.cells.resize(id + 1, None);
+        }
+        let cell = self.cells[id]
+            .get_or_insert_with(|| match tag {
+                CELL_MAP => Cell::Map(Rc::new(RefCell::new(PropertyMap::new()))),
+                _ => Cell::Array(Rc::new(RefCell::new(ArrayValue::new(vec![])))),
+            })
+            .clone();
+        match (tag, &cell) {
+            (CELL_MAP, &Cell::Map(_)) | (CELL_ARRAY, &Cell::Array(_)) => Ok(cell.clone()),
+            _ => Err(Error::InvalidCell(id)),
+        }
+    }
+
+    fn cells(&mut self) -> Result<(), Error> {
+        let len = self.u32()? as usize;
+        if len < self.cells.len() {
+            return Err(Error::InvalidCell(len));
+        }
+        for id in 0..len {
+            let tag = self.u8()?;
+            if id >= self.cells.len() {
+                self.cells.resize(id + 1, None);
+            }
+            match self.cells[id].clone() {
+                Some(Cell::Map(ref map)) if tag == CELL_MAP => {
+                    let contents = self.map()?;
+                    *map.borrow_mut() = contents;
+                }
+                Some(Cell::Array(ref arr)) if tag == CELL_ARRAY => {
+                    let mut elems = vec![];
+                    for _ in 0..self.u32()? {
+                        elems.push(self.value()?);
+                    }
+                    let length = self.u32()? as usize;
+                    let obj = self.map()?;
+                    *arr.borrow_mut() = ArrayValue {
+                        elems: elems,
+                        length: length,
+                        obj: obj,
+                    };
+                }
+                // Every cell is referred to before it is written.
+                _ => return Err(Error::InvalidCell(id)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the environment of a closure or a frame: an object, or undefined if there is none.
+    fn env(&mut self) -> Result<Value, Error> {
+        match self.u8()? {
+            VALUE_UNDEFINED => Ok(Value::Undefined),
+            VALUE_OBJECT => match self.cell(CELL_MAP)? {
+                Cell::Map(map) => Ok(Value::Object(map)),
+                _ => unreachable!(),
+            },
+            tag => Err(Error::InvalidTag(tag)),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, Error> {
+        match self.u8()? {
+            VALUE_UNDEFINED => Ok(Value::Undefined),
+            VALUE_BOOL => Ok(Value::Bool(self.u8()? != 0)),
+            VALUE_NUMBER => {
+                let lo = self.u32()? as u64;
+                let hi = self.u32()? as u64;
+                Ok(Value::Number(f64::from_bits(lo | (hi << 32))))
+            }
+            VALUE_STRING => {
+                let len = self.u32()? as usize;
+                let s = CString::new(self.bytes(len)?.to_vec()).map_err(|_| Error::InvalidString)?;
+                Ok(Value::String(s))
+            }
+            VALUE_FUNCTION => {
+                let pos = self.u32()? as usize;
+                match self.cell(CELL_MAP)? {
+                    Cell::Map(map) => Ok(Value::Function(pos, map)),
+                    _ => unreachable!(),
+                }
+            }
+            VALUE_NEED_THIS => Ok(Value::NeedThis(Box::new(self.value()?))),
+            VALUE_WITH_THIS => {
+                let callee = self.value()?;
+                let this = self.value()?;
+                Ok(Value::WithThis(Box::new((callee, this))))
+            }
+            VALUE_BUILTIN_FUNCTION => Ok(Value::BuiltinFunction(self.u32()? as usize)),
+            VALUE_OBJECT => match self.cell(CELL_MAP)? {
+                Cell::Map(map) => Ok(Value::Object(map)),
+                _ => unreachable!(),
+            },
+            VALUE_ARRAY => match self.cell(CELL_ARRAY)? {
+                Cell::Array(arr) => Ok(Value::Array(arr)),
+                _ => unreachable!(),
+            },
+            VALUE_ARGUMENTS => Ok(Value::Arguments),
+            VALUE_NULL => Ok(Value::Null),
+            VALUE_ACCESSOR => {
+                let getter = self.value()?;
+                let setter = self.value()?;
+                Ok(Value::Accessor(Box::new((getter, setter))))
+            }
+            tag => Err(Error::InvalidTag(tag)),
+        }
+    }
+}
+
+#[test]
+fn roundtrip() {
+    use vm::new_value_function;
+
+    let func = new_value_function(0x10);
+    let mut const_table = ConstantTable::new();
+    const_table.value.push(Value::Number(1.5));
+    const_table.value.push(Value::String(CString::new("hello").unwrap()));
+    const_table.value.push(func.clone());
+    const_table.value.push(Value::Null);
+    const_table.string.push("f".to_string());
+    let mut global_varmap = PropertyMap::new();
+    global_varmap.insert("f".to_string(), Value::NeedThis(Box::new(func)));
+
+    let script = CompiledScript {
+        file_name: "a.js".to_string(),
+        source: "f()".to_string(),
+        insts: vec![1, 2, 3],
+        const_table: const_table,
+        global_varmap: global_varmap,
+        source_map: vec![(0, 0)],
+        function_spans: vec![(0x10, Span::new(0, 3))],
+        scopes: vec![Scope::new(
+            "(toplevel)".to_string(),
+            0,
+            3,
+            vec![("this".to_string(), true, 0)],
+        )],
+        strict: true,
+    };
+    let bytes = script.serialize();
+    assert!(is_compiled_script(&bytes));
+    let loaded = CompiledScript::deserialize(&bytes).unwrap();
+    assert_eq!(loaded.insts, vec![1, 2, 3]);
+    assert_eq!(loaded.source_map, vec![(0, 0)]);
+    assert_eq!(loaded.function_spans, script.function_spans);
+    assert!(loaded.strict);
+    assert_eq!(loaded.scopes, script.scopes);
+    assert_eq!(loaded.const_table.string, vec!["f".to_string()]);
+    assert_eq!(loaded.const_table.value[3], Value::Null);
+    assert_eq!(loaded.serialize(), bytes);
+
+    // The function in the constant table and the global variable are the same object, and its
+    // prototype refers back to it.
+    let props = match (&loaded.const_table.value[2], &loaded.global_varmap["f"]) {
+        (&Value::Function(0x10, ref a), &Value::NeedThis(box Value::Function(0x10, ref b))) => {
+            assert!(Rc::ptr_eq(a, b));
+            a.clone()
+        }
+        _ => panic!(),
+    };
+    let proto = match props.borrow()["prototype"] {
+        Value::Object(ref proto) => proto.clone(),
+        _ => panic!(),
+    };
+    match proto.borrow()["constructor"] {
+        Value::Function(_, ref ctor) => assert!(Rc::ptr_eq(ctor, &props)),
+        _ => panic!(),
+    };
+
+    assert_eq!(
+        CompiledScript::deserialize(&bytes[..bytes.len() - 1]).err(),
+        Some(Error::UnexpectedEOF)
+    );
+    let function = [VALUE_FUNCTION, 0x10, 0, 0, 0, 0, 0, 0, 0];
+    let pos = bytes.windows(function.len()).position(|w| w == function).unwrap();
+    let mut corrupted = bytes.clone();
+    corrupted[pos + 5..pos + 9].copy_from_slice(&[0xff, 0xff, 0xff, 0x7f]);
+    assert_eq!(
+        CompiledScript::deserialize(&corrupted).err(),
+        Some(Error::InvalidCell(0x7fffffff))
+    );
+}
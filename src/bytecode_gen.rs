@@ -1,10 +1,15 @@
 use id::Id;
+use std::collections::HashMap;
 use vm::{
-    ConstantTable, PUSH_INT32, PUSH_INT8, Value, ADD, ASG_FREST_PARAM, CALL, CONSTRUCT,
-    CREATE_ARRAY, CREATE_CONTEXT, CREATE_OBJECT, DIV, END, EQ, GE, GET_ARG_LOCAL, GET_GLOBAL,
-    GET_LOCAL, GET_MEMBER, GT, JMP, JMP_IF_FALSE, LE, LT, MUL, NE, NEG, PUSH_ARGUMENTS, PUSH_CONST,
-    PUSH_FALSE, PUSH_THIS, PUSH_TRUE, REM, RETURN, SEQ, SET_ARG_LOCAL, SET_GLOBAL, SET_LOCAL,
-    SET_MEMBER, SNE, SUB,
+    inst_len, quote_string, ConstantTable, ObjectTemplate, INSTRUCTIONS, PUSH_INT32, PUSH_INT8,
+    Value, ADD, ARRAY_SPREAD, ASG_FREST_PARAM, ASSIGN_TO_CONST, AWAIT, CALL, CALL_DIRECT,
+    CALL_METHOD, CALL_SPREAD, CHECK_INITIALIZED, CONSTRUCT, CONSTRUCT_SPREAD, COPY_ENV,
+    CREATE_ARRAY, CREATE_CONTEXT, CREATE_OBJECT, DELETE_MEMBER, DIV, END, EQ, GE, GET_ARG_LOCAL,
+    GET_ENV, GET_ENV_VAR, GET_GLOBAL, GET_GLOBAL_OR_UNDEFINED, GET_ITERATOR, GET_KEY_ITERATOR,
+    GET_LOCAL, GET_MEMBER, GET_WITH_OBJECT, GT, IN, INSTANCE_OF, ITER_NEXT, JMP, JMP_IF_FALSE,
+    JMP_TABLE, LE, LT, MAKE_CLOSURE, MUL, NE, NEG, NEW_ENV, NEW_PROMISE, NOT, OBJECT_SPREAD, POP,
+    PUSH_ARGUMENTS, PUSH_CONST, PUSH_FALSE, PUSH_THIS, PUSH_TRUE, REM, RESOLVE_PROMISE, RETURN,
+    SEQ, SET_ARG_LOCAL, SET_ENV_VAR, SET_GLOBAL, SET_LOCAL, SET_MEMBER, SNE, SUB, TYPEOF,
 };
 
 pub type ByteCode = Vec<u8>;
@@ -12,13 +17,91 @@ pub type ByteCode = Vec<u8>;
 #[derive(Debug, Clone)]
 pub struct ByteCodeGen {
     pub const_table: ConstantTable,
+    interned_values: HashMap<ConstKey, usize>,
+    interned_strings: HashMap<String, usize>,
+}
+
+/// Key used to share one constant table entry between all uses of the same primitive literal.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ConstKey {
+    Undefined,
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
 }
 
 impl ByteCodeGen {
     pub fn new() -> ByteCodeGen {
         ByteCodeGen {
             const_table: ConstantTable::new(),
+            interned_values: HashMap::new(),
+            interned_strings: HashMap::new(),
+        }
+    }
+
+    /// Returns a ByteCodeGen that appends to `const_table`, so that the code it generates can
+    /// run along with the code already using the table. Constants already in the table are
+    /// shared.
+    pub fn with_const_table(const_table: ConstantTable) -> ByteCodeGen {
+        let mut gen = ByteCodeGen::new();
+        for (id, val) in const_table.value.iter().enumerate() {
+            if let Some(key) = const_key(val) {
+                gen.interned_values.entry(key).or_insert(id);
+            }
+        }
+        for (id, s) in const_table.string.iter().enumerate() {
+            gen.interned_strings.entry(s.clone()).or_insert(id);
         }
+        gen.const_table = const_table;
+        gen
+    }
+}
+
+/// Returns the key of `val` if it is a primitive, which is shared in the constant table.
+fn const_key(val: &Value) -> Option<ConstKey> {
+    match *val {
+        Value::Undefined => Some(ConstKey::Undefined),
+        Value::Null => Some(ConstKey::Null),
+        Value::Bool(b) => Some(ConstKey::Bool(b)),
+        Value::Number(n) => Some(ConstKey::Number(n.to_bits())),
+        Value::String(ref s) => Some(ConstKey::String(s.to_str().unwrap().to_string())),
+        _ => None,
+    }
+}
+
+impl ByteCodeGen {
+    pub fn intern_value(&mut self, val: Value) -> usize {
+        let key = const_key(&val);
+
+        if let Some(ref key) = key {
+            if let Some(id) = self.interned_values.get(key) {
+                return *id;
+            }
+        }
+
+        let id = self.const_table.value.len();
+        self.const_table.value.push(val);
+        if let Some(key) = key {
+            self.interned_values.insert(key, id);
+        }
+        id
+    }
+
+    pub fn intern_string(&mut self, s: String) -> usize {
+        if let Some(id) = self.interned_strings.get(s.as_str()) {
+            return *id;
+        }
+        let id = self.const_table.string.len();
+        self.const_table.string.push(s.clone());
+        self.interned_strings.insert(s, id);
+        id
+    }
+
+    pub fn add_object_template(&mut self, template: ObjectTemplate) -> usize {
+        let id = self.const_table.object_templates.len();
+        self.const_table.object_templates.push(template);
+        id
     }
 }
 
@@ -27,9 +110,15 @@ impl ByteCodeGen {
         insts.push(END);
     }
 
-    pub fn gen_create_context(&self, num_local_var: usize, insts: &mut ByteCode) {
+    pub fn gen_create_context(
+        &self,
+        num_local_var: usize,
+        num_params: usize,
+        insts: &mut ByteCode,
+    ) {
         insts.push(CREATE_CONTEXT);
         self.gen_int32(num_local_var as i32, insts);
+        self.gen_int32(num_params as i32, insts);
     }
 
     pub fn gen_constract(&self, argc: usize, insts: &mut ByteCode) {
@@ -37,9 +126,9 @@ impl ByteCodeGen {
         self.gen_int32(argc as i32, insts);
     }
 
-    pub fn gen_create_object(&self, len: usize, insts: &mut ByteCode) {
+    pub fn gen_create_object(&self, template_id: usize, insts: &mut ByteCode) {
         insts.push(CREATE_OBJECT);
-        self.gen_int32(len as i32, insts);
+        self.gen_int32(template_id as i32, insts);
     }
 
     pub fn gen_create_array(&self, len: usize, insts: &mut ByteCode) {
@@ -63,8 +152,7 @@ impl ByteCodeGen {
 
     pub fn gen_push_const(&mut self, val: Value, insts: &mut ByteCode) {
         insts.push(PUSH_CONST);
-        let id = self.const_table.value.len();
-        self.const_table.value.push(val);
+        let id = self.intern_value(val);
         self.gen_int32(id as i32, insts);
     }
 
@@ -80,6 +168,26 @@ impl ByteCodeGen {
         insts.push(NEG);
     }
 
+    pub fn gen_not(&self, insts: &mut ByteCode) {
+        insts.push(NOT);
+    }
+
+    pub fn gen_in(&self, insts: &mut ByteCode) {
+        insts.push(IN);
+    }
+
+    pub fn gen_instance_of(&self, insts: &mut ByteCode) {
+        insts.push(INSTANCE_OF);
+    }
+
+    pub fn gen_delete_member(&self, insts: &mut ByteCode) {
+        insts.push(DELETE_MEMBER);
+    }
+
+    pub fn gen_typeof(&self, insts: &mut ByteCode) {
+        insts.push(TYPEOF);
+    }
+
     pub fn gen_add(&self, insts: &mut ByteCode) {
         insts.push(ADD);
     }
@@ -130,15 +238,67 @@ impl ByteCodeGen {
 
     pub fn gen_get_global(&mut self, name: String, insts: &mut ByteCode) {
         insts.push(GET_GLOBAL);
-        let id = self.const_table.string.len();
-        self.const_table.string.push(name);
+        let id = self.intern_string(name);
+        self.gen_int32(id as i32, insts);
+    }
+
+    pub fn gen_get_global_or_undefined(&mut self, name: String, insts: &mut ByteCode) {
+        insts.push(GET_GLOBAL_OR_UNDEFINED);
+        let id = self.intern_string(name);
+        self.gen_int32(id as i32, insts);
+    }
+
+    /// Generates a check that the variable `name` declared with let or const has been
+    /// initialized, which takes true if it has.
+    pub fn gen_check_initialized(&mut self, name: String, insts: &mut ByteCode) {
+        insts.push(CHECK_INITIALIZED);
+        let id = self.intern_string(name);
+        self.gen_int32(id as i32, insts);
+    }
+
+    pub fn gen_assign_to_const(&mut self, name: String, insts: &mut ByteCode) {
+        insts.push(ASSIGN_TO_CONST);
+        let id = self.intern_string(name);
         self.gen_int32(id as i32, insts);
     }
 
     pub fn gen_set_global(&mut self, name: String, insts: &mut ByteCode) {
         insts.push(SET_GLOBAL);
-        let id = self.const_table.string.len();
-        self.const_table.string.push(name);
+        let id = self.intern_string(name);
+        self.gen_int32(id as i32, insts);
+    }
+
+    pub fn gen_get_env(&self, insts: &mut ByteCode) {
+        insts.push(GET_ENV);
+    }
+
+    pub fn gen_new_env(&self, insts: &mut ByteCode) {
+        insts.push(NEW_ENV);
+    }
+
+    pub fn gen_copy_env(&self, insts: &mut ByteCode) {
+        insts.push(COPY_ENV);
+    }
+
+    pub fn gen_get_env_var(&mut self, name: String, insts: &mut ByteCode) {
+        insts.push(GET_ENV_VAR);
+        let id = self.intern_string(name);
+        self.gen_int32(id as i32, insts);
+    }
+
+    pub fn gen_set_env_var(&mut self, name: String, insts: &mut ByteCode) {
+        insts.push(SET_ENV_VAR);
+        let id = self.intern_string(name);
+        self.gen_int32(id as i32, insts);
+    }
+
+    pub fn gen_make_closure(&self, insts: &mut ByteCode) {
+        insts.push(MAKE_CLOSURE);
+    }
+
+    pub fn gen_get_with_object(&mut self, name: String, insts: &mut ByteCode) {
+        insts.push(GET_WITH_OBJECT);
+        let id = self.intern_string(name);
         self.gen_int32(id as i32, insts);
     }
 
@@ -167,6 +327,47 @@ impl ByteCodeGen {
         self.gen_int32(argc as i32, insts);
     }
 
+    pub fn gen_call_direct(&self, dst: i32, argc: u32, insts: &mut ByteCode) {
+        insts.push(CALL_DIRECT);
+        self.gen_int32(dst, insts);
+        self.gen_int32(argc as i32, insts);
+    }
+
+    pub fn gen_object_spread(&self, insts: &mut ByteCode) {
+        insts.push(OBJECT_SPREAD);
+    }
+
+    pub fn gen_array_spread(&self, insts: &mut ByteCode) {
+        insts.push(ARRAY_SPREAD);
+    }
+
+    pub fn gen_call_spread(&self, insts: &mut ByteCode) {
+        insts.push(CALL_SPREAD);
+    }
+
+    pub fn gen_construct_spread(&self, insts: &mut ByteCode) {
+        insts.push(CONSTRUCT_SPREAD);
+    }
+
+    pub fn gen_call_method(&self, argc: u32, name_id: usize, insts: &mut ByteCode) {
+        insts.push(CALL_METHOD);
+        self.gen_int32(argc as i32, insts);
+        self.gen_int32(name_id as i32, insts);
+    }
+
+    pub fn gen_get_iterator(&self, insts: &mut ByteCode) {
+        insts.push(GET_ITERATOR);
+    }
+
+    pub fn gen_get_key_iterator(&self, insts: &mut ByteCode) {
+        insts.push(GET_KEY_ITERATOR);
+    }
+
+    pub fn gen_iter_next(&self, dst: i32, insts: &mut ByteCode) {
+        insts.push(ITER_NEXT);
+        self.gen_int32(dst, insts);
+    }
+
     pub fn gen_jmp(&self, dst: i32, insts: &mut ByteCode) {
         insts.push(JMP);
         self.gen_int32(dst, insts);
@@ -177,10 +378,38 @@ impl ByteCodeGen {
         self.gen_int32(dst, insts);
     }
 
+    /// Generates a JMP_TABLE with `len` offsets for the integers from `min`, all of which are 0
+    /// until they are replaced.
+    pub fn gen_jmp_table(&self, min: i32, len: usize, insts: &mut ByteCode) {
+        insts.push(JMP_TABLE);
+        self.gen_int32(min, insts);
+        self.gen_int32(len as i32, insts);
+        for _ in 0..len + 1 {
+            self.gen_int32(0, insts);
+        }
+    }
+
     pub fn gen_return(&self, insts: &mut ByteCode) {
         insts.push(RETURN);
     }
 
+    pub fn gen_new_promise(&self, insts: &mut ByteCode) {
+        insts.push(NEW_PROMISE);
+    }
+
+    pub fn gen_await(&self, promise_id: u32, insts: &mut ByteCode) {
+        insts.push(AWAIT);
+        self.gen_int32(promise_id as i32, insts);
+    }
+
+    pub fn gen_resolve_promise(&self, insts: &mut ByteCode) {
+        insts.push(RESOLVE_PROMISE);
+    }
+
+    pub fn gen_pop(&self, insts: &mut ByteCode) {
+        insts.push(POP);
+    }
+
     pub fn gen_assign_func_rest_param(
         &self,
         num_func_params: usize,
@@ -221,155 +450,130 @@ pub fn slice_to_int32(insts: &[u8]) -> i32 {
 }
 
 pub fn show(code: &ByteCode) {
+    print!("{}", disassemble(code))
+}
+
+/// Returns the instructions in `code`, one per line, each prefixed with its address.
+pub fn disassemble(code: &ByteCode) -> String {
+    let mut out = String::new();
     let mut i = 0;
     while i < code.len() {
-        print!("{:04x} ", i);
-        match code[i] {
-            END => {
-                println!("End");
-                i += 1
-            }
-            CREATE_CONTEXT => {
-                println!("CreateContext");
-                i += 5
-            }
-            CONSTRUCT => {
-                println!("Construct");
-                i += 5
-            }
+        out += format!("{:04x} {}\n", i, INSTRUCTIONS[code[i] as usize].name).as_str();
+        i += inst_len(code, i)
+    }
+    out
+}
+
+/// Returns how many values running `code` from start to end leaves on the stack, or minus how
+/// many it takes off. Jumps are not followed; both branches of conditional code leave the same
+/// values, so running them one after another gives the effect of either.
+pub fn stack_effect(code: &[u8], const_table: &ConstantTable) -> isize {
+    let int32_at = |i: usize| {
+        code[i] as usize
+            + ((code[i + 1] as usize) << 8)
+            + ((code[i + 2] as usize) << 16)
+            + ((code[i + 3] as usize) << 24)
+    };
+    let mut effect = 0;
+    let mut i = 0;
+    while i < code.len() {
+        let (pushed, popped) = match code[i] {
+            END | ASG_FREST_PARAM | JMP => (0, 0),
+            // Only at the start of a function. Takes the number of arguments off the stack.
+            CREATE_CONTEXT => (0, 1),
+            CONSTRUCT | CALL => (1, int32_at(i + 1) + 1),
+            CALL_DIRECT => (1, int32_at(i + 5)),
+            CALL_METHOD => (1, int32_at(i + 1) + 1),
+            CREATE_ARRAY => (1, int32_at(i + 1)),
             CREATE_OBJECT => {
-                println!("CreateObject");
-                i += 5
-            }
-            PUSH_INT8 => {
-                println!("PushInt8");
-                i += 2
-            }
-            PUSH_INT32 => {
-                println!("PushInt32");
-                i += 5
-            }
-            PUSH_FALSE => {
-                println!("PushFalse");
-                i += 1
-            }
-            PUSH_TRUE => {
-                println!("PushFalse");
-                i += 1
-            }
-            PUSH_CONST => {
-                println!("PushConst");
-                i += 5
-            }
-            PUSH_THIS => {
-                println!("PushThis");
-                i += 1
-            }
-            PUSH_ARGUMENTS => {
-                println!("PushArguments");
-                i += 1
-            }
-            NEG => {
-                println!("Neg");
-                i += 1
-            }
-            ADD => {
-                println!("Add");
-                i += 1
-            }
-            SUB => {
-                println!("Sub");
-                i += 1
-            }
-            MUL => {
-                println!("Mul");
-                i += 1
-            }
-            DIV => {
-                println!("Div");
-                i += 1
-            }
-            REM => {
-                println!("Rem");
-                i += 1
-            }
-            LT => {
-                println!("Lt");
-                i += 1
-            }
-            GT => {
-                println!("Gt");
-                i += 1
-            }
-            LE => {
-                println!("Le");
-                i += 1
-            }
-            GE => {
-                println!("Ge");
-                i += 1
-            }
-            EQ => {
-                println!("Eq");
-                i += 1
-            }
-            NE => {
-                println!("Ne");
-                i += 1
-            }
-            GET_MEMBER => {
-                println!("GetMember");
-                i += 1
-            }
-            SET_MEMBER => {
-                println!("SetMember");
-                i += 1
-            }
-            GET_GLOBAL => {
-                println!("GetGlobal");
-                i += 5
-            }
-            SET_GLOBAL => {
-                println!("SetGlobal");
-                i += 5
-            }
-            GET_LOCAL => {
-                println!("GetLocal",);
-                i += 5
-            }
-            SET_LOCAL => {
-                println!("SetLocal",);
-                i += 5
-            }
-            GET_ARG_LOCAL => {
-                println!("GetArgLocal",);
-                i += 5
-            }
-            SET_ARG_LOCAL => {
-                println!("SetArgLocal",);
-                i += 5
-            }
-            JMP_IF_FALSE => {
-                println!("JmpIfFalse");
-                i += 5
-            }
-            JMP => {
-                println!("Jmp");
-                i += 5
-            }
-            CALL => {
-                println!("Call");
-                i += 5
-            }
-            RETURN => {
-                println!("Return");
-                i += 1
-            }
-            ASG_FREST_PARAM => {
-                println!("AssignFunctionRestParam");
-                i += 9
-            }
-            _ => unreachable!(),
+                let template = &const_table.object_templates[int32_at(i + 1)];
+                (1, template.dynamic_keys.len())
+            }
+            PUSH_INT8 | PUSH_INT32 | PUSH_CONST | PUSH_FALSE | PUSH_TRUE | PUSH_THIS
+            | PUSH_ARGUMENTS | GET_GLOBAL | GET_GLOBAL_OR_UNDEFINED | GET_LOCAL | GET_ARG_LOCAL => {
+                (1, 0)
+            }
+            NEG | NOT | TYPEOF => (1, 1),
+            // Takes the awaited value, and pushes the value it resolves to once resumed.
+            AWAIT => (1, 1),
+            NEW_PROMISE => (1, 0),
+            // Takes the value and the promise to resolve with it, and pushes the promise.
+            RESOLVE_PROMISE => (1, 2),
+            ADD | SUB | MUL | DIV | REM | LT | GT | LE | GE | EQ | NE | SEQ | SNE | GET_MEMBER
+            | OBJECT_SPREAD | IN | INSTANCE_OF | DELETE_MEMBER | ARRAY_SPREAD => (1, 2),
+            // Takes the function and the array of its arguments.
+            CALL_SPREAD | CONSTRUCT_SPREAD => (1, 2),
+            SET_MEMBER => (0, 3),
+            SET_GLOBAL | SET_LOCAL | SET_ARG_LOCAL | JMP_IF_FALSE | JMP_TABLE | CHECK_INITIALIZED
+            | ASSIGN_TO_CONST => (0, 1),
+            // Takes the iterated value and pushes it, or its keys, with the position of its next
+            // element.
+            GET_ITERATOR | GET_KEY_ITERATOR => (2, 1),
+            // Pushes the next element, unless it jumps out of the loop.
+            ITER_NEXT => (1, 0),
+            RETURN | POP => (0, 1),
+            GET_ENV => (1, 0),
+            NEW_ENV | COPY_ENV | GET_ENV_VAR | GET_WITH_OBJECT => (1, 1),
+            // Takes the value and the environment.
+            SET_ENV_VAR => (0, 2),
+            // Takes the function and the environment.
+            MAKE_CLOSURE => (1, 2),
+            op => unreachable!("unknown instruction {:02x}", op),
+        };
+        effect += pushed as isize - popped as isize;
+        i += inst_len(code, i);
+    }
+    effect
+}
+
+pub fn show_const_table(const_table: &ConstantTable) {
+    for (i, val) in const_table.value.iter().enumerate() {
+        println!("value  {:04} {}", i, show_const_value(val));
+    }
+    for (i, s) in const_table.string.iter().enumerate() {
+        println!("string {:04} {}", i, quote_string(s, MAX_SHOWN_STRING_LEN));
+    }
+    for (i, template) in const_table.object_templates.iter().enumerate() {
+        let mut props = template
+            .props
+            .iter()
+            .map(|(key, val)| format!("{}: {}", key, show_const_value(val)))
+            .collect::<Vec<String>>();
+        props.sort();
+        println!(
+            "object {:04} {{{}}} dynamic: {:?}",
+            i,
+            props.join(", "),
+            template.dynamic_keys
+        );
+    }
+}
+
+/// Strings longer than this are cut short in dumps of the constant table and in crash reports.
+pub const MAX_SHOWN_STRING_LEN: usize = 60;
+
+// Functions and objects may refer to themselves (e.g. prototype.constructor), so their contents
+// are not printed.
+fn show_const_value(val: &Value) -> String {
+    match val {
+        &Value::Undefined => "undefined".to_string(),
+        &Value::Null => "null".to_string(),
+        &Value::Bool(b) => b.to_string(),
+        &Value::Number(n) => n.to_string(),
+        &Value::String(ref s) => quote_string(s.to_str().unwrap(), MAX_SHOWN_STRING_LEN),
+        &Value::Function(pos, _) => format!("Function({:04x})", pos),
+        &Value::NeedThis(box ref callee) => format!("NeedThis({})", show_const_value(callee)),
+        &Value::WithThis(box (ref callee, _)) => format!("WithThis({})", show_const_value(callee)),
+        &Value::BuiltinFunction(n) => format!("BuiltinFunction({})", n),
+        &Value::Object(ref obj) => {
+            let mut keys = obj.borrow().keys().collect::<Vec<String>>();
+            keys.sort();
+            format!("Object{{{}}}", keys.join(", "))
+        }
+        &Value::Array(ref arr) => format!("Array({})", arr.borrow().length),
+        &Value::Arguments => "Arguments".to_string(),
+        &Value::Accessor(box (ref getter, ref setter)) => {
+            format!("Accessor({}, {})", show_const_value(getter), show_const_value(setter))
         }
     }
 }
@@ -0,0 +1,92 @@
+use lexer::get_line_and_column;
+use vm_codegen::SourceMap;
+
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone)]
+pub struct Coverage {
+    pub hits: Vec<u64>, // Execution count of the instruction starting at each address
+}
+
+impl Coverage {
+    pub fn new(insts_len: usize) -> Coverage {
+        Coverage {
+            hits: vec![0; insts_len],
+        }
+    }
+
+    #[inline]
+    pub fn hit(&mut self, pc: usize) {
+        self.hits[pc] += 1;
+    }
+
+    pub fn executed_instructions(&self) -> Vec<usize> {
+        self.hits
+            .iter()
+            .enumerate()
+            .filter(|&(_, count)| *count > 0)
+            .map(|(pc, _)| pc)
+            .collect()
+    }
+
+    /// Returns the execution count of every line that has code. A line counts as executed as
+    /// many times as the most executed instruction mapped to it.
+    pub fn line_hits(&self, source: &str, source_map: &SourceMap) -> BTreeMap<usize, u64> {
+        let mut lines = BTreeMap::new();
+        for &(pc, pos) in source_map {
+            let (line, _) = get_line_and_column(source, pos);
+            let count = lines.entry(line).or_insert(0);
+            *count = (*count).max(self.hits.get(pc).map(|c| *c).unwrap_or(0));
+        }
+        lines
+    }
+
+    pub fn to_lcov(&self, file_name: &str, source: &str, source_map: &SourceMap) -> String {
+        let lines = self.line_hits(source, source_map);
+        let mut report = format!("TN:\nSF:{}\n", file_name);
+        for (line, count) in &lines {
+            report += format!("DA:{},{}\n", line, count).as_str();
+        }
+        report += format!(
+            "LF:{}\nLH:{}\nend_of_record\n",
+            lines.len(),
+            lines.values().filter(|count| **count > 0).count()
+        ).as_str();
+        report
+    }
+
+    pub fn to_json(&self, file_name: &str, source: &str, source_map: &SourceMap) -> String {
+        let lines = self
+            .line_hits(source, source_map)
+            .iter()
+            .map(|(line, count)| format!("\"{}\":{}", line, count))
+            .collect::<Vec<String>>()
+            .join(",");
+        let insts = self
+            .executed_instructions()
+            .iter()
+            .map(|pc| pc.to_string())
+            .collect::<Vec<String>>()
+            .join(",");
+        format!(
+            "{{\"file\":\"{}\",\"lines\":{{{}}},\"executed_instructions\":[{}]}}\n",
+            escape_json_string(file_name),
+            lines,
+            insts
+        )
+    }
+}
+
+fn escape_json_string(s: &str) -> String {
+    let mut escaped = String::new();
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if (c as u32) < 0x20 => escaped.push_str(format!("\\u{:04x}", c as u32).as_str()),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,142 @@
+use bytecode_gen;
+use vm::{quote_string, Value, VM};
+
+use libc;
+
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use std::panic;
+use std::path::PathBuf;
+use std::process;
+use std::ptr;
+
+const VERSION_STR: &'static str = env!("CARGO_PKG_VERSION");
+
+// The VM whose state goes into crash reports. Only set while it runs.
+static mut CURRENT_VM: *const VM = ptr::null();
+
+/// Makes `vm` the VM described by crash reports until `unwatch` is called.
+pub fn watch(vm: &VM) {
+    unsafe { CURRENT_VM = vm as *const VM }
+}
+
+pub fn unwatch() {
+    unsafe { CURRENT_VM = ptr::null() }
+}
+
+/// Returns where the crash report of the process `pid` is written.
+pub fn path_for(pid: i32) -> PathBuf {
+    env::temp_dir().join(format!("rapidus-crash-{}.txt", pid))
+}
+
+/// Writes a crash report when the current process panics (other than by reporting an error in
+/// the script) or is killed by a fatal signal.
+pub fn install() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        write(format!("panic: {}", info).as_str());
+    }));
+
+    for &signal in &[libc::SIGSEGV, libc::SIGBUS, libc::SIGILL, libc::SIGFPE] {
+        let handler = on_fatal_signal as extern "C" fn(libc::c_int);
+        unsafe { libc::signal(signal, handler as libc::sighandler_t) };
+    }
+}
+
+extern "C" fn on_fatal_signal(signal: libc::c_int) {
+    let name = match signal {
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGILL => "SIGILL",
+        _ => "SIGFPE",
+    };
+    write(format!("signal: {}", name).as_str());
+    // Die of the signal, so that the parent process sees what happened.
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
+
+fn write(reason: &str) {
+    let vm = unsafe { CURRENT_VM.as_ref() };
+    let report = render(reason, vm);
+    if let Ok(mut file) = File::create(path_for(process::id() as i32)) {
+        let _ = file.write_all(report.as_bytes());
+    }
+}
+
+/// Returns the contents of a crash report: what went wrong, and the state of `vm`.
+pub fn render(reason: &str, vm: Option<&VM>) -> String {
+    let mut report = format!("rapidus {} crash report\n{}\n", VERSION_STR, reason);
+
+    let vm = match vm {
+        Some(vm) => vm,
+        None => return report + "\nThe script was not running.\n",
+    };
+
+    let pc = vm.state.pc;
+    report += format!("\npc: {:04x}", pc).as_str();
+    if let Some(ref info) = vm.source {
+        report += format!(" ({})", info.location(info.pos_at(pc as usize))).as_str();
+    }
+    report += format!("\nbp: {}, lp: {}\n", vm.state.bp, vm.state.lp).as_str();
+
+    report += "\nStack (top first):\n";
+    for (i, val) in vm.state.stack.iter().enumerate().rev() {
+        report += format!("{:4} {}\n", i, show_value(val)).as_str();
+    }
+
+    report += "\nCall frames (bp, lp, sp, return pc):\n";
+    for frame in vm.state.history.iter().rev() {
+        let (bp, lp, sp, return_pc) = (frame.bp, frame.lp, frame.sp, frame.return_pc);
+        report += format!("{} {} {} {:04x}\n", bp, lp, sp, return_pc).as_str();
+    }
+
+    report += "\nBytecode:\n";
+    report += bytecode_gen::disassemble(&vm.insts).as_str();
+
+    if let Some(ref info) = vm.source {
+        report += format!("\nScript ({}):\n{}\n", info.file_name, info.source).as_str();
+    }
+    report
+}
+
+// Objects may be in the middle of being modified, so their contents are not shown.
+fn show_value(val: &Value) -> String {
+    match val {
+        &Value::Undefined => "undefined".to_string(),
+        &Value::Null => "null".to_string(),
+        &Value::Bool(b) => b.to_string(),
+        &Value::Number(n) => n.to_string(),
+        &Value::String(ref s) => {
+            quote_string(s.to_str().unwrap(), bytecode_gen::MAX_SHOWN_STRING_LEN)
+        }
+        &Value::Function(pos, _) => format!("Function({:04x})", pos),
+        &Value::NeedThis(box ref callee) => format!("NeedThis({})", show_value(callee)),
+        &Value::WithThis(box (ref callee, _)) => format!("WithThis({})", show_value(callee)),
+        &Value::BuiltinFunction(n) => format!("BuiltinFunction({})", n),
+        &Value::Object(_) => "Object".to_string(),
+        &Value::Array(_) => "Array".to_string(),
+        &Value::Arguments => "Arguments".to_string(),
+        &Value::Accessor(box (ref getter, ref setter)) => {
+            format!("Accessor({}, {})", show_value(getter), show_value(setter))
+        }
+    }
+}
+
+#[test]
+fn render_report() {
+    let mut vm = VM::new();
+    vm.insts = vec![0x05, 0x01, 0x00];
+    vm.state.stack = vec![Value::Number(1.0), Value::Bool(true)];
+    vm.state.pc = 2;
+    let report = render("signal: SIGSEGV", Some(&vm));
+    assert!(report.starts_with("rapidus "));
+    assert!(report.contains("signal: SIGSEGV\n\npc: 0002\n"));
+    assert!(report.contains("Stack (top first):\n   1 true\n   0 1\n"));
+    assert!(report.contains("Bytecode:\n0000 PushInt8\n0002 End\n"));
+    assert!(render("panic: x", None).ends_with("The script was not running.\n"));
+}
@@ -0,0 +1,349 @@
+use diagnostic::SourceInfo;
+use lexer::{get_line, get_line_and_column};
+use node::{Node, NodeBase};
+use parser::Parser;
+use vm::{obj_find_val, Value, VM};
+use vm_codegen::Scope;
+
+use libc;
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::ptr;
+use std::rc::Rc;
+
+const HELP: &'static str = "Commands:
+    b, break [FILE:]LINE   Stop before running LINE (list breakpoints without LINE)
+    s, step                Run to the next line, entering calls
+    n, next                Run to the next line in this function
+    c, continue            Run to the next breakpoint
+    p, print EXPR          Print the value of EXPR in the paused frame
+    bt, backtrace          Print the functions being called
+    q, quit                Stop the program
+    h, help                Print this message
+An empty line repeats the last command.
+";
+
+#[derive(Clone, Debug, PartialEq)]
+enum Mode {
+    Continue,
+    Step,
+    Next(usize), // Pause at the next line in a frame at most this deep
+}
+
+/// A frame being executed: (an address in its code, bp, lp).
+type Frame = (usize, usize, usize);
+
+/// Pauses the VM at breakpoints and between lines, and reads commands from stdin while paused.
+pub struct Debugger {
+    pub breakpoints: Vec<usize>, // lines
+    scopes: Vec<Scope>,
+    entries: HashSet<usize>,      // address of the first instruction of each scope
+    lines: HashMap<usize, usize>, // address -> line, for addresses in the source map
+    mode: Mode,
+    lines_by_depth: Vec<Option<usize>>, // The line last run in each frame
+    last_command: String,
+}
+
+impl Debugger {
+    /// Creates a debugger that pauses before the first line of the program.
+    pub fn new(scopes: Vec<Scope>, info: &SourceInfo) -> Debugger {
+        Debugger {
+            breakpoints: vec![],
+            entries: scopes.iter().map(|scope| scope.start).collect(),
+            scopes: scopes,
+            lines: info.source_map
+                .iter()
+                .map(|&(pc, pos)| (pc, get_line_and_column(info.source.as_str(), pos).0))
+                .collect(),
+            mode: Mode::Step,
+            lines_by_depth: vec![],
+            last_command: "".to_string(),
+        }
+    }
+
+    /// Called by the VM before it runs each instruction.
+    pub fn on_instruction(&mut self, vm: &mut VM) {
+        let pc = vm.state.pc as usize;
+        let depth = vm.state.history.len();
+
+        // Entering a function: nothing has run in the new frame yet.
+        if self.entries.contains(&pc) {
+            self.lines_by_depth.truncate(depth);
+        }
+
+        let line = match self.lines.get(&pc) {
+            Some(&line) => line,
+            None => return,
+        };
+        while self.lines_by_depth.len() <= depth {
+            self.lines_by_depth.push(None);
+        }
+        if self.lines_by_depth[depth] == Some(line) {
+            return;
+        }
+        self.lines_by_depth[depth] = Some(line);
+
+        let pause = match self.mode {
+            Mode::Continue => false,
+            Mode::Step => true,
+            Mode::Next(max_depth) => depth <= max_depth,
+        };
+        if pause || self.breakpoints.contains(&line) {
+            self.pause(vm, line);
+        }
+    }
+
+    fn pause(&mut self, vm: &mut VM, line: usize) {
+        // console.log() writes with printf.
+        unsafe { libc::fflush(ptr::null_mut()) };
+
+        if let Some(ref info) = vm.source {
+            println!(
+                "stopped in {} at {}:{}",
+                self.scope_at(vm.state.pc as usize).name,
+                info.file_name,
+                line
+            );
+            println!("{} | {}", line, get_line(&info.source, line).unwrap_or(""));
+        }
+
+        loop {
+            print!("(rdb) ");
+            io::stdout().flush().unwrap();
+
+            let mut command = String::new();
+            match io::stdin().read_line(&mut command) {
+                Ok(0) | Err(_) => {
+                    // Nobody is left to type commands, so run the program to the end.
+                    println!();
+                    self.mode = Mode::Continue;
+                    self.breakpoints.clear();
+                    return;
+                }
+                Ok(_) => {}
+            }
+            let command = match command.trim() {
+                "" => self.last_command.clone(),
+                command => command.to_string(),
+            };
+            self.last_command = command.clone();
+
+            let mut words = command.splitn(2, char::is_whitespace);
+            let name = words.next().unwrap_or("");
+            let arg = words.next().unwrap_or("").trim();
+            match name {
+                "" => {}
+                "b" | "break" => self.set_breakpoint(vm, arg),
+                "s" | "step" => {
+                    self.mode = Mode::Step;
+                    return;
+                }
+                "n" | "next" => {
+                    self.mode = Mode::Next(vm.state.history.len());
+                    return;
+                }
+                "c" | "continue" => {
+                    self.mode = Mode::Continue;
+                    return;
+                }
+                "p" | "print" => self.print(vm, arg),
+                "bt" | "backtrace" => self.backtrace(vm),
+                "q" | "quit" => ::std::process::exit(0),
+                "h" | "help" => print!("{}", HELP),
+                _ => println!("unknown command '{}'; try 'help'", name),
+            }
+        }
+    }
+
+    fn set_breakpoint(&mut self, vm: &VM, arg: &str) {
+        let info = match vm.source {
+            Some(ref info) => info,
+            None => return,
+        };
+
+        if arg.is_empty() {
+            if self.breakpoints.is_empty() {
+                println!("no breakpoints");
+            }
+            for line in &self.breakpoints {
+                println!("breakpoint at {}:{}", info.file_name, line);
+            }
+            return;
+        }
+
+        let mut parts = arg.rsplitn(2, ':');
+        let line = parts.next().unwrap_or("");
+        if let Some(file_name) = parts.next() {
+            if Path::new(file_name).file_name() != Path::new(&info.file_name).file_name() {
+                println!("no such file: {}", file_name);
+                return;
+            }
+        }
+        let line = match line.parse::<usize>() {
+            Ok(line) => line,
+            Err(_) => {
+                println!("invalid line number: {}", line);
+                return;
+            }
+        };
+
+        // Break at the first line with code from `line` on.
+        match self.lines.values().filter(|&&l| l >= line).min() {
+            Some(&line) => {
+                if !self.breakpoints.contains(&line) {
+                    self.breakpoints.push(line);
+                }
+                println!("breakpoint at {}:{}", info.file_name, line);
+            }
+            None => println!("no code at or after line {}", line),
+        }
+    }
+
+    fn print(&self, vm: &mut VM, expr: &str) {
+        if expr.is_empty() {
+            println!("usage: print EXPR");
+            return;
+        }
+
+        let node = match Parser::new(expr.to_string()).parse() {
+            Err(e) => {
+                eprint!("{}", e.render("[print]", expr));
+                return;
+            }
+            Ok(Node {
+                base: NodeBase::StatementList(ref nodes),
+                ..
+            }) if nodes.len() == 1 => nodes[0].clone(),
+            Ok(_) => {
+                println!("error: expected an expression");
+                return;
+            }
+        };
+
+        let frame = (vm.state.pc as usize, vm.state.bp, vm.state.lp);
+        match self.eval(vm, frame, &node) {
+            Ok(val) => println!("{}", val.inspect()),
+            Err(msg) => println!("error: {}", msg),
+        }
+    }
+
+    fn eval(&self, vm: &mut VM, frame: Frame, node: &Node) -> Result<Value, String> {
+        match node.base {
+            NodeBase::Identifier(ref name) => self.lookup(vm, frame, name),
+            NodeBase::This => self.lookup(vm, frame, "this"),
+            NodeBase::Number(n) => Ok(Value::Number(n)),
+            NodeBase::Boolean(b) => Ok(Value::Bool(b)),
+            NodeBase::Null => Ok(Value::Null),
+            NodeBase::String(ref s) => Ok(Value::String(CString::new(
+                s.as_str(),
+            ).unwrap())),
+            NodeBase::Member(ref parent, ref member) => {
+                let parent = self.eval(vm, frame, parent)?;
+                get_property(vm, parent, member.as_str())
+            }
+            NodeBase::Index(ref parent, ref index) => {
+                let parent = self.eval(vm, frame, parent)?;
+                let key = match self.eval(vm, frame, index)? {
+                    Value::Number(n) => n.to_string(),
+                    Value::String(s) => s.into_string().unwrap(),
+                    _ => return Err("unsupported index".to_string()),
+                };
+                get_property(vm, parent, key.as_str())
+            }
+            _ => Err("only variables, literals and property accesses can be printed".to_string()),
+        }
+    }
+
+    fn lookup(&self, vm: &mut VM, frame: Frame, name: &str) -> Result<Value, String> {
+        // Variables declared at the top level live in its frame, the bottom one.
+        let toplevel = match vm.state.history.get(1) {
+            Some(frame) => (self.scopes[0].start, frame.bp, frame.lp),
+            None => (self.scopes[0].start, vm.state.bp, vm.state.lp),
+        };
+        for &(pc, bp, lp) in &[frame, toplevel] {
+            let var = self.scope_at(pc)
+                .vars
+                .iter()
+                .find(|&&(ref var_name, _, _)| var_name == name);
+            if let Some(&(_, is_arg, id)) = var {
+                let addr = if is_arg { bp + id } else { lp + id };
+                return Ok(vm.state.stack.get(addr).cloned().unwrap_or(Value::Undefined));
+            }
+            // `this` is right below the arguments.
+            if name == "this" {
+                return Ok(vm.state.stack.get(bp - 1).cloned().unwrap_or(Value::Undefined));
+            }
+        }
+        match vm.get_global(name) {
+            Some(val) => Ok(val),
+            None => Err(format!("{} is not defined", name)),
+        }
+    }
+
+    fn backtrace(&self, vm: &VM) {
+        let info = match vm.source {
+            Some(ref info) => info,
+            None => return,
+        };
+
+        let mut frames = vec![(vm.state.pc as usize, vm.state.bp, vm.state.lp)];
+        // history[i] holds the state of the caller of the i-th function being called. The first
+        // entry is not a call.
+        for i in (1..vm.state.history.len()).rev() {
+            let frame = &vm.state.history[i];
+            frames.push((frame.return_pc as usize - 1, frame.bp, frame.lp));
+        }
+
+        for (i, &(pc, _, _)) in frames.iter().enumerate() {
+            let (line, _) = get_line_and_column(info.source.as_str(), info.pos_at(pc + 1));
+            println!("#{} {} at {}:{}", i, self.scope_at(pc).name, info.file_name, line);
+        }
+    }
+
+    fn scope_at(&self, pc: usize) -> &Scope {
+        self.scopes
+            .iter()
+            .find(|scope| scope.start <= pc && pc < scope.end)
+            .unwrap_or(&self.scopes[0])
+    }
+}
+
+fn get_property(vm: &mut VM, parent: Value, key: &str) -> Result<Value, String> {
+    match parent {
+        Value::Object(ref map) if Rc::ptr_eq(map, &vm.global_objects) => {
+            Ok(vm.get_global(key).unwrap_or(Value::Undefined))
+        }
+        Value::Object(map) | Value::Function(_, map) => Ok(obj_find_val(&*map.borrow(), key)),
+        Value::Array(array) => {
+            let array = array.borrow();
+            match key.parse::<usize>() {
+                Ok(i) if i < array.length => Ok(array.elems[i].clone()),
+                Ok(_) => Ok(Value::Undefined),
+                Err(_) if key == "length" => Ok(Value::Number(array.length as f64)),
+                Err(_) => Ok(obj_find_val(&array.obj, key)),
+            }
+        }
+        Value::String(s) => {
+            let s = s.to_str().unwrap();
+            match key.parse::<usize>() {
+                Ok(i) => Ok(s.chars()
+                    .nth(i)
+                    .map(|c| Value::String(CString::new(c.to_string()).unwrap()))
+                    .unwrap_or(Value::Undefined)),
+                Err(_) if key == "length" => Ok(Value::Number(
+                    s.chars().fold(0, |x, c| x + c.len_utf16()) as f64,
+                )),
+                Err(_) => Ok(Value::Undefined),
+            }
+        }
+        Value::Undefined => Err(format!(
+            "Cannot read properties of undefined (reading '{}')",
+            key
+        )),
+        _ => Ok(Value::Undefined),
+    }
+}
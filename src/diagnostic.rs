@@ -0,0 +1,193 @@
+use lexer;
+use lexer::{get_line, get_line_and_column, is_line_terminator};
+use vm_codegen::{FunctionSpans, SourceMap};
+
+use ansi_term::{Colour, Style};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static USE_COLOR: AtomicBool = AtomicBool::new(true);
+
+pub fn set_color(enabled: bool) {
+    USE_COLOR.store(enabled, Ordering::Relaxed)
+}
+
+pub fn color_enabled() -> bool {
+    USE_COLOR.load(Ordering::Relaxed)
+}
+
+/// An error at some position in source code, rendered like:
+///
+/// ```text
+/// error: expect ')'
+///  --> example.js:2:13
+///   |
+/// 2 | console.log(1;
+///   |              ^
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub level: Level,
+    pub message: String,
+    pub pos: usize, // position in source code
+    pub len: usize, // length of the underlined span
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+impl Diagnostic {
+    /// Creates an error underlining the token at `pos`.
+    pub fn new(message: &str, source: &str, pos: usize) -> Diagnostic {
+        Diagnostic {
+            level: Level::Error,
+            message: message.to_string(),
+            pos: pos,
+            len: token_len(source, pos),
+        }
+    }
+
+    /// Creates a warning underlining the token at `pos`.
+    pub fn warning(message: &str, source: &str, pos: usize) -> Diagnostic {
+        Diagnostic {
+            level: Level::Warning,
+            ..Diagnostic::new(message, source, pos)
+        }
+    }
+
+    pub fn render(&self, file_name: &str, source: &str) -> String {
+        let color = color_enabled();
+        let paint = |style: Style, s: &str| {
+            if color {
+                style.paint(s).to_string()
+            } else {
+                s.to_string()
+            }
+        };
+
+        let (line, column) = get_line_and_column(source, self.pos);
+        let source_line = get_line(source, line).unwrap_or("");
+        let gutter = " ".repeat(line.to_string().len());
+        let underline = " ".repeat(column - 1) + "^".repeat(self.len.max(1)).as_str();
+        let blue = Colour::Blue.bold();
+        let (level, level_color) = match self.level {
+            Level::Error => ("error", Colour::Red.bold()),
+            Level::Warning => ("warning", Colour::Yellow.bold()),
+        };
+
+        format!(
+            "{}{}\n{}{} {}:{}:{}\n{} {}\n{} {} {}\n{} {} {}\n",
+            paint(level_color, level),
+            paint(Style::new().bold(), format!(": {}", self.message).as_str()),
+            gutter,
+            paint(blue, "-->"),
+            file_name,
+            line,
+            column,
+            gutter,
+            paint(blue, "|"),
+            paint(blue, line.to_string().as_str()),
+            paint(blue, "|"),
+            source_line,
+            gutter,
+            paint(blue, "|"),
+            paint(level_color, underline.as_str()),
+        )
+    }
+}
+
+/// The source code of a running program, used to point runtime errors at it and to show the
+/// code of its functions.
+#[derive(Clone, Debug)]
+pub struct SourceInfo {
+    pub file_name: String,
+    pub source: String,
+    pub source_map: SourceMap,
+    pub function_spans: FunctionSpans,
+    pub files: Vec<(usize, String)>, // Where each file after the first starts in source
+}
+
+impl SourceInfo {
+    /// Returns the name of the file that the position `pos` in the source code is in, the source
+    /// code of the file, and the position in it.
+    pub fn locate(&self, pos: usize) -> (&str, &str, usize) {
+        let i = self.files.iter().take_while(|&&(start, _)| start <= pos).count();
+        let (start, file_name) = match i {
+            0 => (0, self.file_name.as_str()),
+            i => (self.files[i - 1].0, self.files[i - 1].1.as_str()),
+        };
+        let end = self.files.get(i).map_or(self.source.len(), |&(end, _)| end);
+        (file_name, &self.source[start..end], pos - start)
+    }
+
+    /// Returns "file:line:column" of the position `pos` in the source code.
+    pub fn location(&self, pos: usize) -> String {
+        let (file_name, source, pos) = self.locate(pos);
+        let (line, column) = get_line_and_column(source, pos);
+        format!("{}:{}:{}", file_name, line, column)
+    }
+
+    /// Returns the position in source code of the instruction that `pc` is in the middle of.
+    pub fn pos_at(&self, pc: usize) -> usize {
+        self.source_map
+            .iter()
+            .take_while(|&&(inst_pc, _)| inst_pc < pc)
+            .last()
+            .map(|&(_, pos)| pos)
+            .unwrap_or(0)
+    }
+
+    /// Returns the source code of the function at `pc`, as Function.prototype.toString shows it.
+    pub fn function_source(&self, pc: usize) -> Option<&str> {
+        let &(_, span) = self.function_spans.iter().find(|&&(func_pc, _)| func_pc == pc)?;
+        Some(self.source.get(span.start..span.end)?.trim_end())
+    }
+}
+
+/// Returns the number of characters in the token at `pos`, or 1 if there is no token there.
+fn token_len(source: &str, pos: usize) -> usize {
+    if pos >= source.len() {
+        return 1;
+    }
+    let mut lexer = lexer::Lexer::new(source[pos..].to_string());
+    match lexer.read_token() {
+        Ok(tok) => source[pos + tok.pos..pos + lexer.pos]
+            .split(is_line_terminator)
+            .next()
+            .map(|s| s.chars().count())
+            .unwrap_or(1),
+        Err(_) => 1,
+    }
+}
+
+#[test]
+fn render() {
+    set_color(false);
+    let source = "var a = 1\nconsole.log(a +, 2)";
+    let diag = Diagnostic::new("unexpected token", source, 25);
+    assert_eq!(
+        diag.render("a.js", source),
+        "error: unexpected token\n --> a.js:2:16\n  |\n\
+         2 | console.log(a +, 2)\n  |                ^\n"
+    );
+    let diag = Diagnostic::new("not a function", source, 10);
+    assert_eq!(
+        diag.render("a.js", source),
+        "error: not a function\n --> a.js:2:1\n  |\n2 | console.log(a +, 2)\n  | ^^^^^^^\n"
+    );
+    let diag = Diagnostic::warning("unreachable code", source, 0);
+    assert_eq!(
+        diag.render("a.js", source),
+        "warning: unreachable code\n --> a.js:1:1\n  |\n1 | var a = 1\n  | ^^^\n"
+    );
+    let source = "var a = 1\r\n\u{2028}console.log(a +, 2)";
+    let diag = Diagnostic::new("unexpected token", source, 29);
+    assert_eq!(
+        diag.render("a.js", source),
+        "error: unexpected token\n --> a.js:3:16\n  |\n\
+         3 | console.log(a +, 2)\n  |                ^\n"
+    );
+}
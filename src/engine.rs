@@ -0,0 +1,2004 @@
+use bytecode_file::{self, CompiledScript};
+use bytecode_gen::{ByteCode, ByteCodeGen};
+use coverage::Coverage;
+use diagnostic::SourceInfo;
+use error::{Error, Span};
+use extract_anony_func::AnonymousFunctionExtractor;
+use fv_finder::FreeVariableFinder;
+use fv_solver::FreeVariableSolver;
+use jit;
+use module::{FileResolver, ModuleResolver};
+use native_module::{self, NativeModule};
+use node::Node;
+use parser::Parser;
+use vm::{FatalError, Value, VM};
+use vm_codegen::VMCodeGen;
+
+use rand::random;
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::panic;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+// The seed of Math.random in deterministic mode.
+const DETERMINISTIC_SEED: u64 = 0x2545f4914f6cdd1d;
+
+/// Runs scripts through the whole pipeline: the parser, the AST passes, codegen and the VM.
+/// Engines are configured with a builder:
+///
+/// ```no_run
+/// let engine = rapidus::Engine::builder().strict(true).max_call_depth(1000).build();
+/// let result = engine.eval("var a = 1; a + 2");
+/// ```
+///
+/// Errors in the script are returned, and Error::render formats them for a terminal. Uncaught
+/// exceptions are also reported on stderr by the VM, like when running a file.
+#[derive(Clone)]
+pub struct Engine {
+    file_name: String,
+    strict: bool,
+    jit: bool,
+    jit_func_threshold: Option<usize>,
+    jit_loop_threshold: Option<usize>,
+    max_call_depth: Option<usize>,
+    stack_limit: Option<usize>,
+    deterministic: bool,
+    coverage: bool,
+    stdout: Option<Rc<dyn Fn(&str)>>,
+    module_resolver: Rc<dyn ModuleResolver>,
+    native_modules: Vec<Rc<dyn NativeModule>>,
+    compile_hooks: Option<Rc<dyn CompileHooks>>,
+}
+
+/// Lets the host watch the engine compile a script, e.g. to dump the AST or time the phases, as
+/// the debugging options of the rapidus command do. The methods do nothing by default.
+pub trait CompileHooks {
+    /// Called with the AST the parser made.
+    fn parsed(&self, _node: &Node) {}
+
+    /// Called with the AST after the passes that resolve its variables.
+    fn resolved(&self, _node: &Node) {}
+
+    /// Returns whether `phase_time` is called. Timing the lexer slows it down, so phases are only
+    /// timed if this returns true.
+    fn times_phases(&self) -> bool {
+        false
+    }
+
+    /// Called with the time each phase of compiling took: "lex", "parse", "ast passes" and
+    /// "codegen".
+    fn phase_time(&self, _phase: &'static str, _time: Duration) {}
+}
+
+/// Sets the options of an Engine. Options that are not set keep the defaults of the rapidus
+/// command.
+///
+/// There is no heap limit yet, since the VM does not keep track of the memory it allocates.
+/// (The --max-old-space option of the command limits the memory of the whole process.)
+#[derive(Clone)]
+pub struct EngineBuilder {
+    engine: Engine,
+}
+
+impl EngineBuilder {
+    /// Sets the file name shown in error messages.
+    pub fn file_name(mut self, file_name: &str) -> EngineBuilder {
+        self.engine.file_name = file_name.to_string();
+        self
+    }
+
+    pub fn strict(mut self, strict: bool) -> EngineBuilder {
+        self.engine.strict = strict;
+        self
+    }
+
+    pub fn jit(mut self, enabled: bool) -> EngineBuilder {
+        self.engine.jit = enabled;
+        self
+    }
+
+    /// Sets how many times a function is called before the JIT compiles it.
+    pub fn jit_func_threshold(mut self, calls: usize) -> EngineBuilder {
+        self.engine.jit_func_threshold = Some(calls);
+        self
+    }
+
+    /// Sets how many times a loop is run before the JIT compiles it.
+    pub fn jit_loop_threshold(mut self, runs: usize) -> EngineBuilder {
+        self.engine.jit_loop_threshold = Some(runs);
+        self
+    }
+
+    /// Limits the depth of calls, like --stack-size.
+    pub fn max_call_depth(mut self, depth: usize) -> EngineBuilder {
+        self.engine.max_call_depth = Some(depth);
+        self
+    }
+
+    /// Limits the number of values on the operand stack, like --stack-limit. It holds the
+    /// arguments and locals of every active call as well as temporaries. Calls made by code
+    /// that the JIT compiled do not use it.
+    pub fn stack_limit(mut self, values: usize) -> EngineBuilder {
+        self.engine.stack_limit = Some(values);
+        self
+    }
+
+    /// Makes Math.random return the same numbers on every run.
+    pub fn deterministic(mut self, deterministic: bool) -> EngineBuilder {
+        self.engine.deterministic = deterministic;
+        self
+    }
+
+    /// Counts how many times each instruction of the scripts runs, in VM::coverage.
+    pub fn coverage(mut self, enabled: bool) -> EngineBuilder {
+        self.engine.coverage = enabled;
+        self
+    }
+
+    /// Passes what scripts print (with console.log etc.) to `hook` instead of writing it to
+    /// stdout. Code compiled by the JIT writes to stdout directly, so this turns the JIT off.
+    pub fn stdout<F: Fn(&str) + 'static>(mut self, hook: F) -> EngineBuilder {
+        self.engine.stdout = Some(Rc::new(hook));
+        self
+    }
+
+    /// Sets where modules are loaded from. By default, they are read from files.
+    pub fn module_resolver<R: ModuleResolver + 'static>(mut self, resolver: R) -> EngineBuilder {
+        self.engine.module_resolver = Rc::new(resolver);
+        self
+    }
+
+    /// Adds a module of native functions, registered in every VM the engine creates.
+    pub fn native_module<M: NativeModule + 'static>(mut self, module: M) -> EngineBuilder {
+        self.engine.native_modules.push(Rc::new(module));
+        self
+    }
+
+    pub fn compile_hooks<H: CompileHooks + 'static>(mut self, hooks: H) -> EngineBuilder {
+        self.engine.compile_hooks = Some(Rc::new(hooks));
+        self
+    }
+
+    pub fn build(self) -> Engine {
+        self.engine
+    }
+}
+
+impl Engine {
+    pub fn new() -> Engine {
+        Engine::builder().build()
+    }
+
+    pub fn builder() -> EngineBuilder {
+        EngineBuilder {
+            engine: Engine {
+                file_name: "[eval]".to_string(),
+                strict: false,
+                jit: true,
+                jit_func_threshold: None,
+                jit_loop_threshold: None,
+                max_call_depth: None,
+                stack_limit: None,
+                deterministic: false,
+                coverage: false,
+                stdout: None,
+                module_resolver: Rc::new(FileResolver),
+                native_modules: vec![],
+                compile_hooks: None,
+            },
+        }
+    }
+
+    /// Returns a VM configured with the options of the engine.
+    pub fn create_vm(&self) -> VM {
+        let mut vm = VM::new();
+        vm.jit.enabled = self.jit && self.stdout.is_none();
+        if let Some(calls) = self.jit_func_threshold {
+            vm.jit.func_threshold = calls;
+        }
+        if let Some(runs) = self.jit_loop_threshold {
+            vm.jit.loop_threshold = runs;
+        }
+        vm.max_call_depth = self.max_call_depth;
+        vm.stack_limit = self.stack_limit;
+        vm.stdout = self.stdout.clone();
+        vm.module_resolver = self.module_resolver.clone();
+        for module in &self.native_modules {
+            native_module::register(&**module, &mut vm);
+        }
+        jit::seed_math_random(if self.deterministic {
+            DETERMINISTIC_SEED
+        } else {
+            random()
+        });
+        vm
+    }
+
+    /// Runs `source` and returns its completion value, the value of its last expression
+    /// statement.
+    pub fn eval(&self, source: &str) -> Result<Value, Error> {
+        let mut vm = self.create_vm();
+        self.run_in(source, &mut vm)
+    }
+
+    /// Runs `source` and returns the VM it ran in, so that host code can call the functions the
+    /// script defines with VM::call.
+    pub fn run(&self, source: &str) -> Result<VM, Error> {
+        let mut vm = self.create_vm();
+        self.run_in(source, &mut vm)?;
+        Ok(vm)
+    }
+
+    /// Runs `source` in `vm`, after the code it has run before (e.g. the previous lines typed in a
+    /// REPL), and returns its completion value. The variables and functions `source` declares are
+    /// global variables: functions compiled before see them, and so does code run later. A
+    /// function declared again replaces the old one for every caller.
+    ///
+    /// The variables of a script run by Engine::run are its own, and its calls go to its own
+    /// functions; only its functions are seen by the code run after it.
+    pub fn eval_in(&self, source: &str, vm: &mut VM) -> Result<Value, Error> {
+        catch_errors(vm, |vm| self.compile_more(source, vm).map(|pc| vm.run_more(pc)))
+    }
+
+    /// Compiles `source` and returns a VM ready to run it with VM::run_for.
+    pub fn load(&self, source: &str) -> Result<VM, Error> {
+        let mut vm = self.create_vm();
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| self.compile(source, &mut vm)));
+        match result {
+            Ok(Ok(insts)) => vm.load(insts),
+            Ok(Err(e)) => return Err(e),
+            Err(payload) => return Err(panic_to_error(payload, &vm)),
+        }
+        Ok(vm)
+    }
+
+    /// Runs `source` and returns a snapshot of the VM it ran in. VMs restored from the snapshot
+    /// start with the globals the script set up, without running it again.
+    pub fn snapshot(&self, source: &str) -> Result<Vec<u8>, Error> {
+        let vm = self.run(source)?;
+        Ok(bytecode_file::snapshot(&vm))
+    }
+
+    /// Returns a VM configured with the options of the engine, in the state saved in `snapshot`.
+    pub fn restore(&self, snapshot: &[u8]) -> Result<VM, bytecode_file::Error> {
+        let mut vm = self.create_vm();
+        bytecode_file::restore_snapshot(snapshot, &mut vm)?;
+        Ok(vm)
+    }
+
+    fn run_in(&self, source: &str, vm: &mut VM) -> Result<Value, Error> {
+        catch_errors(vm, |vm| self.compile(source, vm).map(|insts| vm.run(insts)))
+    }
+
+    /// Compiles `source` without running it, e.g. to save it with CompiledScript::serialize.
+    pub fn compile_script(&self, source: &str) -> Result<CompiledScript, Error> {
+        let (mut node, strict) = self.parse(source)?;
+        self.resolve(&mut node);
+
+        let start = Instant::now();
+        let mut codegen = VMCodeGen::new();
+        let mut insts = vec![];
+        codegen.compile(&node, &mut insts, &mut HashMap::new())?;
+        self.phase_time("codegen", start);
+
+        Ok(CompiledScript {
+            file_name: self.file_name.clone(),
+            source: source.to_string(),
+            insts: insts,
+            const_table: codegen.bytecode_gen.const_table,
+            global_varmap: codegen.global_varmap,
+            source_map: codegen.source_map,
+            function_spans: codegen.function_spans,
+            scopes: codegen.scopes,
+            strict: strict,
+        })
+    }
+
+    /// Loads what `script` needs into `vm`, and returns the instructions to run with VM::run.
+    pub fn load_script(&self, script: CompiledScript, vm: &mut VM) -> ByteCode {
+        vm.strict = script.strict;
+        vm.const_table = script.const_table;
+        (*vm.global_objects)
+            .borrow_mut()
+            .extend(script.global_varmap);
+        vm.source = Some(SourceInfo {
+            file_name: script.file_name,
+            source: script.source,
+            source_map: script.source_map,
+            function_spans: script.function_spans,
+            files: vec![],
+        });
+        if self.coverage {
+            vm.coverage = Some(Coverage::new(script.insts.len()));
+        }
+        script.insts
+    }
+
+    /// Compiles `source` for `vm`, and loads what the code needs into it.
+    fn compile(&self, source: &str, vm: &mut VM) -> Result<ByteCode, Error> {
+        let script = self.compile_script(source)?;
+        Ok(self.load_script(script, vm))
+    }
+
+    /// Parses `source`, and returns its AST and whether it is strict mode code.
+    fn parse(&self, source: &str) -> Result<(Node, bool), Error> {
+        let mut parser = Parser::new(source.to_string());
+        parser.file_name = self.file_name.clone();
+        parser.strict = self.strict;
+        let timed = self.compile_hooks.as_ref().map_or(false, |h| h.times_phases());
+        if timed {
+            parser.lexer.elapsed = Some(Duration::new(0, 0));
+        }
+
+        let start = Instant::now();
+        let node = parser.parse()?;
+        if let Some(ref hooks) = self.compile_hooks {
+            // The lexer runs on demand while parsing.
+            let lex_time = parser.lexer.elapsed.unwrap_or(Duration::new(0, 0));
+            if timed {
+                hooks.phase_time("lex", lex_time);
+                hooks.phase_time("parse", start.elapsed() - lex_time);
+            }
+            hooks.parsed(&node);
+        }
+        Ok((node, parser.strict))
+    }
+
+    /// Runs the passes that resolve the variables of `node`.
+    fn resolve(&self, node: &mut Node) {
+        let start = Instant::now();
+        AnonymousFunctionExtractor::new().run_toplevel(node);
+        FreeVariableFinder::new().run_toplevel(node);
+        FreeVariableSolver::new().run_toplevel(node);
+        self.phase_time("ast passes", start);
+        if let Some(ref hooks) = self.compile_hooks {
+            hooks.resolved(node);
+        }
+    }
+
+    /// Tells the compile hooks how long `phase`, which began at `start`, took.
+    fn phase_time(&self, phase: &'static str, start: Instant) {
+        match self.compile_hooks {
+            Some(ref hooks) if hooks.times_phases() => hooks.phase_time(phase, start.elapsed()),
+            _ => {}
+        }
+    }
+
+    /// Compiles `source` after the code in `vm`, and returns the address it starts at. The
+    /// constant table and the global variables of `vm` are added to, so that the cached slots of
+    /// the globals stay valid.
+    fn compile_more(&self, source: &str, vm: &mut VM) -> Result<usize, Error> {
+        let (mut node, strict) = self.parse(source)?;
+        let globals = node.declare_globals();
+        self.resolve(&mut node);
+
+        let start = Instant::now();
+        let mut codegen = VMCodeGen::new();
+        codegen.incremental = true;
+        codegen.bytecode_gen = ByteCodeGen::with_const_table(vm.const_table.clone());
+        let pc = vm.insts.len();
+        if let Err(e) = codegen.compile(&node, &mut vm.insts, &mut HashMap::new()) {
+            vm.insts.truncate(pc);
+            return Err(e);
+        }
+        self.phase_time("codegen", start);
+
+        vm.strict = strict;
+        vm.const_table = codegen.bytecode_gen.const_table;
+        for name in globals {
+            if vm.get_global(name.as_str()).is_none() {
+                vm.set_global(name, Value::Undefined);
+            }
+        }
+        for (name, val) in codegen.global_varmap {
+            vm.set_global(name, val);
+        }
+
+        let file_name = self.file_name.clone();
+        let info = vm.source.get_or_insert_with(|| SourceInfo {
+            file_name: file_name.clone(),
+            source: String::new(),
+            source_map: vec![],
+            function_spans: vec![],
+            files: vec![],
+        });
+        // Each piece of code starts on a new line, so that errors show the lines they are in.
+        if !info.source.is_empty() && !info.source.ends_with('\n') {
+            info.source.push('\n');
+        }
+        let offset = info.source.len();
+        // Code from another file is numbered from its own first line.
+        let last_file_name = info.files.last().map_or(&info.file_name, |&(_, ref name)| name);
+        if *last_file_name != file_name {
+            info.files.push((offset, file_name));
+        }
+        info.source.push_str(source);
+        info.source_map.extend(
+            codegen
+                .source_map
+                .iter()
+                .map(|&(pc, pos)| (pc, pos + offset)),
+        );
+        info.function_spans.extend(
+            codegen.function_spans.iter().map(|&(pc, span)| {
+                (
+                    pc,
+                    Span {
+                        start: span.start + offset,
+                        end: span.end + offset,
+                    },
+                )
+            }),
+        );
+        Ok(pc)
+    }
+}
+
+/// Runs `f`, and turns the errors reported while it runs into an Err.
+fn catch_errors<F>(vm: &mut VM, f: F) -> Result<Value, Error>
+where
+    F: FnOnce(&mut VM) -> Result<Value, Error>,
+{
+    let error_count = vm.error_count;
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| f(vm)));
+
+    match result {
+        Ok(Ok(_)) if vm.error_count > error_count => {
+            Err(Error::Runtime(vm.last_error.clone().unwrap()))
+        }
+        Ok(result) => result,
+        Err(payload) => Err(panic_to_error(payload, vm)),
+    }
+}
+
+/// Returns the error that `vm` stopped running with.
+fn panic_to_error(payload: Box<dyn Any + Send>, vm: &VM) -> Error {
+    if payload.is::<FatalError>() {
+        Error::Runtime(vm.last_error.clone().unwrap())
+    } else if let Some(msg) = payload.downcast_ref::<&str>() {
+        Error::Internal(msg.to_string())
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        Error::Internal(msg.clone())
+    } else {
+        Error::Internal("unknown error".to_string())
+    }
+}
+
+/// Runs `source` with the default options and returns its completion value.
+pub fn eval(source: &str) -> Result<Value, Error> {
+    Engine::new().eval(source)
+}
+
+#[test]
+fn eval_scripts() {
+    assert_eq!(eval("var a = 2\na * 3"), Ok(Value::Number(6.0)));
+    assert_eq!(
+        eval("var = 1").map_err(|e| e.to_string()),
+        Err("parse error: expect a variable name".to_string())
+    );
+    assert_eq!(
+        eval("var a = 1\n~a").map_err(|e| e.to_string()),
+        Err("codegen error: unsupported unary operator".to_string())
+    );
+    match Engine::builder().strict(true).build().eval("function f() { a = 1 }\nf()") {
+        Err(Error::Runtime(e)) => {
+            assert_eq!(e.kind, ::error::ErrorKind::ReferenceError);
+            assert_eq!(e.message, "a is not defined");
+            assert_eq!(e.stack_trace.len(), 1);
+        }
+        result => panic!("{:?}", result),
+    }
+    match eval("var o = {}\no.a.b\no = 1") {
+        Err(Error::Runtime(e)) => assert_eq!(
+            e.to_string(),
+            "TypeError: Cannot read properties of undefined (reading 'b')"
+        ),
+        result => panic!("{:?}", result),
+    }
+
+    let out = Rc::new(::std::cell::RefCell::new(String::new()));
+    let out_ = out.clone();
+    let engine = Engine::builder()
+        .deterministic(true)
+        .stdout(move |s| out_.borrow_mut().push_str(s))
+        .build();
+    let script = "console.log(Math.random(), [1, 'a'])";
+    assert_eq!(engine.eval(script), Ok(Value::Undefined));
+    let first = out.borrow().clone();
+    assert!(first.ends_with(" [ 1, 'a', ]\n"));
+    out.borrow_mut().clear();
+    engine.eval(script).unwrap();
+    assert_eq!(*out.borrow(), first);
+
+    // The script stops at the error.
+    out.borrow_mut().clear();
+    assert!(engine.eval("var o\no.a = 1\nconsole.log('after')").is_err());
+    assert_eq!(*out.borrow(), "");
+}
+
+#[test]
+fn call_functions() {
+    use std::ffi::CString;
+
+    let mut vm = Engine::new()
+        .run("function add(a, b) { return a + b }\nfunction fail() { var a = 1; a() }")
+        .unwrap();
+    let add = vm.get_global("add").unwrap();
+    assert_eq!(
+        vm.call(add, Value::Undefined, &[Value::Number(1.0), Value::Number(2.0)]),
+        Ok(Value::Number(3.0))
+    );
+    let fail = vm.get_global("fail").unwrap();
+    assert_eq!(
+        vm.call(fail, Value::Undefined, &[]),
+        Err(Value::String(CString::new("1 is not a function").unwrap()))
+    );
+    assert_eq!(vm.get_global("nothing"), None);
+
+    // The VM can still be used after an error it cannot continue from.
+    let mut vm = Engine::builder()
+        .strict(true)
+        .build()
+        .run("function set() { b = 1 }\nfunction get() { return 2 }")
+        .unwrap();
+    let set = vm.get_global("set").unwrap();
+    assert_eq!(
+        vm.call(set, Value::Undefined, &[]),
+        Err(Value::String(CString::new("b is not defined").unwrap()))
+    );
+    let get = vm.get_global("get").unwrap();
+    assert_eq!(vm.call(get, Value::Undefined, &[]), Ok(Value::Number(2.0)));
+}
+
+#[test]
+fn run_step_by_step() {
+    let script = "var sum = 0, i = 0\nwhile (i < 100) {\n  sum += i\n  i += 1\n}\nsum";
+    let mut vm = Engine::builder().jit(false).build().load(script).unwrap();
+    let mut steps = 1;
+    loop {
+        match vm.run_for(50) {
+            Ok(Some(val)) => {
+                assert_eq!(val, Value::Number(4950.0));
+                break;
+            }
+            Ok(None) => steps += 1,
+            Err(e) => panic!("{:?}", e),
+        }
+    }
+    assert!(steps > 1);
+}
+
+#[test]
+fn run_calls_without_recursion() {
+    // Calls no longer use the Rust stack, so deep recursion is limited by max_call_depth only.
+    let script = "function sum(n) { if (n == 0) return 0; return n + sum(n - 1) }\nsum(20000)";
+    let engine = Engine::builder().jit(false).build();
+    assert_eq!(engine.eval(script), Ok(Value::Number(200010000.0)));
+
+    let script = "function P(x) { this.x = x; return 1 }\nfunction make(C, x) { return new C(x) }\n\
+                  var p = make(P, make(P, 2).x + 1)\np.x";
+    let mut vm = engine.load(script).unwrap();
+    let mut steps = 1;
+    loop {
+        match vm.run_for(3) {
+            Ok(Some(val)) => {
+                assert_eq!(val, Value::Number(3.0));
+                break;
+            }
+            Ok(None) => steps += 1,
+            Err(e) => panic!("{:?}", e),
+        }
+    }
+    assert!(steps > 1);
+}
+
+#[test]
+fn pass_any_number_of_arguments() {
+    let script = "function f(a, b) { b = 2; return [a, b, arguments.length] }
+function g(a, ...rest) { return [rest.length, arguments[2]] }
+function P(a, b) { this.n = arguments.length; this.b = b }
+var o = { m: function (a) { return this === o && arguments.length == 1 } }
+var m = o.m(1);
+[f(), f(1), f(1, 3, 5), g(1, 2, 3), g(), (new P(1)).n, (new P(1)).b, m, f.call(null, 1)]";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some(",2,0,1,2,1,1,2,3,2,3,0,,1,,true,1,2,1".to_string())
+    );
+    // Local variables stay where they are whatever the number of arguments, also when the
+    // function has been compiled by the JIT.
+    let script = "function add(a, b) { var c = 10; return a + b + c }
+function h(a, b, c) { var l = 5; return [a, b, c, l].toString() }
+var s = 0, n = 0
+for (var i = 0; i < 20; i = i + 1) { s = s + add(i, 1, 7, 8); n = add(i) }
+[s, n, h(1), h(1, 2, 3, 4), h.call(null), h(...[1, 2, 3, 4, 5])].toString()";
+    for engine in vec![
+        Engine::builder().jit(false).build(),
+        Engine::builder().jit_func_threshold(2).build(),
+    ] {
+        assert_eq!(
+            engine.eval(script).ok().map(|val| val.to_string()),
+            Some("410,NaN,1,,,5,1,2,3,5,,,,5,1,2,3,5".to_string())
+        );
+    }
+}
+
+#[test]
+fn rest_parameters() {
+    // The JIT leaves functions with a rest parameter to the interpreter.
+    let script = "function f(a, ...rest) { return a + rest }
+function g(a, b, ...c) { c.push(a); return c }
+var out
+for (var i = 0; i < 20; i = i + 1) out = f(i)
+var arrow = (...xs) => xs.length;
+[out, typeof out, g(1).length, g(1, 2, 3, 4).length, g(1, 2, 3, 4)[2], arrow(), arrow(1, 2)]";
+    for engine in vec![
+        Engine::builder().jit(false).build(),
+        Engine::builder().jit_func_threshold(2).build(),
+    ] {
+        assert_eq!(
+            engine.eval(script).ok().map(|val| val.to_string()),
+            Some("19,string,1,3,1,0,2".to_string())
+        );
+    }
+}
+
+#[test]
+fn default_parameters() {
+    let script = "function f(a, b = 10, c = a + b) { return [a, b, c] }
+function g(a = this.n, b = arguments.length) { return [a, b] }
+function h(v = 1) { return function () { return v } }
+var o = { n: 4, g: g }
+var arrow = (x = 3, ...rest) => x * 2 + rest.length;
+[f(1), f(1, 2), f(1, undefined, 5), f(1, null), o.g(), o.g(9, 8, 7), h()(), h(3)(),
+  arrow(), arrow(4, 0, 0)]";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("1,10,11,1,2,3,1,10,5,1,,1,4,0,9,8,1,3,6,10".to_string())
+    );
+}
+
+#[test]
+fn restore_snapshots() {
+    let script = "var config = { scale: 3 }
+var fs = []
+for (let i = 1; i < 3; i += 1) fs.push(() => i * config.scale)
+function scale(n) { return fs[n - 1]() }";
+    let snapshot = Engine::new().snapshot(script).unwrap();
+    for n in 1..3 {
+        let mut vm = Engine::new().restore(&snapshot).unwrap();
+        let scale = vm.get_global("scale").unwrap();
+        assert_eq!(
+            vm.call(scale, Value::Undefined, &[Value::Number(n as f64)]),
+            Ok(Value::Number(n as f64 * 3.0))
+        );
+    }
+    assert_eq!(
+        Engine::new().restore(&snapshot[..8]).err(),
+        Some(bytecode_file::Error::UnexpectedEOF)
+    );
+
+    // Corrupted snapshots fail to restore instead of making VMs that index out of bounds.
+    let mut invalid_indices = 0;
+    for i in 0..snapshot.len() {
+        let mut corrupted = snapshot.clone();
+        corrupted[i] ^= 0xff;
+        if let Err(bytecode_file::Error::InvalidIndex(_)) = Engine::new().restore(&corrupted) {
+            invalid_indices += 1;
+        }
+    }
+    assert!(invalid_indices > 0);
+}
+
+#[test]
+fn compile_scripts_with_hooks() {
+    struct Hooks(Rc<::std::cell::RefCell<Vec<String>>>);
+    impl CompileHooks for Hooks {
+        fn parsed(&self, _node: &Node) {
+            self.0.borrow_mut().push("parsed".to_string())
+        }
+        fn resolved(&self, _node: &Node) {
+            self.0.borrow_mut().push("resolved".to_string())
+        }
+        fn times_phases(&self) -> bool {
+            true
+        }
+        fn phase_time(&self, phase: &'static str, _time: Duration) {
+            self.0.borrow_mut().push(phase.to_string())
+        }
+    }
+
+    let events = Rc::new(::std::cell::RefCell::new(vec![]));
+    let engine = Engine::builder()
+        .compile_hooks(Hooks(events.clone()))
+        .coverage(true)
+        .build();
+    let script = engine.compile_script("var a = 1\na + 1").unwrap();
+    assert_eq!(
+        *events.borrow(),
+        ["lex", "parse", "parsed", "ast passes", "resolved", "codegen"]
+    );
+    let mut vm = engine.create_vm();
+    let insts = engine.load_script(script, &mut vm);
+    assert_eq!(vm.run(insts), Value::Number(2.0));
+    assert!(vm.coverage.is_some());
+
+    // Errors are returned without being printed.
+    assert_eq!(
+        engine.compile_script("~1").err().map(|e| e.to_string()),
+        Some("codegen error: unsupported unary operator".to_string())
+    );
+}
+
+#[test]
+fn count_work() {
+    let script = "function P(x) { this.x = x }\nfunction f() { return [1, 2] }\nf(); f(); new P(1)";
+    let vm = Engine::builder().jit(false).build().run(script).unwrap();
+    let stats = vm.stats();
+    assert_eq!(stats.calls, 3);
+    assert_eq!(stats.allocations, 3);
+    assert_eq!(stats.jit_compilations, 0);
+    assert!(stats.instructions > 10);
+}
+
+#[test]
+fn enumerate_properties_in_order() {
+    let script = "var o = { b: 1, a: f(), c: 2 }\no[10] = 3\no[2] = 4\nfunction f() { return 0 }";
+    let keys = eval(&format!("{}\nObject.keys(o)", script)).unwrap();
+    assert_eq!(keys.inspect(), "[ '2', '10', 'b', 'a', 'c' ]");
+}
+
+#[test]
+fn call_methods_of_primitives() {
+    let string = |s: &str| Value::String(::std::ffi::CString::new(s).unwrap());
+    assert_eq!(eval("'abc'.toUpperCase()").ok(), Some(string("ABC")));
+    assert_eq!(eval("'abcdef'.slice(1, -1)").ok(), Some(string("bcde")));
+    assert_eq!(eval("'abcabc'.indexOf('c', 3)").ok(), Some(Value::Number(5.0)));
+    assert_eq!(eval("(5).toFixed(2)").ok(), Some(string("5.00")));
+    assert_eq!(eval("true.toString()").ok(), Some(string("true")));
+    assert_eq!(eval("'abc'.length").ok(), Some(Value::Number(3.0)));
+    assert_eq!(eval("(1).foo").ok(), Some(Value::Undefined));
+}
+
+#[test]
+fn unicode_strings() {
+    let script = "var composed = '\\u00e9', decomposed = 'e\\u0301'
+var words = ['apple', '\\u00e9clair', 'resume', 'Resume', 'r\\u00e9sum\\u00e9', 'zebra', 'Zebra']
+var sorted = true
+for (var i = 1; i < words.length; i += 1) {
+  sorted = sorted && words[i - 1].localeCompare(words[i]) < 0
+}
+var results = [composed === decomposed, composed.normalize('NFD') === decomposed,
+  decomposed.normalize() === composed, '\\ufb01'.normalize('NFKC'),
+  composed.localeCompare(decomposed), 'a'.localeCompare('b'), 'b'.localeCompare('a'),
+  'stra\\u00dfe'.toUpperCase(), '\\u039f\\u0394\\u039f\\u03a3'.toLowerCase(),
+  '\\u0130'.toLocaleLowerCase().length, sorted]
+results.toString()";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some(
+            "false,true,true,fi,0,-1,1,STRASSE,οδος,2,true".to_string()
+        )
+    );
+    match eval("'a'.normalize('NFX')") {
+        Err(Error::Runtime(e)) => assert_eq!(
+            e.message,
+            "the normalization form should be one of NFC, NFD, NFKC, NFKD"
+        ),
+        result => panic!("{:?}", result),
+    }
+}
+
+#[test]
+fn call_non_functions() {
+    let message = |script: &str| match eval(script) {
+        Err(Error::Runtime(e)) => e.message,
+        result => panic!("{:?}", result),
+    };
+    assert_eq!(message("var n = 1\nn(2, 3)"), "1 is not a function");
+    assert_eq!(message("var s = 'a'\ns()"), "\"a\" is not a function");
+    assert_eq!(message("var u\nu()"), "undefined is not a function");
+    assert_eq!(message("var o = {}\no.f()"), "undefined is not a function");
+    assert_eq!(message("var n = 1\nnew n()"), "1 is not a constructor");
+}
+
+#[test]
+fn assign_to_members() {
+    let script = "var calls = 0
+function f() { calls += 1; return 'k' }
+var o = { k: 1, a: { b: {} } }, m = [[0, 0], [0, 0]]
+o[f()] += 4
+o.a.b.c = o.a.b.d = 7
+m[1][0] = 3
+m[1][1] -= o[f()] = 2
+o.k + o.a.b.c + o.a.b.d + m[1][0] + m[1][1] + calls * 100";
+    assert_eq!(eval(script).ok(), Some(Value::Number(217.0)));
+}
+
+#[test]
+fn declare_functions_in_blocks() {
+    let script = "function f() {
+  var r = 0
+  for (var i = 0; i < 3; i += 1) {
+    function g() { return i + y }
+    y = 10
+    r += g()
+  }
+  return r
+  var y
+}
+if (true) {
+  var x = 100
+  function h() { return x + f() }
+}
+h()";
+    assert_eq!(eval(script).ok(), Some(Value::Number(133.0)));
+}
+
+#[test]
+fn name_function_expressions() {
+    let script = "var fact = 0
+var f = function fact(n) {
+  var g = function() {}
+  if (n < 2) return 1
+  return n * fact(n - 1)
+}
+var h = function fact(fact) { return fact }
+f(5) + h(3) + fact";
+    assert_eq!(eval(script).ok(), Some(Value::Number(123.0)));
+}
+
+#[test]
+fn invoke_function_expressions() {
+    let script = "var n = (function(x) { return x * 2 })(3)
+var add = (a, b) => a + b
+var counter = (function(start) {
+  return () => { start += 1; return start }
+})(10)
+counter()
+n + add(1, 2) + ((x) => (y) => x * y)(3)(4) + counter()";
+    assert_eq!(eval(script).ok(), Some(Value::Number(33.0)));
+}
+
+#[test]
+fn compare_strings() {
+    for &(src, expected) in &[
+        ("'a' < 'b'", true),
+        ("'abc' < 'abd'", true),
+        ("'Z' < 'a'", true),
+        ("'10' < '9'", true),
+        ("'b' <= 'a'", false),
+        ("'a' === 'a'", true),
+        ("'a' !== 'b'", true),
+        ("'10' < 9", false),
+        ("'x' < 1", false),
+        ("'x' >= 1", false),
+        ("'1' == 1", true),
+        ("'1' === 1", false),
+        ("'' == 0", true),
+        ("true == '1'", true),
+        ("var o = {}\no === o", true),
+        ("var o = {}\no == {}", false),
+        ("[1, 2] == '1,2'", true),
+        ("[10] < [9]", true),
+    ] {
+        assert_eq!(eval(src), Ok(Value::Bool(expected)), "{}", src);
+    }
+}
+
+#[test]
+fn coerce_conditions_to_booleans() {
+    let script = "var calls = 0
+function f(v) { calls = calls + 1; return v }
+var a = [!0, !'', !'0', !!{}, 0 / 0 ? 1 : 2, f(0) && f(1), f('') || f(3)]
+var n = 3
+while (n) n = n - 1
+a.push(calls, n, true ? false ? 4 : 5 : 6)
+a";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("true,true,false,true,2,0,3,3,0,5".to_string())
+    );
+}
+
+#[test]
+fn read_global_constants() {
+    let script = "undefined = 1
+NaN = 2
+Infinity = 3
+var types = [typeof undefined, typeof NaN, typeof '', typeof {}, typeof [], typeof f]
+function f() {}
+[NaN === NaN, 1 / 0 === Infinity, -Infinity < 0, undefined == undefined, types]";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("false,true,true,true,undefined,number,string,object,object,function".to_string())
+    );
+}
+
+#[test]
+fn enumerate_global_variables() {
+    let script = "x = 1
+this.y = 2
+function f() { return x + y }
+var g = function () { return 3 }
+var keys = Object.keys(this), forIn = []
+for (var k in this) forIn.push(k)
+delete this.x
+x = 4
+var after = Object.keys(this)
+var i = 0, j = 0
+while (keys[i] !== 'f') i += 1
+while (after[j] !== 'f') j += 1;
+[keys.slice(i), forIn.slice(i), after.slice(j), f(), this.f === f, 'y' in this, g()]";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("f,x,y,f,x,y,f,y,x,6,true,true,3".to_string())
+    );
+
+    // Variables declared by code run in the same VM are properties of the global object too.
+    let engine = Engine::new();
+    let mut vm = engine.create_vm();
+    assert!(engine.eval_in("var a = 1", &mut vm).is_ok());
+    let script = "var k = Object.keys(this); [k[k.length - 2], k[k.length - 1]]";
+    let keys = engine.eval_in(script, &mut vm);
+    assert_eq!(keys.ok().map(|val| val.to_string()), Some("a,k".to_string()));
+    assert_eq!(
+        engine.eval_in("this.a = 5; a", &mut vm),
+        Ok(Value::Number(5.0))
+    );
+}
+
+#[test]
+fn compare_null() {
+    let script = "var o = { a: null };
+[o.a === null, null == undefined, null === undefined, null == 0, null == false, !null,
+ null < 1, typeof null, null, Object.keys(o).length]";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("true,true,false,false,false,true,true,object,,1".to_string())
+    );
+    match eval("var a = null\na.b = 1") {
+        Err(Error::Runtime(e)) => {
+            assert_eq!(e.message, "Cannot set properties of null (setting 'b')")
+        }
+        result => panic!("{:?}", result),
+    }
+}
+
+#[test]
+fn convert_functions_to_strings() {
+    let string = |s: &str| Value::String(::std::ffi::CString::new(s).unwrap());
+    let script = "function add(a, b) {\n  return a + b\n}\nString(add)";
+    assert_eq!(eval(script).ok(), Some(string("function add(a, b) {\n  return a + b\n}")));
+    let script = "var sq = function (x) { return x * x };\nsq.toString()";
+    assert_eq!(eval(script).ok(), Some(string("function (x) { return x * x }")));
+    let script = "var inc = (x) => x + 1\nString(inc)";
+    assert_eq!(eval(script).ok(), Some(string("(x) => x + 1")));
+    let script = "String(Math.floor)";
+    assert_eq!(eval(script).ok(), Some(string("function floor() { [native code] }")));
+    let script = "[String({}), ({}).toString(), [1, [2, 3]].toString(), String(null), String()]";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("[object Object],[object Object],1,2,3,null,".to_string())
+    );
+}
+
+#[test]
+fn limit_the_operand_stack() {
+    let script = "function f(n) { var a = 0, b = 0; return n + f(n + 1) }\nf(0)";
+    match Engine::builder().jit(false).stack_limit(1000).build().eval(script) {
+        Err(Error::Runtime(e)) => assert_eq!(e.message, "maximum operand stack size exceeded"),
+        result => panic!("{:?}", result),
+    }
+
+    let vm = Engine::builder().stack_limit(1000).build().run("[1, 2, 3]").unwrap();
+    assert!(vm.stats().max_stack_len >= 4);
+}
+
+#[test]
+fn call_methods() {
+    let script = "var o = { n: 2, get: function () { return this.n }, floor: Math.floor }
+function P(v) { this.v = v }
+P.prototype.get = function () { return this.v }
+var p = new P(3);
+[o.get(), o.floor(1.5), p.get(), 'ab'.charAt(1), (2.345).toFixed(1), [1, 2].toString()]";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("2,1,3,b,2.3,1,2".to_string())
+    );
+    match eval("var o = { n: 1 }\no.n()") {
+        Err(Error::Runtime(e)) => assert_eq!(e.message, "1 is not a function"),
+        result => panic!("{:?}", result),
+    }
+}
+
+#[test]
+fn spread_objects() {
+    let script = "var a = { x: 1, y: 2 }
+function f() { return 5 }
+var b = { w: 0, ...a, y: 3, z: f(), ...null, ...[7], ...'h', x: 9 }
+var c = { ...a };
+c.x = 4;
+[b.w, b.x, b.y, b.z, b[0], a.x, c.x, c.y]";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("0,9,3,5,h,1,4,2".to_string())
+    );
+}
+
+#[test]
+fn destructure_values() {
+    let script = "var [a = 1, b, ...rest] = [undefined, 2, 3, 4]
+var { x: { y }, z = 5, w: [p, , q] = [7, 8, 9] } = { x: { y: 6 } }
+function f([m, n] = [1, 2], { o = 3 } = {}) { return [m, n, o] }
+var g = ({ a, b }) => a + b;
+[a, b, rest.length, rest[1], y, z, p, q, f(), f([4, 5], { o: 6 }), g({ a: 1, b: 2 })]";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("1,2,2,4,6,5,7,9,1,2,3,4,5,6,3".to_string())
+    );
+    match eval("var [a]") {
+        Err(Error::Parse(e)) => {
+            assert_eq!(e.message, "missing initializer in destructuring declaration")
+        }
+        result => panic!("{:?}", result),
+    }
+}
+
+#[test]
+fn for_of_loops() {
+    let script = "var s = '', o = {}, last
+for (var x of [1, 2, 3]) s += x
+for (var c of 'héllo') s += c.toUpperCase()
+for (o.k of [4, 5]) {}
+for (var [a, b] of [[6, 7], [8, 9]]) s += a * b
+function f() { for (var v of arguments) { if (v > 1) return v } }
+for (last of [10, 11, 12]) { if (last == 11) continue; if (last == 12) break; s += last }
+[s, o.k, f(1, 2, 3), last]";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("123HÉLLO427210,5,2,12".to_string())
+    );
+    match eval("for (var x of {}) {}") {
+        Err(Error::Runtime(e)) => assert_eq!(e.message, "object is not iterable"),
+        result => panic!("{:?}", result),
+    }
+}
+
+#[test]
+fn for_in_loops() {
+    let script = "var o = { a: 1, b: 2 }, arr = [3, 4], s = '', t = {}
+for (var k in o) { o.c = 3; s += k + o[k] }
+arr.x = 5
+for (var i in arr) s += i + arr[i]
+for (t.k in 'hi') s += t.k
+for (var k in null) s += k
+s + ' ' + t.k";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("a1b20314x501 1".to_string())
+    );
+    match eval("for (1 in {}) {}") {
+        Err(Error::Parse(e)) => assert_eq!(e.message, "invalid left-hand side in for-in"),
+        result => panic!("{:?}", result),
+    }
+}
+
+#[test]
+fn own_and_inherited_properties() {
+    let script = "function F() { this.a = 1 }
+F.prototype.b = 2
+var f = new F(), arr = [1], s = ''
+arr.c = 3
+function bit(x) { return x ? '1' : '0' }
+for (var key of ['a', 'b', 'c', '0', 'length', 'toString']) {
+  s += bit(key in f) + bit(f.hasOwnProperty(key)) + bit(key in arr) + bit(arr.hasOwnProperty(key))
+  s += bit(arr.propertyIsEnumerable(key)) + ' '
+}
+s";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("11000 10000 00111 00111 00110 10100 ".to_string())
+    );
+}
+
+#[test]
+fn index_objects() {
+    let script = "var o = { b: 'b' }, keys = []
+for (var i = 2; i >= 0; i -= 1) o[i] = i * 10
+o['01'] = 'not an index'
+o[4000] = 'far'
+o['3'] = 30
+o[1.5] = 'fraction'
+o[-1] = 'negative'
+for (var k in o) keys.push(k)
+var sum = 0
+for (var i = 0; i < 4; i += 1) sum += o[i]
+keys.push(sum, o['2'], o[4000], 3 in o, '4000' in o, 5 in o, o.hasOwnProperty(0), o[1.5])
+keys.toString()";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some(
+            "0,1,2,3,4000,b,01,1.5,-1,60,20,far,true,true,false,true,fraction".to_string()
+        )
+    );
+}
+
+#[test]
+fn delete_operator() {
+    let script = "function F() { this.own = 1 }
+F.prototype.inherited = 2
+var f = new F(), o = { a: 1, b: 2, c: 3, 0: 'zero' }, arr = [1, 2, 3], key = 'b', x = 1
+var results = []
+results.push(delete o.a, delete o[key], delete o[0], delete o.missing, Object.keys(o))
+results.push(delete f.own, delete f.inherited, f.inherited, 'own' in f)
+results.push(delete arr[1], arr.length, arr[1], delete arr.length, arr.length)
+results.push(delete 'abc'[0], delete 'abc'.length, delete F.prototype, typeof F.prototype)
+results.push(delete x, x, delete (1 + 2), delete Object.freeze({ a: 1 }).a)
+results.toString()";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some(
+            "true,true,true,true,c,true,true,2,false,true,3,,false,3,false,false,false,object,\
+             false,1,true,false"
+                .to_string()
+        )
+    );
+    for (script, msg) in vec![
+        ("'use strict'; delete [].length", "cannot delete property 'length' of array"),
+        ("delete null.a", "cannot delete properties of null (deleting 'a')"),
+    ] {
+        match eval(script) {
+            Err(Error::Runtime(e)) => assert_eq!(e.message, msg),
+            result => panic!("{:?}", result),
+        }
+    }
+    match eval("'use strict'; var x; delete x") {
+        Err(Error::Parse(e)) => assert_eq!(
+            e.message,
+            "cannot delete an unqualified identifier in strict mode"
+        ),
+        result => panic!("{:?}", result),
+    }
+}
+
+#[test]
+fn in_operator() {
+    let script = "class A { m() {} }
+class B extends A {}
+function F() {}
+F.prototype.p = 1
+function hasFirst() { return 0 in arguments }
+var arr = [1, 2], results = [];
+results.push('p' in new F(), 'q' in new F(), 0 in arr, 2 in arr, 'length' in arr, 'call' in F)
+results.push('m' in new B(), 'then' in Promise.resolve(1), 'x' in { x: undefined })
+results.push('a' in { get a() { return 1 } }, 1 + 1 in { 2: 0 }, !('x' in {}))
+results.push(hasFirst(), hasFirst(1))
+for (var i = (0 in arr) ? 1 : 2; false; ) {}
+results.push(i)
+results.toString()";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some(
+            "true,false,true,false,true,true,true,true,true,true,true,true,false,true,1"
+                .to_string()
+        )
+    );
+    // The initializer of a for statement cannot use 'in' without parentheses.
+    assert!(eval("for (var i = 0 in {} ? 1 : 2; false; ) {}").is_err());
+    match eval("'length' in 'abc'") {
+        Err(Error::Runtime(e)) => {
+            assert_eq!(e.message, "cannot use 'in' operator to search for 'length' in \"abc\"")
+        }
+        result => panic!("{:?}", result),
+    }
+}
+
+#[test]
+fn instanceof_operator() {
+    let script = "function F() {}
+function G() {}
+G.prototype = new F()
+class A {}
+class B extends A {}
+var g = new G(), b = new B()
+async function h() {}
+var results = [g instanceof G, g instanceof F, new F() instanceof G, b instanceof A,
+  b instanceof B, new A() instanceof B, 1 instanceof F, {} instanceof F,
+  h() instanceof Promise, b instanceof Promise, !(g instanceof F)]
+results.toString()";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("true,true,false,true,true,false,false,false,true,false,false".to_string())
+    );
+    match eval("({}) instanceof {}") {
+        Err(Error::Runtime(e)) => {
+            assert_eq!(e.message, "right-hand side of 'instanceof' is not callable")
+        }
+        result => panic!("{:?}", result),
+    }
+}
+
+#[test]
+fn spread_iterables() {
+    let script = "function sum(a, b, c) { return a + b + c }
+var xs = [1, 2, 3], o = { n: 10, add: function (a, b) { return this.n + a + b } }
+function args() { return [...arguments] }
+var ys = [0, ...xs, 4, ...'hé', ...args(5, 6)]
+xs.push(...[7])
+var zs = Array.from({ length: 2, 0: 'a' }, function (x, i) { return i + String(x) })
+function P(a, b) { this.s = a + b + arguments.length }
+var p = new P(...'ab', ...xs);
+[sum(...xs), o.add(...[1, 2]), ys.length, ys[5], Array.from('ab')[1], zs[0] + zs[1], p.s,
+  p.constructor === P]";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("6,13,9,h,b,0a1undefined,ab6,true".to_string())
+    );
+    match eval("f(...1)") {
+        Err(Error::Runtime(e)) => assert_eq!(e.message, "1 is not iterable"),
+        result => panic!("{:?}", result),
+    }
+}
+
+#[test]
+fn switch_statements() {
+    let script = "function dense(n) {
+  switch (n) { case 3: n += 1; case 1: return 'a' + n; default: return 'd'; case 2: return 'b' }
+}
+function sparse(x) {
+  var r = ''
+  switch (x) { case 'a': r += 'A'; break; case 10: r += 'T'; default: r += 'D' }
+  return r
+}
+var s = ''
+for (var i = 0; i < 5; i += 1) {
+  switch (i) { case 1: continue; case 3: break; default: s += i }
+  s += dense(i) + sparse(i)
+}
+s + sparse('a') + sparse(10) + dense('1')";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("0dD2bDa4D4dDATDd".to_string())
+    );
+    match eval("switch (1) { default: default: }") {
+        Err(Error::Parse(e)) => {
+            assert_eq!(e.message, "more than one default clause in switch statement")
+        }
+        result => panic!("{:?}", result),
+    }
+}
+
+#[test]
+fn do_while_loops() {
+    let script = "var i = 0, s = ''
+do s += i; while ((i += 1) < 3)
+do { i += 1; if (i == 5) continue; if (i == 7) break; s += i } while (true)
+if (s) do s += '!'; while (false); else s += '?'
+s";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("01246!".to_string())
+    );
+
+    // The loop is compiled by the JIT as other loops are.
+    let script = "function sum(n) {
+  var t = 0, k = 0
+  do { t += k; k += 1; if (k == 5) continue } while (k < n)
+  return t
+}";
+    let mut vm = Engine::builder().jit_loop_threshold(2).build().run(script).unwrap();
+    let sum = vm.get_global("sum").unwrap();
+    assert_eq!(
+        vm.call(sum, Value::Undefined, &[Value::Number(100.0)]),
+        Ok(Value::Number(4950.0))
+    );
+    assert_eq!(vm.stats().jit_compilations, 1);
+}
+
+#[test]
+fn convert_objects_to_primitives() {
+    let script = "var calls = { s: '' }
+var a = { valueOf: function () { calls.s += 'a'; return 2 }, toString: function () { return 'A' } }
+var b = { valueOf: function () { calls.s += 'b'; return {} }, toString: function () { return '3' } }
+var c = { toString: function () { return 'C' } }
+var r = []
+r.push(a + b, a * b, b > a, a == 2, c + 1, 'x' + a, String(a), [1, 2] + 1, '3' - 1)
+r.push(calls.s)
+r";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("23,6,true,true,C1,x2,A,1,21,2,ababbaaa".to_string())
+    );
+    match eval("({ valueOf: function () { return {} }, toString: null }) + 1") {
+        Err(Error::Runtime(e)) => assert_eq!(e.message, "cannot convert object to primitive value"),
+        result => panic!("{:?}", result),
+    }
+}
+
+#[test]
+fn read_undeclared_variables() {
+    let script = "function f(a) { return [typeof a, typeof missing, typeof f, typeof Math] }
+f(1)";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("number,undefined,function,object".to_string())
+    );
+    match eval("typeof missing.a") {
+        Err(Error::Runtime(e)) => assert_eq!(e.message, "missing is not defined"),
+        result => panic!("{:?}", result),
+    }
+}
+
+#[test]
+fn labelled_statements() {
+    let script = "var s = ''
+outer: for (var i = 0; i < 4; i += 1) {
+  for (var j = 0; j < 4; j += 1) {
+    if (j == 2) continue outer
+    if (i == 3) break outer
+    s += i + '' + j + ' '
+  }
+}
+a: for (var x of [1, 2, 3]) {
+  for (var y of [1, 2]) {
+    for (var k in { p: 1 }) { if (y == 2) continue a; if (x == 3) break a; s += x + k }
+  }
+}
+b: { s += '.'; if (s) break b; s += 'never' }
+var n = 0
+c: while (true) { switch (n) { case 3: break c; default: n += 1; continue c } }
+s + n";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("00 01 10 11 20 21 1p2p.3".to_string())
+    );
+    match eval("a: while (true) { b: { continue b } }") {
+        Err(Error::Codegen(e)) => assert_eq!(
+            e.message,
+            "illegal continue statement: 'b' does not denote an iteration statement"
+        ),
+        result => panic!("{:?}", result),
+    }
+
+    // Jumping out of an inner loop leaves the loops the JIT compiles.
+    let script = "function count(m) {
+  var c = 0
+  o: for (var i = 0; i < m; i += 1) {
+    for (var j = 0; j < m; j += 1) { if (j > i) continue o; c += 1 }
+  }
+  return c
+}";
+    let mut vm = Engine::builder().jit_loop_threshold(2).build().run(script).unwrap();
+    let count = vm.get_global("count").unwrap();
+    assert_eq!(
+        vm.call(count, Value::Undefined, &[Value::Number(10.0)]),
+        Ok(Value::Number(55.0))
+    );
+    assert_eq!(vm.stats().jit_compilations, 2);
+}
+
+#[test]
+fn block_scoped_declarations() {
+    let script = "var s = ''
+let a = 1
+{ let a = 2; s += a }
+s += a
+const [x, { y = x + 1 }] = [3, {}]
+s += x + y
+for (let i = 0; i < 3; i += 1) { const t = i * 2; s += t }
+for (const k in { p: 1 }) s += k
+switch (1) { case 1: let z = 1; case 2: s += z }
+var next = (function () { let n = 0; return function () { n += 1; return n } })()
+next()
+s += next()
+s";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("217024p12".to_string())
+    );
+    for &(script, message) in &[
+        ("const c = 1; c = 2", "assignment to constant variable 'c'"),
+        ("{ let v = w; let w = 1 }", "cannot access 'w' before initialization"),
+        ("let t = typeof t", "cannot access 't' before initialization"),
+        (
+            "switch (2) { case 1: let z = 1; break; default: z }",
+            "cannot access 'z' before initialization",
+        ),
+        // Closures check the variables they share with the function declaring them.
+        (
+            "function g() { const c = 1; (function () { c = 2 })(); return c }\ng()",
+            "assignment to constant variable 'c'",
+        ),
+        (
+            "function g() { const f = () => x; f(); let x = 1 }\ng()",
+            "cannot access 'x' before initialization",
+        ),
+    ] {
+        match eval(script) {
+            Err(Error::Runtime(e)) => assert_eq!(e.message, message),
+            result => panic!("{:?}", result),
+        }
+    }
+    match eval("let d = 1; let d = 2") {
+        Err(Error::Codegen(e)) => assert_eq!(e.message, "identifier 'd' has already been declared"),
+        result => panic!("{:?}", result),
+    }
+
+    // Variables initialized where they are read need no checks, so the JIT compiles the loop.
+    let script = "function sum(n) {
+  let s = 0
+  for (let i = 0; i < n; i += 1) { const t = i * 2; s += t }
+  return s
+}";
+    let mut vm = Engine::builder().jit_loop_threshold(2).build().run(script).unwrap();
+    let sum = vm.get_global("sum").unwrap();
+    assert_eq!(
+        vm.call(sum, Value::Undefined, &[Value::Number(10.0)]),
+        Ok(Value::Number(90.0))
+    );
+    assert_eq!(vm.stats().jit_compilations, 1);
+}
+
+#[test]
+fn per_iteration_bindings() {
+    // Each iteration of a loop has its own let and const, which closures keep.
+    let script = "let fs = []
+for (let i = 0; i < 3; i += 1) fs.push(() => i)
+fs.map(f => f())";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("0,1,2".to_string())
+    );
+    let script = "var fs = []
+for (let i = 0; i < 2; i += 1) {
+  const j = i * 10
+  for (let k = 0; k < 2; k += 1) fs.push(() => { i += 1; return i + j + k })
+  if (i > 0) continue
+}
+let n = 0
+while (n < 2) { const m = n; fs.push(() => m); n += 1 }
+fs.map((f, index, arr) => f() + index * arr.length)";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("1,9,24,32,24,31".to_string())
+    );
+}
+
+#[test]
+fn run_code_incrementally() {
+    let engine = Engine::new();
+    let mut vm = engine.create_vm();
+    let mut eval_in = |source: &str| {
+        engine
+            .eval_in(source, &mut vm)
+            .map(|val| val.to_string())
+            .map_err(|e| e.to_string())
+    };
+    assert_eq!(
+        eval_in("var a = 1; function f() { return a + b }"),
+        Ok("undefined".to_string())
+    );
+    // Old functions see the globals declared later, and new code sees the old ones.
+    assert_eq!(eval_in("var b = 10\nf()"), Ok("11".to_string()));
+    assert_eq!(
+        eval_in("let c = f() + 1; const d = 'd'; var a; c + d + a"),
+        Ok("12d1".to_string())
+    );
+    // Calls go to the function declared last.
+    assert_eq!(eval_in("function g() { return f() + f() }\ng()"), Ok("22".to_string()));
+    assert_eq!(eval_in("function f() { return 'new' }\ng()"), Ok("newnew".to_string()));
+    // Errors stop the code they are in, but not the code run after.
+    assert!(eval_in("b = 20; missing()").is_err());
+    assert!(eval_in("var (").is_err());
+    assert_eq!(eval_in("for (var i = 0; i < 3; i += 1) b += i\nb"), Ok("23".to_string()));
+    assert_eq!(
+        eval_in("var h = function() { return [1, 2, 3].length }; h() + 'x'"),
+        Ok("3x".to_string())
+    );
+    let script = "function o() { function i() { return a + b } return i() }
+{ let q = 5; var w = function() { return q } }
+o() + w()";
+    assert_eq!(eval_in(script), Ok("29".to_string()));
+}
+
+#[test]
+fn run_files_in_one_scope() {
+    let mut vm = Engine::new().create_vm();
+    let files = vec![
+        ("prelude.js", "var n = 1\nfunction inc() { n += 1 }"),
+        ("main.js", "inc()\nvar m = n * 10\nm"),
+        ("last.js", "\n\nm + missing"),
+    ];
+    let mut results = vec![];
+    for &(file_name, source) in &files {
+        let engine = Engine::builder().file_name(file_name).build();
+        results.push(engine.eval_in(source, &mut vm).map(|val| val.to_string()));
+    }
+    assert_eq!(results[0], Ok("undefined".to_string()));
+    assert_eq!(results[1], Ok("20".to_string()));
+
+    // Errors are located in the file they are in.
+    let span = match results[2] {
+        Err(Error::Runtime(ref e)) => e.span.unwrap(),
+        ref result => panic!("expected a runtime error, got {:?}", result),
+    };
+    let info = vm.source.as_ref().unwrap();
+    assert_eq!(info.location(span.start), "last.js:3:5");
+    assert_eq!(info.locate(span.start), ("last.js", "\n\nm + missing", 6));
+    assert_eq!(info.location(0), "prelude.js:1:1");
+    assert_eq!(info.location(info.files[0].0 + 6), "main.js:2:1");
+}
+
+#[test]
+fn async_functions() {
+    let script = "var out = []
+async function twice(x) {
+  out.push('twice ' + x)
+  var y = await x
+  return y * 2
+}
+async function sum(n) {
+  var total = 0
+  for (var i = 1; i <= n; i += 1) total += await twice(i)
+  out.push('sum ' + total)
+  return total
+}
+var add = async (a, b) => (await a) + (await b)
+var last = async function () { out.push('last ' + await add(sum(2), 4)) }
+var p = sum(3)
+last()
+out.push('sync')";
+    let mut vm = Engine::new().create_vm();
+    let engine = Engine::new();
+    assert_eq!(engine.eval_in(script, &mut vm).map(|val| val.to_string()), Ok("3".to_string()));
+    // Awaited values are only used once the script has finished.
+    assert_eq!(
+        engine.eval_in("out.toString()", &mut vm).map(|val| val.to_string()),
+        Ok("twice 1,twice 1,sync,twice 2,twice 2,twice 3,sum 6,sum 12,last 10".to_string())
+    );
+
+    // `await` is an identifier outside of async functions, where it cannot be used.
+    assert!(eval("function f(p) { return await p }").is_err());
+    assert!(eval("async function f() { await }").is_err());
+    assert_eq!(eval("var await = 1; await + 1").ok(), Some(Value::Number(2.0)));
+    assert_eq!(eval("var async = x => x; async(3)").ok(), Some(Value::Number(3.0)));
+}
+
+#[test]
+fn promises() {
+    let script = "var out = []
+var p = new Promise((resolve, reject) => { out.push('executor'); resolve(1); reject(2) })
+p.then(v => v + 1)
+  .then(v => { out.push('then ' + v); return Promise.reject('bad') })
+  .then(v => out.push('skipped'))
+  .catch(e => { out.push('caught ' + e); return 'ok' })
+  .finally(() => out.push('finally'))
+  .then(v => out.push('after ' + v))
+var later
+new Promise(resolve => { later = resolve }).then(v => out.push('later ' + v))
+Promise.resolve(p).then(v => out.push('same ' + (Promise.resolve(p) === p)))
+async function f() { out.push('unreached ' + await Promise.reject('no')) }
+f().then(undefined, e => out.push('rejected ' + e))
+async function g(x) { return Promise.resolve(x * 2) }
+g(3).then(v => later(v))
+out.push('sync')";
+    let mut vm = Engine::new().create_vm();
+    let engine = Engine::new();
+    assert!(engine.eval_in(script, &mut vm).is_ok());
+    assert_eq!(
+        engine.eval_in("out.toString()", &mut vm).map(|val| val.to_string()),
+        Ok("executor,sync,same true,then 2,rejected no,later 6,caught bad,finally,after ok"
+            .to_string())
+    );
+
+    assert!(eval("new Promise(1)").is_err());
+    assert!(eval("Promise.resolve(1).then.call({})").is_err());
+    assert_eq!(
+        eval("typeof Promise.resolve(1).catch").map(|val| val.to_string()),
+        Ok("function".to_string())
+    );
+}
+
+#[test]
+fn with_statement() {
+    let script = "var o = { a: 1, b: 2, get c() { return this.a + 10 } }
+o.m = function () { return this.a }
+var a = 'global a', z = 0, seen = []
+function f(x) {
+  var b = 'local b'
+  with (o) {
+    seen.push(a, b, c, x, m(), typeof q, typeof a)
+    a = 5
+    z = a + b
+    b += 1
+    with ({ b: 'inner' }) seen.push(a, b)
+  }
+  return b
+}
+seen.push(f(3))
+with (Math) seen.push(floor(2.5), pow(2, 3))
+seen.push(o.a, o.b, z, a)
+seen.toString()";
+    assert_eq!(
+        eval(script).map(|val| val.to_string()),
+        Ok("1,2,11,3,1,undefined,number,5,inner,local b,2,8,5,3,7,global a".to_string())
+    );
+
+    // Functions made in the body look names up in the objects too.
+    let script = "var seen = [], r = { k: 'r' }
+function f() {
+  var p = { v: 'p', n: 0 }, v = 'outer', q = 0
+  var fs = []
+  with (p) {
+    fs.push(() => v, function () { n += 1; return n }, () => { var v = 'own'; return v })
+    with ({ w: 'inner' }) fs.push(() => () => v + w)
+    fs.push(() => { q = 5; return typeof nothing })
+  }
+  for (var i = 0; i < fs.length; i += 1) seen.push(i == 3 ? fs[i]()() : fs[i]())
+  seen.push(p.n, q, v)
+}
+f()
+with (r) { function g() { return k } }
+for (let i = 0; i < 2; i += 1) with ({ k: i }) r[i] = () => k + i
+seen.push(g(), r[0](), r[1]())
+seen.toString()";
+    assert_eq!(
+        eval(script).map(|val| val.to_string()),
+        Ok("p,1,own,pinner,undefined,1,5,outer,r,0,2".to_string())
+    );
+
+    match Engine::builder().strict(true).build().eval("with ({}) {}") {
+        Err(Error::Parse(e)) => {
+            assert_eq!(e.message, "with statements are not allowed in strict mode")
+        }
+        result => panic!("expected a parse error, got {:?}", result),
+    }
+    assert!(eval("function f() { 'use strict'; with ({}) {} }").is_err());
+}
+
+#[test]
+fn arrow_functions() {
+    let script = "var double = x => x * 2
+var add = (a, b) => { return a + b }
+var o = {
+  v: 1,
+  m: function(a, b, d = () => this.v) {
+    var f = () => () => this.v + arguments[0] + arguments.length
+    return [f()(), d()]
+  },
+}
+add(double(2), 1) + ',' + o.m(10, 20)";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("5,13,1".to_string())
+    );
+    let script = "function F() { this.x = 3; var self = () => this; this.get = () => self().x }
+new F().get()";
+    assert_eq!(eval(script).ok(), Some(Value::Number(3.0)));
+}
+
+#[test]
+fn freeze_and_seal_objects() {
+    let script = "var o = Object.freeze({ a: 1, b: { c: 2 } })
+o.a = 5; o.z = 1; o.b.c = 3
+var s = Object.seal({ a: 1 })
+s.a = 2; s.b = 3
+var arr = Object.seal([1, 2])
+arr[0] = 9; arr[2] = 1; arr.length = 1;
+[o.a, o.z, o.b.c, s.a, s.b, arr, Object.isFrozen(o), Object.isSealed(o), Object.isFrozen(s),
+  Object.isFrozen(o.b), Object.isFrozen(1)]";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("1,,3,2,,9,2,true,true,false,false,true".to_string())
+    );
+    let engine = Engine::builder().strict(true).build();
+    for (script, msg) in vec![
+        (
+            "Object.freeze({ a: 1 }).a = 2",
+            "cannot assign to read only property 'a' of object",
+        ),
+        (
+            "Object.seal({}).a = 2",
+            "cannot add property a, object is not extensible",
+        ),
+        (
+            "Object.seal([1, 2]).length = 0",
+            "cannot delete property '1' of [object Array]",
+        ),
+    ] {
+        match engine.eval(script) {
+            Err(Error::Runtime(e)) => assert_eq!(e.message, msg),
+            result => panic!("{:?}", result),
+        }
+    }
+    // Member stores are not compiled by the JIT, so they are checked however hot the loop is.
+    let script = "var o = Object.freeze({ n: 0 }), s = 0
+for (var i = 0; i < 10; i += 1) { o.n = i; s += i }
+o.n + s";
+    let engine = Engine::builder().jit_loop_threshold(2).build();
+    assert_eq!(engine.eval(script).ok(), Some(Value::Number(45.0)));
+}
+
+#[test]
+fn object_to_string_tags() {
+    let script = "function f() { return toString.call(arguments) }
+var toString = Object.prototype.toString;
+[toString.call([]), toString.call(f), toString.call(Math.pow), toString.call(null),
+  toString.call(undefined), toString.call(1), toString.call('a'), toString.call(true),
+  toString.call({}), f(), Object.prototype.hasOwnProperty.call({ a: 1 }, 'a'),
+  Math.pow.call(null, 2, 3)]";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some(
+            "[object Array],[object Function],[object Function],[object Null],\
+             [object Undefined],[object Number],[object String],[object Boolean],\
+             [object Object],[object Arguments],true,8"
+                .to_string()
+        )
+    );
+}
+
+#[test]
+fn template_literals() {
+    let script = "function tag(strings, x, y) { return [strings.length, strings.raw[2], x, y] }
+function same(strings) { return strings }
+function f() { return same`q` }
+var a = 1, b = 'x';
+[`a=${a} b=${b}!`, `${a}${a + 1}`, `${`in${a}`} {${ { k: 2 }.k }}`, tag`one ${a} two ${b}\\n`,
+  f() === f(), same`\\unicode`[0], same`\\unicode`.raw[0]]";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("a=1 b=x!,12,in1 {2},3,\\n,1,x,true,,\\unicode".to_string())
+    );
+}
+
+#[test]
+fn classes() {
+    let script = "class Point {
+  constructor(x, y) { this.x = x; this.y = y }
+  sum() { return this.x + this.y }
+  scaled(k) { return new Point(this.x * k, this.y * k) }
+  static origin() { return new Point(0, 0) }
+  static() { return 's' }
+}
+var Empty = class { hi() { return 'hi' } }
+var p = new Point(1, 2);
+[p.sum(), p.scaled(3).sum(), Point.origin().sum(), p.static(), p.constructor === Point,
+  Point.prototype.sum === p.sum, new Empty().hi(), typeof Point]";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("3,9,0,s,true,true,hi,function".to_string())
+    );
+    for (script, msg) in vec![
+        ("new A(); class A {}", "cannot access 'A' before initialization"),
+        ("class {}", "expect class name"),
+        (
+            "class A { constructor() {} constructor() {} }",
+            "a class may only have one constructor",
+        ),
+    ] {
+        match eval(script) {
+            Err(Error::Parse(e)) => assert_eq!(e.message, msg),
+            Err(Error::Runtime(e)) => assert_eq!(e.message, msg),
+            result => panic!("{:?}", result),
+        }
+    }
+}
+
+#[test]
+fn class_inheritance() {
+    let script = "class Shape {
+  constructor(name) { this.name = name }
+  describe() { return 'a ' + this.name }
+  static create(name) { return new this(name) }
+  static kind() { return 'shape' }
+}
+class Square extends Shape {
+  constructor(side) { super('square'); this.side = side }
+  describe() { return super.describe() + ' of ' + this.side }
+  area() { var f = () => super['describe'](); return f() + ': ' + this.side * this.side }
+  static kind() { return 'derived ' + super.kind() }
+}
+class Named extends Square {}
+var s = new Square(3), n = new Named(2);
+[s.describe(), s.area(), n.describe(), n.name, Named.kind(), Shape.create('dot').describe(),
+  n.constructor === Named, Named.prototype.__proto__ === Square.prototype,
+  (function () { return 5 }).call({})]";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some(
+            "a square of 3,a square: 9,a square of 2,square,derived shape,a dot,true,true,5"
+                .to_string()
+        )
+    );
+    for (script, msg) in vec![
+        ("super.x", "'super' outside of a method"),
+        ("class A { m() { function f() { super.m() } } }", "'super' outside of a method"),
+        (
+            "class A { constructor() { super() } }",
+            "'super()' is only allowed in the constructor of a derived class",
+        ),
+    ] {
+        match eval(script) {
+            Err(Error::Parse(e)) => assert_eq!(e.message, msg),
+            result => panic!("{:?}", result),
+        }
+    }
+}
+
+#[test]
+fn remainder() {
+    let script = "[5.5 % 2, -7 % 3, 7 % -3, 5 % 0, 1 / (-0 % 1), 3 % Infinity, Infinity % 2,
+  1e20 % 3]";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("1.5,-1,1,NaN,-Infinity,3,NaN,1".to_string())
+    );
+    // The JIT computes the same remainders.
+    let script = "var s = 0
+for (var i = 0; i < 50; i += 1) { s = s + (i - 4.5) % 2.5 + (0 - i) % 7 }
+s";
+    for engine in vec![Engine::new(), Engine::builder().jit_loop_threshold(2).build()] {
+        assert_eq!(engine.eval(script).ok(), Some(Value::Number(-107.0)));
+    }
+}
+
+#[test]
+fn unary_minus() {
+    let script = "[-'5', -' 0x10 ', -'abc', -{}, -[], -[7], -true, -null, -undefined,
+  -{ valueOf: function () { return 2 } }, 1 / -'']";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("-5,-16,NaN,NaN,0,-7,-1,0,NaN,-2,-Infinity".to_string())
+    );
+    // The JIT converts booleans to numbers too.
+    let script = "var s = 0
+for (var i = 0; i < 50; i += 1) { s = s + -(i < 20) - -(i > 45) }
+s";
+    for engine in vec![Engine::new(), Engine::builder().jit_loop_threshold(2).build()] {
+        assert_eq!(engine.eval(script).ok(), Some(Value::Number(-16.0)));
+    }
+}
+
+#[test]
+fn every_instruction_runs() {
+    use bytecode_gen::disassemble;
+    use std::collections::HashSet;
+    use vm::{END, GET_WITH_OBJECT, INSTRUCTIONS};
+
+    // The code generator emits every instruction for this script, and the instruction table
+    // describes each of them.
+    let script = "var log = []
+function f(a, ...rest) {
+  var g = f.helper
+  a = a + 1
+  log.push(typeof undeclared, typeof a, !a, -a, 'x' in { x: 1 }, arguments.length)
+  for (var k in { p: 1 }) log.push(k)
+  for (var v of [...rest, 2]) log.push(v)
+  switch (a) { case 1: log.push('one'); break; case 2: log.push('two') }
+  switch (a - 1) { case 0: log.push(0); case 1: let s = 'one'; case 2: log.push(s) }
+  if (a === 2 && a !== 3 && a == 2 && a != 3 && a < 3 && a > 1 && a <= 2 && a >= 2) {
+    log.push(a * 2 / 4 % 3 - 1, 100000, this === undefined, false)
+  } else {
+    log.push(true)
+  }
+  return g(...rest)
+}
+f.helper = function () { return arguments.length }
+let later = 1
+const fixed = { ...{ o: 1, gone: 2 } }
+delete fixed.gone
+function readLater() { return later }
+if (log.length > 100) fixed = 2
+var made = new f.helper(...[])
+async function tick() { await 0 }
+tick()
+log.push(f(1, 3, 4), made.constructor === new f.helper().constructor, made instanceof f.helper)
+log.push(readLater(), fixed.o)
+var counters = []
+for (let c = 0; c < 2; c += 1) { let d = c; counters.push(() => { c += d; return c }) }
+log.push(counters[1]())
+with ({ w: 3 }) log.push((() => w)())
+log.toString()";
+    let vm = Engine::new().load(script).unwrap();
+    let emitted: HashSet<u8> = disassemble(&vm.insts)
+        .lines()
+        .map(|line| {
+            let addr = line.split_whitespace().next().unwrap();
+            vm.insts[usize::from_str_radix(addr, 16).unwrap()]
+        })
+        .collect();
+    assert_eq!(emitted, (END..=GET_WITH_OBJECT).collect());
+    assert_eq!(INSTRUCTIONS.len(), GET_WITH_OBJECT as usize + 1);
+    assert!(INSTRUCTIONS.iter().enumerate().all(|(op, inst)| inst.op as usize == op));
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some(
+            "undefined,number,false,-2,true,3,p,3,4,2,two,one,0,100000,false,false,2,true,true,1,1,\
+             2,3"
+                .to_string()
+        )
+    );
+}
+
+#[test]
+fn accessors() {
+    let script = "var o = {
+  _x: 1,
+  get x() { return this._x * 10 },
+  set x(v) { this._x = v },
+  get only() { return 'ro' },
+  set wo(v) { this.written = v },
+  get: 5,
+}
+var log = [o.x]
+o.x = 7
+o.only = 3
+o.wo = 'w'
+log.push(o.x, o._x, o.only, o.wo, o.written, o.get)
+var c = { __proto__: o }
+c.x = 2
+log.push(c.x, o._x, c.hasOwnProperty('_x'), { ...o }.x)
+var counter = { n: 0, get next() { this.n = this.n + 1; return this.n } }
+log.push(counter.next, counter['next'])
+log.push({ get a() { return 1 }, a: 2 }.a, { a: 2, get a() { return 3 } }.a)
+log.toString()";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("10,70,7,ro,,w,5,20,7,true,70,1,2,2,3".to_string())
+    );
+    match eval("({ get x(a) { return a } })") {
+        Err(Error::Parse(e)) => assert_eq!(e.message, "a getter must not have parameters"),
+        result => panic!("{:?}", result),
+    }
+    match eval("({ set x() {} })") {
+        Err(Error::Parse(e)) => assert_eq!(e.message, "a setter must have exactly one parameter"),
+        result => panic!("{:?}", result),
+    }
+    match Engine::builder().strict(true).build().eval("var o = { get x() { return 1 } }\no.x = 2") {
+        Err(Error::Runtime(e)) => assert_eq!(e.message, "cannot set 'x', which has only a getter"),
+        result => panic!("{:?}", result),
+    }
+}
+
+#[test]
+fn index_strings() {
+    let script = "function at(s, i) { return s[i] }
+var last
+for (var i = 0; i < 20; i = i + 1) last = at('abc', i % 4);
+['hello'[1], 'hello'[5], 'hello'[-1], 'hello'[1.5], 'hello'['4'], 'héllo'[1], last, at('xy', 1)]";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("e,,,,o,é,,y".to_string())
+    );
+}
+
+#[test]
+fn global_constants() {
+    use bytecode_gen::disassemble;
+
+    let script = "function area(r) { return PI * r * r }
+function describe() { return NAME + (DEBUG ? '!' : '') + typeof NOTHING + LIMIT }
+function shadow(PI) { let NAME = 'b'; return PI + NAME }
+const PI = 3, NAME = 'a', DEBUG = true, NOTHING = null, LIMIT = [1]
+var results = [area(2), describe(), shadow(1), PI]
+results.toString()";
+    assert_eq!(
+        eval(script).ok().map(|val| val.to_string()),
+        Some("12,a!object1,1b,3".to_string())
+    );
+    // Only the constant initialized with something other than a literal is looked up.
+    let vm = Engine::new().load(script).unwrap();
+    let lookups = disassemble(&vm.insts)
+        .lines()
+        .filter(|line| line.ends_with(" GetGlobal"))
+        .count();
+    assert_eq!(lookups, 1);
+
+    match eval("const N = 1\nfunction f() { N = 2 }\nf()") {
+        Err(Error::Runtime(e)) => assert_eq!(e.message, "assignment to constant variable 'N'"),
+        result => panic!("{:?}", result),
+    }
+}
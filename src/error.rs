@@ -0,0 +1,217 @@
+use diagnostic::{Diagnostic, Level};
+
+use std::error;
+use std::fmt;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+/// A range of positions in source code: [start, end).
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span {
+            start: start,
+            end: end,
+        }
+    }
+}
+
+/// An error from any stage of running a script.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    Lex(LexError),
+    Parse(ParseError),
+    Codegen(CodegenError),
+    Runtime(RuntimeError),
+    Internal(String), // A bug in rapidus
+}
+
+/// A character sequence that is not a token.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LexError {
+    pub message: String,
+    pub span: Span,
+}
+
+/// Tokens that do not make up a program.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    pub message: String,
+    pub span: Span,
+}
+
+/// A program that parses but uses features that cannot be compiled yet.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CodegenError {
+    pub message: String,
+    pub span: Span,
+}
+
+/// An error while running the program.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuntimeError {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub span: Option<Span>,     // Where the error happened, if the source is known
+    pub stack_trace: Vec<Span>, // The calls being made, innermost first
+}
+
+/// The constructor that JavaScript would make a runtime error with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorKind {
+    Error, // Errors of rapidus itself, such as running out of the instruction limit
+    TypeError,
+    RangeError,
+    ReferenceError,
+}
+
+impl Error {
+    /// Returns the message without the stage the error comes from.
+    pub fn message(&self) -> &str {
+        match self {
+            &Error::Lex(ref e) => e.message.as_str(),
+            &Error::Parse(ref e) => e.message.as_str(),
+            &Error::Codegen(ref e) => e.message.as_str(),
+            &Error::Runtime(ref e) => e.message.as_str(),
+            &Error::Internal(ref message) => message.as_str(),
+        }
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            &Error::Lex(ref e) => Some(e.span),
+            &Error::Parse(ref e) => Some(e.span),
+            &Error::Codegen(ref e) => Some(e.span),
+            &Error::Runtime(ref e) => e.span,
+            &Error::Internal(_) => None,
+        }
+    }
+
+    /// Renders the error like the errors rapidus prints, pointing at the code in `source`.
+    pub fn render(&self, file_name: &str, source: &str) -> String {
+        match self.span() {
+            Some(span) => Diagnostic {
+                level: Level::Error,
+                message: self.message().to_string(),
+                pos: span.start,
+                len: span.end - span.start,
+            }.render(file_name, source),
+            None => format!("error: {}\n", self.message()),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Error::Lex(ref e) => write!(f, "lex error: {}", e),
+            &Error::Parse(ref e) => write!(f, "parse error: {}", e),
+            &Error::Codegen(ref e) => write!(f, "codegen error: {}", e),
+            &Error::Runtime(ref e) => write!(f, "runtime error: {}", e),
+            &Error::Internal(ref message) => write!(f, "internal error: {}", message),
+        }
+    }
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// Shows the message after the kind, as in "TypeError: x is not a function".
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ErrorKind::Error => write!(f, "{}", self.message),
+            kind => write!(f, "{}: {}", kind, self.message),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            &Error::Lex(ref e) => Some(e),
+            &Error::Parse(ref e) => Some(e),
+            &Error::Codegen(ref e) => Some(e),
+            &Error::Runtime(ref e) => Some(e),
+            &Error::Internal(_) => None,
+        }
+    }
+}
+
+impl error::Error for LexError {}
+impl error::Error for ParseError {}
+impl error::Error for CodegenError {}
+impl error::Error for RuntimeError {}
+
+impl From<LexError> for Error {
+    fn from(e: LexError) -> Error {
+        Error::Lex(e)
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Error {
+        Error::Parse(e)
+    }
+}
+
+impl From<CodegenError> for Error {
+    fn from(e: CodegenError) -> Error {
+        Error::Codegen(e)
+    }
+}
+
+impl From<RuntimeError> for Error {
+    fn from(e: RuntimeError) -> Error {
+        Error::Runtime(e)
+    }
+}
+
+#[test]
+fn render() {
+    use diagnostic::set_color;
+
+    set_color(false);
+    let source = "var a = 1\na(";
+    let error = Error::Parse(ParseError {
+        message: "expect ')'".to_string(),
+        span: Span::new(11, 12),
+    });
+    assert_eq!(error.to_string(), "parse error: expect ')'");
+    assert_eq!(
+        error.render("a.js", source),
+        "error: expect ')'\n --> a.js:2:2\n  |\n2 | a(\n  |  ^\n"
+    );
+    assert_eq!(
+        Error::Internal("oops".to_string()).render("a.js", source),
+        "error: oops\n"
+    );
+}
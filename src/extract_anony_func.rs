@@ -1,4 +1,4 @@
-use node::{FunctionDeclNode, Node, NodeBase, PropertyDefinition};
+use node::{FormalParameters, FunctionDeclNode, Node, NodeBase, PropertyDefinition};
 
 use rand::random;
 use std::collections::HashSet;
@@ -7,6 +7,7 @@ use std::collections::HashSet;
 pub struct AnonymousFunctionExtractor {
     pub pending_anonymous_function: Vec<Vec<Node>>,
     pub mangled_anonymous_function_name: Vec<(String, String)>,
+    pub in_with: bool, // Inside the body of a with statement, or of a function declared there
 }
 
 impl AnonymousFunctionExtractor {
@@ -14,6 +15,7 @@ impl AnonymousFunctionExtractor {
         AnonymousFunctionExtractor {
             pending_anonymous_function: vec![vec![]],
             mangled_anonymous_function_name: vec![],
+            in_with: false,
         }
     }
 
@@ -39,7 +41,16 @@ impl AnonymousFunctionExtractor {
                     self.run(node)
                 }
             }
-            NodeBase::FunctionDecl(FunctionDeclNode { ref mut body, .. }) => {
+            NodeBase::FunctionDecl(FunctionDeclNode {
+                ref params,
+                ref mut body,
+                ref mut in_with,
+                ..
+            }) => {
+                *in_with = self.in_with;
+                let outer_names = self.mangled_anonymous_function_name.len();
+                self.shadow_names(params, body);
+
                 let mut body = if let &mut NodeBase::StatementList(ref mut body) = &mut body.base {
                     body
                 } else {
@@ -52,14 +63,21 @@ impl AnonymousFunctionExtractor {
                     self.run(node)
                 }
 
+                self.mangled_anonymous_function_name.truncate(outer_names);
+
                 for pending_anonymous_function in self.pending_anonymous_function.last().unwrap() {
                     body.push(pending_anonymous_function.clone())
                 }
 
                 self.pending_anonymous_function.pop();
             }
-            NodeBase::FunctionExpr(_, _, _) => {
-                if let NodeBase::FunctionExpr(mut name, mut params, mut body) = node.clone().base {
+            NodeBase::FunctionExpr(_, _, _, _, _) => {
+                if let NodeBase::FunctionExpr(mut name, mut params, mut body, span, is_async) =
+                    node.clone().base
+                {
+                    let outer_names = self.mangled_anonymous_function_name.len();
+                    // The name of a function expression is bound only in its own body, where
+                    // its parameters and declarations may shadow it.
                     let mut name_ = match name {
                         Some(name) => {
                             let new_name = format!("anonymous.{}.{}", name, random::<u32>());
@@ -69,6 +87,7 @@ impl AnonymousFunctionExtractor {
                         }
                         None => format!("anonymous.{}", random::<u32>()),
                     };
+                    self.shadow_names(&params, &mut body);
 
                     let mut body = if let NodeBase::StatementList(body) = body.base {
                         body
@@ -76,11 +95,16 @@ impl AnonymousFunctionExtractor {
                         unreachable!()
                     };
 
+                    // Functions in the body are declared in it, so that they can capture its
+                    // variables.
+                    self.pending_anonymous_function.push(vec![]);
+
                     for node in body.iter_mut() {
                         self.run(node)
                     }
 
-                    self.mangled_anonymous_function_name.pop();
+                    body.extend(self.pending_anonymous_function.pop().unwrap());
+                    self.mangled_anonymous_function_name.truncate(outer_names);
 
                     self.pending_anonymous_function
                         .last_mut()
@@ -92,21 +116,38 @@ impl AnonymousFunctionExtractor {
                                 use_this: false,
                                 fv: HashSet::new(),
                                 params: params,
-                                body: Box::new(Node::new(NodeBase::StatementList(body), 0)),
+                                body: Box::new(Node::new(NodeBase::StatementList(body), node.pos)),
+                                span: span,
+                                is_async: is_async,
+                                in_with: self.in_with,
                             }),
-                            0,
+                            node.pos,
                         ));
-                    *node = Node::new(NodeBase::Identifier(name_), 0);
+                    *node = Node::new(NodeBase::Identifier(name_), node.pos);
                 }
             }
-            NodeBase::Call(ref mut callee, ref mut args) => {
+            NodeBase::Call(ref mut callee, ref mut args)
+            | NodeBase::TaggedTemplate(ref mut callee, _, ref mut args) => {
                 self.run(callee);
                 for arg in args {
                     self.run(arg)
                 }
             }
+            NodeBase::Class(ref mut constructor, ref mut heritage, ref mut methods) => {
+                self.run(constructor);
+                if let Some(heritage) = heritage {
+                    self.run(heritage)
+                }
+                for &mut (_, ref mut method, _) in methods {
+                    self.run(method)
+                }
+            }
+            NodeBase::Array(ref mut elems) => for elem in elems {
+                self.run(elem)
+            },
+            NodeBase::Spread(ref mut expr) => self.run(expr),
             NodeBase::New(ref mut expr) => self.run(expr),
-            NodeBase::VarDecl(_, ref mut init) => {
+            NodeBase::VarDecl(_, ref mut init) | NodeBase::LexicalDecl(_, ref mut init, _) => {
                 if let &mut Some(ref mut init) = init {
                     self.run(init)
                 }
@@ -128,19 +169,50 @@ impl AnonymousFunctionExtractor {
                 self.run(&mut *then);
                 self.run(&mut *else_);
             }
-            NodeBase::While(ref mut cond, ref mut body) => {
+            NodeBase::While(ref mut cond, ref mut body)
+            | NodeBase::DoWhile(ref mut body, ref mut cond) => {
                 self.run(&mut *cond);
                 self.run(&mut *body);
             }
+            NodeBase::With(ref mut obj, ref mut body) => {
+                self.run(&mut *obj);
+                if let NodeBase::StatementList(ref mut nodes) = body.base {
+                    declare_block_functions(nodes);
+                }
+                let outer_in_with = self.in_with;
+                self.in_with = true;
+                self.run(&mut *body);
+                self.in_with = outer_in_with;
+            }
+            NodeBase::Label(_, ref mut body) => self.run(&mut *body),
+            NodeBase::Switch(ref mut discriminant, ref mut cases) => {
+                self.run(&mut *discriminant);
+                for &mut (ref mut label, ref mut body) in cases {
+                    if let &mut Some(ref mut label) = label {
+                        self.run(label);
+                    }
+                    self.run(body);
+                }
+            }
             NodeBase::For(ref mut init, ref mut cond, ref mut step, ref mut body) => {
                 self.run(&mut *init);
                 self.run(&mut *cond);
                 self.run(&mut *step);
                 self.run(&mut *body);
             }
+            NodeBase::ForIn(ref mut var, ref mut iterable, ref mut body)
+            | NodeBase::ForOf(ref mut var, ref mut iterable, ref mut body) => {
+                self.run(&mut *var);
+                self.run(&mut *iterable);
+                self.run(&mut *body);
+            }
             NodeBase::Assign(_, ref mut src) => {
                 self.run(&mut *src);
             }
+            NodeBase::CompoundAssign(ref mut dst, ref mut src, _) => {
+                self.run(&mut *dst);
+                self.run(&mut *src);
+            }
             NodeBase::UnaryOp(ref mut expr, _) => {
                 self.run(&mut *expr);
             }
@@ -162,7 +234,10 @@ impl AnonymousFunctionExtractor {
                 for property in properties.iter_mut() {
                     match property {
                         &mut PropertyDefinition::IdentifierReference(_) => {}
-                        &mut PropertyDefinition::Property(_, ref mut node) => self.run(node),
+                        &mut PropertyDefinition::Property(_, ref mut node)
+                        | &mut PropertyDefinition::Getter(_, ref mut node)
+                        | &mut PropertyDefinition::Setter(_, ref mut node)
+                        | &mut PropertyDefinition::Spread(ref mut node) => self.run(node),
                     }
                 }
             }
@@ -171,6 +246,25 @@ impl AnonymousFunctionExtractor {
         }
     }
 
+    /// Keeps the names of outer function expressions from being mangled where the parameters
+    /// or the declarations of `body` shadow them.
+    fn shadow_names(&mut self, params: &FormalParameters, body: &mut Node) {
+        let mut names: Vec<String> = params.iter().map(|param| param.name.clone()).collect();
+        for node in body.hoisted_decls_mut() {
+            match node.base {
+                NodeBase::FunctionDecl(FunctionDeclNode { ref name, .. })
+                | NodeBase::VarDecl(ref name, _)
+                | NodeBase::LexicalDecl(ref name, _, _) => names.push(name.clone()),
+                _ => {}
+            }
+        }
+        for name in names {
+            if self.get_mangled_anonymous_function_name(name.as_str()).is_some() {
+                self.mangled_anonymous_function_name.push((name.clone(), name));
+            }
+        }
+    }
+
     fn get_mangled_anonymous_function_name(&self, name: &str) -> Option<&String> {
         for (before_mangled, after_mangled) in self.mangled_anonymous_function_name.iter().rev() {
             if before_mangled == name {
@@ -180,3 +274,30 @@ impl AnonymousFunctionExtractor {
         None
     }
 }
+
+/// Turns the functions declared in the block `nodes` into variables that function expressions are
+/// assigned to at its start, which is how sloppy mode declares functions in blocks. Functions made
+/// when a with statement is entered see its object.
+fn declare_block_functions(nodes: &mut Vec<Node>) {
+    let (functions, others): (Vec<Node>, Vec<Node>) =
+        nodes.drain(..).partition(|node| match node.base {
+            NodeBase::FunctionDecl(_) => true,
+            _ => false,
+        });
+    for node in functions {
+        if let NodeBase::FunctionDecl(FunctionDeclNode {
+            name,
+            params,
+            body,
+            span,
+            is_async,
+            ..
+        }) = node.base
+        {
+            let func = NodeBase::FunctionExpr(Some(name.clone()), params, body, span, is_async);
+            let init = Some(Box::new(Node::new(func, node.pos)));
+            nodes.push(Node::new(NodeBase::VarDecl(name, init), node.pos));
+        }
+    }
+    nodes.extend(others);
+}
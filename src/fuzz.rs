@@ -0,0 +1,103 @@
+use engine::Engine;
+use error::Error;
+use vm::Value;
+
+use std::panic;
+use std::str;
+use std::thread;
+
+// Instructions a script may run, so that infinite loops end.
+const FUZZ_INSTRUCTION_LIMIT: usize = 100_000;
+// Calls of scripts keep their frames in the VM, not on the Rust stack, so the depth bounds the
+// memory and time that deep recursion takes before the instruction limit ends it. Builtins calling
+// back into scripts, e.g. map(), do nest the interpreter, which FUZZ_STACK_SIZE has room for.
+const FUZZ_MAX_CALL_DEPTH: usize = 300;
+// Reading and compiling code recurses once for each level of nesting, which takes tens of
+// kilobytes of stack in debug builds, so scripts get a stack that fits the deepest code the parser
+// accepts whatever thread the fuzzer calls from.
+const FUZZ_STACK_SIZE: usize = 64 << 20;
+
+/// Compiles and runs `bytes` as a script, for fuzzers. Errors in the script are fine and
+/// ignored; this panics only on bugs in rapidus, so that fuzzers report them.
+///
+/// What the script prints is discarded, process.exit is not available, and scripts that run too
+/// long are stopped.
+pub fn fuzz_check(bytes: &[u8]) {
+    let bytes = bytes.to_vec();
+    let result = thread::Builder::new()
+        .stack_size(FUZZ_STACK_SIZE)
+        .spawn(move || check(&bytes))
+        .unwrap()
+        .join();
+    if let Err(payload) = result {
+        panic::resume_unwind(payload)
+    }
+}
+
+fn check(bytes: &[u8]) {
+    let source = match str::from_utf8(bytes) {
+        Ok(source) => source,
+        Err(_) => return,
+    };
+    let engine = Engine::builder()
+        .jit(false)
+        .max_call_depth(FUZZ_MAX_CALL_DEPTH)
+        .deterministic(true)
+        .stdout(|_| {})
+        .build();
+
+    let mut vm = match engine.load(source) {
+        Ok(vm) => vm,
+        Err(Error::Internal(msg)) => panic!("{}", msg),
+        Err(_) => return,
+    };
+    if let Some(&Value::Object(ref process)) = vm.global_objects.borrow().get("process") {
+        process.borrow_mut().remove("exit");
+    }
+    vm.instruction_limit = Some(FUZZ_INSTRUCTION_LIMIT);
+    let _ = vm.run_for(FUZZ_INSTRUCTION_LIMIT);
+}
+
+#[test]
+fn fuzz_check_inputs_that_panicked() {
+    for source in &[
+        "var o = { \"b",
+        "[a = b + 23;",
+        "12==;",
+        "\x0c",
+        "\"\\u12\"",
+        "1 = 2",
+        "1.2.3",
+        "new F",
+        "undefined.x",
+        "Math.pow()",
+        "function f(){} f.call()",
+        "function A(x, y) { y = 1 } A()",
+        "var a = []; a[1e9] = 1",
+        "var o = {}; o.o = o; console.log(o)",
+        "while (true) {}",
+        "function f() { f() } f()",
+        "Object.keys({ \"\\x00\": 1 })",
+        "for (var k in { \"\\x00\": 1 }) {}",
+    ] {
+        fuzz_check(source.as_bytes());
+    }
+    fuzz_check(b"\xff");
+}
+
+#[test]
+fn fuzz_check_deeply_nested_inputs() {
+    for &(open, close) in &[
+        ("(", ")"),
+        ("[", "]"),
+        ("{", "}"),
+        ("f(", ")"),
+        ("!", ""),
+        ("a => ", ""),
+        ("function f() {", "}"),
+        ("`${", "}`"),
+    ] {
+        let source = format!("{}1{}", open.repeat(10_000), close.repeat(10_000));
+        fuzz_check(source.as_bytes());
+    }
+}
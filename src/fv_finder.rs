@@ -6,6 +6,7 @@ use std::collections::{HashMap, HashSet};
 #[derive(Debug, Clone)]
 pub struct FreeVariableFinder {
     pub varmap: Vec<HashSet<String>>,
+    pub function_names: Vec<HashSet<String>>, // Names in varmap that are declared as functions
     pub cur_fv: Vec<HashSet<String>>,
     pub mangled_function_name: Vec<HashMap<String, String>>,
     pub use_this: Vec<bool>,
@@ -19,6 +20,7 @@ impl FreeVariableFinder {
         varmap.insert("Math".to_string());
         FreeVariableFinder {
             varmap: vec![varmap],
+            function_names: vec![HashSet::new()],
             cur_fv: vec![HashSet::new()],
             mangled_function_name: vec![],
             use_this: vec![false],
@@ -30,6 +32,7 @@ impl FreeVariableFinder {
             &mut NodeBase::StatementList(ref mut nodes) => {
                 let mut func_decl_index = vec![];
                 self.varmap.push(HashSet::new()); // main ( local )
+                self.function_names.push(HashSet::new());
 
                 for (i, node) in nodes.iter_mut().enumerate() {
                     match node.base {
@@ -65,30 +68,27 @@ impl FreeVariableFinder {
                 ref mut body,
                 ..
             }) => {
+                let own_name = if let Some(mangled_name) = mangled_name {
+                    mangled_name.clone()
+                } else {
+                    name.clone()
+                };
                 self.varmap.push(HashSet::new());
-                self.varmap
-                    .last_mut()
-                    .unwrap()
-                    .insert(if let Some(mangled_name) = mangled_name {
-                        mangled_name.clone()
-                    } else {
-                        name.clone()
-                    });
+                self.varmap.last_mut().unwrap().insert(own_name.clone());
+                self.function_names.push(HashSet::new());
+                self.function_names.last_mut().unwrap().insert(own_name);
 
                 for param in params.clone() {
                     self.varmap.last_mut().unwrap().insert(param.name);
                 }
 
-                let mut body = if let &mut NodeBase::StatementList(ref mut body) = &mut body.base {
-                    body
-                } else {
-                    unreachable!()
-                };
-
                 self.mangled_function_name.push(HashMap::new());
 
-                let mut func_decl_index = vec![];
-                for (i, node) in body.iter_mut().enumerate() {
+                // Functions declared in blocks and loops belong to the function as well, and
+                // its variables are known before any of its statements.
+                // TODO: Free variables other than the let and const of loops live in globals, so
+                // every call of the function shares one binding per variable.
+                for node in body.hoisted_decls_mut() {
                     match &mut node.base {
                         &mut NodeBase::FunctionDecl(FunctionDeclNode {
                             ref name,
@@ -112,20 +112,35 @@ impl FreeVariableFinder {
 
                             if nested {
                                 *mangled_name = mangled_name2.clone();
-                                self.varmap
-                                    .last_mut()
-                                    .unwrap()
-                                    .insert(mangled_name.clone().unwrap());
-                            } else {
-                                self.varmap.last_mut().unwrap().insert(name.clone());
                             }
-
-                            func_decl_index.push(i)
+                            let name = mangled_name.clone().unwrap_or_else(|| name.clone());
+                            self.varmap.last_mut().unwrap().insert(name.clone());
+                            self.function_names.last_mut().unwrap().insert(name);
+                        }
+                        &mut NodeBase::VarDecl(ref name, _)
+                        | &mut NodeBase::LexicalDecl(ref name, _, _) => {
+                            self.varmap.last_mut().unwrap().insert(name.clone());
                         }
                         _ => {}
                     }
                 }
 
+                let mut body = if let &mut NodeBase::StatementList(ref mut body) = &mut body.base {
+                    body
+                } else {
+                    unreachable!()
+                };
+
+                let func_decl_index: Vec<usize> = body
+                    .iter()
+                    .enumerate()
+                    .filter(|&(_, node)| match node.base {
+                        NodeBase::FunctionDecl(_) => true,
+                        _ => false,
+                    })
+                    .map(|(i, _)| i)
+                    .collect();
+
                 self.cur_fv.push(HashSet::new());
                 self.use_this.push(false);
 
@@ -145,6 +160,7 @@ impl FreeVariableFinder {
                 *use_this = self.use_this.pop().unwrap();
 
                 self.varmap.pop();
+                self.function_names.pop();
 
                 self.varmap
                     .last_mut()
@@ -164,13 +180,28 @@ impl FreeVariableFinder {
                     self.cur_fv.last_mut().unwrap().remove(name);
                 }
             }
-            &mut NodeBase::Call(ref mut callee, ref mut args) => {
+            &mut NodeBase::Call(ref mut callee, ref mut args)
+            | &mut NodeBase::TaggedTemplate(ref mut callee, _, ref mut args) => {
                 self.run(callee);
                 for arg in args {
                     self.run(arg)
                 }
             }
-            &mut NodeBase::VarDecl(ref name, ref mut init) => {
+            &mut NodeBase::Class(ref mut constructor, ref mut heritage, ref mut methods) => {
+                self.run(constructor);
+                if let Some(heritage) = heritage {
+                    self.run(heritage)
+                }
+                for &mut (_, ref mut method, _) in methods {
+                    self.run(method)
+                }
+            }
+            &mut NodeBase::Array(ref mut elems) => for elem in elems {
+                self.run(elem)
+            },
+            &mut NodeBase::Spread(ref mut expr) => self.run(expr),
+            &mut NodeBase::VarDecl(ref name, ref mut init)
+            | &mut NodeBase::LexicalDecl(ref name, ref mut init, _) => {
                 self.varmap.last_mut().unwrap().insert(name.clone());
                 if let &mut Some(ref mut init) = init {
                     self.run(init)
@@ -212,7 +243,10 @@ impl FreeVariableFinder {
                                 ),
                             );
                         }
-                        &mut PropertyDefinition::Property(_, ref mut node) => self.run(node),
+                        &mut PropertyDefinition::Property(_, ref mut node)
+                        | &mut PropertyDefinition::Getter(_, ref mut node)
+                        | &mut PropertyDefinition::Setter(_, ref mut node)
+                        | &mut PropertyDefinition::Spread(ref mut node) => self.run(node),
                     }
                 }
             }
@@ -221,16 +255,34 @@ impl FreeVariableFinder {
                 self.run(&mut *then);
                 self.run(&mut *else_);
             }
-            &mut NodeBase::While(ref mut cond, ref mut body) => {
+            &mut NodeBase::While(ref mut cond, ref mut body)
+            | &mut NodeBase::DoWhile(ref mut body, ref mut cond)
+            | &mut NodeBase::With(ref mut cond, ref mut body) => {
                 self.run(&mut *cond);
                 self.run(&mut *body);
             }
+            &mut NodeBase::Label(_, ref mut body) => self.run(&mut *body),
+            &mut NodeBase::Switch(ref mut discriminant, ref mut cases) => {
+                self.run(&mut *discriminant);
+                for &mut (ref mut label, ref mut body) in cases {
+                    if let &mut Some(ref mut label) = label {
+                        self.run(label);
+                    }
+                    self.run(body);
+                }
+            }
             NodeBase::For(ref mut init, ref mut cond, ref mut step, ref mut body) => {
                 self.run(&mut *init);
                 self.run(&mut *cond);
                 self.run(&mut *step);
                 self.run(&mut *body);
             }
+            NodeBase::ForIn(ref mut var, ref mut iterable, ref mut body)
+            | NodeBase::ForOf(ref mut var, ref mut iterable, ref mut body) => {
+                self.run(&mut *var);
+                self.run(&mut *iterable);
+                self.run(&mut *body);
+            }
             &mut NodeBase::Assign(ref mut dst, ref mut src) => {
                 match &mut dst.base {
                     &mut NodeBase::Identifier(ref name) => {
@@ -255,6 +307,10 @@ impl FreeVariableFinder {
                 }
                 self.run(&mut *src);
             }
+            &mut NodeBase::CompoundAssign(ref mut dst, ref mut src, _) => {
+                self.run(&mut *dst);
+                self.run(&mut *src);
+            }
             &mut NodeBase::UnaryOp(ref mut expr, _) => {
                 self.run(&mut *expr);
             }
@@ -288,7 +344,19 @@ impl FreeVariableFinder {
             }
         }
 
-        if !is_cur_scope_var && is_already_appeared_var_but_not_in_cur_scope_or_global {
+        // Every function is compiled into a global, so functions are never captured.
+        let is_function = self
+            .varmap
+            .iter()
+            .zip(self.function_names.iter())
+            .rev()
+            .find(|&(varmap, _)| varmap.contains(name.as_str()))
+            .map_or(false, |(_, function_names)| function_names.contains(name.as_str()));
+
+        if !is_cur_scope_var
+            && !is_function
+            && is_already_appeared_var_but_not_in_cur_scope_or_global
+        {
             self.cur_fv.last_mut().unwrap().insert(name.clone());
         }
     }
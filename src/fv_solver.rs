@@ -1,4 +1,4 @@
-use node::{FunctionDeclNode, Node, NodeBase, PropertyDefinition};
+use node::{FormalParameters, FunctionDeclNode, Node, NodeBase, PropertyDefinition};
 
 use rand::random;
 use std::collections::{HashMap, HashSet};
@@ -21,10 +21,9 @@ impl FreeVariableSolver {
 
     pub fn run_toplevel(&mut self, node: &mut Node) {
         match &mut node.base {
-            &mut NodeBase::StatementList(ref mut nodes) => {
-                let mut func_decl_index = vec![];
+            &mut NodeBase::StatementList(_) => {
                 let mut map = HashMap::new();
-                for (i, node) in nodes.iter_mut().enumerate() {
+                for node in node.hoisted_decls_mut() {
                     if let &mut NodeBase::FunctionDecl(FunctionDeclNode { ref mut fv, .. }) =
                         &mut node.base
                     {
@@ -34,12 +33,25 @@ impl FreeVariableSolver {
                                 format!("{}.{}", name.clone(), random::<u32>()),
                             );
                         }
-                        fv.clear();
-                        func_decl_index.push(i)
                     }
                 }
+                self.rename_free_variables(node, &map);
                 self.mangled_name.push(map);
 
+                let nodes = match node.base {
+                    NodeBase::StatementList(ref mut nodes) => nodes,
+                    _ => unreachable!(),
+                };
+                let func_decl_index: Vec<usize> = nodes
+                    .iter()
+                    .enumerate()
+                    .filter(|&(_, node)| match node.base {
+                        NodeBase::FunctionDecl(_) => true,
+                        _ => false,
+                    })
+                    .map(|(i, _)| i)
+                    .collect();
+
                 for index in func_decl_index {
                     self.run(&mut nodes[index]);
                 }
@@ -68,31 +80,40 @@ impl FreeVariableSolver {
             NodeBase::FunctionDecl(FunctionDeclNode {
                 ref mut name,
                 ref mut mangled_name,
+                ref params,
                 ref mut body,
                 ..
             }) => {
-                let mut body = if let &mut NodeBase::StatementList(ref mut body) = &mut body.base {
-                    body
-                } else {
-                    unreachable!()
-                };
-
+                let declared = declared_names(params, body);
                 let mut map = HashMap::new();
-                for node in body.iter_mut() {
+                for node in body.hoisted_decls_mut() {
                     if let &mut NodeBase::FunctionDecl(FunctionDeclNode { ref mut fv, .. }) =
                         &mut node.base
                     {
                         for name in fv.iter() {
-                            if self.get_mangled_name(name.as_str()).is_none() {
+                            // A variable of this function is not the outer one it shadows.
+                            if declared.contains(name) || self.get_mangled_name(name).is_none() {
                                 map.insert(
                                     name.clone(),
                                     format!("{}.{}", name.clone(), random::<u32>()),
                                 );
                             }
                         }
-                        fv.clear();
                     }
                 }
+                // The other variables shadowing outer ones keep their names.
+                for name in declared {
+                    if !map.contains_key(name.as_str()) && self.get_mangled_name(&name).is_some() {
+                        map.insert(name.clone(), name);
+                    }
+                }
+                self.rename_free_variables(body, &map);
+
+                let mut body = if let &mut NodeBase::StatementList(ref mut body) = &mut body.base {
+                    body
+                } else {
+                    unreachable!()
+                };
 
                 if let Some(x) = self.get_mangled_name(name.as_str()){ 
                    * mangled_name = Some(x);
@@ -105,14 +126,40 @@ impl FreeVariableSolver {
                     self.run(node);
                 }
 
-                self.mangled_name.pop();
+                // Captured parameters live in globals like captured variables, so the arguments
+                // are copied there first.
+                let map = self.mangled_name.pop().unwrap();
+                for param in params.iter().rev() {
+                    let mangled_name = map.get(param.name.as_str());
+                    if let Some(mangled_name) = mangled_name.filter(|name| **name != param.name) {
+                        let copy = NodeBase::Assign(
+                            Box::new(Node::new(NodeBase::Identifier(mangled_name.clone()), 0)),
+                            Box::new(Node::new(NodeBase::Identifier(param.name.clone()), 0)),
+                        );
+                        body.insert(0, Node::new(copy, 0));
+                    }
+                }
             }
-            NodeBase::Call(ref mut callee, ref mut args) => {
+            NodeBase::Call(ref mut callee, ref mut args)
+            | NodeBase::TaggedTemplate(ref mut callee, _, ref mut args) => {
                 self.run(callee);
                 for arg in args {
                     self.run(arg)
                 }
             }
+            NodeBase::Class(ref mut constructor, ref mut heritage, ref mut methods) => {
+                self.run(constructor);
+                if let Some(heritage) = heritage {
+                    self.run(heritage)
+                }
+                for &mut (_, ref mut method, _) in methods {
+                    self.run(method)
+                }
+            }
+            NodeBase::Array(ref mut elems) => for elem in elems {
+                self.run(elem)
+            },
+            NodeBase::Spread(ref mut expr) => self.run(expr),
             NodeBase::Return(ref mut val) => {
                 if let &mut Some(ref mut val) = val {
                     self.run(&mut **val)
@@ -136,7 +183,10 @@ impl FreeVariableSolver {
                 for property in properties.iter_mut() {
                     match property {
                         &mut PropertyDefinition::IdentifierReference(_) => unreachable!(),
-                        &mut PropertyDefinition::Property(_, ref mut node) => self.run(node),
+                        &mut PropertyDefinition::Property(_, ref mut node)
+                        | &mut PropertyDefinition::Getter(_, ref mut node)
+                        | &mut PropertyDefinition::Setter(_, ref mut node)
+                        | &mut PropertyDefinition::Spread(ref mut node) => self.run(node),
                     }
                 }
             }
@@ -145,17 +195,36 @@ impl FreeVariableSolver {
                 self.run(&mut *then);
                 self.run(&mut *else_);
             }
-            NodeBase::While(ref mut cond, ref mut body) => {
+            NodeBase::While(ref mut cond, ref mut body)
+            | NodeBase::DoWhile(ref mut body, ref mut cond)
+            | NodeBase::With(ref mut cond, ref mut body) => {
                 self.run(&mut *cond);
                 self.run(&mut *body);
             }
+            NodeBase::Label(_, ref mut body) => self.run(&mut *body),
+            NodeBase::Switch(ref mut discriminant, ref mut cases) => {
+                self.run(&mut *discriminant);
+                for &mut (ref mut label, ref mut body) in cases {
+                    if let &mut Some(ref mut label) = label {
+                        self.run(label);
+                    }
+                    self.run(body);
+                }
+            }
             NodeBase::For(ref mut init, ref mut cond, ref mut step, ref mut body) => {
                 self.run(&mut *init);
                 self.run(&mut *cond);
                 self.run(&mut *step);
                 self.run(&mut *body);
             }
-            NodeBase::Assign(ref mut dst, ref mut src) => {
+            NodeBase::ForIn(ref mut var, ref mut iterable, ref mut body)
+            | NodeBase::ForOf(ref mut var, ref mut iterable, ref mut body) => {
+                self.run(&mut *var);
+                self.run(&mut *iterable);
+                self.run(&mut *body);
+            }
+            NodeBase::Assign(ref mut dst, ref mut src)
+            | NodeBase::CompoundAssign(ref mut dst, ref mut src, _) => {
                 self.run(&mut *dst);
                 self.run(&mut *src);
             }
@@ -181,6 +250,16 @@ impl FreeVariableSolver {
                     }
                 }
             }
+            // A variable used by closures stays declared with let or const under its new name, so
+            // that they check it too.
+            NodeBase::LexicalDecl(ref mut name, ref mut init, _) => {
+                if let Some(mangled_name) = self.get_mangled_name(name.as_str()) {
+                    *name = mangled_name;
+                }
+                if let &mut Some(ref mut init) = init {
+                    self.run(init);
+                }
+            }
             NodeBase::UnaryOp(ref mut expr, _) => {
                 self.run(&mut *expr);
             }
@@ -193,18 +272,52 @@ impl FreeVariableSolver {
                 self.run(&mut *then);
                 self.run(&mut *else_);
             }
+            NodeBase::New(ref mut expr) => self.run(&mut *expr),
             _ => {}
         }
     }
 
+    /// Renames the free variables of the functions declared in `body` as `map` and the outer
+    /// functions rename them, so that the code generator knows which variables each closure uses.
+    fn rename_free_variables(&self, body: &mut Node, map: &HashMap<String, String>) {
+        for node in body.hoisted_decls_mut() {
+            if let NodeBase::FunctionDecl(FunctionDeclNode { ref mut fv, .. }) = node.base {
+                *fv = fv
+                    .iter()
+                    .map(|name| match map.get(name) {
+                        Some(mangled_name) => mangled_name.clone(),
+                        None => self.get_mangled_name(name).unwrap_or_else(|| name.clone()),
+                    })
+                    .collect();
+            }
+        }
+    }
+
+    /// Returns the name that the variable `name` is renamed to, unless it is left as it is.
     fn get_mangled_name(&self, name: &str) -> Option<String> {
         for map in self.mangled_name.iter().rev() {
             for (before_mangled, after_mangled) in map {
                 if before_mangled == name {
-                    return Some(after_mangled.clone());
+                    return Some(after_mangled.clone()).filter(|after| after != name);
                 }
             }
         }
         None
     }
 }
+
+/// Returns the names of the parameters of a function and of the declarations in its `body`.
+fn declared_names(params: &FormalParameters, body: &mut Node) -> HashSet<String> {
+    let mut names: HashSet<String> = params.iter().map(|param| param.name.clone()).collect();
+    for node in body.hoisted_decls_mut() {
+        match node.base {
+            NodeBase::FunctionDecl(FunctionDeclNode { ref name, .. })
+            | NodeBase::VarDecl(ref name, _)
+            | NodeBase::LexicalDecl(ref name, _, _) => {
+                names.insert(name.clone());
+            }
+            _ => {}
+        }
+    }
+    names
+}
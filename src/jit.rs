@@ -1,10 +1,11 @@
 use builtin;
 use vm;
 use vm::{
-    PUSH_INT32, PUSH_INT8, ADD, ASG_FREST_PARAM, CALL, CONSTRUCT, CREATE_ARRAY, CREATE_CONTEXT,
-    CREATE_OBJECT, DIV, END, EQ, GE, GET_ARG_LOCAL, GET_GLOBAL, GET_LOCAL, GET_MEMBER, GT, JMP,
-    JMP_IF_FALSE, LE, LT, MUL, NE, NEG, PUSH_ARGUMENTS, PUSH_CONST, PUSH_FALSE, PUSH_THIS,
-    PUSH_TRUE, REM, RETURN, SEQ, SET_ARG_LOCAL, SET_GLOBAL, SET_LOCAL, SET_MEMBER, SNE, SUB,
+    inst_len, PUSH_INT32, PUSH_INT8, ADD, ASG_FREST_PARAM, CALL, CALL_DIRECT, CALL_METHOD,
+    CONSTRUCT, CREATE_ARRAY, CREATE_CONTEXT, CREATE_OBJECT, DIV, END, EQ, GE, GET_ARG_LOCAL,
+    GET_GLOBAL, GET_LOCAL, GET_MEMBER, GT, JMP, JMP_IF_FALSE, LE, LT, MUL, NE, NEG, POP,
+    PUSH_ARGUMENTS, PUSH_CONST, PUSH_FALSE, PUSH_THIS, PUSH_TRUE, REM, RETURN, SEQ, SET_ARG_LOCAL,
+    SET_GLOBAL, SET_LOCAL, SET_MEMBER, SNE, SUB,
 };
 
 use rand::{random, thread_rng, RngCore};
@@ -18,6 +19,8 @@ use llvm::prelude::*;
 
 use std::ffi::CString;
 use std::ptr;
+use std::sync::Once;
+use std::time::{Duration, Instant};
 
 const MAX_FUNCTION_PARAMS: usize = 3;
 
@@ -132,11 +135,17 @@ pub struct TracingJit {
     module: LLVMModuleRef,
     builder: LLVMBuilderRef,
     pass_manager: LLVMPassManagerRef,
+    pub compile_time: Duration, // Total time spent in compiling functions and loops
+    pub compilations: usize,    // Number of functions and loops compiled
+    pub enabled: bool,
+    pub func_threshold: usize, // Number of calls before a function is compiled
+    pub loop_threshold: usize, // Number of runs before a loop is compiled
 }
 
 impl TracingJit {
     pub unsafe fn new() -> TracingJit {
-        MATH_RAND_SEED = thread_rng().next_u64();
+        // Seeded only once, so that creating another VM does not undo seed_math_random().
+        MATH_RAND_SEEDED.call_once(|| seed_math_random(thread_rng().next_u64()));
 
         llvm::target::LLVM_InitializeNativeTarget();
         llvm::target::LLVM_InitializeNativeAsmPrinter();
@@ -165,6 +174,11 @@ impl TracingJit {
             module: module,
             builder: LLVMCreateBuilderInContext(context),
             pass_manager: pm,
+            compile_time: Duration::new(0, 0),
+            compilations: 0,
+            enabled: true,
+            func_threshold: 5,
+            loop_threshold: 7,
             cur_func: None,
             builtin_funcs: {
                 let mut hmap = HashMap::new();
@@ -267,7 +281,14 @@ impl TracingJit {
 }
 
 unsafe fn cur_bb_has_no_terminator(builder: LLVMBuilderRef) -> bool {
-    LLVMIsATerminatorInst(LLVMGetLastInstruction(LLVMGetInsertBlock(builder))) == ptr::null_mut()
+    has_no_terminator(LLVMGetInsertBlock(builder))
+}
+
+/// Whether `bb` does not end with a terminator yet. An empty block, such as the one a JMP right
+/// after a JMP_IF_FALSE is generated into, has no last instruction to look at.
+unsafe fn has_no_terminator(bb: LLVMBasicBlockRef) -> bool {
+    let last = LLVMGetLastInstruction(bb);
+    last == ptr::null_mut() || LLVMIsATerminatorInst(last) == ptr::null_mut()
 }
 
 impl TracingJit {
@@ -278,6 +299,10 @@ impl TracingJit {
         pc: usize,
         argc: usize,
     ) -> Option<fn()> {
+        if !self.enabled {
+            return None;
+        }
+
         if !self.func_is_called_enough_times(pc) {
             self.inc_count(pc);
             return None;
@@ -297,6 +322,7 @@ impl TracingJit {
             }
         }
 
+        let compile_start = Instant::now();
         let name = format!("func.{}", random::<u32>());
 
         // If gen_code fails, it means the function can't be JIT-compiled and should never be
@@ -306,6 +332,7 @@ impl TracingJit {
             Ok(llvm_func) => llvm_func,
             Err(()) => {
                 self.func_info.get_mut(&pc).unwrap().jit_info.cannot_jit = true;
+                self.compile_time += compile_start.elapsed();
                 return None;
             }
         };
@@ -372,6 +399,9 @@ impl TracingJit {
         );
         let f = ::std::mem::transmute::<u64, fn()>(f_raw);
 
+        self.compile_time += compile_start.elapsed();
+        self.compilations += 1;
+
         let info = self.func_info.get_mut(&pc).unwrap();
         info.func_addr = Some(f);
         info.llvm_func = Some(llvm_func);
@@ -429,8 +459,7 @@ impl TracingJit {
         }
 
         let func_pos = pc;
-        pc += 1; // CreateContext
-        pc += 4; // |- num_local_var
+        pc += inst_len(insts, pc); // CreateContext
 
         let mut compilation_failed = false;
         if let Err(_) = self.gen_body(
@@ -447,7 +476,7 @@ impl TracingJit {
 
         let mut iter_bb = LLVMGetFirstBasicBlock(func);
         while iter_bb != ptr::null_mut() {
-            if LLVMIsATerminatorInst(LLVMGetLastInstruction(iter_bb)) == ptr::null_mut() {
+            if has_no_terminator(iter_bb) {
                 let terminator_builder = LLVMCreateBuilderInContext(self.context);
                 LLVMPositionBuilderAtEnd(terminator_builder, iter_bb);
                 LLVMBuildRet(terminator_builder, LLVMConstNull(func_ret_ty));
@@ -483,6 +512,10 @@ impl TracingJit {
         vm_state: &mut vm::VMState,
         end: usize,
     ) -> Option<isize> {
+        if !self.enabled {
+            return None;
+        }
+
         let bgn = vm_state.pc as usize;
 
         if !self.loop_is_called_enough_times(bgn) {
@@ -511,6 +544,7 @@ impl TracingJit {
             }
         }
 
+        let compile_start = Instant::now();
         let name = format!("func.{}", random::<u32>());
 
         // If gen_code fails, it means the function can't be JIT-compiled and should never be
@@ -520,6 +554,7 @@ impl TracingJit {
                 Ok(info) => info,
                 Err(()) => {
                     self.loop_info.get_mut(&bgn).unwrap().jit_info.cannot_jit = true;
+                    self.compile_time += compile_start.elapsed();
                     return None;
                 }
             };
@@ -586,6 +621,9 @@ impl TracingJit {
         );
         let f = ::std::mem::transmute::<u64, fn(*mut f64, *mut f64) -> i32>(f_raw);
 
+        self.compile_time += compile_start.elapsed();
+        self.compilations += 1;
+
         let info = self.loop_info.get_mut(&bgn).unwrap();
         info.func_addr = Some(f);
         info.llvm_func = Some(llvm_func);
@@ -676,7 +714,7 @@ impl TracingJit {
 
         let mut iter_bb = LLVMGetFirstBasicBlock(func);
         while iter_bb != ptr::null_mut() {
-            if LLVMIsATerminatorInst(LLVMGetLastInstruction(iter_bb)) == ptr::null_mut() {
+            if has_no_terminator(iter_bb) {
                 let terminator_builder = LLVMCreateBuilderInContext(self.context);
                 LLVMPositionBuilderAtEnd(terminator_builder, iter_bb);
                 LLVMBuildRet(
@@ -748,12 +786,6 @@ impl TracingJit {
 
         while pc < end {
             match insts[pc] {
-                END => pc += 1,
-                CREATE_CONTEXT => pc += 5,
-                RETURN => pc += 1,
-                ASG_FREST_PARAM => pc += 9,
-                CONSTRUCT | CREATE_OBJECT | PUSH_CONST | PUSH_INT32 | SET_GLOBAL | CREATE_ARRAY
-                | CALL => pc += 5,
                 SET_ARG_LOCAL | GET_ARG_LOCAL => {
                     pc += 1;
                     get_int32!(insts, pc, id, usize);
@@ -764,13 +796,11 @@ impl TracingJit {
                     get_int32!(insts, pc, id, usize);
                     local_vars.insert(id);
                 }
-                JMP | JMP_IF_FALSE => pc += 5,
-                PUSH_INT8 => pc += 2,
-                PUSH_FALSE | PUSH_TRUE | PUSH_THIS | ADD | SUB | MUL | DIV | REM | LT
-                | PUSH_ARGUMENTS | NEG | GT | LE | GE | EQ | NE | GET_MEMBER | SET_MEMBER => {
-                    pc += 1
-                }
-                GET_GLOBAL => pc += 5,
+                END | CREATE_CONTEXT | RETURN | ASG_FREST_PARAM | CALL_DIRECT | CALL_METHOD
+                | CONSTRUCT | CREATE_OBJECT | PUSH_CONST | PUSH_INT32 | SET_GLOBAL | CREATE_ARRAY
+                | CALL | JMP | JMP_IF_FALSE | PUSH_INT8 | PUSH_FALSE | PUSH_TRUE | PUSH_THIS | ADD
+                | SUB | MUL | DIV | REM | LT | PUSH_ARGUMENTS | NEG | GT | LE | GE | EQ | NE
+                | GET_MEMBER | POP | GET_GLOBAL => pc += inst_len(insts, pc),
                 _ => return Err(()),
             }
         }
@@ -821,12 +851,6 @@ impl TracingJit {
                 match insts[pc] {
                     END => break,
                     CREATE_CONTEXT if is_func_jit => break,
-                    CREATE_CONTEXT => pc += 5,
-                    RETURN => pc += 1,
-                    ASG_FREST_PARAM => pc += 9,
-                    CONSTRUCT | CREATE_OBJECT | PUSH_CONST | PUSH_INT32 | SET_GLOBAL
-                    | GET_LOCAL | SET_ARG_LOCAL | GET_ARG_LOCAL | CREATE_ARRAY | SET_LOCAL
-                    | CALL => pc += 5,
                     JMP | JMP_IF_FALSE => {
                         pc += 1;
                         get_int32!(insts, pc, dst, i32);
@@ -836,12 +860,12 @@ impl TracingJit {
                             LLVMAppendBasicBlock(func, CString::new("").unwrap().as_ptr()),
                         );
                     }
-                    PUSH_INT8 => pc += 2,
-                    PUSH_FALSE | PUSH_TRUE | PUSH_THIS | ADD | SUB | MUL | DIV | REM | LT
-                    | PUSH_ARGUMENTS | NEG | GT | LE | GE | EQ | NE | GET_MEMBER | SET_MEMBER => {
-                        pc += 1
-                    }
-                    GET_GLOBAL => pc += 5,
+                    CREATE_CONTEXT | RETURN | ASG_FREST_PARAM | CALL_DIRECT | CALL_METHOD
+                    | CONSTRUCT | CREATE_OBJECT | PUSH_CONST | PUSH_INT32 | SET_GLOBAL
+                    | GET_LOCAL | SET_ARG_LOCAL | GET_ARG_LOCAL | CREATE_ARRAY | SET_LOCAL | CALL
+                    | PUSH_INT8 | PUSH_FALSE | PUSH_TRUE | PUSH_THIS | ADD | SUB | MUL | DIV | REM
+                    | LT | PUSH_ARGUMENTS | NEG | GT | LE | GE | EQ | NE | GET_MEMBER | POP
+                    | GET_GLOBAL => pc += inst_len(insts, pc),
                     _ => return Err(()),
                 }
             }
@@ -860,14 +884,26 @@ impl TracingJit {
             match insts[pc] {
                 END => break,
                 CREATE_CONTEXT => break,
-                ASG_FREST_PARAM => pc += 9,
-                CONSTRUCT | CREATE_OBJECT | SET_GLOBAL | CREATE_ARRAY => pc += 5,
+                // The rest parameter is an array, which compiled code cannot hold.
+                ASG_FREST_PARAM => return Err(()),
+                CONSTRUCT | CREATE_OBJECT | SET_GLOBAL | CREATE_ARRAY => pc += inst_len(insts, pc),
                 JMP_IF_FALSE => {
                     pc += 1;
                     get_int32!(insts, pc, dst, i32);
                     let bb_then = LLVMAppendBasicBlock(func, CString::new("").unwrap().as_ptr());
                     let bb_else = try_opt!(labels.get(&((pc as i32 + dst) as usize)));
                     let cond_val = try_stack!(stack.pop());
+                    let cond_val = match infer_ty(cond_val, &None)? {
+                        ValueType::Bool => cond_val,
+                        // A number is true unless it is 0 or NaN.
+                        _ => LLVMBuildFCmp(
+                            self.builder,
+                            llvm::LLVMRealPredicate::LLVMRealONE,
+                            cond_val,
+                            LLVMConstReal(LLVMDoubleTypeInContext(self.context), 0.0),
+                            CString::new("fone").unwrap().as_ptr(),
+                        ),
+                    };
                     LLVMBuildCondBr(self.builder, cond_val, bb_then, *bb_else);
                     LLVMPositionBuilderAtEnd(self.builder, bb_then);
                 }
@@ -939,27 +975,13 @@ impl TracingJit {
                     pc += 1;
                     let rhs = try_stack!(stack.pop());
                     let lhs = try_stack!(stack.pop());
+                    // frem is fmod, which is what % of JS is.
                     stack.push((
-                        LLVMBuildSIToFP(
+                        LLVMBuildFRem(
                             self.builder,
-                            LLVMBuildSRem(
-                                self.builder,
-                                LLVMBuildFPToSI(
-                                    self.builder,
-                                    lhs,
-                                    LLVMInt64TypeInContext(self.context),
-                                    CString::new("").unwrap().as_ptr(),
-                                ),
-                                LLVMBuildFPToSI(
-                                    self.builder,
-                                    rhs,
-                                    LLVMInt64TypeInContext(self.context),
-                                    CString::new("").unwrap().as_ptr(),
-                                ),
-                                CString::new("frem").unwrap().as_ptr(),
-                            ),
-                            LLVMDoubleTypeInContext(self.context),
-                            CString::new("").unwrap().as_ptr(),
+                            lhs,
+                            rhs,
+                            CString::new("frem").unwrap().as_ptr(),
                         ),
                         None,
                     ));
@@ -1087,6 +1109,16 @@ impl TracingJit {
                 NEG => {
                     pc += 1;
                     let val = try_stack!(stack.pop());
+                    // A boolean is converted to 1 or 0 first. Strings are not compiled.
+                    let val = match infer_ty(val, &None)? {
+                        ValueType::Bool => LLVMBuildUIToFP(
+                            self.builder,
+                            val,
+                            LLVMDoubleTypeInContext(self.context),
+                            CString::new("uitofp").unwrap().as_ptr(),
+                        ),
+                        _ => val,
+                    };
                     stack.push((
                         LLVMBuildFNeg(self.builder, val, CString::new("fneg").unwrap().as_ptr()),
                         None,
@@ -1129,11 +1161,27 @@ impl TracingJit {
                     let src = try_stack!(stack.pop());
                     LLVMBuildStore(self.builder, src, self.declare_local_var(n, false, env));
                 }
-                CALL => {
+                CALL | CALL_METHOD => {
+                    let op = insts[pc];
                     pc += 1;
                     get_int32!(insts, pc, argc, usize);
 
-                    let callee = try_opt!(stack.pop());
+                    let callee = if op == CALL_METHOD {
+                        // Only methods of known objects, like console.log, can be called.
+                        get_int32!(insts, pc, name_id, usize);
+                        match try_opt!(try_opt!(stack.pop()).1) {
+                            vm::Value::Object(map) => (
+                                ptr::null_mut(),
+                                Some(vm::obj_find_val(
+                                    &*map.borrow(),
+                                    const_table.string[name_id].as_str(),
+                                )),
+                            ),
+                            _ => return Err(()),
+                        }
+                    } else {
+                        try_opt!(stack.pop())
+                    };
 
                     if let Some(callee) = callee.1 {
                         let mut args = vec![];
@@ -1170,6 +1218,7 @@ impl TracingJit {
                                     0,
                                     CString::new("").unwrap().as_ptr(),
                                 );
+                                stack.push((ptr::null_mut(), Some(vm::Value::Undefined)));
                             }
                             vm::Value::BuiltinFunction(builtin::PROCESS_STDOUT_WRITE) => {
                                 for (arg, ty) in args {
@@ -1187,6 +1236,7 @@ impl TracingJit {
                                         _ => return Err(()),
                                     };
                                 }
+                                stack.push((ptr::null_mut(), Some(vm::Value::Undefined)));
                             }
                             vm::Value::BuiltinFunction(builtin::MATH_FLOOR) => stack.push((
                                 LLVMBuildCall(
@@ -1247,6 +1297,38 @@ impl TracingJit {
                         ));
                     }
                 }
+                CALL_DIRECT => {
+                    pc += 1;
+                    get_int32!(insts, pc, dst, usize);
+                    get_int32!(insts, pc, argc, usize);
+
+                    let callee = if is_func_jit && dst == func_pos {
+                        func
+                    } else {
+                        match self.func_info.get(&dst) {
+                            Some(FuncInfo { llvm_func, .. }) if llvm_func.is_some() => {
+                                llvm_func.unwrap()
+                            }
+                            _ => return Err(()),
+                        }
+                    };
+
+                    let mut llvm_args = vec![];
+                    for _ in 0..argc {
+                        llvm_args.push(try_stack!(stack.pop()));
+                    }
+                    llvm_args.reverse();
+                    stack.push((
+                        LLVMBuildCall(
+                            self.builder,
+                            callee,
+                            llvm_args.as_mut_ptr(),
+                            llvm_args.len() as u32,
+                            CString::new("").unwrap().as_ptr(),
+                        ),
+                        None,
+                    ));
+                }
                 GET_MEMBER => {
                     pc += 1; // get_member
                     let member = try_opt!(try_opt!(stack.pop()).1);
@@ -1347,13 +1429,20 @@ impl TracingJit {
                         None,
                     ));
                 }
-                PUSH_THIS | PUSH_ARGUMENTS | SET_MEMBER => pc += 1,
+                PUSH_THIS | PUSH_ARGUMENTS => pc += inst_len(insts, pc),
+                // Stores to members are left to the VM, which knows whether objects are frozen
+                // or sealed.
+                SET_MEMBER => return Err(()),
+                POP => {
+                    pc += 1;
+                    try_opt!(stack.pop());
+                }
                 RETURN if is_func_jit => {
                     pc += 1;
                     let val = try_stack!(stack.pop());
                     LLVMBuildRet(self.builder, val);
                 }
-                GET_GLOBAL => pc += 5,
+                GET_GLOBAL => pc += inst_len(insts, pc),
                 _ => return Err(()),
             }
         }
@@ -1474,12 +1563,12 @@ pub unsafe fn run_loop_llvm_func(
 impl TracingJit {
     #[inline]
     fn func_is_called_enough_times(&mut self, pc: usize) -> bool {
-        *self.count.entry(pc).or_insert(0) >= 5
+        *self.count.entry(pc).or_insert(0) >= self.func_threshold
     }
 
     #[inline]
     fn loop_is_called_enough_times(&mut self, pc: usize) -> bool {
-        *self.count.entry(pc).or_insert(0) >= 7
+        *self.count.entry(pc).or_insert(0) >= self.loop_threshold
     }
 
     #[inline]
@@ -1533,6 +1622,13 @@ pub extern "C" fn math_floor(n: f64) -> f64 {
 
 // TODO: Find a better way for rand gen. (rand::random is slow)
 static mut MATH_RAND_SEED: u64 = 0xf6d582196d588cac;
+static MATH_RAND_SEEDED: Once = Once::new();
+
+/// Makes Math.random return the same sequence of numbers for the same `seed`.
+pub fn seed_math_random(seed: u64) {
+    // xorshift gets stuck at 0.
+    unsafe { MATH_RAND_SEED = if seed == 0 { 0xf6d582196d588cac } else { seed } }
+}
 #[no_mangle]
 pub extern "C" fn math_random() -> f64 {
     unsafe {
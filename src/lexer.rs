@@ -2,6 +2,7 @@ use parser::Error;
 use token::{convert_reserved_keyword, Kind, Symbol, Token};
 
 use std::collections::VecDeque;
+use std::time::{Duration, Instant};
 
 use encoding::all::UTF_16BE;
 use encoding::{DecoderTrap, Encoding};
@@ -13,6 +14,8 @@ pub struct Lexer {
     pub line: usize,
     pub buf: VecDeque<Token>,
     pub pos_line_list: Vec<(usize, usize)>, // pos, line
+    pub elapsed: Option<Duration>,          // Time spent in reading tokens, if measured
+    pub templates: Vec<usize>, // Unclosed braces in each substitution of a template being read
 }
 
 impl Lexer {
@@ -23,6 +26,8 @@ impl Lexer {
             line: 1,
             buf: VecDeque::new(),
             pos_line_list: vec![],
+            elapsed: None,
+            templates: vec![],
         }
     }
 }
@@ -59,6 +64,18 @@ impl Lexer {
     }
 
     pub fn read_token(&mut self) -> Result<Token, Error> {
+        match self.elapsed {
+            Some(elapsed) => {
+                let start = Instant::now();
+                let tok = self.scan_token();
+                self.elapsed = Some(elapsed + start.elapsed());
+                tok
+            }
+            None => self.scan_token(),
+        }
+    }
+
+    fn scan_token(&mut self) -> Result<Token, Error> {
         if !self.buf.is_empty() {
             return Ok(self.buf.pop_front().unwrap());
         }
@@ -72,11 +89,20 @@ impl Lexer {
         match self.next_char()? {
             'a'...'z' | 'A'...'Z' | '_' => self.read_identifier(),
             '0'...'9' => self.read_number(),
+            '.' if self.code[self.pos + 1..].starts_with(|c: char| c.is_ascii_digit()) => {
+                self.read_number()
+            }
             '\'' | '\"' => self.read_string_literal(),
-            '\n' => self.read_line_terminator(),
+            '`' => self.read_template_part(),
+            // The '}' that ends a substitution continues its template.
+            '}' if self.templates.last() == Some(&0) => {
+                self.templates.pop();
+                self.read_template_part()
+            }
+            c if is_line_terminator(c) => self.read_line_terminator(),
             c if c.is_whitespace() => {
                 self.skip_whitespace()?;
-                self.read_token()
+                self.scan_token()
             }
             _ => self.read_symbol(),
         }
@@ -85,10 +111,11 @@ impl Lexer {
 
 impl Lexer {
     fn skip_line_comment(&mut self) -> Result<(), Error> {
-        self.just_skip_while(|c| c != '\n')
+        self.just_skip_while(|c| !is_line_terminator(c))
     }
 
     fn skip_normal_comment(&mut self) -> Result<(), Error> {
+        let start = self.pos;
         let mut last_char_is_asterisk = false;
         self.just_skip_while(|c| {
             let end_of_comment = last_char_is_asterisk && c == '/';
@@ -98,6 +125,7 @@ impl Lexer {
             !end_of_comment
         })?;
         assert_eq!(self.skip_char()?, '/');
+        self.line += count_line_terminators(&self.code[start..self.pos]);
         Ok(())
     }
 }
@@ -116,36 +144,73 @@ impl Lexer {
 }
 
 impl Lexer {
+    /// Reads a numeric literal straight out of the source. Decimal literals are converted by
+    /// str::parse, which rounds correctly and never depends on the locale; binary, octal and
+    /// hexadecimal ones by pow2_radix_to_f64.
     pub fn read_number(&mut self) -> Result<Token, Error> {
         let pos = self.pos;
         self.pos_line_list.push((pos, self.line));
-        let mut is_float = false;
-        let mut last = self.next_char()?;
-        let num = self.skip_while(|c| {
-            is_float = is_float || c == '.';
-            let is_f = "eEpP".contains(last) && "+-".contains(c);
-            let is_end_of_num = !c.is_alphanumeric() && c != '.' && !is_f;
-            if is_end_of_num {
-                is_float = is_float || is_f;
-            } else {
-                last = c;
-            }
-            !is_end_of_num
-        })?;
 
-        let num: f64 = if is_float {
-            num.parse().unwrap()
-        } else if num.len() > 2 && num.chars().nth(1).unwrap() == 'x' {
-            self.read_hex_num(&num[2..]) as f64
-        } else if num.len() > 2 && num.chars().nth(1).unwrap() == 'b' {
-            self.read_bin_num(&num[2..]) as f64
-        } else if num.chars().nth(0).unwrap() == '0' {
-            self.read_oct_num(&num[1..]) as f64
-        } else {
-            self.read_dec_num(num.as_str()) as f64
+        let (end, num) = {
+            let code = self.code.as_bytes();
+            let digits_end = |i: usize, radix: u32| {
+                i + code[i..]
+                    .iter()
+                    .take_while(|&&b| is_digit(b, radix))
+                    .count()
+            };
+            let prefix = code.get(pos + 1).map(|b| b.to_ascii_lowercase());
+            match (code[pos], prefix) {
+                (b'0', Some(b'x')) | (b'0', Some(b'o')) | (b'0', Some(b'b')) => {
+                    let bits = match prefix {
+                        Some(b'x') => 4,
+                        Some(b'o') => 3,
+                        _ => 1,
+                    };
+                    let end = digits_end(pos + 2, 1 << bits);
+                    if end == pos + 2 {
+                        return Err(Error::UnexpectedToken(end));
+                    }
+                    (end, pow2_radix_to_f64(&self.code[pos + 2..end], bits))
+                }
+                // A legacy octal literal like 0755. 08 and 09 are decimal.
+                (b'0', Some(b'0'...b'7'))
+                    if code[digits_end(pos, 8)..]
+                        .first()
+                        .map_or(true, |&b| !is_digit(b, 10)) =>
+                {
+                    let end = digits_end(pos, 8);
+                    (end, pow2_radix_to_f64(&self.code[pos + 1..end], 3))
+                }
+                _ => {
+                    let mut end = digits_end(pos, 10);
+                    if code.get(end) == Some(&b'.') {
+                        end = digits_end(end + 1, 10);
+                    }
+                    if let Some(b'e') | Some(b'E') = code.get(end) {
+                        let mut exp = end + 1;
+                        if let Some(b'+') | Some(b'-') = code.get(exp) {
+                            exp += 1;
+                        }
+                        end = digits_end(exp, 10);
+                        if end == exp {
+                            return Err(Error::UnexpectedToken(exp));
+                        }
+                    }
+                    let num = self.code[pos..end]
+                        .parse()
+                        .map_err(|_| Error::UnexpectedToken(pos))?;
+                    (end, num)
+                }
+            }
         };
 
-        Ok(Token::new_number(num, pos))
+        self.pos = end;
+        // A literal cannot run into an identifier, as in `3in x` or `1.toString()`.
+        match self.code[end..].chars().next() {
+            Some(c) if c.is_alphanumeric() || c == '_' => Err(Error::UnexpectedToken(end)),
+            _ => Ok(Token::new_number(num, pos)),
+        }
     }
 
     fn read_hex_num(&mut self, num_literal: &str) -> i64 {
@@ -154,26 +219,30 @@ impl Lexer {
             _ => n,
         })
     }
+}
 
-    fn read_dec_num(&mut self, num_literal: &str) -> i64 {
-        num_literal.chars().fold(0, |n, c| match c {
-            '0'...'9' => n * 10 + c.to_digit(10).unwrap() as i64,
-            _ => n,
-        })
-    }
+fn is_digit(b: u8, radix: u32) -> bool {
+    (b as char).is_digit(radix)
+}
 
-    fn read_oct_num(&mut self, num_literal: &str) -> i64 {
-        num_literal.chars().fold(0, |n, c| match c {
-            '0'...'7' => n * 8 + c.to_digit(8).unwrap() as i64,
-            _ => n,
-        })
-    }
-    fn read_bin_num(&mut self, num_literal: &str) -> i64 {
-        num_literal.chars().fold(0, |n, c| match c {
-            '0' | '1' => n * 2 + c.to_digit(2).unwrap() as i64,
-            _ => n,
-        })
+/// Converts the digits of a binary, octal or hexadecimal literal, `bits` bits per digit, to the
+/// nearest f64, rounding half to even as decimal literals are.
+fn pow2_radix_to_f64(digits: &str, bits: u32) -> f64 {
+    let mut mantissa = 0u64;
+    let mut exponent = 0;
+    let mut sticky = false;
+    for c in digits.chars() {
+        let digit = c.to_digit(1 << bits).unwrap() as u64;
+        if mantissa >> (64 - bits) == 0 {
+            mantissa = mantissa << bits | digit;
+        } else {
+            exponent += bits as i32;
+            sticky = sticky || digit != 0;
+        }
     }
+    // Once the mantissa is full it holds at least 61 significant bits, so its lowest bit lies
+    // below the rounding position and can stand for the digits that did not fit.
+    (mantissa | sticky as u64) as f64 * 2f64.powi(exponent)
 }
 
 impl Lexer {
@@ -195,6 +264,20 @@ impl Lexer {
         Ok(Token::new_string(s, pos))
     }
 
+    /// Reads the `len` hex digits of an escape sequence.
+    fn read_hex_digits(&mut self, len: usize) -> Result<String, Error> {
+        let hex = self.code[self.pos..]
+            .chars()
+            .take(len)
+            .take_while(|c| c.is_ascii_hexdigit())
+            .collect::<String>();
+        if hex.len() != len {
+            return Err(Error::UnexpectedToken(self.pos));
+        }
+        self.pos += len;
+        Ok(hex)
+    }
+
     fn read_escaped_char(&mut self) -> Result<Vec<char>, Error> {
         let c = self.skip_char()?;
         Ok(match c {
@@ -207,25 +290,17 @@ impl Lexer {
             't' => vec!['\x09'],
             'v' => vec!['\x0b'],
             'x' => {
-                let hex = self.skip_while(|c| c.is_alphanumeric())?;
+                let hex = self.read_hex_digits(2)?;
                 vec![self.read_hex_num(hex.as_str()) as u8 as char]
             }
             'u' => {
                 let mut u8s = vec![];
                 loop {
-                    let hex = self.skip_while(|c| c.is_alphanumeric())?;
-                    let mut i = 0;
-                    while i < hex.len() {
-                        u8s.push(
-                            self.read_hex_num(&hex[i..i + if i + 2 > hex.len() { 1 } else { 2 }])
-                                as u8,
-                        );
-                        i += 2;
-                    }
-                    if u8s.len() % 2 != 0 {
-                        // TODO: Support \u{X..X}
-                        unimplemented!("unsupported escape sequence");
-                    }
+                    // TODO: Support \u{X..X}
+                    let hex = self.read_hex_digits(4)?;
+                    let unit = self.read_hex_num(hex.as_str());
+                    u8s.push((unit >> 8) as u8);
+                    u8s.push(unit as u8);
                     let save_pos = self.pos;
                     // TODO: Error handling
                     if self.skip_char()? == '\\' && self.skip_char()? == 'u' {
@@ -235,8 +310,9 @@ impl Lexer {
                         break;
                     }
                 }
+                // Lone surrogates cannot be kept in UTF-8 strings.
                 UTF_16BE
-                    .decode(u8s.as_slice(), DecoderTrap::Strict)
+                    .decode(u8s.as_slice(), DecoderTrap::Replace)
                     .unwrap()
                     .chars()
                     .collect::<Vec<char>>()
@@ -246,6 +322,42 @@ impl Lexer {
     }
 }
 
+impl Lexer {
+    /// Reads a part of a template literal, from its '`' or from the '}' that ends a substitution
+    /// to the '${' that begins the next substitution or to the closing '`'. The cooked string is
+    /// None if an escape sequence is invalid, which is only allowed in tagged templates.
+    fn read_template_part(&mut self) -> Result<Token, Error> {
+        let pos = self.pos;
+        self.pos_line_list.push((pos, self.line));
+        self.skip_char()?;
+        let start = self.pos;
+        let mut cooked = Some("".to_string());
+        let (end, tail) = loop {
+            let end = self.pos;
+            match self.skip_char().or(Err(Error::UnexpectedEOF))? {
+                '`' => break (end, true),
+                '$' if self.skip_char_if_any('{')? => break (end, false),
+                '\\' => match self.read_escaped_char() {
+                    Ok(chars) => cooked.iter_mut().for_each(|s| s.extend(chars.iter())),
+                    Err(_) => cooked = None,
+                },
+                // '\r' and "\r\n" in templates are always '\n'.
+                '\r' => {
+                    self.skip_char_if_any('\n')?;
+                    cooked.iter_mut().for_each(|s| s.push('\n'))
+                }
+                c => cooked.iter_mut().for_each(|s| s.push(c)),
+            }
+        };
+        let raw = self.code[start..end].replace("\r\n", "\n").replace('\r', "\n");
+        self.line += count_line_terminators(raw.as_str());
+        if !tail {
+            self.templates.push(0);
+        }
+        Ok(Token::new_template(cooked, raw, tail, pos))
+    }
+}
+
 impl Lexer {
     pub fn read_symbol(&mut self) -> Result<Token, Error> {
         let pos = self.pos;
@@ -263,12 +375,6 @@ impl Lexer {
                         symbol = Symbol::AssignSub;
                     }
                 }
-                '>' => {
-                    assert_eq!(self.skip_char()?, '>');
-                    if c == '-' {
-                        symbol = Symbol::Arrow;
-                    }
-                }
                 '+' => {
                     assert_eq!(self.skip_char()?, '+');
                     if c == '+' {
@@ -317,6 +423,8 @@ impl Lexer {
                     } else {
                         Symbol::Eq
                     }
+                } else if self.skip_char_if_any('>')? {
+                    symbol = Symbol::Arrow
                 } else {
                     symbol = Symbol::Assign
                 }
@@ -385,8 +493,18 @@ impl Lexer {
             ')' => symbol = Symbol::ClosingParen,
             '[' => symbol = Symbol::OpeningBoxBracket,
             ']' => symbol = Symbol::ClosingBoxBracket,
-            '{' => symbol = Symbol::OpeningBrace,
-            '}' => symbol = Symbol::ClosingBrace,
+            '{' => {
+                if let Some(braces) = self.templates.last_mut() {
+                    *braces += 1;
+                }
+                symbol = Symbol::OpeningBrace
+            }
+            '}' => {
+                if let Some(braces) = self.templates.last_mut() {
+                    *braces -= 1;
+                }
+                symbol = Symbol::ClosingBrace
+            }
             ',' => symbol = Symbol::Comma,
             ';' => symbol = Symbol::Semicolon,
             ':' => symbol = Symbol::Colon,
@@ -415,7 +533,10 @@ impl Lexer {
 impl Lexer {
     pub fn read_line_terminator(&mut self) -> Result<Token, Error> {
         let pos = self.pos;
-        assert_eq!(self.skip_char()?, '\n');
+        // "\r\n" is a single line terminator.
+        if self.skip_char()? == '\r' {
+            self.skip_char_if_any('\n')?;
+        }
         self.line += 1;
         Ok(Token::new_line_terminator(pos))
     }
@@ -423,7 +544,7 @@ impl Lexer {
 
 impl Lexer {
     fn skip_whitespace(&mut self) -> Result<(), Error> {
-        self.skip_while(|c| c == ' ' || c == '\t').and(Ok(()))
+        self.skip_while(|c| !is_line_terminator(c) && c.is_whitespace()).and(Ok(()))
     }
 
     fn skip_while<F>(&mut self, mut f: F) -> Result<String, Error>
@@ -497,25 +618,13 @@ impl Lexer {
             }
             _ => {}
         };
-        let code = self.code.as_bytes();
-        let start_pos = {
-            let mut p = pos as i32;
-            while p > 0 && code[p as usize] != b'\n' {
-                p -= 1;
-            }
-            if code[p as usize] == b'\n' {
-                p += 1
-            }
-            p as usize
-        };
-        let end_pos = {
-            let mut p = start_pos as i32;
-            while p < code.len() as i32 && code[p as usize] as char != '\n' {
-                p += 1;
-            }
-            p as usize
-        };
-        let surrounding_code = String::from_utf8(code[start_pos..end_pos].to_vec())
+        let code = self.code.as_str();
+        // Errors may be reported at the end of the code.
+        pos = ::std::cmp::min(pos, code.len());
+        let start_pos = line_start(code, pos);
+        let surrounding_code = code[start_pos..]
+            .split(is_line_terminator)
+            .next()
             .unwrap()
             .to_string();
         let mut err_point = String::new();
@@ -527,6 +636,56 @@ impl Lexer {
     }
 }
 
+/// Returns the 1-origin line and column of the first token at or after `pos`.
+pub fn get_line_and_column(code: &str, pos: usize) -> (usize, usize) {
+    let mut pos = pos.min(code.len());
+    while let Some(c) = code[pos..].chars().next() {
+        if !c.is_whitespace() {
+            break;
+        }
+        pos += c.len_utf8();
+    }
+    let line_start = line_start(code, pos);
+    let line = count_line_terminators(&code[..line_start]) + 1;
+    let column = code[line_start..pos].chars().count() + 1;
+    (line, column)
+}
+
+/// Returns the `line`th line of `code`, 1-origin, without its line terminator.
+pub fn get_line(code: &str, line: usize) -> Option<&str> {
+    let mut rest = code;
+    for _ in 1..line {
+        let end = rest.find(is_line_terminator)?;
+        rest = &rest[end + line_terminator_len(&rest[end..])..];
+    }
+    rest.split(is_line_terminator).next()
+}
+
+/// '\n', '\r', U+2028 and U+2029 end a line, and so does "\r\n" as a whole.
+pub fn is_line_terminator(c: char) -> bool {
+    c == '\n' || c == '\r' || c == '\u{2028}' || c == '\u{2029}'
+}
+
+pub fn count_line_terminators(code: &str) -> usize {
+    code.matches(is_line_terminator).count() - code.matches("\r\n").count()
+}
+
+/// Returns the length in bytes of the line terminator that `code` starts with.
+pub fn line_terminator_len(code: &str) -> usize {
+    if code.starts_with("\r\n") {
+        2
+    } else {
+        code.chars().next().map_or(0, |c| c.len_utf8())
+    }
+}
+
+/// Returns where the line containing `pos` starts.
+fn line_start(code: &str, pos: usize) -> usize {
+    code[..pos]
+        .rfind(is_line_terminator)
+        .map_or(0, |p| p + line_terminator_len(&code[p..]))
+}
+
 #[test]
 fn number() {
     let mut lexer = Lexer::new("1 2 0x34 056 7.89 0b10".to_string());
@@ -536,6 +695,65 @@ fn number() {
     assert_eq!(lexer.next().unwrap().kind, Kind::Number(46.0));
     assert_eq!(lexer.next().unwrap().kind, Kind::Number(7.89));
     assert_eq!(lexer.next().unwrap().kind, Kind::Number(2.0));
+
+    let code = "1e3 2.5E-1 .5 5. 0X1f 0o17 0B11 09 08.5 0 9007199254740993 0x20000000000001";
+    let mut lexer = Lexer::new(code.to_string());
+    for num in &[
+        1000.0,
+        0.25,
+        0.5,
+        5.0,
+        31.0,
+        15.0,
+        3.0,
+        9.0,
+        8.5,
+        0.0,
+        9007199254740992.0,
+        9007199254740992.0,
+    ] {
+        assert_eq!(lexer.next().unwrap().kind, Kind::Number(*num));
+    }
+
+    let mut lexer = Lexer::new("1..toString".to_string());
+    assert_eq!(lexer.next().unwrap().kind, Kind::Number(1.0));
+    assert_eq!(lexer.next().unwrap().kind, Kind::Symbol(Symbol::Point));
+
+    for code in &["0x", "0b2", "1e", "1e+", "3in", "1.toString", "0o8"] {
+        let mut lexer = Lexer::new(code.to_string());
+        assert!(lexer.next().is_err(), "{}", code);
+    }
+}
+
+#[test]
+fn number_round_trip() {
+    use rand::random;
+    for _ in 0..10000 {
+        let num = f64::from_bits(random::<u64>()).abs();
+        if !num.is_finite() {
+            continue;
+        }
+        for code in &[format!("{:?}", num), format!("{:e}", num), format!("{:.25e}", num)] {
+            let mut lexer = Lexer::new(code.clone());
+            assert_eq!(
+                lexer.next().unwrap().kind,
+                Kind::Number(code.parse().unwrap()),
+                "{}",
+                code
+            );
+        }
+        let int = (random::<u64>() as u128) << 64 | random::<u64>() as u128;
+        let int = int >> (random::<u32>() % 128);
+        for code in &[
+            format!("0x{:x}", int),
+            format!("0o{:o}", int),
+            format!("0b{:b}", int),
+            format!("{}", int),
+        ] {
+            let mut lexer = Lexer::new(code.clone());
+            assert_eq!(lexer.next().unwrap().kind, Kind::Number(int as f64), "{}", code);
+        }
+    }
 }
 
 #[test]
@@ -563,7 +781,7 @@ fn keyword() {
     use token::Keyword;
 
     let mut lexer = Lexer::new(
-        "break case catch continue debugger default \
+        "break case catch const continue debugger default \
          delete do else finally for function if in instanceof \
          new return switch this throw try typeof \
          var void while with"
@@ -572,6 +790,7 @@ fn keyword() {
     assert_eq!(lexer.next().unwrap().kind, Kind::Keyword(Keyword::Break,));
     assert_eq!(lexer.next().unwrap().kind, Kind::Keyword(Keyword::Case,));
     assert_eq!(lexer.next().unwrap().kind, Kind::Keyword(Keyword::Catch,));
+    assert_eq!(lexer.next().unwrap().kind, Kind::Keyword(Keyword::Const,));
     assert_eq!(
         lexer.next().unwrap().kind,
         Kind::Keyword(Keyword::Continue,)
@@ -612,7 +831,7 @@ fn keyword() {
 #[test]
 fn symbol() {
     let mut lexer = Lexer::new(
-        "() {} [] , ; : . -> ++ -- + - * / % **\
+        "() {} [] , ; : . => ++ -- + - * / % **\
          ! ~ << >> >>> < <= > >= == != === !== & | ^ && || \
          ? = += -= *= /= %= <<= >>= &= |= ^= \
          &&= ||= #"
@@ -709,6 +928,14 @@ fn line_terminator() {
         lexer.next().unwrap().kind,
         Kind::Identifier("world".to_string())
     );
+
+    let mut lexer = Lexer::new("a\r\nb\rc\u{2028}d\u{2029}/*\r\n*/e".to_string());
+    for name in &["a", "b", "c", "d"] {
+        assert_eq!(lexer.next().unwrap().kind, Kind::Identifier(name.to_string()));
+        assert_eq!(lexer.read_token().unwrap().kind, Kind::LineTerminator);
+    }
+    assert_eq!(lexer.next().unwrap().kind, Kind::Identifier("e".to_string()));
+    assert_eq!(lexer.line, 6);
 }
 
 #[test]
@@ -722,6 +949,27 @@ fn escape_seq() {
     );
 }
 
+#[test]
+fn template() {
+    let mut lexer = Lexer::new("`a${ {b: 1}.b }c\\n${`d`}`".to_string());
+    for kind in vec![
+        Kind::Template(Some("a".to_string()), "a".to_string(), false),
+        Kind::Symbol(Symbol::OpeningBrace),
+        Kind::Identifier("b".to_string()),
+        Kind::Symbol(Symbol::Colon),
+        Kind::Number(1.0),
+        Kind::Symbol(Symbol::ClosingBrace),
+        Kind::Symbol(Symbol::Point),
+        Kind::Identifier("b".to_string()),
+        Kind::Template(Some("c\n".to_string()), "c\\n".to_string(), false),
+        Kind::Template(Some("d".to_string()), "d".to_string(), true),
+        Kind::Template(Some("".to_string()), "".to_string(), true),
+    ] {
+        assert_eq!(lexer.next().unwrap().kind, kind);
+    }
+    assert!(lexer.next().is_err());
+}
+
 #[test]
 fn comment() {
     let mut lexer = Lexer::new(
@@ -742,3 +990,23 @@ fn comment() {
         Kind::Identifier("y".to_string())
     );
 }
+
+#[test]
+fn line_and_column() {
+    let code = "var a = 1\n  a = a +\n\n2";
+    assert_eq!(get_line_and_column(code, 0), (1, 1));
+    assert_eq!(get_line_and_column(code, 4), (1, 5));
+    assert_eq!(get_line_and_column(code, 9), (2, 3));
+    assert_eq!(get_line_and_column(code, 19), (4, 1));
+
+    let code = "var a = 1\r\n  a = a +\r\r\n2\u{2028}b\u{2029}  c";
+    assert_eq!(get_line_and_column(code, 9), (2, 3));
+    assert_eq!(get_line_and_column(code, 19), (2, 9));
+    assert_eq!(get_line_and_column(code, 20), (4, 1));
+    assert_eq!(get_line_and_column(code, 27), (5, 1));
+    assert_eq!(get_line_and_column(code, 31), (6, 3));
+    assert_eq!(get_line(code, 2), Some("  a = a +"));
+    assert_eq!(get_line(code, 3), Some(""));
+    assert_eq!(get_line(code, 6), Some("  c"));
+    assert_eq!(get_line(code, 7), None);
+}
@@ -2,24 +2,54 @@
 #![feature(tool_attributes)]
 #![feature(repeat_generic_slice)]
 
+#[cfg(feature = "bench")]
+pub mod bench;
+pub mod bytecode_file;
 pub mod bytecode_gen;
+pub mod coverage;
+pub mod crash_report;
+pub mod debugger;
+pub mod diagnostic;
+pub mod engine;
+pub mod error;
 pub mod extract_anony_func;
+pub mod fuzz;
 pub mod fv_finder;
 pub mod fv_solver;
 pub mod id;
 pub mod jit;
 pub mod lexer;
+pub mod lint;
+pub mod module;
+pub mod native_module;
 pub mod node;
 pub mod parser;
+pub mod promise;
+pub mod property_map;
+#[cfg(feature = "serde")]
+pub mod serde_value;
+pub mod test262;
+pub mod test_runner;
 pub mod token;
 pub mod vm;
 pub mod vm_codegen;
 pub mod builtin;
 
+pub use engine::{eval, Engine};
+pub use error::Error;
+pub use fuzz::fuzz_check;
+pub use native_module::NativeModule;
+
 extern crate ansi_term;
 extern crate encoding;
+extern crate indexmap;
 extern crate libc;
 extern crate llvm_sys as llvm;
 extern crate nix;
 extern crate rand;
+extern crate unicode_normalization;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde")]
+extern crate serde_json;
 // extern crate cpuprofiler;
@@ -0,0 +1,265 @@
+use diagnostic::Diagnostic;
+use node::{FunctionDeclNode, Node, NodeBase, PropertyDefinition};
+
+use std::collections::HashSet;
+
+/// Finds code that is valid but probably a mistake. Runs on the AST right after parsing.
+#[derive(Debug, Clone)]
+pub struct Linter {
+    source: String,
+    pub varmap: Vec<HashSet<String>>, // Names declared in each enclosing function
+    pub warnings: Vec<Diagnostic>,
+    with_depth: usize, // Number of with statements around the code being checked
+}
+
+impl Linter {
+    pub fn new(source: &str) -> Linter {
+        let mut varmap = HashSet::new(); // global
+        varmap.insert("console".to_string());
+        varmap.insert("process".to_string());
+        varmap.insert("Math".to_string());
+        Linter {
+            source: source.to_string(),
+            varmap: vec![varmap],
+            warnings: vec![],
+            with_depth: 0,
+        }
+    }
+
+    pub fn run_toplevel(&mut self, node: &Node) {
+        let mut varmap = HashSet::new();
+        collect_decls(node, &mut varmap);
+        self.varmap.push(varmap);
+        self.run(node);
+        self.varmap.pop();
+    }
+
+    fn run(&mut self, node: &Node) {
+        match node.base {
+            NodeBase::StatementList(ref nodes) => {
+                let mut jumped = false;
+                for node in nodes {
+                    match node.base {
+                        // Function declarations are hoisted, so they are reachable anywhere.
+                        NodeBase::FunctionDecl(_) => {}
+                        _ if jumped => {
+                            self.warn("unreachable code", start_pos(node));
+                            jumped = false;
+                        }
+                        NodeBase::Return(_) | NodeBase::Break(_) | NodeBase::Continue(_) => {
+                            jumped = true
+                        }
+                        _ => {}
+                    }
+                    self.run(node)
+                }
+            }
+            NodeBase::FunctionDecl(FunctionDeclNode {
+                ref params,
+                ref body,
+                ..
+            })
+            | NodeBase::FunctionExpr(_, ref params, ref body, _, _) => {
+                let mut varmap = HashSet::new();
+                if let NodeBase::FunctionExpr(Some(ref name), _, _, _, _) = node.base {
+                    varmap.insert(name.clone());
+                }
+                for param in params {
+                    varmap.insert(param.name.clone());
+                    if let Some(ref init) = param.init {
+                        self.run(init)
+                    }
+                }
+                collect_decls(body, &mut varmap);
+                self.varmap.push(varmap);
+                self.run(body);
+                self.varmap.pop();
+            }
+            NodeBase::Assign(ref dst, ref src) => {
+                if let NodeBase::Identifier(ref name) = dst.base {
+                    let declared = self.varmap.iter().any(|varmap| varmap.contains(name));
+                    if self.with_depth == 0 && !declared {
+                        let msg = format!("assignment to undeclared variable '{}'", name);
+                        self.warn(msg.as_str(), dst.pos);
+                    }
+                }
+                self.run(dst);
+                self.run(src);
+            }
+            NodeBase::CompoundAssign(ref dst, ref src, _) => {
+                self.run(dst);
+                self.run(src);
+            }
+            NodeBase::VarDecl(_, Some(ref init)) | NodeBase::LexicalDecl(_, Some(ref init), _) => {
+                self.run(init)
+            }
+            NodeBase::Member(ref parent, _) => self.run(parent),
+            NodeBase::Index(ref parent, ref idx) => {
+                self.run(parent);
+                self.run(idx);
+            }
+            NodeBase::New(ref expr) => self.run(expr),
+            NodeBase::Class(ref constructor, ref heritage, ref methods) => {
+                self.run(constructor);
+                if let Some(heritage) = heritage {
+                    self.run(heritage)
+                }
+                for &(_, ref method, _) in methods {
+                    self.run(method)
+                }
+            }
+            NodeBase::Call(ref callee, ref args)
+            | NodeBase::TaggedTemplate(ref callee, _, ref args) => {
+                self.run(callee);
+                for arg in args {
+                    self.run(arg)
+                }
+            }
+            NodeBase::If(ref cond, ref then, ref else_) => {
+                self.run(cond);
+                self.run(then);
+                self.run(else_);
+            }
+            NodeBase::While(ref cond, ref body) => {
+                self.run(cond);
+                self.run(body);
+            }
+            // Names in the body may be properties of the object, so they are not checked.
+            NodeBase::With(ref obj, ref body) => {
+                self.run(obj);
+                self.with_depth += 1;
+                self.run(body);
+                self.with_depth -= 1;
+            }
+            NodeBase::DoWhile(ref body, ref cond) => {
+                self.run(body);
+                self.run(cond);
+            }
+            NodeBase::Label(_, ref body) => self.run(body),
+            NodeBase::For(ref init, ref cond, ref step, ref body) => {
+                self.run(init);
+                self.run(cond);
+                self.run(step);
+                self.run(body);
+            }
+            NodeBase::Switch(ref discriminant, ref cases) => {
+                self.run(discriminant);
+                for &(ref label, ref body) in cases {
+                    if let Some(ref label) = *label {
+                        self.run(label);
+                    }
+                    self.run(body);
+                }
+            }
+            NodeBase::ForIn(ref var, ref iterable, ref body)
+            | NodeBase::ForOf(ref var, ref iterable, ref body) => {
+                self.run(var);
+                self.run(iterable);
+                self.run(body);
+            }
+            NodeBase::UnaryOp(ref expr, _) => self.run(expr),
+            NodeBase::BinaryOp(ref lhs, ref rhs, _) => {
+                self.run(lhs);
+                self.run(rhs);
+            }
+            NodeBase::TernaryOp(ref cond, ref then, ref else_) => {
+                self.run(cond);
+                self.run(then);
+                self.run(else_);
+            }
+            NodeBase::Return(Some(ref val)) => self.run(val),
+            NodeBase::Array(ref elems) => for elem in elems {
+                self.run(elem)
+            },
+            NodeBase::Spread(ref expr) => self.run(expr),
+            NodeBase::Object(ref props) => for prop in props {
+                match prop {
+                    &PropertyDefinition::Property(_, ref val)
+                    | &PropertyDefinition::Getter(_, ref val)
+                    | &PropertyDefinition::Setter(_, ref val)
+                    | &PropertyDefinition::Spread(ref val) => self.run(val),
+                    &PropertyDefinition::IdentifierReference(_) => {}
+                }
+            },
+            _ => {}
+        }
+    }
+
+    fn warn(&mut self, msg: &str, pos: usize) {
+        let warning = Diagnostic::warning(msg, self.source.as_str(), pos);
+        self.warnings.push(warning)
+    }
+}
+
+/// Returns the position where the code of `node` starts. The position of an operation is the
+/// position of its operator.
+fn start_pos(node: &Node) -> usize {
+    match node.base {
+        NodeBase::Member(ref lhs, _)
+        | NodeBase::Index(ref lhs, _)
+        | NodeBase::Call(ref lhs, _)
+        | NodeBase::TaggedTemplate(ref lhs, _, _)
+        | NodeBase::Assign(ref lhs, _)
+        | NodeBase::CompoundAssign(ref lhs, _, _)
+        | NodeBase::BinaryOp(ref lhs, _, _)
+        | NodeBase::TernaryOp(ref lhs, _, _) => start_pos(lhs).min(node.pos),
+        _ => node.pos,
+    }
+}
+
+/// Collects the names declared with var, let, const and function in the body of a function, not
+/// in the functions nested in it.
+fn collect_decls(node: &Node, varmap: &mut HashSet<String>) {
+    match node.base {
+        NodeBase::StatementList(ref nodes) => for node in nodes {
+            collect_decls(node, varmap)
+        },
+        NodeBase::FunctionDecl(FunctionDeclNode { ref name, .. }) => {
+            varmap.insert(name.clone());
+        }
+        NodeBase::VarDecl(ref name, _) | NodeBase::LexicalDecl(ref name, _, _) => {
+            varmap.insert(name.clone());
+        }
+        NodeBase::If(_, ref then, ref else_) => {
+            collect_decls(then, varmap);
+            collect_decls(else_, varmap);
+        }
+        NodeBase::While(_, ref body)
+        | NodeBase::DoWhile(ref body, _)
+        | NodeBase::Label(_, ref body)
+        | NodeBase::With(_, ref body) => collect_decls(body, varmap),
+        NodeBase::Switch(_, ref cases) => for &(_, ref body) in cases {
+            collect_decls(body, varmap)
+        },
+        NodeBase::For(ref init, _, _, ref body)
+        | NodeBase::ForIn(ref init, _, ref body)
+        | NodeBase::ForOf(ref init, _, ref body) => {
+            collect_decls(init, varmap);
+            collect_decls(body, varmap);
+        }
+        _ => {}
+    }
+}
+
+#[test]
+fn lint() {
+    use parser::Parser;
+
+    let source = "var a = 1\nfunction f(x) {\n  return x\n  b = x\n  var c\n  c = 2\n}\nd = a\n\
+                  switch (a) { case 1: break; default: }\n";
+    let node = Parser::new(source.to_string()).parse_all();
+    let mut linter = Linter::new(source);
+    linter.run_toplevel(&node);
+    assert_eq!(
+        linter
+            .warnings
+            .iter()
+            .map(|warning| warning.message.clone())
+            .collect::<Vec<String>>(),
+        vec![
+            "unreachable code".to_string(),
+            "assignment to undeclared variable 'b'".to_string(),
+            "assignment to undeclared variable 'd'".to_string(),
+        ]
+    );
+}
@@ -1,157 +1,1524 @@
 extern crate rapidus;
+use rapidus::builtin;
+use rapidus::bytecode_file;
 use rapidus::bytecode_gen;
-use rapidus::extract_anony_func;
-use rapidus::fv_finder;
-use rapidus::fv_solver;
+use rapidus::crash_report;
+use rapidus::debugger;
+use rapidus::diagnostic;
+use rapidus::engine::{CompileHooks, EngineBuilder};
 use rapidus::lexer;
+use rapidus::lint;
 use rapidus::parser;
+use rapidus::test262;
+use rapidus::test_runner;
+use rapidus::node::Node;
 use rapidus::vm;
-use rapidus::vm_codegen;
+use rapidus::Engine;
 
 extern crate clap;
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, Shell, SubCommand};
 
+extern crate libc;
 extern crate nix;
+use nix::sys::signal;
 use nix::sys::wait::*;
 use nix::unistd::*;
 
-use std::collections::HashMap;
-use std::fs::OpenOptions;
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
 use std::io::prelude::*;
+use std::iter;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
 
 const VERSION_STR: &'static str = env!("CARGO_PKG_VERSION");
 
+// Exit statuses, besides 0 and the ones passed to process.exit(). See AFTER_HELP.
+const EXIT_ERROR: i32 = 1;
+const EXIT_INTERNAL_ERROR: i32 = 70;
+const EXIT_TIMEOUT: i32 = 124;
+
+// The module that modules preloaded with -r are imported by.
+const PRELOAD_REFERRER: &'static str = "[preload]";
+
+// More options, separated by whitespace. Flags add to those on the command line, and values given
+// on the command line override the ones here.
+const OPTIONS_ENV_VAR: &'static str = "RAPIDUS_OPTIONS";
+
+const AFTER_HELP: &'static str = "EXIT STATUS:
+    0     The script ran to the end
+    N     The script called process.exit(N)
+    1     A syntax error, a runtime error, or a file that cannot be read
+    70    An internal error in rapidus
+    124   The script ran longer than --timeout
+
+ENVIRONMENT:
+    RAPIDUS_OPTIONS   Options added to the command line, e.g. '--no-jit --time'. Flags set there
+                      cannot be unset, but values given on the command line are used instead";
+
 fn main() {
     let app = App::new("Rapidus")
         .version(VERSION_STR)
         .author("uint256_t")
         .about("A toy JavaScript engine")
+        .after_help(AFTER_HELP)
+        .setting(AppSettings::TrailingVarArg)
+        .arg(
+            Arg::with_name("dump-tokens")
+                .help("Print the tokens of the program")
+                .long("dump-tokens"),
+        )
+        .arg(
+            Arg::with_name("dump-ast")
+                .help("Print the AST right after parsing")
+                .long("dump-ast"),
+        )
+        .arg(
+            Arg::with_name("dump-ast-resolved")
+                .help("Print the AST after anonymous functions and free variables are resolved")
+                .long("dump-ast-resolved"),
+        )
+        .arg(
+            Arg::with_name("dump-bytecode")
+                .help("Print the generated bytecode")
+                .long("dump-bytecode"),
+        )
+        .arg(
+            Arg::with_name("dump-consts")
+                .help("Print the constant table")
+                .long("dump-consts"),
+        )
+        .arg(
+            Arg::with_name("no-color")
+                .help("Do not use colors in error messages")
+                .long("no-color"),
+        )
+        .arg(
+            Arg::with_name("watch")
+                .help("Run the script again whenever the file changes")
+                .long("watch")
+                .conflicts_with_all(&["eval", "print"]),
+        )
+        .arg(
+            Arg::with_name("check")
+                .help("Report syntax errors and warnings without running the script")
+                .long("check")
+                .conflicts_with("watch"),
+        )
         .arg(
-            Arg::with_name("debug")
-                .help("Show useful information for debugging")
-                .long("debug"),
+            Arg::with_name("strict")
+                .help("Run the whole program in strict mode")
+                .long("strict"),
         )
-        .arg(Arg::with_name("file").help("Input file name").index(1));
+        .arg(
+            Arg::with_name("no-jit")
+                .help("Run everything in the interpreter")
+                .long("no-jit"),
+        )
+        .arg(
+            Arg::with_name("jit-func-threshold")
+                .help("Compile a function after it is called CALLS times")
+                .long("jit-func-threshold")
+                .value_name("CALLS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("jit-loop-threshold")
+                .help("Compile a loop after it is run RUNS times")
+                .long("jit-loop-threshold")
+                .value_name("RUNS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-old-space")
+                .help("Limit the memory of the script to MB megabytes")
+                .long("max-old-space")
+                .value_name("MB")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("stack-size")
+                .help("Limit the depth of nested function calls to N")
+                .long("stack-size")
+                .value_name("N")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("stack-limit")
+                .help("Limit the values on the operand stack (arguments, locals, temporaries) to N")
+                .long("stack-limit")
+                .value_name("N")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .help("Stop the script after SECONDS seconds")
+                .long("timeout")
+                .value_name("SECONDS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("time")
+                .help("Print the time spent in each phase of compilation and execution")
+                .long("time"),
+        )
+        .arg(
+            Arg::with_name("coverage")
+                .help("Write a coverage report to FILE (JSON if FILE ends with .json, else lcov)")
+                .long("coverage")
+                .value_name("FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("require")
+                .help("Run MODULE before the script, in the same global scope")
+                .short("r")
+                .long("require")
+                .value_name("MODULE")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("preload")
+                .help("Run the script FILE before the script, in the same global scope")
+                .long("preload")
+                .value_name("FILE")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("eval")
+                .help("Evaluate SCRIPT")
+                .short("e")
+                .long("eval")
+                .value_name("SCRIPT")
+                .takes_value(true)
+                .conflicts_with("print"),
+        )
+        .arg(
+            Arg::with_name("print")
+                .help("Evaluate SCRIPT and print its completion value")
+                .short("p")
+                .long("print")
+                .value_name("SCRIPT")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("file")
+                .help("Input file name ('-' reads the program from stdin)")
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("args")
+                .help("Arguments passed to the script as process.argv")
+                .multiple(true)
+                .index(2),
+        )
+        .subcommand(
+            SubCommand::with_name("compile")
+                .about("Compile a script to bytecode without running it")
+                .arg(
+                    Arg::with_name("file")
+                        .help("Input file name")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .help("Output file name (defaults to the input file name with .rbc)")
+                        .short("o")
+                        .long("output")
+                        .value_name("FILE")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("test")
+                .about("Run the tests in *.test.js files")
+                .arg(
+                    Arg::with_name("paths")
+                        .help("Test files, or directories to search for them (defaults to '.')")
+                        .multiple(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("test262")
+                .about("Run ECMAScript conformance tests from a test262 checkout")
+                .arg(
+                    Arg::with_name("paths")
+                        .help("Test files, or directories to search for them")
+                        .required(true)
+                        .multiple(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("harness")
+                        .help("test262 harness directory (found from the test paths by default)")
+                        .long("harness")
+                        .takes_value(true)
+                        .value_name("DIR"),
+                )
+                .arg(
+                    Arg::with_name("verbose")
+                        .help("Show every result and the output of failing tests")
+                        .short("v")
+                        .long("verbose"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("bench")
+                .about("Run a script several times and report how long it takes")
+                .arg(
+                    Arg::with_name("file")
+                        .help("Input file name")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("runs")
+                        .help("Number of measured runs (default: 10)")
+                        .short("n")
+                        .long("runs")
+                        .value_name("N")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("warmup")
+                        .help("Number of runs before measuring (default: 2)")
+                        .long("warmup")
+                        .value_name("N")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("compare-jit")
+                        .help("Measure with and without the JIT, and compare")
+                        .long("compare-jit"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("debug")
+                .about("Run a script under an interactive debugger")
+                .setting(AppSettings::TrailingVarArg)
+                .arg(
+                    Arg::with_name("file")
+                        .help("Input file name")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("args")
+                        .help("Arguments passed to the script as process.argv")
+                        .multiple(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("completions")
+                .about("Print a completion script for a shell")
+                .arg(
+                    Arg::with_name("shell")
+                        .help("The shell to complete in")
+                        .required(true)
+                        .possible_values(&Shell::variants())
+                        .index(1),
+                ),
+        )
+        .subcommand(SubCommand::with_name("man").about("Print the manual page"))
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("Run a script or a precompiled .rbc file")
+                .setting(AppSettings::TrailingVarArg)
+                .arg(
+                    Arg::with_name("file")
+                        .help("Input file name")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("args")
+                        .help("Arguments passed to the script as process.argv")
+                        .multiple(true)
+                        .index(2),
+                ),
+        );
     let app_matches = app.clone().get_matches();
+    let flags = OptionMatches {
+        cli: &app_matches,
+        env: env_options(&app),
+    };
+
+    diagnostic::set_color(
+        !flags.is_present("no-color") && isatty(libc::STDERR_FILENO).unwrap_or(false),
+    );
 
-    if let Some(filename) = app_matches.value_of("file") {
-        if !app_matches.is_present("debug") {
-            run(filename);
+    let mut options = RunOptions {
+        coverage_output: flags.value_of("coverage"),
+        print_result: false,
+        show_result_label: true,
+        dump_tokens: flags.is_present("dump-tokens"),
+        dump_ast: flags.is_present("dump-ast"),
+        dump_ast_resolved: flags.is_present("dump-ast-resolved"),
+        dump_bytecode: flags.is_present("dump-bytecode"),
+        dump_consts: flags.is_present("dump-consts"),
+        time: flags.is_present("time"),
+        strict: flags.is_present("strict"),
+        jit: !flags.is_present("no-jit"),
+        jit_func_threshold: flags.number_of("jit-func-threshold"),
+        jit_loop_threshold: flags.number_of("jit-loop-threshold"),
+        max_old_space: flags.number_of("max-old-space"),
+        stack_size: flags.number_of("stack-size"),
+        stack_limit: flags.number_of("stack-limit"),
+        timeout: flags.number_of("timeout"),
+        argv: vec![],
+        preloads: flags.values_of("require"),
+        preload_files: flags.values_of("preload"),
+        test: false,
+        test262: false,
+        debug: false,
+    };
+
+    match app_matches.subcommand() {
+        ("compile", Some(matches)) => {
+            let file_name = matches.value_of("file").unwrap();
+            let output = match matches.value_of("output") {
+                Some(output) => output.to_string(),
+                None => Path::new(file_name)
+                    .with_extension("rbc")
+                    .to_string_lossy()
+                    .into_owned(),
+            };
+            ::std::process::exit(compile_to_file(file_name, output.as_str(), &options))
+        }
+        ("test", Some(matches)) => {
+            let mut paths = values_of(matches, "paths");
+            if paths.is_empty() {
+                paths.push(".");
+            }
+            options.show_result_label = false;
+            options.test = true;
+            if !run_tests(paths, &options) {
+                ::std::process::exit(EXIT_ERROR)
+            }
             return;
         }
+        ("test262", Some(matches)) => {
+            let paths = values_of(matches, "paths");
+            let harness_dir = match matches.value_of("harness") {
+                Some(dir) => Some(PathBuf::from(dir)),
+                None => test262::find_harness_dir(Path::new(paths[0])),
+            };
+            let harness_dir = match harness_dir {
+                Some(dir) => dir,
+                None => {
+                    eprintln!("error: cannot find the test262 harness directory; use --harness");
+                    ::std::process::exit(1)
+                }
+            };
+            options.show_result_label = false;
+            options.test262 = true;
+            run_test262(paths, harness_dir.as_path(), matches.is_present("verbose"), &options);
+            return;
+        }
+        ("bench", Some(matches)) => {
+            let runs = number_of(matches, "runs").unwrap_or(10).max(1);
+            let warmup = number_of(matches, "warmup").unwrap_or(2);
+            options.show_result_label = false;
+            ::std::process::exit(bench(
+                matches.value_of("file").unwrap(),
+                runs,
+                warmup,
+                matches.is_present("compare-jit"),
+                &mut options,
+            ))
+        }
+        ("debug", Some(matches)) => {
+            let file_name = matches.value_of("file").unwrap();
+            options.argv = script_argv(Some(file_name), values_of(matches, "args"));
+            options.show_result_label = false;
+            // JIT-compiled code cannot be paused.
+            options.jit = false;
+            options.debug = true;
+            ::std::process::exit(run_file(file_name, &options))
+        }
+        ("completions", Some(matches)) => {
+            let shell = matches.value_of("shell").unwrap().parse().unwrap();
+            app.clone()
+                .gen_completions_to("rapidus", shell, &mut io::stdout());
+            return;
+        }
+        ("man", Some(_)) => {
+            print!("{}", man_page(&app));
+            return;
+        }
+        ("run", Some(matches)) => {
+            let file_name = matches.value_of("file").unwrap();
+            options.argv = script_argv(Some(file_name), values_of(matches, "args"));
+            ::std::process::exit(run_file(file_name, &options))
+        }
+        _ => {}
+    }
+
+    if let Some(script) = app_matches
+        .value_of("eval")
+        .or(app_matches.value_of("print"))
+    {
+        options.print_result = app_matches.is_present("print");
+        options.show_result_label = false;
+        // There is no script file, so every positional argument belongs to the script.
+        let mut args = values_of(&app_matches, "file");
+        args.append(&mut values_of(&app_matches, "args"));
+        options.argv = script_argv(None, args);
+        if app_matches.is_present("check") {
+            ::std::process::exit(check("[eval]", script.to_string(), &options))
+        }
+        ::std::process::exit(run("[eval]", script.to_string(), &options))
+    }
+
+    let filename = match app_matches.value_of("file") {
+        Some(filename) => Some(filename),
+        // Programs piped into rapidus are run as if '-' was given.
+        None if !isatty(libc::STDIN_FILENO).unwrap_or(true) => Some("-"),
+        None => None,
+    };
 
-        let mut file_body = String::new();
+    let args = values_of(&app_matches, "args");
+    options.argv = match filename {
+        Some("-") | None => script_argv(None, args),
+        Some(filename) => script_argv(Some(filename), args),
+    };
 
-        match OpenOptions::new().read(true).open(filename) {
-            Ok(mut ok) => ok
-                .read_to_string(&mut file_body)
-                .ok()
-                .expect("cannot read file"),
+    let status = match filename {
+        Some("-") if app_matches.is_present("watch") => {
+            eprintln!("error: cannot watch the program read from stdin");
+            EXIT_ERROR
+        }
+        Some("-") => match read_stdin() {
+            Some(file_body) if app_matches.is_present("check") => {
+                check("[stdin]", file_body, &options)
+            }
+            Some(file_body) => run("[stdin]", file_body, &options),
+            None => EXIT_ERROR,
+        },
+        Some(filename) if app_matches.is_present("check") => options
+            .preload_files
+            .iter()
+            .chain(Some(&filename))
+            .map(|&file_name| match read_source(file_name) {
+                Some(file_body) => check(file_name, file_body, &options),
+                None => EXIT_ERROR,
+            })
+            .max()
+            .unwrap(),
+        Some(filename) if app_matches.is_present("watch") => watch(filename, &options),
+        Some(filename) => run_file(filename, &options),
+        None => 0,
+    };
+    ::std::process::exit(status)
+}
+
+struct RunOptions<'a> {
+    coverage_output: Option<&'a str>,
+    print_result: bool,
+    show_result_label: bool,
+    dump_tokens: bool,
+    dump_ast: bool,
+    dump_ast_resolved: bool,
+    dump_bytecode: bool,
+    dump_consts: bool,
+    time: bool,
+    strict: bool,
+    jit: bool,
+    jit_func_threshold: Option<usize>, // in calls
+    jit_loop_threshold: Option<usize>, // in runs
+    max_old_space: Option<u64>, // in megabytes
+    stack_size: Option<usize>,  // maximum call depth
+    stack_limit: Option<usize>, // maximum number of values on the operand stack
+    timeout: Option<f64>,       // in seconds
+    argv: Vec<String>,
+    preloads: Vec<&'a str>,      // Modules run before the script
+    preload_files: Vec<&'a str>, // Scripts run before the script
+    test: bool,    // Provide test(), assert() and expect()
+    test262: bool, // Provide $262
+    debug: bool,   // Run under the debugger
+}
+
+impl<'a> RunOptions<'a> {
+    /// Returns whether anything runs before the script, in which case it is run by run_scripts.
+    fn has_preloads(&self) -> bool {
+        !self.preloads.is_empty() || !self.preload_files.is_empty()
+    }
+}
+
+/// Returns the manual page, in roff, made from the help message.
+fn man_page(app: &App) -> String {
+    let mut help = vec![];
+    app.clone().write_long_help(&mut help).unwrap();
+    let help = String::from_utf8(help).unwrap();
+
+    let mut page = format!(
+        ".TH RAPIDUS 1 \"\" \"rapidus {}\" \"User Commands\"\n\
+         .SH NAME\nrapidus \\- A toy JavaScript engine\n.SH DESCRIPTION\n.nf\n",
+        VERSION_STR
+    );
+    for line in help.lines() {
+        let line = line.replace('\\', "\\e");
+        // Lines starting with these characters would be read as requests.
+        if line.starts_with('.') || line.starts_with('\'') {
+            page.push_str("\\&");
+        }
+        page.push_str(line.as_str());
+        page.push('\n');
+    }
+    page.push_str(".fi\n");
+    page
+}
+
+/// The options given on the command line and in RAPIDUS_OPTIONS. A flag is set if either sets it,
+/// and a value on the command line is used over the one in RAPIDUS_OPTIONS.
+struct OptionMatches<'a> {
+    cli: &'a clap::ArgMatches<'a>,
+    env: clap::ArgMatches<'a>,
+}
+
+impl<'a> OptionMatches<'a> {
+    fn is_present(&self, name: &str) -> bool {
+        self.cli.is_present(name) || self.env.is_present(name)
+    }
+
+    fn value_of(&self, name: &str) -> Option<&str> {
+        self.cli.value_of(name).or_else(|| self.env.value_of(name))
+    }
+
+    fn number_of<T: FromStr>(&self, name: &str) -> Option<T> {
+        number_of(self.cli, name).or_else(|| number_of(&self.env, name))
+    }
+
+    /// Returns the values in RAPIDUS_OPTIONS, and then the ones on the command line.
+    fn values_of(&self, name: &str) -> Vec<&str> {
+        let mut values = values_of(&self.env, name);
+        values.append(&mut values_of(self.cli, name));
+        values
+    }
+}
+
+/// Returns the options in RAPIDUS_OPTIONS, exiting if it is not a list of options.
+fn env_options<'a>(app: &App<'a, '_>) -> clap::ArgMatches<'a> {
+    let options = env::var(OPTIONS_ENV_VAR).unwrap_or_default();
+    let args = iter::once("rapidus").chain(options.split_whitespace());
+    let matches = app.clone().get_matches_from_safe(args).unwrap_or_else(|e| {
+        eprintln!("{}: {}", OPTIONS_ENV_VAR, e.message);
+        ::std::process::exit(EXIT_ERROR)
+    });
+    if matches.is_present("file") || matches.subcommand_name().is_some() {
+        eprintln!("error: {} must contain only options", OPTIONS_ENV_VAR);
+        ::std::process::exit(EXIT_ERROR)
+    }
+    matches
+}
+
+/// Returns the value of the numeric option `name`, exiting if it is not a number.
+fn number_of<T: FromStr>(matches: &clap::ArgMatches, name: &str) -> Option<T> {
+    matches.value_of(name).map(|n| {
+        n.parse().unwrap_or_else(|_| {
+            eprintln!("error: --{} expects a number, got '{}'", name, n);
+            ::std::process::exit(EXIT_ERROR)
+        })
+    })
+}
+
+fn values_of<'a>(matches: &'a clap::ArgMatches, name: &str) -> Vec<&'a str> {
+    matches
+        .values_of(name)
+        .map(|values| values.collect())
+        .unwrap_or(vec![])
+}
+
+/// Returns process.argv: the path to rapidus, the path to the script if any, and the arguments.
+fn script_argv(file_name: Option<&str>, args: Vec<&str>) -> Vec<String> {
+    let mut argv = vec![
+        env::current_exe()
+            .map(|path| path.to_string_lossy().into_owned())
+            .unwrap_or("rapidus".to_string()),
+    ];
+    if let Some(file_name) = file_name {
+        argv.push(
+            fs::canonicalize(file_name)
+                .map(|path| path.to_string_lossy().into_owned())
+                .unwrap_or(file_name.to_string()),
+        );
+    }
+    argv.extend(args.iter().map(|arg| arg.to_string()));
+    argv
+}
+
+/// Wall-clock time spent in each phase, reported by --time.
+struct PhaseTimes {
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl PhaseTimes {
+    fn new() -> PhaseTimes {
+        PhaseTimes { phases: vec![] }
+    }
+
+    fn add(&mut self, phase: &'static str, time: Duration) {
+        self.phases.push((phase, time));
+    }
+
+    fn show(&self) {
+        let mut total = Duration::new(0, 0);
+        eprintln!("Time:");
+        for &(phase, ref time) in &self.phases {
+            eprintln!("  {:<12} {:>10.3}ms", phase, to_ms(*time));
+            total += *time;
+        }
+        eprintln!("  {:<12} {:>10.3}ms", "total", to_ms(total));
+    }
+}
+
+fn to_ms(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + d.subsec_nanos() as f64 / 1e6
+}
+
+fn read_file(file_name: &str) -> Option<Vec<u8>> {
+    let mut file_body = vec![];
+
+    match OpenOptions::new().read(true).open(file_name) {
+        Ok(mut ok) => match ok.read_to_end(&mut file_body) {
+            Ok(_) => Some(file_body),
             Err(e) => {
-                println!("error: {}", e);
-                return;
+                eprintln!("error: cannot read file: {}", e);
+                None
+            }
+        },
+        Err(e) => {
+            eprintln!("error: {}", e);
+            None
+        }
+    }
+}
+
+fn read_stdin() -> Option<String> {
+    let mut body = String::new();
+    match io::stdin().read_to_string(&mut body) {
+        Ok(_) => Some(body),
+        Err(e) => {
+            eprintln!("error: cannot read stdin: {}", e);
+            None
+        }
+    }
+}
+
+fn read_source(file_name: &str) -> Option<String> {
+    match String::from_utf8(read_file(file_name)?) {
+        Ok(file_body) => Some(file_body),
+        Err(e) => {
+            eprintln!("error: cannot read file: {}", e);
+            None
+        }
+    }
+}
+
+/// Runs a script, or a precompiled .rbc file, and returns the exit status.
+fn run_file(file_name: &str, options: &RunOptions) -> i32 {
+    let file_body = match read_file(file_name) {
+        Some(file_body) => file_body,
+        None => return EXIT_ERROR,
+    };
+
+    if bytecode_file::is_compiled_script(&file_body) && options.has_preloads() {
+        eprintln!("error: {}: a bytecode file must be run by itself", file_name);
+        return EXIT_ERROR;
+    }
+    if bytecode_file::is_compiled_script(&file_body) {
+        return match bytecode_file::CompiledScript::deserialize(&file_body) {
+            Ok(script) => in_child_process(|| {
+                set_process_limits(options);
+                let mut times = PhaseTimes::new();
+                let vm = execute(script, options, &mut times);
+                if options.time {
+                    times.show()
+                }
+                exit_status(&vm)
+            }),
+            Err(e) => {
+                eprintln!("error: {}: broken bytecode file: {:?}", file_name, e);
+                EXIT_ERROR
             }
         };
+    }
 
-        let mut lexer = lexer::Lexer::new(file_body.clone());
+    match String::from_utf8(file_body) {
+        Ok(file_body) => run(file_name, file_body, options),
+        Err(e) => {
+            eprintln!("error: cannot read file: {}", e);
+            EXIT_ERROR
+        }
+    }
+}
 
-        println!("Lexer:");
-        while let Ok(token) = lexer.next() {
-            println!("{:?}", token);
+/// Reads the scripts given with --preload, which cannot be bytecode files.
+fn read_preload_files<'a>(options: &RunOptions<'a>) -> Option<Vec<(&'a str, String)>> {
+    let mut scripts = vec![];
+    for &file_name in &options.preload_files {
+        let file_body = read_file(file_name)?;
+        if bytecode_file::is_compiled_script(&file_body) {
+            eprintln!("error: {}: a bytecode file must be run by itself", file_name);
+            return None;
+        }
+        match String::from_utf8(file_body) {
+            Ok(file_body) => scripts.push((file_name, file_body)),
+            Err(e) => {
+                eprintln!("error: cannot read file: {}", e);
+                return None;
+            }
         }
+    }
+    Some(scripts)
+}
 
-        let mut parser = parser::Parser::new(file_body);
+/// Runs the modules preloaded with -r, the scripts given with --preload and then the script in one
+/// VM, so that the later ones see the global variables and functions of the earlier ones. Stops at
+/// the first one that fails.
+fn run_scripts(file_name: &str, file_body: String, options: &RunOptions) -> i32 {
+    let single_script_options = [
+        ("--dump-tokens", options.dump_tokens),
+        ("--dump-ast", options.dump_ast),
+        ("--dump-ast-resolved", options.dump_ast_resolved),
+        ("--dump-bytecode", options.dump_bytecode),
+        ("--dump-consts", options.dump_consts),
+        ("--time", options.time),
+        ("--coverage", options.coverage_output.is_some()),
+        ("the debugger", options.debug),
+    ];
+    if let Some(&(option, _)) = single_script_options.iter().find(|&&(_, used)| used) {
+        eprintln!("error: {} cannot be used with -r or --preload", option);
+        return EXIT_ERROR;
+    }
+    let mut scripts = match read_preload_files(options) {
+        Some(scripts) => scripts,
+        None => return EXIT_ERROR,
+    };
+    scripts.push((file_name, file_body));
 
-        println!("Parser:");
-        let mut node = parser.parse_all();
-        println!("{:?}", node);
+    in_child_process(|| {
+        set_process_limits(options);
+        let mut vm = create_vm(&engine_builder(options).build(), options);
+        vm.set_argv(options.argv.clone());
 
-        extract_anony_func::AnonymousFunctionExtractor::new().run_toplevel(&mut node);
-        fv_finder::FreeVariableFinder::new().run_toplevel(&mut node);
-        println!("extract_anony_func, fv_finder:\n {:?}", node);
-        fv_solver::FreeVariableSolver::new().run_toplevel(&mut node);
+        // Preloaded modules are found by the module resolver, relative to the current directory.
+        let resolver = vm.module_resolver.clone();
+        let mut modules = vec![];
+        for specifier in &options.preloads {
+            match resolver.resolve(specifier, PRELOAD_REFERRER) {
+                Ok(module) => modules.push((module.name, module.source)),
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    return EXIT_ERROR;
+                }
+            }
+        }
+        let scripts = scripts
+            .into_iter()
+            .map(|(file_name, file_body)| (file_name.to_string(), file_body));
 
-        println!("extract_anony_func, fv_finder, fv_solver:\n {:?}", node);
+        if options.show_result_label {
+            println!("Result:");
+        }
+        crash_report::watch(&vm);
+        let mut result = Ok(vm::Value::Undefined);
+        for (file_name, file_body) in modules.into_iter().chain(scripts) {
+            let engine = engine_builder(options)
+                .file_name(file_name.as_str())
+                .build();
+            result = engine.eval_in(file_body.as_str(), &mut vm);
+            if let Err(ref e) = result {
+                report_compile_error(e, file_name.as_str(), file_body.as_str());
+                break;
+            }
+        }
+        crash_report::unwatch();
+
+        match result {
+            Ok(result) => {
+                if options.print_result {
+                    unsafe { builtin::console_log(vec![result], &mut vm) };
+                }
+                exit_status(&vm)
+            }
+            Err(_) => EXIT_ERROR,
+        }
+    })
+}
+
+/// Runs the file, and runs it again every time it is modified.
+fn watch(file_name: &str, options: &RunOptions) -> ! {
+    let mut last_modified = None;
+    loop {
+        let modified = fs::metadata(file_name).and_then(|m| m.modified()).ok();
+        if modified != last_modified {
+            last_modified = modified;
+            // Clear the screen and move the cursor to the top left.
+            print!("\x1b[2J\x1b[H");
+            io::stdout().flush().unwrap();
+            run_file(file_name, options);
+            println!("[watching {} for changes]", file_name);
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+}
+
+fn run(file_name: &str, file_body: String, options: &RunOptions) -> i32 {
+    if options.has_preloads() {
+        return run_scripts(file_name, file_body, options);
+    }
+    in_child_process(|| {
+        set_process_limits(options);
+        let mut times = PhaseTimes::new();
+        let script = compile(file_name, file_body, options, &mut times);
+        let vm = execute(script, options, &mut times);
+        if options.time {
+            times.show()
+        }
+        exit_status(&vm)
+    })
+}
+
+/// Reports every syntax error and warning in the script without running it.
+fn check(file_name: &str, file_body: String, options: &RunOptions) -> i32 {
+    in_child_process(|| {
+        let mut parser = parser::Parser::new(file_body.clone());
+        parser.file_name = file_name.to_string();
+        parser.strict = options.strict;
+        parser.recover = true;
+
+        // Errors the parser cannot recover from end parsing.
+        let node = parser.parse();
+        let mut errors = mem::replace(&mut parser.errors, vec![]);
+        let mut warnings = mem::replace(&mut parser.warnings, vec![]);
+        match node {
+            Ok(node) => {
+                let mut linter = lint::Linter::new(file_body.as_str());
+                linter.run_toplevel(&node);
+                warnings.extend(linter.warnings);
+                warnings.sort_by_key(|warning| warning.pos);
+
+                if errors.is_empty() {
+                    let engine = engine_builder(options).file_name(file_name).build();
+                    if let Err(e) = engine.compile_script(file_body.as_str()) {
+                        errors.push(e)
+                    }
+                }
+            }
+            Err(e) => errors.push(e),
+        }
+
+        for error in &errors {
+            eprint!("{}", error.render(file_name, file_body.as_str()));
+        }
+        let errors = errors.len();
+        for warning in &warnings {
+            eprint!("{}", warning.render(file_name, file_body.as_str()));
+        }
+        if errors > 0 || !warnings.is_empty() {
+            eprintln!(
+                "{}: {} error{}, {} warning{}",
+                file_name,
+                errors,
+                if errors == 1 { "" } else { "s" },
+                warnings.len(),
+                if warnings.len() == 1 { "" } else { "s" }
+            );
+        }
+        if errors > 0 {
+            EXIT_ERROR
+        } else {
+            0
+        }
+    })
+}
+
+/// Applies --max-old-space and --timeout to the current process.
+fn set_process_limits(options: &RunOptions) {
+    if let Some(megabytes) = options.max_old_space {
+        let bytes = megabytes.saturating_mul(1024 * 1024) as libc::rlim_t;
+        let limit = libc::rlimit {
+            rlim_cur: bytes,
+            rlim_max: bytes,
+        };
+        if unsafe { libc::setrlimit(libc::RLIMIT_DATA, &limit) } != 0 {
+            eprintln!("warning: cannot limit memory: {}", io::Error::last_os_error());
+        }
+    }
+
+    if let Some(seconds) = options.timeout {
+        // The process is killed by SIGALRM when the timer expires.
+        let timer = libc::itimerval {
+            it_interval: libc::timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            it_value: libc::timeval {
+                tv_sec: seconds.trunc() as libc::time_t,
+                tv_usec: (seconds.fract() * 1e6) as libc::suseconds_t,
+            },
+        };
+        unsafe { libc::setitimer(libc::ITIMER_REAL, &timer, ptr::null_mut()) };
+    }
+}
+
+/// Returns the exit status for a script that ran to the end (process.exit() exits right away).
+fn exit_status(vm: &vm::VM) -> i32 {
+    if vm.error_count > 0 {
+        EXIT_ERROR
+    } else {
+        0
+    }
+}
+
+/// Runs the script `runs` times after `warmup` runs, and prints statistics of the run times.
+/// Returns the exit status.
+fn bench(
+    file_name: &str,
+    runs: usize,
+    warmup: usize,
+    compare_jit: bool,
+    options: &mut RunOptions,
+) -> i32 {
+    let file_body = match read_source(file_name) {
+        Some(file_body) => file_body,
+        None => return EXIT_ERROR,
+    };
+
+    let modes = if compare_jit {
+        vec![true, false]
+    } else {
+        vec![options.jit]
+    };
+    println!("{} ({} runs, {} warmup)", file_name, runs, warmup);
+
+    let mut means = vec![];
+    for jit in modes {
+        options.jit = jit;
+        let mut times = vec![];
+        for i in 0..warmup + runs {
+            let time = match bench_run(file_name, &file_body, options) {
+                Some(time) => time,
+                None => {
+                    eprintln!("error: {} did not finish", file_name);
+                    return EXIT_ERROR;
+                }
+            };
+            if i >= warmup {
+                times.push(to_ms(time));
+            }
+        }
+
+        let n = times.len() as f64;
+        let min = times.iter().cloned().fold(::std::f64::INFINITY, f64::min);
+        let mean = times.iter().sum::<f64>() / n;
+        let var = times.iter().map(|t| (t - mean) * (t - mean)).sum::<f64>() / (n - 1.0).max(1.0);
+        println!(
+            "  {:<12} min {:>10.3}ms  mean {:>10.3}ms  stddev {:>8.3}ms",
+            if jit { "jit" } else { "interpreter" },
+            min,
+            mean,
+            var.sqrt()
+        );
+        means.push(mean);
+    }
+
+    if means.len() == 2 {
+        println!("  jit is {:.2}x as fast as the interpreter", means[1] / means[0]);
+    }
+    0
+}
+
+/// Compiles and runs the script once in a child process, with its output discarded. Returns the
+/// time it took, or None if it crashed.
+fn bench_run(file_name: &str, file_body: &str, options: &RunOptions) -> Option<Duration> {
+    let (mut reader, writer) = pipe();
+
+    in_child_process(|| {
+        if let Ok(null) = OpenOptions::new().write(true).open("/dev/null") {
+            unsafe { libc::dup2(null.as_raw_fd(), libc::STDOUT_FILENO) };
+        }
+        let start = Instant::now();
+        let mut times = PhaseTimes::new();
+        let script = compile(file_name, file_body.to_string(), options, &mut times);
+        execute(script, options, &mut times);
+        let elapsed = start.elapsed();
+        let mut writer = writer;
+        let _ = write!(writer, "{} {}", elapsed.as_secs(), elapsed.subsec_nanos());
+        0
+    });
+
+    let mut result = String::new();
+    let _ = reader.read_to_string(&mut result);
+    let result = result
+        .split(' ')
+        .filter_map(|n| n.parse().ok())
+        .collect::<Vec<u64>>();
+    if result.len() == 2 {
+        Some(Duration::new(result[0], result[1] as u32))
+    } else {
+        None
+    }
+}
+
+fn compile_to_file(file_name: &str, output: &str, options: &RunOptions) -> i32 {
+    let file_body = match read_source(file_name) {
+        Some(file_body) => file_body,
+        None => return EXIT_ERROR,
+    };
+
+    in_child_process(|| {
+        let mut times = PhaseTimes::new();
+        let script = compile(file_name, file_body, options, &mut times);
+        let written = File::create(output).and_then(|mut f| f.write_all(&script.serialize()));
+        if options.time {
+            times.show()
+        }
+        match written {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("error: cannot write bytecode file: {}", e);
+                EXIT_ERROR
+            }
+        }
+    })
+}
+
+/// Runs every test file in `paths`, each in its own process. Returns true if all tests passed.
+fn run_tests(paths: Vec<&str>, options: &RunOptions) -> bool {
+    let files = paths
+        .iter()
+        .flat_map(|path| test_runner::find_test_files(Path::new(path)))
+        .collect::<Vec<PathBuf>>();
+
+    let (mut passed, mut failed) = (0, 0);
+    for file in &files {
+        let file_name = file.to_string_lossy().into_owned();
+        println!("{}", file_name);
+        let (file_passed, file_failed) = run_test_file(file_name.as_str(), options);
+        passed += file_passed;
+        failed += file_failed;
+    }
+
+    println!(
+        "\n{} passed, {} failed ({} files)",
+        passed,
+        failed,
+        files.len()
+    );
+    failed == 0
+}
+
+/// Runs a test file and returns the number of passed and failed tests in it.
+fn run_test_file(file_name: &str, options: &RunOptions) -> (usize, usize) {
+    let file_body = match read_source(file_name) {
+        Some(file_body) => file_body,
+        None => return (0, 1),
+    };
+
+    // The child reports its results through a pipe.
+    let (mut reader, writer) = pipe();
+
+    in_child_process(|| {
+        let mut times = PhaseTimes::new();
+        let script = compile(file_name, file_body, options, &mut times);
+        let vm = execute(script, options, &mut times);
+        let mut writer = writer;
+        let _ = write!(writer, "{} {}", vm.tests.passed, vm.tests.failed);
+        0
+    });
+    // Our end of `writer` was closed with the closure, so this reads until the child exits.
+
+    let mut results = String::new();
+    let _ = reader.read_to_string(&mut results);
+    let results = results
+        .split(' ')
+        .filter_map(|n| n.parse().ok())
+        .collect::<Vec<usize>>();
+    if results.len() == 2 {
+        (results[0], results[1])
+    } else {
+        // The process died before reporting.
+        println!("  FAILED (crashed)");
+        (0, 1)
+    }
+}
+
+/// Runs test262 tests and prints a conformance summary.
+fn run_test262(paths: Vec<&str>, harness_dir: &Path, verbose: bool, options: &RunOptions) {
+    let tests = paths
+        .iter()
+        .flat_map(|path| test262::find_tests(Path::new(path)))
+        .collect::<Vec<PathBuf>>();
+
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    for test in &tests {
+        let test_name = test.to_string_lossy().into_owned();
+        match run_test262_file(test_name.as_str(), harness_dir, verbose, options) {
+            test262::Outcome::Pass => {
+                passed += 1;
+                if verbose {
+                    println!("PASS {}", test_name);
+                }
+            }
+            test262::Outcome::Fail => {
+                failed += 1;
+                println!("FAIL {}", test_name);
+            }
+            test262::Outcome::Skip(reason) => {
+                skipped += 1;
+                if verbose {
+                    println!("SKIP {} ({})", test_name, reason);
+                }
+            }
+        }
+    }
 
-        let mut vm_codegen = vm_codegen::VMCodeGen::new();
-        let mut insts = vec![];
-        let mut func_addr_in_bytecode_and_its_entity = HashMap::new();
-        vm_codegen.compile(&node, &mut insts, &mut func_addr_in_bytecode_and_its_entity);
+    let run = passed + failed;
+    println!(
+        "\n{} passed, {} failed, {} skipped ({} tests)",
+        passed,
+        failed,
+        skipped,
+        tests.len()
+    );
+    println!(
+        "conformance: {:.2}% of the tests run",
+        if run == 0 {
+            0.0
+        } else {
+            passed as f64 * 100.0 / run as f64
+        }
+    );
+}
 
-        bytecode_gen::show(&insts);
+fn run_test262_file(
+    test_name: &str,
+    harness_dir: &Path,
+    verbose: bool,
+    options: &RunOptions,
+) -> test262::Outcome {
+    let test = match read_source(test_name) {
+        Some(test) => test,
+        None => return test262::Outcome::Fail,
+    };
+    let metadata = test262::parse_metadata(test.as_str());
+    if let Some(reason) = metadata.unsupported() {
+        return test262::Outcome::Skip(reason);
+    }
+    let source = match test262::test_source(harness_dir, &metadata, test.as_str()) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("error: cannot read the harness for {}: {}", test_name, e);
+            return test262::Outcome::Fail;
+        }
+    };
 
-        // println!("Result:");
-        // let mut vm = vm::VM::new();
-        // vm.global_objects.extend(vm_codegen.global_varmap);
-        // vm.run(insts);
+    // The child reports how far the test got through a pipe.
+    let (mut reader, writer) = pipe();
 
-        // println!("VM CodeGen Test:");
-        // vm_codegen::test();
+    in_child_process(|| {
+        if !verbose {
+            if let Ok(null) = OpenOptions::new().write(true).open("/dev/null") {
+                unsafe {
+                    libc::dup2(null.as_raw_fd(), libc::STDOUT_FILENO);
+                    libc::dup2(null.as_raw_fd(), libc::STDERR_FILENO);
+                }
+            }
+        }
+        let mut writer = writer;
+        let mut times = PhaseTimes::new();
+        // Syntax errors panic in the parser.
+        let script = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            compile(test_name, source, options, &mut times)
+        }));
+        if let Ok(script) = script {
+            let _ = write!(writer, "parsed ");
+            let vm = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                execute(script, options, &mut times)
+            }));
+            if let Ok(vm) = vm {
+                if vm.error_count == 0 {
+                    let _ = write!(writer, "completed");
+                }
+            }
+        }
+        0
+    });
+
+    let mut progress = String::new();
+    let _ = reader.read_to_string(&mut progress);
+    metadata.outcome(
+        progress.contains("parsed"),
+        progress.contains("completed"),
+    )
+}
+
+/// Returns the reading and writing ends of a pipe, for a child process to report to its parent.
+fn pipe() -> (File, File) {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        panic!("Rapidus Internal Error: pipe failed");
     }
+    unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
 }
 
-fn run(file_name: &str) {
+/// Runs `f` in a child process and returns the exit status of the child, which is what `f` returns
+/// unless it panics or crashes.
+fn in_child_process<F: FnOnce() -> i32>(f: F) -> i32 {
     match fork() {
         Ok(ForkResult::Parent { child, .. }) => match waitpid(child, None) {
             Ok(ok) => match ok {
-                WaitStatus::Exited(_, status) => if status != 0 {
-                    println!("exited. status: {}", status)
-                },
+                WaitStatus::Exited(pid, EXIT_INTERNAL_ERROR) => {
+                    show_crash_report(pid);
+                    EXIT_INTERNAL_ERROR
+                }
+                WaitStatus::Exited(_, status) => status,
+                // Set off by --timeout.
+                WaitStatus::Signaled(_, signal::SIGALRM, _) => {
+                    io::stdout().flush().unwrap();
+                    eprintln!("error: the script timed out");
+                    EXIT_TIMEOUT
+                }
                 WaitStatus::Signaled(pid, status, _) => {
                     // We can do anything (like calling destructors) here.
-                    println!("child: pid={:?}, status={:?}", pid, status);
-                    println!("Rapidus Internal Error: segmentation fault");
+                    if status == signal::SIGSEGV {
+                        eprintln!("Rapidus Internal Error: segmentation fault");
+                    } else {
+                        eprintln!("Rapidus Internal Error: killed by {:?}", status);
+                    }
+                    show_crash_report(pid);
+                    EXIT_INTERNAL_ERROR
                 }
                 e => panic!("Rapidus Internal Error: VM exited abnormally!: {:?}", e),
             },
             Err(e) => panic!("Rapidus Internal Error: waitpid failed: {:?}", e),
         },
         Ok(ForkResult::Child) => {
-            let mut file_body = String::new();
-
-            match OpenOptions::new().read(true).open(file_name) {
-                Ok(mut ok) => match ok.read_to_string(&mut file_body).ok() {
-                    Some(x) => x,
-                    None => {
-                        panic!("error: cannot read file");
-                    }
+            crash_report::install();
+            let status = match panic::catch_unwind(panic::AssertUnwindSafe(f)) {
+                Ok(status) => status,
+                Err(e) => if e.is::<rapidus::Error>() || e.is::<vm::FatalError>() {
+                    EXIT_ERROR
+                } else {
+                    EXIT_INTERNAL_ERROR
                 },
-                Err(e) => {
-                    println!("error: {}", e);
-                    return;
-                }
             };
+            // Don't return to the caller, which may go on to run something else (e.g. --watch).
+            io::stdout().flush().unwrap();
+            ::std::process::exit(status)
+        }
+        Err(e) => panic!("Rapidus Internal Error: fork failed: {:?}", e),
+    }
+}
 
-            let mut parser = parser::Parser::new(file_body);
+/// Points to the crash report that the child process `pid` wrote, if any.
+fn show_crash_report(pid: Pid) {
+    let path = crash_report::path_for(libc::pid_t::from(pid));
+    if path.is_file() {
+        eprintln!(
+            "A crash report was written to {}. Please attach it to a bug report.",
+            path.display()
+        );
+    }
+}
 
-            let mut node = parser.parse_all();
+fn compile(
+    file_name: &str,
+    file_body: String,
+    options: &RunOptions,
+    times: &mut PhaseTimes,
+) -> bytecode_file::CompiledScript {
+    if options.dump_tokens {
+        let mut lexer = lexer::Lexer::new(file_body.clone());
+        println!("Tokens:");
+        while let Ok(token) = lexer.next() {
+            println!("{:?}", token);
+        }
+    }
 
-            extract_anony_func::AnonymousFunctionExtractor::new().run_toplevel(&mut node);
-            fv_finder::FreeVariableFinder::new().run_toplevel(&mut node);
-            fv_solver::FreeVariableSolver::new().run_toplevel(&mut node);
+    let hooks = DebugHooks {
+        dump_ast: options.dump_ast,
+        dump_ast_resolved: options.dump_ast_resolved,
+        times: if options.time {
+            Some(Rc::new(RefCell::new(PhaseTimes::new())))
+        } else {
+            None
+        },
+    };
+    let phase_times = hooks.times.clone();
+    let engine = engine_builder(options)
+        .file_name(file_name)
+        .compile_hooks(hooks)
+        .build();
+    let result = engine.compile_script(file_body.as_str());
+    if let Some(phase_times) = phase_times {
+        times.phases.append(&mut phase_times.borrow_mut().phases);
+    }
 
-            let mut vm_codegen = vm_codegen::VMCodeGen::new();
-            let mut insts = vec![];
-            let mut func_addr_in_bytecode_and_its_entity = HashMap::new();
-            vm_codegen.compile(
-                &node,
-                &mut insts,
-                &mut func_addr_in_bytecode_and_its_entity,
-            );
+    match result {
+        Ok(script) => script,
+        Err(e) => {
+            report_compile_error(&e, file_name, file_body.as_str());
+            // The error has been reported, so don't let the panic hook print anything.
+            panic::resume_unwind(Box::new(e))
+        }
+    }
+}
 
-            // bytecode_gen::show(&insts);
+/// Prints the AST and keeps the time each phase took while the engine compiles a script, for
+/// --dump-ast, --dump-ast-resolved and --time.
+struct DebugHooks {
+    dump_ast: bool,
+    dump_ast_resolved: bool,
+    times: Option<Rc<RefCell<PhaseTimes>>>,
+}
 
-            println!("Result:");
+impl CompileHooks for DebugHooks {
+    fn parsed(&self, node: &Node) {
+        if self.dump_ast {
+            println!("AST:\n{:?}", node);
+        }
+    }
 
-            // println!("{:?}", insts);
+    fn resolved(&self, node: &Node) {
+        if self.dump_ast_resolved {
+            println!("Resolved AST:\n{:?}", node);
+        }
+    }
+
+    fn times_phases(&self) -> bool {
+        self.times.is_some()
+    }
 
-            let mut vm = vm::VM::new();
-            vm.const_table = vm_codegen.bytecode_gen.const_table;
-            (*vm.global_objects)
-                .borrow_mut()
-                .extend(vm_codegen.global_varmap);
-            vm.run(insts);
+    fn phase_time(&self, phase: &'static str, time: Duration) {
+        if let Some(ref times) = self.times {
+            times.borrow_mut().add(phase, time);
         }
-        Err(e) => panic!("Rapidus Internal Error: fork failed: {:?}", e),
     }
 }
+
+/// Prints the errors that the engine finds in a script. The VM reports runtime errors itself, and
+/// the panic hook reports internal errors.
+fn report_compile_error(error: &rapidus::Error, file_name: &str, source: &str) {
+    match error {
+        &rapidus::Error::Runtime(_) | &rapidus::Error::Internal(_) => {}
+        error => eprint!("{}", error.render(file_name, source)),
+    }
+}
+
+/// Returns a builder of engines with the options in `options`.
+fn engine_builder(options: &RunOptions) -> EngineBuilder {
+    let mut builder = Engine::builder()
+        .strict(options.strict)
+        .jit(options.jit)
+        .coverage(options.coverage_output.is_some());
+    if let Some(calls) = options.jit_func_threshold {
+        builder = builder.jit_func_threshold(calls);
+    }
+    if let Some(runs) = options.jit_loop_threshold {
+        builder = builder.jit_loop_threshold(runs);
+    }
+    if let Some(depth) = options.stack_size {
+        builder = builder.max_call_depth(depth);
+    }
+    if let Some(values) = options.stack_limit {
+        builder = builder.stack_limit(values);
+    }
+    builder
+}
+
+/// Returns a VM made by `engine`, with $262 if running test262 tests.
+fn create_vm(engine: &Engine, options: &RunOptions) -> vm::VM {
+    let mut vm = engine.create_vm();
+    if options.test262 {
+        test262::define_host_object(&mut vm);
+    }
+    vm
+}
+
+fn execute(
+    mut script: bytecode_file::CompiledScript,
+    options: &RunOptions,
+    times: &mut PhaseTimes,
+) -> vm::VM {
+    if options.dump_bytecode {
+        println!("Bytecode:");
+        bytecode_gen::show(&script.insts);
+    }
+
+    if options.dump_consts {
+        println!("Constants:");
+        bytecode_gen::show_const_table(&script.const_table);
+    }
+
+    if options.show_result_label {
+        println!("Result:");
+    }
+
+    let start = Instant::now();
+    let engine = engine_builder(options).build();
+    let mut vm = create_vm(&engine, options);
+    times.add("vm init", start.elapsed());
+    let scopes = mem::replace(&mut script.scopes, vec![]);
+    let insts = engine.load_script(script, &mut vm);
+    vm.set_argv(options.argv.clone());
+    if options.debug {
+        vm.debugger = Some(debugger::Debugger::new(
+            scopes,
+            vm.source.as_ref().unwrap(),
+        ));
+    }
+    if options.test {
+        vm.set_global("test".to_string(), vm::Value::BuiltinFunction(builtin::TEST));
+        vm.set_global("assert".to_string(), vm::Value::BuiltinFunction(builtin::ASSERT));
+        vm.set_global("expect".to_string(), vm::Value::BuiltinFunction(builtin::EXPECT));
+    }
+    let start = Instant::now();
+    crash_report::watch(&vm);
+    let result = vm.run(insts);
+    crash_report::unwatch();
+    times.add("jit compile", vm.jit.compile_time);
+    times.add("execution", start.elapsed() - vm.jit.compile_time);
+
+    if options.print_result {
+        unsafe { builtin::console_log(vec![result], &mut vm) };
+    }
+
+    if let (Some(output), Some(coverage), Some(info)) =
+        (options.coverage_output, vm.coverage.take(), vm.source.as_ref())
+    {
+        let file_name = info.file_name.as_str();
+        let report = if output.ends_with(".json") {
+            coverage.to_json(file_name, &info.source, &info.source_map)
+        } else {
+            coverage.to_lcov(file_name, &info.source, &info.source_map)
+        };
+        let written = File::create(output).and_then(|mut f| f.write_all(report.as_bytes()));
+        if let Err(e) = written {
+            eprintln!("error: cannot write coverage report: {}", e);
+            // The error has been reported, so don't let the panic hook print anything.
+            panic::resume_unwind(Box::new(vm::FatalError))
+        }
+    }
+
+    vm
+}
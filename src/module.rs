@@ -0,0 +1,64 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+/// The code of a module.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModuleSource {
+    pub name: String, // Shown in error messages, and the referrer of the modules it imports
+    pub source: String,
+}
+
+/// Finds the code of modules, so that embedders can serve modules from memory, archives or the
+/// network instead of the file system.
+///
+/// TODO: Neither import nor require is supported yet. Both should load modules through the
+/// resolver of the VM.
+pub trait ModuleResolver {
+    /// Finds the module `specifier` imported by the module named `referrer`.
+    fn resolve(&self, specifier: &str, referrer: &str) -> Result<ModuleSource, String>;
+}
+
+/// Reads modules from files. Specifiers are paths relative to the directory of the referrer.
+#[derive(Clone, Debug)]
+pub struct FileResolver;
+
+impl ModuleResolver for FileResolver {
+    fn resolve(&self, specifier: &str, referrer: &str) -> Result<ModuleSource, String> {
+        let dir = Path::new(referrer).parent().unwrap_or(Path::new(""));
+        let path = dir.join(specifier);
+        let mut source = String::new();
+        match File::open(&path).and_then(|mut file| file.read_to_string(&mut source)) {
+            Ok(_) => Ok(ModuleSource {
+                name: path.to_string_lossy().into_owned(),
+                source: source,
+            }),
+            Err(e) => Err(format!("cannot load module '{}': {}", specifier, e)),
+        }
+    }
+}
+
+#[test]
+fn resolve_files() {
+    use std::env;
+    use std::fs;
+
+    let dir = env::temp_dir().join(format!("rapidus-module-test-{}", ::std::process::id()));
+    fs::create_dir_all(dir.join("lib")).unwrap();
+    fs::write(dir.join("lib/a.js"), "var a = 1").unwrap();
+
+    let referrer = dir.join("main.js");
+    let module = FileResolver
+        .resolve("./lib/a.js", referrer.to_str().unwrap())
+        .unwrap();
+    assert_eq!(module.source, "var a = 1");
+    assert!(module.name.ends_with("a.js"));
+    assert!(
+        FileResolver
+            .resolve("./b.js", referrer.to_str().unwrap())
+            .unwrap_err()
+            .starts_with("cannot load module './b.js': ")
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+}
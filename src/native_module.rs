@@ -0,0 +1,97 @@
+use vm::{NativeFunction, PropertyMap, Value, VM};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// A set of native functions and objects that a crate installs into VMs, e.g. a binding to a C
+/// library. Modules are added to an Engine with EngineBuilder::native_module, and registered in
+/// every VM the engine creates, in the order they were added.
+pub trait NativeModule {
+    /// The name scripts will import the module by.
+    fn name(&self) -> &str;
+
+    /// Installs the functions and objects of the module.
+    fn register(&self, ctx: &mut Context);
+}
+
+/// What a NativeModule installs its functions and objects with.
+pub struct Context<'a> {
+    vm: &'a mut VM,
+    exports: PropertyMap,
+}
+
+impl<'a> Context<'a> {
+    /// Returns a function value that calls `f`. The VM keeps `f` in its table of builtin
+    /// functions, so registering the same modules in the same order gives every VM the same
+    /// values, and snapshots can be restored into any of them.
+    pub fn function(&mut self, f: NativeFunction) -> Value {
+        self.vm.builtin_functions.push(f);
+        Value::BuiltinFunction(self.vm.builtin_functions.len() - 1)
+    }
+
+    /// Sets the global variable `name` to `val`.
+    pub fn set_global(&mut self, name: &str, val: Value) {
+        self.vm.set_global(name.to_string(), val)
+    }
+
+    /// Adds `val` to the namespace of the module as `name`.
+    pub fn export(&mut self, name: &str, val: Value) {
+        self.exports.insert(name.to_string(), val);
+    }
+
+    pub fn vm(&mut self) -> &mut VM {
+        self.vm
+    }
+}
+
+/// Registers `module` in `vm`. The object made of its exports is kept in vm.native_modules.
+///
+/// TODO: Neither import nor require is supported yet. Both should look up native modules before
+/// asking the module resolver.
+pub fn register(module: &dyn NativeModule, vm: &mut VM) {
+    let exports = {
+        let mut ctx = Context {
+            vm,
+            exports: PropertyMap::new(),
+        };
+        module.register(&mut ctx);
+        ctx.exports
+    };
+    vm.native_modules.insert(
+        module.name().to_string(),
+        Value::Object(Rc::new(RefCell::new(exports))),
+    );
+}
+
+#[test]
+fn register_native_module() {
+    struct Answer;
+
+    unsafe fn answer(_args: Vec<Value>, vm: &mut VM) {
+        vm.state.stack.push(Value::Number(42.0))
+    }
+
+    impl NativeModule for Answer {
+        fn name(&self) -> &str {
+            "answer"
+        }
+
+        fn register(&self, ctx: &mut Context) {
+            let answer = ctx.function(answer);
+            ctx.set_global("answer", answer.clone());
+            ctx.export("answer", answer);
+        }
+    }
+
+    let engine = ::engine::Engine::builder().native_module(Answer).build();
+    assert_eq!(engine.eval("answer() + 1").ok(), Some(Value::Number(43.0)));
+
+    let mut vm = engine.create_vm();
+    let answer = vm.get_global("answer");
+    match vm.native_modules.get("answer") {
+        Some(&Value::Object(ref exports)) => {
+            assert_eq!(exports.borrow().get("answer"), answer.as_ref())
+        }
+        _ => panic!("answer was not registered"),
+    }
+}
@@ -1,8 +1,14 @@
+use error::Span;
+
 use std::boxed::Box;
 use std::collections::HashSet;
 
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
 // TODO: Support all features: https://tc39.github.io/ecma262/#prod-FormalParameter
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct FormalParameter {
     pub name: String,
     pub init: Option<Node>,
@@ -23,12 +29,20 @@ impl FormalParameter {
 
 // TODO: Support all features: https://tc39.github.io/ecma262/#prod-PropertyDefinition
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub enum PropertyDefinition {
     IdentifierReference(String), // Not used in phases after fv_finder. This is replaced with Property(_, _) in fv_finder.
     Property(String, Node),
+    /// `...expr`, which copies the own enumerable properties of `expr`.
+    Spread(Node),
+    /// `get name() {...}`, whose function is called when the property is read.
+    Getter(String, Node),
+    /// `set name(val) {...}`, whose function is called when the property is assigned to.
+    Setter(String, Node),
 }
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct FunctionDeclNode {
     pub name: String,
     pub mangled_name: Option<String>,
@@ -36,40 +50,59 @@ pub struct FunctionDeclNode {
     pub use_this: bool,
     pub params: FormalParameters,
     pub body: Box<Node>,
+    pub span: Span, // The source code of the whole function, which toString() returns
+    pub is_async: bool,
+    pub in_with: bool, // Inside a with statement, whose objects it looks names up in
 }
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub enum NodeBase {
     StatementList(Vec<Node>),
     FunctionDecl(FunctionDeclNode),
-    FunctionExpr(Option<String>, FormalParameters, Box<Node>), // Name, params, body
+    // Name, params, body, source, whether it is async
+    FunctionExpr(Option<String>, FormalParameters, Box<Node>, Span, bool),
     VarDecl(String, Option<Box<Node>>),
+    LexicalDecl(String, Option<Box<Node>>, LexicalKind), // Declared with let or const
     Member(Box<Node>, String),
     Index(Box<Node>, Box<Node>),
     New(Box<Node>),
     Call(Box<Node>, Vec<Node>),
+    TaggedTemplate(Box<Node>, Vec<(Option<String>, String)>, Vec<Node>), // Tag, strings, values
+    // Constructor, Superclass, [(Name, Method, Whether static)]
+    Class(Box<Node>, Option<Box<Node>>, Vec<(String, Node, bool)>),
     If(Box<Node>, Box<Node>, Box<Node>), // Cond, Then, Else
     While(Box<Node>, Box<Node>),         // Cond, Body
+    DoWhile(Box<Node>, Box<Node>),       // Body, Cond
     For(Box<Node>, Box<Node>, Box<Node>, Box<Node>), // Init, Cond, Step, Body
+    ForIn(Box<Node>, Box<Node>, Box<Node>),           // Var, Object, Body
+    ForOf(Box<Node>, Box<Node>, Box<Node>),           // Var, Iterable, Body
+    Switch(Box<Node>, Vec<(Option<Node>, Node)>), // Discriminant, [(Label or default, Body)]
+    Label(String, Box<Node>),
+    With(Box<Node>, Box<Node>), // Object, Body
     Assign(Box<Node>, Box<Node>),
+    CompoundAssign(Box<Node>, Box<Node>, BinOp), // Member or index target, value, op
     UnaryOp(Box<Node>, UnaryOp),
     BinaryOp(Box<Node>, Box<Node>, BinOp),
     TernaryOp(Box<Node>, Box<Node>, Box<Node>),
     Return(Option<Box<Node>>),
-    Break,
-    Continue,
+    Break(Option<String>),
+    Continue(Option<String>),
     Array(Vec<Node>),
     Object(Vec<PropertyDefinition>),
+    Spread(Box<Node>), // `...expr` as an element of an array literal or an argument of a call
     Identifier(String),
     This,
     Arguments,
     String(String),
     Boolean(bool),
+    Null,
     Number(f64),
     Nope,
 }
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct Node {
     pub base: NodeBase,
     pub pos: usize,
@@ -82,9 +115,120 @@ impl Node {
             pos: pos,
         }
     }
+
+    /// Returns the function and variable declarations among the statements `self`, including
+    /// those in blocks and loops, but not those in inner functions. These are what a function
+    /// body declares. Variables declared with let or const are among them, even though they
+    /// belong to their blocks.
+    pub fn hoisted_decls_mut(&mut self) -> Vec<&mut Node> {
+        let mut decls = vec![];
+        collect_hoisted_decls(self, &mut decls);
+        decls
+    }
+
+    /// Returns whether `self` is a literal whose value is known without running anything.
+    pub fn is_literal(&self) -> bool {
+        match self.base {
+            NodeBase::String(_) | NodeBase::Number(_) | NodeBase::Boolean(_) | NodeBase::Null => {
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Turns the variables declared by the program `self` into global variables, so that code
+    /// compiled later can see them, and returns their names. The declarations become assignments
+    /// of their initializers. let and const declared in blocks stay in their blocks.
+    ///
+    /// TODO: let and const at the top level are plain global variables, without a temporal dead
+    /// zone and without protection against assignment.
+    pub fn declare_globals(&mut self) -> Vec<String> {
+        let mut names = vec![];
+        if let NodeBase::StatementList(ref mut nodes) = self.base {
+            for node in nodes {
+                if let NodeBase::LexicalDecl(_, _, _) = node.base {
+                    declare_global(node, &mut names)
+                }
+            }
+        }
+        for decl in self.hoisted_decls_mut() {
+            if let NodeBase::VarDecl(_, _) = decl.base {
+                declare_global(decl, &mut names)
+            }
+        }
+        names
+    }
+}
+
+fn declare_global(decl: &mut Node, names: &mut Vec<String>) {
+    let pos = decl.pos;
+    let (name, init) = match decl.base {
+        // Variables made up by the parser are only used where they are declared.
+        NodeBase::VarDecl(ref name, _) | NodeBase::LexicalDecl(ref name, _, _)
+            if name.starts_with('%') =>
+        {
+            return
+        }
+        NodeBase::VarDecl(ref name, ref mut init) => (name.clone(), init.take()),
+        NodeBase::LexicalDecl(ref name, ref mut init, _) => (
+            name.clone(),
+            Some(init.take().unwrap_or_else(|| {
+                Box::new(Node::new(NodeBase::Identifier("undefined".to_string()), pos))
+            })),
+        ),
+        _ => return,
+    };
+    decl.base = match init {
+        Some(init) => NodeBase::Assign(
+            Box::new(Node::new(NodeBase::Identifier(name.clone()), pos)),
+            init,
+        ),
+        None => NodeBase::Nope,
+    };
+    names.push(name);
+}
+
+fn collect_hoisted_decls<'a>(node: &'a mut Node, decls: &mut Vec<&'a mut Node>) {
+    if let NodeBase::FunctionDecl(_) | NodeBase::VarDecl(_, _) | NodeBase::LexicalDecl(_, _, _) =
+        node.base
+    {
+        decls.push(node);
+        return;
+    }
+    match node.base {
+        NodeBase::StatementList(ref mut nodes) => for node in nodes {
+            collect_hoisted_decls(node, decls)
+        },
+        NodeBase::If(_, ref mut then, ref mut else_) => {
+            collect_hoisted_decls(then, decls);
+            collect_hoisted_decls(else_, decls);
+        }
+        NodeBase::While(_, ref mut body)
+        | NodeBase::DoWhile(ref mut body, _)
+        | NodeBase::Label(_, ref mut body)
+        | NodeBase::With(_, ref mut body) => collect_hoisted_decls(body, decls),
+        NodeBase::Switch(_, ref mut cases) => for &mut (_, ref mut body) in cases {
+            collect_hoisted_decls(body, decls)
+        },
+        NodeBase::For(ref mut init, _, _, ref mut body)
+        | NodeBase::ForIn(ref mut init, _, ref mut body)
+        | NodeBase::ForOf(ref mut init, _, ref mut body) => {
+            collect_hoisted_decls(init, decls);
+            collect_hoisted_decls(body, decls);
+        }
+        _ => {}
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum LexicalKind {
+    Let,
+    Const,
 }
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub enum UnaryOp {
     Delete,
     Void,
@@ -97,9 +241,11 @@ pub enum UnaryOp {
     PrDec,
     PoInc, // Postfix
     PoDec,
+    Await,
 }
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub enum BinOp {
     Add,
     Sub,
@@ -120,6 +266,8 @@ pub enum BinOp {
     Gt,
     Le,
     Ge,
+    In,
+    InstanceOf,
     Shl,
     Shr,
     ZFShr,
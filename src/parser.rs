@@ -1,14 +1,16 @@
+use diagnostic::Diagnostic;
+use error;
+use error::{LexError, ParseError, Span};
 use lexer;
-use lexer::ErrorMsgKind;
+use lexer::{count_line_terminators, is_line_terminator, line_terminator_len, ErrorMsgKind};
 use node::{
-    BinOp, FormalParameter, FormalParameters, FunctionDeclNode, Node, NodeBase, PropertyDefinition,
-    UnaryOp,
+    BinOp, FormalParameter, FormalParameters, FunctionDeclNode, LexicalKind, Node, NodeBase,
+    PropertyDefinition, UnaryOp,
 };
 use std::collections::HashSet;
+use std::panic;
 use token::{Keyword, Kind, Symbol};
 
-use ansi_term::Colour;
-
 macro_rules! token_start_pos {
     ($var:ident, $lexer:expr) => {
         let $var = $lexer.pos;
@@ -26,51 +28,402 @@ pub enum Error {
 #[derive(Clone, Debug)]
 pub struct Parser {
     pub lexer: lexer::Lexer,
+    pub file_name: String,         // Shown in error messages
+    pub strict: bool,              // Whether the code being read is strict mode code
+    pub recover: bool,             // Keep reading after a syntax error, to report all of them
+    pub errors: Vec<error::Error>, // Syntax errors recovered from
+    pub warnings: Vec<Diagnostic>, // Code that is valid but probably a mistake
+    in_arrow_function: bool,       // Whether the code being read is the body of an arrow function
+    in_async_function: bool,       // Whether the code being read is the body of an async function
+    arrow_this: bool,              // Whether arrow functions use `this` of the enclosing function
+    arrow_arguments: bool,         // Whether arrow functions use `arguments` of it
+    num_pattern_vars: usize,       // Variables made to hold the values of destructuring patterns
+    no_in: bool,                   // Whether `in` ends expressions, as in for statement heads
+    lexical_kind: Option<LexicalKind>, // Whether the declarations being read use let or const
+    home_object: Option<(String, MethodKind)>, // The class of the method being read, for super
+    uses_super: bool,              // Whether the methods of the class being read use super
+    num_class_vars: usize,         // Variables made to hold classes whose methods use super
+    class_vars: Vec<String>,       // Those of them declared in the function being read
+    depth: usize,                  // How many statements, expressions and functions enclose
+}
+
+/// How deeply statements, expressions and functions may nest. Reading code, and every pass over
+/// the AST after that, recurses on the Rust stack once for each level, and deeper code would
+/// overflow the 8 MiB stack of a main thread in release builds.
+const MAX_NESTING_DEPTH: usize = 500;
+
+/// What kind of method of a class is being read, which decides what `super` refers to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MethodKind {
+    Method,
+    StaticMethod,
+    Constructor,
+    DerivedConstructor,
 }
 
 impl Parser {
     pub fn new(code: String) -> Parser {
         Parser {
             lexer: lexer::Lexer::new(code),
+            file_name: "[unknown]".to_string(),
+            strict: false,
+            recover: false,
+            errors: vec![],
+            warnings: vec![],
+            in_arrow_function: false,
+            in_async_function: false,
+            arrow_this: false,
+            arrow_arguments: false,
+            num_pattern_vars: 0,
+            no_in: false,
+            lexical_kind: None,
+            home_object: None,
+            uses_super: false,
+            num_class_vars: 0,
+            class_vars: vec![],
+            depth: 0,
         }
     }
 
-    fn show_error_at(&self, pos: usize, kind: ErrorMsgKind, msg: &str) -> ! {
-        let (source_at_err_point, pos) = self.lexer.get_code_around_err_point(pos, kind);
-        println!(
-            "{}({}): {}\n{}",
-            Colour::Red.bold().paint("error"),
-            self.lexer
-                .pos_line_list
-                .iter()
-                .find(|(p, _)| *p == pos)
-                .unwrap()
-                .1,
-            msg,
-            source_at_err_point,
-        );
-        panic!()
+    /// Stops parsing because of a syntax error. parse() catches the ParseError.
+    fn show_error_at(&mut self, pos: usize, kind: ErrorMsgKind, msg: &str) -> ! {
+        let (_, pos) = self.lexer.get_code_around_err_point(pos, kind);
+        panic::resume_unwind(Box::new(ParseError {
+            message: msg.to_string(),
+            span: self.span_at(pos),
+        }))
+    }
+
+    /// Reads with `read` one level deeper into the code, which fails if the code nests too deeply.
+    fn nested<T, F>(&mut self, read: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut Parser) -> Result<T, Error>,
+    {
+        if self.depth == MAX_NESTING_DEPTH {
+            let pos = self.lexer.pos;
+            self.show_error_at(pos, ErrorMsgKind::Normal, "too deeply nested")
+        }
+        self.depth += 1;
+        let result = read(self);
+        self.depth -= 1;
+        result
+    }
+
+    /// Reads the next token, which must be `symbol`.
+    fn expect(&mut self, symbol: Symbol, msg: &str) -> Result<(), Error> {
+        let tok = self.lexer.next()?;
+        if tok.kind != Kind::Symbol(symbol) {
+            self.show_error_at(tok.pos, ErrorMsgKind::LastToken, msg);
+        }
+        Ok(())
+    }
+
+    /// Returns the span of the token at `pos`.
+    fn span_at(&self, pos: usize) -> Span {
+        let len = Diagnostic::new("", self.lexer.code.as_str(), pos).len;
+        Span::new(pos, pos + len)
     }
 }
 
 impl Parser {
+    /// Parses the whole program.
+    pub fn parse(&mut self) -> Result<Node, error::Error> {
+        match panic::catch_unwind(panic::AssertUnwindSafe(|| self.read_script())) {
+            Ok(Ok(node)) => Ok(node),
+            Ok(Err(Error::UnexpectedToken(pos))) => Err(error::Error::Lex(LexError {
+                message: "invalid or unexpected token".to_string(),
+                span: self.span_at(pos),
+            })),
+            Ok(Err(Error::UnsupportedFeature(pos))) => Err(error::Error::Parse(ParseError {
+                message: "unsupported feature".to_string(),
+                span: self.span_at(pos),
+            })),
+            Ok(Err(_)) => {
+                let pos = self.lexer.pos_line_list.last().map(|&(pos, _)| pos).unwrap_or(0);
+                Err(error::Error::Parse(ParseError {
+                    message: "reach unexpected EOF".to_string(),
+                    span: self.span_at(pos),
+                }))
+            }
+            Err(payload) => match payload.downcast::<ParseError>() {
+                Ok(e) => Err(error::Error::Parse(*e)),
+                Err(payload) => panic::resume_unwind(payload),
+            },
+        }
+    }
+
+    /// Parses the whole program. A syntax error is reported on stderr, and then raised as an
+    /// error::Error panic.
     pub fn parse_all(&mut self) -> Node {
-        match self.read_script() {
-            Ok(ok) => ok,
-            Err(Error::NormalEOF) => unreachable!(),
-            // TODO: Show an appropriate error message depending on the kind of _e.
-            Err(_e) => self.show_error_at(
-                self.lexer.pos_line_list.last().unwrap().0,
-                ErrorMsgKind::Normal,
-                "reach unexpected EOF",
-            ),
+        match self.parse() {
+            Ok(node) => node,
+            Err(e) => {
+                eprint!(
+                    "{}",
+                    e.render(self.file_name.as_str(), self.lexer.code.as_str())
+                );
+                // The error has been reported, so don't let the panic hook print anything.
+                panic::resume_unwind(Box::new(e))
+            }
         }
     }
 }
 
 impl Parser {
     fn read_script(&mut self) -> Result<Node, Error> {
-        self.read_statement_list(false)
+        token_start_pos!(pos, self.lexer);
+        if self.starts_with_use_strict() {
+            self.strict = true;
+        }
+        let mut script = self.read_statement_list(false)?;
+        script.pos = pos;
+        let mut decls = self.arrow_this_and_arguments(pos);
+        decls.append(&mut self.class_var_decls(pos));
+        prepend_statements(&mut script, decls);
+        Ok(script)
+    }
+
+    /// Reads the parameters and the body of a function that is not an arrow function, after its
+    /// name. Returns them with the statements that run first prepended to the body. `home_object`
+    /// is the class of a method, and None for other functions.
+    fn read_function_params_and_body(
+        &mut self,
+        name: Option<&String>,
+        home_object: Option<(String, MethodKind)>,
+        is_async: bool,
+        pos: usize,
+    ) -> Result<(FormalParameters, Node), Error> {
+        self.nested(|parser| {
+            parser.read_function_params_and_body_inner(name, home_object, is_async, pos)
+        })
+    }
+
+    fn read_function_params_and_body_inner(
+        &mut self,
+        name: Option<&String>,
+        home_object: Option<(String, MethodKind)>,
+        is_async: bool,
+        pos: usize,
+    ) -> Result<(FormalParameters, Node), Error> {
+        let outer_this = ::std::mem::replace(&mut self.arrow_this, false);
+        let outer_arguments = ::std::mem::replace(&mut self.arrow_arguments, false);
+        let outer_home_object = ::std::mem::replace(&mut self.home_object, home_object);
+        let outer_class_vars = ::std::mem::replace(&mut self.class_vars, vec![]);
+
+        self.expect(Symbol::OpeningParen, "expect '('")?;
+        let (params, mut prologue) = self.read_formal_parameters()?;
+
+        self.expect(Symbol::OpeningBrace, "expect '{'")?;
+        let (mut body, strict) = self.read_function_body(false, is_async)?;
+        self.check_function(name, &params, strict, pos);
+        let mut decls = self.arrow_this_and_arguments(pos);
+        decls.append(&mut self.class_var_decls(pos));
+        prologue.splice(0..0, decls);
+        prepend_statements(&mut body, prologue);
+
+        self.arrow_this = outer_this;
+        self.arrow_arguments = outer_arguments;
+        self.home_object = outer_home_object;
+        self.class_vars = outer_class_vars;
+        Ok((params, body))
+    }
+
+    /// Returns the declarations of the variables that hold the classes defined in the function
+    /// just read whose methods use super. The methods capture them to find the superclass.
+    fn class_var_decls(&mut self, pos: usize) -> Vec<Node> {
+        self.class_vars
+            .drain(..)
+            .map(|name| Node::new(NodeBase::VarDecl(name, None), pos))
+            .collect()
+    }
+
+    /// Returns the declarations of the variables through which the arrow functions in the
+    /// function just read use its `this` and `arguments`. Arrow functions do not have their own,
+    /// so they capture these variables like any other variable of the enclosing function.
+    /// `arguments` is copied into an array, since it only refers to the arguments of the function
+    /// being run.
+    fn arrow_this_and_arguments(&mut self, pos: usize) -> Vec<Node> {
+        let mut decls = vec![];
+        if ::std::mem::replace(&mut self.arrow_this, false) {
+            decls.push(Node::new(
+                NodeBase::VarDecl(
+                    ARROW_THIS.to_string(),
+                    Some(Box::new(Node::new(NodeBase::This, pos))),
+                ),
+                pos,
+            ));
+        }
+        if ::std::mem::replace(&mut self.arrow_arguments, false) {
+            decls.push(Node::new(
+                NodeBase::VarDecl(
+                    ARROW_ARGUMENTS.to_string(),
+                    Some(Box::new(Node::new(
+                        NodeBase::Array(vec![Node::new(
+                            NodeBase::Spread(Box::new(Node::new(NodeBase::Arguments, pos))),
+                            pos,
+                        )]),
+                        pos,
+                    ))),
+                ),
+                pos,
+            ));
+        }
+        decls
+    }
+
+    /// Reads the body of a function, which is strict mode code if it begins with "use strict".
+    /// Returns the body and whether it is strict.
+    fn read_function_body(&mut self, arrow: bool, is_async: bool) -> Result<(Node, bool), Error> {
+        token_start_pos!(pos, self.lexer);
+        let outer_strict = self.strict;
+        let outer_arrow = ::std::mem::replace(&mut self.in_arrow_function, arrow);
+        let outer_async = ::std::mem::replace(&mut self.in_async_function, is_async);
+        let outer_no_in = ::std::mem::replace(&mut self.no_in, false);
+        if self.starts_with_use_strict() {
+            self.strict = true;
+        }
+        let mut body = self.read_statement_list(true)?;
+        body.pos = pos;
+        let strict = self.strict;
+        self.strict = outer_strict;
+        self.in_arrow_function = outer_arrow;
+        self.in_async_function = outer_async;
+        self.no_in = outer_no_in;
+        Ok((body, strict))
+    }
+}
+
+impl Parser {
+    /// Returns the position next to the tokens read so far. Tokens looked ahead at are not
+    /// counted, but the whitespace and comments before them are.
+    fn read_pos(&self) -> usize {
+        self.lexer.buf.front().map_or(self.lexer.pos, |tok| tok.pos)
+    }
+
+    /// Runs `f` to look at the tokens ahead, and goes back to where we were so that node
+    /// positions don't change.
+    fn look_ahead<T, F: FnOnce(&mut lexer::Lexer) -> T>(&mut self, f: F) -> T {
+        let (pos, line, buf, templates, pos_line_list_len) = (
+            self.lexer.pos,
+            self.lexer.line,
+            self.lexer.buf.clone(),
+            self.lexer.templates.clone(),
+            self.lexer.pos_line_list.len(),
+        );
+        let result = f(&mut self.lexer);
+        self.lexer.pos = pos;
+        self.lexer.line = line;
+        self.lexer.buf = buf;
+        self.lexer.templates = templates;
+        self.lexer.pos_line_list.truncate(pos_line_list_len);
+        result
+    }
+
+    /// Returns true if the statements about to be read begin with a "use strict" directive.
+    fn starts_with_use_strict(&mut self) -> bool {
+        let (tok, next) = self.look_ahead(|lexer| (lexer.next(), lexer.read_token()));
+
+        let tok = match tok {
+            Ok(tok) => tok,
+            Err(_) => return false,
+        };
+        let ends_statement = match next {
+            Ok(ref next) => match next.kind {
+                Kind::Symbol(Symbol::Semicolon)
+                | Kind::Symbol(Symbol::ClosingBrace)
+                | Kind::LineTerminator => true,
+                _ => false,
+            },
+            Err(_) => true,
+        };
+        // The directive must be written without escapes, and be a statement by itself.
+        match tok.kind {
+            Kind::String(ref s) if s == "use strict" => {
+                ends_statement && self.lexer.code[tok.pos + 1..].starts_with("use strict")
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns true if the template token at `pos` is the first part of its template, not the
+    /// part after a substitution.
+    fn begins_template(&self, pos: usize) -> bool {
+        self.lexer.code[pos..].starts_with('`')
+    }
+
+    /// Returns true if the token at `pos` is the first one on its line.
+    fn starts_line(&self, pos: usize) -> bool {
+        self.lexer.code[..pos]
+            .trim_end_matches(|c: char| c.is_whitespace() && !is_line_terminator(c))
+            .ends_with(is_line_terminator)
+    }
+
+    /// Reports identifiers that strict mode code cannot use.
+    fn check_identifier_reference(&mut self, name: &str, pos: usize) {
+        if self.strict && is_strict_reserved_word(name) {
+            self.show_error_at(
+                pos,
+                ErrorMsgKind::Normal,
+                format!("'{}' is a reserved word in strict mode", name).as_str(),
+            )
+        }
+    }
+
+    /// Reports names that strict mode code cannot declare or assign to.
+    fn check_binding_identifier(&mut self, name: &str, pos: usize) {
+        self.check_identifier_reference(name, pos);
+        if name == "let" && self.lexical_kind.is_some() {
+            self.show_error_at(
+                pos,
+                ErrorMsgKind::Normal,
+                "let is disallowed as a lexically bound name",
+            )
+        }
+        if self.strict && (name == "eval" || name == "arguments") {
+            self.show_error_at(
+                pos,
+                ErrorMsgKind::Normal,
+                format!("cannot declare or assign to '{}' in strict mode", name).as_str(),
+            )
+        }
+    }
+
+    /// Checks the name and the parameters of a function, which are restricted if its body is
+    /// strict.
+    fn check_function(
+        &mut self,
+        name: Option<&String>,
+        params: &FormalParameters,
+        strict: bool,
+        pos: usize,
+    ) {
+        if !strict {
+            return;
+        }
+        let outer_strict = ::std::mem::replace(&mut self.strict, true);
+        if let Some(name) = name {
+            self.check_binding_identifier(name, pos);
+        }
+        let mut names = HashSet::new();
+        for param in params {
+            self.check_binding_identifier(param.name.as_str(), pos);
+            if !names.insert(param.name.as_str()) {
+                self.show_error_at(
+                    pos,
+                    ErrorMsgKind::Normal,
+                    format!("duplicate parameter '{}' in strict mode", param.name).as_str(),
+                )
+            }
+        }
+        self.strict = outer_strict;
+    }
+}
+
+fn is_strict_reserved_word(name: &str) -> bool {
+    match name {
+        "implements" | "interface" | "let" | "package" | "private" | "protected" | "public"
+        | "static" | "yield" => true,
+        _ => false,
     }
 }
 
@@ -94,8 +447,28 @@ impl Parser {
                 }
             }
 
-            match self.read_statement_list_item() {
-                Ok(ok) => items.push(ok),
+            let item = if self.recover {
+                let depth = self.depth;
+                match panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                    self.read_statement_list_item_into(&mut items)
+                })) {
+                    Ok(item) => item,
+                    Err(payload) => match payload.downcast::<ParseError>() {
+                        Ok(e) => {
+                            self.depth = depth;
+                            self.skip_to_next_line(e.span.start);
+                            self.errors.push(error::Error::Parse(*e));
+                            continue;
+                        }
+                        Err(payload) => panic::resume_unwind(payload),
+                    },
+                }
+            } else {
+                self.read_statement_list_item_into(&mut items)
+            };
+
+            match item {
+                Ok(()) => {}
                 Err(Error::NormalEOF) => return Err(Error::UnexpectedEOF),
                 Err(e) => return Err(e),
             }
@@ -106,6 +479,38 @@ impl Parser {
         Ok(Node::new(NodeBase::StatementList(items), pos))
     }
 
+    /// Skips the rest of the line where a syntax error was found, so that reading can go on from
+    /// the next line.
+    fn skip_to_next_line(&mut self, error_pos: usize) {
+        self.lexer.buf.clear();
+
+        let next_line = match self.lexer.code[error_pos..].find(is_line_terminator) {
+            Some(i) => error_pos + i + line_terminator_len(&self.lexer.code[error_pos + i..]),
+            None => self.lexer.code.len(),
+        };
+        if next_line > self.lexer.pos {
+            self.lexer.line += count_line_terminators(&self.lexer.code[self.lexer.pos..next_line]);
+        } else {
+            self.lexer.line -= count_line_terminators(&self.lexer.code[next_line..self.lexer.pos]);
+        }
+        self.lexer.pos = next_line;
+    }
+
+    /// Reads a statement or a declaration into `items`. The variables of a let or const
+    /// declaration are put in one by one, so that they are found among the statements of the
+    /// block they belong to.
+    fn read_statement_list_item_into(&mut self, items: &mut Vec<Node>) -> Result<(), Error> {
+        if self.is_lexical_declaration() {
+            let kind = self.read_lexical_kind()?;
+            let list =
+                self.with_lexical_kind(Some(kind), Parser::read_variable_declaration_list)?;
+            flatten_statements(list, items);
+        } else {
+            items.push(self.read_statement_list_item()?);
+        }
+        Ok(())
+    }
+
     fn read_statement_list_item(&mut self) -> Result<Node, Error> {
         if self.is_declaration() {
             self.read_declaration()
@@ -115,22 +520,48 @@ impl Parser {
     }
 
     fn read_statement(&mut self) -> Result<Node, Error> {
+        self.nested(Parser::read_statement_inner)
+    }
+
+    fn read_statement_inner(&mut self) -> Result<Node, Error> {
         let tok = self.lexer.next()?;
         match tok.kind {
             Kind::Keyword(Keyword::If) => self.read_if_statement(),
             Kind::Keyword(Keyword::Var) => self.read_variable_statement(),
             Kind::Keyword(Keyword::While) => self.read_while_statement(),
+            Kind::Keyword(Keyword::With) => self.read_with_statement(tok.pos),
+            Kind::Keyword(Keyword::Do) => self.read_do_while_statement(),
             Kind::Keyword(Keyword::For) => self.read_for_statement(),
+            Kind::Keyword(Keyword::Switch) => self.read_switch_statement(),
             Kind::Keyword(Keyword::Return) => self.read_return_statement(),
             Kind::Keyword(Keyword::Break) => self.read_break_statement(),
             Kind::Keyword(Keyword::Continue) => self.read_continue_statement(),
             Kind::Symbol(Symbol::OpeningBrace) => self.read_block_statement(),
+            Kind::Symbol(Symbol::Semicolon) => Ok(Node::new(NodeBase::Nope, tok.pos)),
+            Kind::Identifier(ref name) if self.is_followed_by_colon() => {
+                self.read_labelled_statement(name.clone(), tok.pos)
+            }
             _ => {
                 self.lexer.unget(&tok);
                 self.read_expression_statement()
             }
         }
     }
+
+    fn is_followed_by_colon(&mut self) -> bool {
+        match self.look_ahead(|lexer| lexer.next()) {
+            Ok(tok) => tok.kind == Kind::Symbol(Symbol::Colon),
+            Err(_) => false,
+        }
+    }
+
+    /// https://tc39.github.io/ecma262/#prod-LabelledStatement
+    /// Reads a labelled statement whose label at `pos` has been read.
+    fn read_labelled_statement(&mut self, label: String, pos: usize) -> Result<Node, Error> {
+        self.expect(Symbol::Colon, "expect ':'")?;
+        let body = self.read_statement()?;
+        Ok(Node::new(NodeBase::Label(label, Box::new(body)), pos))
+    }
 }
 
 impl Parser {
@@ -164,27 +595,328 @@ impl Parser {
     /// https://tc39.github.io/ecma262/#prod-VariableDeclaration
     fn read_variable_declaration(&mut self) -> Result<Node, Error> {
         token_start_pos!(pos, self.lexer);
-        let name = match self.lexer.next()?.kind {
+        let tok = self.lexer.next()?;
+        let name = match tok.kind {
             Kind::Identifier(name) => name,
-            _ => unimplemented!(),
+            Kind::Symbol(Symbol::OpeningBoxBracket) | Kind::Symbol(Symbol::OpeningBrace) => {
+                self.lexer.unget(&tok);
+                return self.read_pattern_declaration();
+            }
+            _ => self.show_error_at(pos, ErrorMsgKind::Normal, "expect a variable name"),
         };
+        self.check_binding_identifier(name.as_str(), pos);
 
         if self.lexer.skip(Kind::Symbol(Symbol::Assign)) {
-            Ok(Node::new(
-                NodeBase::VarDecl(name, Some(Box::new(self.read_initializer()?))),
-                pos,
-            ))
+            let init = self.read_initializer()?;
+            Ok(Node::new(self.binding_decl(name, Some(Box::new(init))), pos))
         } else {
-            Ok(Node::new(NodeBase::VarDecl(name, None), pos))
+            if self.lexical_kind == Some(LexicalKind::Const) {
+                self.show_error_at(
+                    pos,
+                    ErrorMsgKind::Normal,
+                    "missing initializer in const declaration",
+                )
+            }
+            Ok(Node::new(self.binding_decl(name, None), pos))
         }
     }
 
     /// https://tc39.github.io/ecma262/#prod-Initializer
     fn read_initializer(&mut self) -> Result<Node, Error> {
-        self.read_assignment_expression()
+        // Functions in the initializer declare their own variables.
+        let outer_lexical_kind = self.lexical_kind.take();
+        let init = self.read_assignment_expression();
+        self.lexical_kind = outer_lexical_kind;
+        init
+    }
+
+    /// Returns the declaration of `name`, made with let or const if the declarations being read
+    /// are. The variables made up to hold the values of patterns are always declared with var.
+    fn binding_decl(&self, name: String, init: Option<Box<Node>>) -> NodeBase {
+        match self.lexical_kind {
+            Some(kind) if !name.starts_with('%') => NodeBase::LexicalDecl(name, init, kind),
+            _ => NodeBase::VarDecl(name, init),
+        }
+    }
+
+    /// Reads a variable declaration whose target is a destructuring pattern, like
+    /// `[a, {b}] = val`.
+    fn read_pattern_declaration(&mut self) -> Result<Node, Error> {
+        let tok = self.lexer.next()?;
+        self.lexer.unget(&tok);
+        let var = self.new_pattern_var();
+        let mut decls = vec![];
+        self.read_binding_pattern(var.as_str(), &mut decls)?;
+        self.read_pattern_initializer(var, decls, tok.pos)
+    }
+
+    /// Reads the initializer of a destructuring declaration, whose pattern at `pos` has been read
+    /// into `decls`, which bind its variables to the parts of the value of `var`.
+    fn read_pattern_initializer(
+        &mut self,
+        var: String,
+        mut decls: Vec<Node>,
+        pos: usize,
+    ) -> Result<Node, Error> {
+        if !self.lexer.skip(Kind::Symbol(Symbol::Assign)) {
+            self.show_error_at(
+                pos,
+                ErrorMsgKind::Normal,
+                "missing initializer in destructuring declaration",
+            )
+        }
+        let init = self.read_initializer()?;
+        decls.insert(
+            0,
+            Node::new(NodeBase::VarDecl(var, Some(Box::new(init))), pos),
+        );
+        Ok(Node::new(NodeBase::StatementList(decls), pos))
     }
 }
 
+impl Parser {
+    /// Returns the name of a new variable to hold a value being destructured or iterated. The name
+    /// cannot be written in source code, so it never clashes with the names of other variables.
+    fn new_pattern_var(&mut self) -> String {
+        self.num_pattern_vars += 1;
+        format!("%pattern{}", self.num_pattern_vars - 1)
+    }
+
+    /// https://tc39.github.io/ecma262/#prod-BindingPattern
+    /// Reads a destructuring pattern, and appends to `decls` the declarations of the variables it
+    /// binds to the parts of the value of the variable `src`. Patterns are turned into plain
+    /// declarations here, so the phases after parsing never see them.
+    fn read_binding_pattern(&mut self, src: &str, decls: &mut Vec<Node>) -> Result<(), Error> {
+        let tok = self.lexer.next()?;
+        match tok.kind {
+            Kind::Symbol(Symbol::OpeningBoxBracket) => self.read_array_binding_pattern(src, decls),
+            Kind::Symbol(Symbol::OpeningBrace) => self.read_object_binding_pattern(src, decls),
+            _ => self.show_error_at(tok.pos, ErrorMsgKind::Normal, "expect '[' or '{'"),
+        }
+    }
+
+    /// https://tc39.github.io/ecma262/#prod-ArrayBindingPattern
+    fn read_array_binding_pattern(
+        &mut self,
+        src: &str,
+        decls: &mut Vec<Node>,
+    ) -> Result<(), Error> {
+        let mut index = 0;
+        loop {
+            let tok = self.lexer.next()?;
+            match tok.kind {
+                Kind::Symbol(Symbol::ClosingBoxBracket) => break,
+                // An elision skips an element.
+                Kind::Symbol(Symbol::Comma) => {
+                    index += 1;
+                    continue;
+                }
+                // The rest element is an array of the elements left.
+                Kind::Symbol(Symbol::Rest) => {
+                    let array = Box::new(identifier_node(src, tok.pos));
+                    let slice = Node::new(NodeBase::Member(array, "slice".to_string()), tok.pos);
+                    let index = Node::new(NodeBase::Number(index as f64), tok.pos);
+                    let rest = Node::new(NodeBase::Call(Box::new(slice), vec![index]), tok.pos);
+                    self.read_binding_element(rest, false, decls)?;
+                    self.expect(Symbol::ClosingBoxBracket, "expect ']' after a rest element")?;
+                    break;
+                }
+                _ => self.lexer.unget(&tok),
+            }
+
+            let elem = Node::new(
+                NodeBase::Index(
+                    Box::new(identifier_node(src, tok.pos)),
+                    Box::new(Node::new(NodeBase::Number(index as f64), tok.pos)),
+                ),
+                tok.pos,
+            );
+            self.read_binding_element(elem, true, decls)?;
+            index += 1;
+
+            if !self.lexer.skip(Kind::Symbol(Symbol::Comma)) {
+                self.expect(Symbol::ClosingBoxBracket, "expect ',' or ']'")?;
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// https://tc39.github.io/ecma262/#prod-ObjectBindingPattern
+    fn read_object_binding_pattern(
+        &mut self,
+        src: &str,
+        decls: &mut Vec<Node>,
+    ) -> Result<(), Error> {
+        loop {
+            let tok = self.lexer.next()?;
+            let key = match tok.kind {
+                Kind::Symbol(Symbol::ClosingBrace) => break,
+                Kind::Identifier(ref name) => name.clone(),
+                Kind::String(ref s) => s.clone(),
+                Kind::Number(n) => format!("{}", n),
+                // TODO: Support rest properties.
+                Kind::Symbol(Symbol::Rest) => return Err(Error::UnsupportedFeature(tok.pos)),
+                _ => self.show_error_at(tok.pos, ErrorMsgKind::Normal, "expect a property name"),
+            };
+            let prop = Node::new(
+                NodeBase::Member(Box::new(identifier_node(src, tok.pos)), key.clone()),
+                tok.pos,
+            );
+
+            if self.lexer.skip(Kind::Symbol(Symbol::Colon)) {
+                self.read_binding_element(prop, true, decls)?;
+            } else if let Kind::Identifier(_) = tok.kind {
+                // A shorthand property, like `{a}` or `{a = 1}`.
+                self.check_binding_identifier(key.as_str(), tok.pos);
+                self.read_binding_initializer(key, prop, tok.pos, decls)?;
+            } else {
+                self.show_error_at(tok.pos, ErrorMsgKind::LastToken, "expect ':'")
+            }
+
+            if !self.lexer.skip(Kind::Symbol(Symbol::Comma)) {
+                self.expect(Symbol::ClosingBrace, "expect ',' or '}'")?;
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// https://tc39.github.io/ecma262/#prod-BindingElement
+    /// Reads a variable name or a nested pattern to bind `val` to, followed by a default value if
+    /// `initializer` allows one.
+    fn read_binding_element(
+        &mut self,
+        val: Node,
+        initializer: bool,
+        decls: &mut Vec<Node>,
+    ) -> Result<(), Error> {
+        let tok = self.lexer.next()?;
+        let (name, pattern_decls) = match tok.kind {
+            Kind::Identifier(name) => {
+                self.check_binding_identifier(name.as_str(), tok.pos);
+                (name, vec![])
+            }
+            Kind::Symbol(Symbol::OpeningBoxBracket) | Kind::Symbol(Symbol::OpeningBrace) => {
+                self.lexer.unget(&tok);
+                let var = self.new_pattern_var();
+                let mut pattern_decls = vec![];
+                self.read_binding_pattern(var.as_str(), &mut pattern_decls)?;
+                (var, pattern_decls)
+            }
+            _ => self.show_error_at(tok.pos, ErrorMsgKind::Normal, "expect a variable name"),
+        };
+        if initializer {
+            self.read_binding_initializer(name, val, tok.pos, decls)?;
+        } else {
+            decls.push(Node::new(self.binding_decl(name, Some(Box::new(val))), tok.pos));
+        }
+        decls.extend(pattern_decls);
+        Ok(())
+    }
+
+    /// Appends to `decls` the declaration of `name` with the value `val`, or with the default
+    /// value after '=' if there is one and `val` is undefined.
+    fn read_binding_initializer(
+        &mut self,
+        name: String,
+        val: Node,
+        pos: usize,
+        decls: &mut Vec<Node>,
+    ) -> Result<(), Error> {
+        if !self.lexer.skip(Kind::Symbol(Symbol::Assign)) {
+            decls.push(Node::new(self.binding_decl(name, Some(Box::new(val))), pos));
+            return Ok(());
+        }
+        let default = self.read_initializer()?;
+        if self.lexical_kind.is_none() {
+            decls.push(Node::new(
+                NodeBase::VarDecl(name.clone(), Some(Box::new(val))),
+                pos,
+            ));
+            decls.push(assign_if_undefined(name.as_str(), default, pos));
+            return Ok(());
+        }
+        // A variable declared with const cannot be given its default after the declaration, so
+        // the value is chosen before.
+        let var = self.new_pattern_var();
+        decls.push(Node::new(NodeBase::VarDecl(var.clone(), Some(Box::new(val))), pos));
+        let val = NodeBase::TernaryOp(
+            Box::new(is_undefined(var.as_str(), pos)),
+            Box::new(default),
+            Box::new(identifier_node(var.as_str(), pos)),
+        );
+        decls.push(Node::new(self.binding_decl(name, Some(Box::new(Node::new(val, pos)))), pos));
+        Ok(())
+    }
+}
+
+/// Appends the statements of `node` to `items`, taking those of nested statement lists out.
+fn flatten_statements(node: Node, items: &mut Vec<Node>) {
+    match node.base {
+        NodeBase::StatementList(nodes) => for node in nodes {
+            flatten_statements(node, items)
+        },
+        _ => items.push(node),
+    }
+}
+
+// The variables that hold `this` and `arguments` of a function for its arrow functions.
+const ARROW_THIS: &'static str = "%this";
+const ARROW_ARGUMENTS: &'static str = "%arguments";
+
+/// Puts `statements` at the start of the function body `body`.
+fn prepend_statements(body: &mut Node, statements: Vec<Node>) {
+    if let NodeBase::StatementList(ref mut body) = body.base {
+        body.splice(0..0, statements);
+    }
+}
+
+// The rest parameter of the constructor a derived class gets if it does not define one.
+const DEFAULT_CONSTRUCTOR_ARGS: &'static str = "%args";
+
+/// Returns `class_var.__proto__.call(this, ...args)`, which calls the superclass of the class
+/// held in the variable `class_var`.
+fn super_call(class_var: &str, this: Node, mut args: Vec<Node>, pos: usize) -> Node {
+    let class = Box::new(identifier_node(class_var, pos));
+    let superclass = Node::new(NodeBase::Member(class, "__proto__".to_string()), pos);
+    let callee = Node::new(NodeBase::Member(Box::new(superclass), "call".to_string()), pos);
+    args.insert(0, this);
+    Node::new(NodeBase::Call(Box::new(callee), args), pos)
+}
+
+/// Returns an identifier node that refers to `name`.
+fn identifier_node(name: &str, pos: usize) -> Node {
+    Node::new(NodeBase::Identifier(name.to_string()), pos)
+}
+
+/// Returns `name === undefined`.
+fn is_undefined(name: &str, pos: usize) -> Node {
+    Node::new(
+        NodeBase::BinaryOp(
+            Box::new(identifier_node(name, pos)),
+            Box::new(identifier_node("undefined", pos)),
+            BinOp::SEq,
+        ),
+        pos,
+    )
+}
+
+/// Returns `if (name === undefined) name = val`, which gives a variable its default value.
+fn assign_if_undefined(name: &str, val: Node, pos: usize) -> Node {
+    Node::new(
+        NodeBase::If(
+            Box::new(is_undefined(name, pos)),
+            Box::new(Node::new(
+                NodeBase::Assign(Box::new(identifier_node(name, pos)), Box::new(val)),
+                pos,
+            )),
+            Box::new(Node::new(NodeBase::Nope, pos)),
+        ),
+        pos,
+    )
+}
+
 impl Parser {
     fn read_if_statement(&mut self) -> Result<Node, Error> {
         token_start_pos!(pos, self.lexer);
@@ -226,9 +958,9 @@ impl Parser {
 impl Parser {
     fn read_while_statement(&mut self) -> Result<Node, Error> {
         token_start_pos!(pos, self.lexer);
-        assert_eq!(self.lexer.next()?.kind, Kind::Symbol(Symbol::OpeningParen));
+        self.expect(Symbol::OpeningParen, "expect '('")?;
         let cond = self.read_expression()?;
-        assert_eq!(self.lexer.next()?.kind, Kind::Symbol(Symbol::ClosingParen));
+        self.expect(Symbol::ClosingParen, "expect ')'")?;
 
         let body = self.read_statement()?;
 
@@ -238,28 +970,152 @@ impl Parser {
         ))
     }
 
+    /// https://tc39.github.io/ecma262/#prod-WithStatement
+    fn read_with_statement(&mut self, pos: usize) -> Result<Node, Error> {
+        if self.strict {
+            let msg = "with statements are not allowed in strict mode";
+            self.show_error_at(pos, ErrorMsgKind::Normal, msg)
+        }
+        self.expect(Symbol::OpeningParen, "expect '('")?;
+        let obj = self.read_expression()?;
+        self.expect(Symbol::ClosingParen, "expect ')'")?;
+
+        let body = self.read_statement()?;
+
+        Ok(Node::new(
+            NodeBase::With(Box::new(obj), Box::new(body)),
+            pos,
+        ))
+    }
+
+    /// https://tc39.github.io/ecma262/#prod-SwitchStatement
+    fn read_switch_statement(&mut self) -> Result<Node, Error> {
+        token_start_pos!(pos, self.lexer);
+        self.expect(Symbol::OpeningParen, "expect '('")?;
+        let discriminant = self.read_expression()?;
+        self.expect(Symbol::ClosingParen, "expect ')'")?;
+        self.expect(Symbol::OpeningBrace, "expect '{'")?;
+
+        let mut cases = vec![];
+        let mut has_default = false;
+        loop {
+            let tok = self.lexer.next().or(Err(Error::UnexpectedEOF))?;
+            let label = match tok.kind {
+                Kind::Symbol(Symbol::ClosingBrace) => break,
+                Kind::Keyword(Keyword::Case) => Some(self.read_expression()?),
+                Kind::Keyword(Keyword::Default) if !has_default => {
+                    has_default = true;
+                    None
+                }
+                Kind::Keyword(Keyword::Default) => self.show_error_at(
+                    tok.pos,
+                    ErrorMsgKind::Normal,
+                    "more than one default clause in switch statement",
+                ),
+                _ => return Err(Error::UnexpectedToken(tok.pos)),
+            };
+            self.expect(Symbol::Colon, "expect ':'")?;
+            cases.push((label, self.read_case_body()?));
+        }
+
+        Ok(Node::new(
+            NodeBase::Switch(Box::new(discriminant), cases),
+            pos,
+        ))
+    }
+
+    /// Reads the statements of a case clause up to the next clause or the end of the switch.
+    fn read_case_body(&mut self) -> Result<Node, Error> {
+        token_start_pos!(pos, self.lexer);
+        let mut items = vec![];
+        loop {
+            let tok = self.lexer.next().or(Err(Error::UnexpectedEOF))?;
+            self.lexer.unget(&tok);
+            match tok.kind {
+                Kind::Keyword(Keyword::Case)
+                | Kind::Keyword(Keyword::Default)
+                | Kind::Symbol(Symbol::ClosingBrace) => break,
+                _ => self.read_statement_list_item_into(&mut items)?,
+            }
+            self.lexer.skip(Kind::Symbol(Symbol::Semicolon));
+        }
+        Ok(Node::new(NodeBase::StatementList(items), pos))
+    }
+
+    fn read_do_while_statement(&mut self) -> Result<Node, Error> {
+        token_start_pos!(pos, self.lexer);
+        let body = self.read_statement()?;
+        self.lexer.skip(Kind::Symbol(Symbol::Semicolon));
+
+        let tok = self.lexer.next().or(Err(Error::UnexpectedEOF))?;
+        if tok.kind != Kind::Keyword(Keyword::While) {
+            self.show_error_at(tok.pos, ErrorMsgKind::LastToken, "expect 'while'")
+        }
+        self.expect(Symbol::OpeningParen, "expect '('")?;
+        let cond = self.read_expression()?;
+        self.expect(Symbol::ClosingParen, "expect ')'")?;
+        // The semicolon after a do-while statement is optional.
+        self.lexer.skip(Kind::Symbol(Symbol::Semicolon));
+
+        Ok(Node::new(
+            NodeBase::DoWhile(Box::new(body), Box::new(cond)),
+            pos,
+        ))
+    }
+
     fn read_for_statement(&mut self) -> Result<Node, Error> {
         token_start_pos!(pos, self.lexer);
-        assert_eq!(self.lexer.next()?.kind, Kind::Symbol(Symbol::OpeningParen));
-        let init = if self.lexer.skip(Kind::Keyword(Keyword::Var)) {
-            self.read_variable_statement()?
-        } else if self.lexer.skip(Kind::Symbol(Symbol::Semicolon)) {
+        self.expect(Symbol::OpeningParen, "expect '('")?;
+        let init = if self.lexer.skip(Kind::Symbol(Symbol::Semicolon)) {
             Node::new(NodeBase::Nope, 0)
         } else {
-            self.read_expression()?
+            let lexical_kind = if self.is_lexical_declaration() {
+                Some(self.read_lexical_kind()?)
+            } else {
+                None
+            };
+            let init = if lexical_kind.is_some() || self.lexer.skip(Kind::Keyword(Keyword::Var)) {
+                match self.with_lexical_kind(lexical_kind, |parser| {
+                    parser.without_in(Parser::read_for_declaration)
+                })? {
+                    ForDeclaration::Each(each, var, bindings) => {
+                        return self.read_for_each_statement(each, var, bindings, pos)
+                    }
+                    // The variables are put in one list, which is the scope of the loop.
+                    ForDeclaration::Init(init) if lexical_kind.is_some() => {
+                        let init_pos = init.pos;
+                        let mut decls = vec![];
+                        flatten_statements(init, &mut decls);
+                        Node::new(NodeBase::StatementList(decls), init_pos)
+                    }
+                    ForDeclaration::Init(init) => init,
+                }
+            } else {
+                let expr = self.without_in(Parser::read_expression)?;
+                let tok = self.lexer.next()?;
+                if let Some(each) = for_each_kind(&tok.kind) {
+                    let var = self.new_pattern_var();
+                    let assign = self.for_each_assignment(each, expr, var.as_str(), pos)?;
+                    return self.read_for_each_statement(each, var, vec![assign], pos);
+                }
+                self.lexer.unget(&tok);
+                expr
+            };
+            self.expect(Symbol::Semicolon, "expect ';'")?;
+            init
         };
         let cond = if self.lexer.skip(Kind::Symbol(Symbol::Semicolon)) {
             Node::new(NodeBase::Boolean(true), 0)
         } else {
             let step = self.read_expression()?;
-            assert_eq!(self.lexer.next()?.kind, Kind::Symbol(Symbol::Semicolon));
+            self.expect(Symbol::Semicolon, "expect ';'")?;
             step
         };
         let step = if self.lexer.skip(Kind::Symbol(Symbol::ClosingParen)) {
             Node::new(NodeBase::Nope, 0)
         } else {
             let step = self.read_expression()?;
-            assert_eq!(self.lexer.next()?.kind, Kind::Symbol(Symbol::ClosingParen));
+            self.expect(Symbol::ClosingParen, "expect ')'")?;
             step
         };
 
@@ -275,17 +1131,181 @@ impl Parser {
             pos,
         ))
     }
+
+    /// Runs `read` with `in` read as the keyword of a for-in statement instead of as an operator.
+    fn without_in<T>(&mut self, read: fn(&mut Parser) -> Result<T, Error>) -> Result<T, Error> {
+        let outer_no_in = ::std::mem::replace(&mut self.no_in, true);
+        let result = read(self);
+        self.no_in = outer_no_in;
+        result
+    }
+
+    /// Runs `read` with the declarations it reads made with let or const as `kind` says, or with
+    /// var if it is None.
+    fn with_lexical_kind<T>(
+        &mut self,
+        kind: Option<LexicalKind>,
+        read: fn(&mut Parser) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let outer_lexical_kind = ::std::mem::replace(&mut self.lexical_kind, kind);
+        let result = read(self);
+        self.lexical_kind = outer_lexical_kind;
+        result
+    }
+
+    /// Reads the declarations after `var`, `let` or `const` in a for statement. If `in` or `of`
+    /// follows the variable or the pattern declared first, the statement is a for-in or a for-of
+    /// statement.
+    fn read_for_declaration(&mut self) -> Result<ForDeclaration, Error> {
+        let tok = self.lexer.next()?;
+        match tok.kind {
+            Kind::Identifier(ref name) => {
+                let next = self.lexer.next()?;
+                if let Some(each) = for_each_kind(&next.kind) {
+                    self.check_binding_identifier(name.as_str(), tok.pos);
+                    let var = self.new_pattern_var();
+                    let val = identifier_node(&var, tok.pos);
+                    let decl =
+                        Node::new(self.binding_decl(name.clone(), Some(Box::new(val))), tok.pos);
+                    return Ok(ForDeclaration::Each(each, var, vec![decl]));
+                }
+                self.lexer.unget(&tok);
+                self.lexer.unget(&next);
+            }
+            Kind::Symbol(Symbol::OpeningBoxBracket) | Kind::Symbol(Symbol::OpeningBrace) => {
+                self.lexer.unget(&tok);
+                let var = self.new_pattern_var();
+                let mut decls = vec![];
+                self.read_binding_pattern(var.as_str(), &mut decls)?;
+                let next = self.lexer.next()?;
+                if let Some(each) = for_each_kind(&next.kind) {
+                    return Ok(ForDeclaration::Each(each, var, decls));
+                }
+                self.lexer.unget(&next);
+                let mut list = vec![self.read_pattern_initializer(var, decls, tok.pos)?];
+                if self.lexer.skip(Kind::Symbol(Symbol::Comma)) {
+                    list.push(self.read_variable_declaration_list()?);
+                }
+                return Ok(ForDeclaration::Init(Node::new(
+                    NodeBase::StatementList(list),
+                    tok.pos,
+                )));
+            }
+            _ => self.lexer.unget(&tok),
+        }
+        Ok(ForDeclaration::Init(self.read_variable_statement()?))
+    }
+
+    /// Returns the assignment of the variable `var` to `target`, the target of a for-in or a
+    /// for-of statement that declares no variable.
+    fn for_each_assignment(
+        &mut self,
+        each: ForEach,
+        target: Node,
+        var: &str,
+        pos: usize,
+    ) -> Result<Node, Error> {
+        match target.base {
+            NodeBase::Identifier(_) | NodeBase::Member(_, _) | NodeBase::Index(_, _) => {}
+            NodeBase::Array(_) | NodeBase::Object(_) => {
+                return Err(Error::UnsupportedFeature(target.pos))
+            }
+            _ => {
+                let msg = match each {
+                    ForEach::In => "invalid left-hand side in for-in",
+                    ForEach::Of => "invalid left-hand side in for-of",
+                };
+                self.show_error_at(target.pos, ErrorMsgKind::Normal, msg)
+            }
+        }
+        Ok(Node::new(
+            NodeBase::Assign(Box::new(target), Box::new(identifier_node(var, pos))),
+            pos,
+        ))
+    }
+
+    /// https://tc39.github.io/ecma262/#prod-ForInOfStatement
+    /// Reads the rest of a for-in or a for-of statement after `in` or `of`. Each key or value is
+    /// put in the variable `var`, and `bindings` assign it to the target of the loop before the
+    /// body runs.
+    fn read_for_each_statement(
+        &mut self,
+        each: ForEach,
+        var: String,
+        mut bindings: Vec<Node>,
+        pos: usize,
+    ) -> Result<Node, Error> {
+        let val = match each {
+            ForEach::In => self.read_expression()?,
+            ForEach::Of => self.read_assignment_expression()?,
+        };
+        self.expect(Symbol::ClosingParen, "expect ')'")?;
+
+        bindings.push(self.read_statement()?);
+
+        let var = Box::new(Node::new(NodeBase::VarDecl(var, None), pos));
+        let body = Box::new(Node::new(NodeBase::StatementList(bindings), pos));
+        Ok(Node::new(
+            match each {
+                ForEach::In => NodeBase::ForIn(var, Box::new(val), body),
+                ForEach::Of => NodeBase::ForOf(var, Box::new(val), body),
+            },
+            pos,
+        ))
+    }
+}
+
+/// What `var` starts in the head of a for statement.
+enum ForDeclaration {
+    /// The variable holding each key of a for-in statement or each value of a for-of statement,
+    /// and the declarations that bind it.
+    Each(ForEach, String, Vec<Node>),
+    /// The declarations run before a plain for statement.
+    Init(Node),
+}
+
+/// Whether a for statement iterates the keys or the values of an object.
+#[derive(Clone, Copy)]
+enum ForEach {
+    In,
+    Of,
+}
+
+/// Returns which of `in` and `of` the token `kind` is, if either.
+fn for_each_kind(kind: &Kind) -> Option<ForEach> {
+    match *kind {
+        Kind::Keyword(Keyword::In) => Some(ForEach::In),
+        Kind::Identifier(ref name) if name == "of" => Some(ForEach::Of),
+        _ => None,
+    }
 }
 
 impl Parser {
     fn read_break_statement(&mut self) -> Result<Node, Error> {
         let pos = self.lexer.pos - "break".len();
-        return Ok(Node::new(NodeBase::Break, pos));
+        let label = self.read_jump_label();
+        return Ok(Node::new(NodeBase::Break(label), pos));
     }
 
     fn read_continue_statement(&mut self) -> Result<Node, Error> {
         let pos = self.lexer.pos - "continue".len();
-        return Ok(Node::new(NodeBase::Continue, pos));
+        let label = self.read_jump_label();
+        return Ok(Node::new(NodeBase::Continue(label), pos));
+    }
+
+    /// Reads the label of a break or continue statement, which must be on the same line.
+    fn read_jump_label(&mut self) -> Option<String> {
+        let tok = match self.lexer.read_token() {
+            Ok(tok) => tok,
+            Err(_) => return None,
+        };
+        match tok.kind {
+            Kind::Identifier(ref name) => Some(name.clone()),
+            _ => {
+                self.lexer.unget(&tok);
+                None
+            }
+        }
     }
 }
 
@@ -320,25 +1340,34 @@ impl Parser {
     /// https://tc39.github.io/ecma262/#prod-AssignmentExpression
     // TODO: Implement all features.
     fn read_assignment_expression(&mut self) -> Result<Node, Error> {
+        self.nested(Parser::read_assignment_expression_inner)
+    }
+
+    fn read_assignment_expression_inner(&mut self) -> Result<Node, Error> {
         token_start_pos!(pos, self.lexer);
         let mut lhs = self.read_conditional_expression()?;
         if let Ok(tok) = self.lexer.next() {
+            // `x op= y` is read as `x = x op y`, except when x is a member or an index: its
+            // base and key must be evaluated only once.
             macro_rules! assignop {
                 ($op:ident) => {{
-                    lhs = Node::new(
-                        NodeBase::Assign(
-                            Box::new(lhs.clone()),
-                            Box::new(Node::new(
-                                NodeBase::BinaryOp(
-                                    Box::new(lhs),
-                                    Box::new(self.read_assignment_expression()?),
-                                    BinOp::$op,
-                                ),
-                                pos,
-                            )),
+                    let rhs = self.read_assignment_expression()?;
+                    lhs = match lhs.base {
+                        NodeBase::Member(_, _) | NodeBase::Index(_, _) => Node::new(
+                            NodeBase::CompoundAssign(Box::new(lhs), Box::new(rhs), BinOp::$op),
+                            pos,
                         ),
-                        pos,
-                    );
+                        _ => Node::new(
+                            NodeBase::Assign(
+                                Box::new(lhs.clone()),
+                                Box::new(Node::new(
+                                    NodeBase::BinaryOp(Box::new(lhs), Box::new(rhs), BinOp::$op),
+                                    pos,
+                                )),
+                            ),
+                            pos,
+                        ),
+                    };
                 }};
             }
             match tok.kind {
@@ -359,6 +1388,15 @@ impl Parser {
                 _ => self.lexer.unget(&tok),
             }
         }
+        if let NodeBase::Assign(ref dst, _) = lhs.base {
+            match dst.base {
+                NodeBase::Identifier(ref name) => {
+                    self.check_binding_identifier(name.as_str(), dst.pos)
+                }
+                NodeBase::Member(_, _) | NodeBase::Index(_, _) => {}
+                _ => self.show_error_at(dst.pos, ErrorMsgKind::Normal, "invalid assignment target"),
+            }
+        }
         Ok(lhs)
     }
 
@@ -368,9 +1406,10 @@ impl Parser {
         let lhs = self.read_logical_or_expression()?;
         if let Ok(tok) = self.lexer.next() {
             if let Kind::Symbol(Symbol::Question) = tok.kind {
-                let then_ = self.read_conditional_expression()?;
-                assert_eq!(self.lexer.next()?.kind, Kind::Symbol(Symbol::Colon));
-                let else_ = self.read_conditional_expression()?;
+                // Both branches may be assignments, like `a ? b = 1 : c = 2`.
+                let then_ = self.read_assignment_expression()?;
+                self.expect(Symbol::Colon, "expect ':'")?;
+                let else_ = self.read_assignment_expression()?;
                 return Ok(Node::new(
                     NodeBase::TernaryOp(Box::new(lhs), Box::new(then_), Box::new(else_)),
                     pos,
@@ -425,11 +1464,30 @@ impl Parser {
     );
 
     /// https://tc39.github.io/ecma262/#prod-RelationalExpression
-    expression!(
-        read_relational_expression,
-        read_shift_expression,
-        [Symbol::Lt, Symbol::Gt, Symbol::Le, Symbol::Ge]
-    );
+    fn read_relational_expression(&mut self) -> Result<Node, Error> {
+        let mut lhs = self.read_shift_expression()?;
+        while let Ok(tok) = self.lexer.next() {
+            token_start_pos!(pos, self.lexer);
+            let op = match tok.kind {
+                Kind::Symbol(ref op)
+                    if [Symbol::Lt, Symbol::Gt, Symbol::Le, Symbol::Ge].contains(op) =>
+                {
+                    op.as_binop().unwrap()
+                }
+                Kind::Keyword(Keyword::In) if !self.no_in => BinOp::In,
+                Kind::Keyword(Keyword::Instanceof) => BinOp::InstanceOf,
+                _ => {
+                    self.lexer.unget(&tok);
+                    break;
+                }
+            };
+            lhs = Node::new(
+                NodeBase::BinaryOp(Box::new(lhs), Box::new(self.read_shift_expression()?), op),
+                pos,
+            );
+        }
+        Ok(lhs)
+    }
 
     /// https://tc39.github.io/ecma262/#prod-ShiftExpression
     expression!(
@@ -453,32 +1511,48 @@ impl Parser {
     );
 
     /// https://tc39.github.io/ecma262/#prod-ExponentiationExpression
+    /// `**` is right-associative, and its left operand cannot be a unary expression: `-a ** b`
+    /// is an error, since it is unclear whether it means `(-a) ** b` or `-(a ** b)`.
     fn read_exponentiation_expression(&mut self) -> Result<Node, Error> {
         if self.is_unary_expression() {
-            return self.read_unary_expression();
+            let expr = self.read_unary_expression()?;
+            if let Ok(tok) = self.lexer.next() {
+                if tok.kind == Kind::Symbol(Symbol::Exp) {
+                    self.show_error_at(
+                        tok.pos,
+                        ErrorMsgKind::Normal,
+                        "the operand of '**' cannot be a unary expression without parentheses",
+                    )
+                }
+                self.lexer.unget(&tok);
+            }
+            return Ok(expr);
         }
         token_start_pos!(pos, self.lexer);
         let lhs = self.read_update_expression()?;
-        while let Ok(tok) = self.lexer.next() {
+        if let Ok(tok) = self.lexer.next() {
             if let Kind::Symbol(Symbol::Exp) = tok.kind {
                 return Ok(Node::new(
                     NodeBase::BinaryOp(
                         Box::new(lhs),
-                        Box::new(self.read_update_expression()?),
+                        Box::new(self.read_exponentiation_expression()?),
                         BinOp::Exp,
                     ),
                     pos,
                 ));
-            } else {
-                self.lexer.unget(&tok);
-                break;
             }
+            self.lexer.unget(&tok);
         }
         Ok(lhs)
     }
 
     fn is_unary_expression(&mut self) -> bool {
-        match self.lexer.peek() {
+        // An operand may be on the next line.
+        let tok = self.lexer.next();
+        if let Ok(ref tok) = tok {
+            self.lexer.unget(tok);
+        }
+        match tok {
             Ok(ok) => match ok.kind {
                 Kind::Keyword(Keyword::Delete)
                 | Kind::Keyword(Keyword::Void)
@@ -487,6 +1561,7 @@ impl Parser {
                 | Kind::Symbol(Symbol::Sub)
                 | Kind::Symbol(Symbol::BitwiseNot)
                 | Kind::Symbol(Symbol::Not) => true,
+                Kind::Identifier(ref name) => name == "await",
                 _ => false,
             },
             Err(_) => false,
@@ -495,13 +1570,26 @@ impl Parser {
 
     /// https://tc39.github.io/ecma262/#prod-UnaryExpression
     fn read_unary_expression(&mut self) -> Result<Node, Error> {
+        self.nested(Parser::read_unary_expression_inner)
+    }
+
+    fn read_unary_expression_inner(&mut self) -> Result<Node, Error> {
         token_start_pos!(pos, self.lexer);
         let tok = self.lexer.next()?;
         match tok.kind {
-            Kind::Keyword(Keyword::Delete) => Ok(Node::new(
-                NodeBase::UnaryOp(Box::new(self.read_unary_expression()?), UnaryOp::Delete),
-                pos,
-            )),
+            Kind::Keyword(Keyword::Delete) => {
+                let operand = self.read_unary_expression()?;
+                if let NodeBase::Identifier(_) = operand.base {
+                    if self.strict {
+                        let msg = "cannot delete an unqualified identifier in strict mode";
+                        self.show_error_at(operand.pos, ErrorMsgKind::Normal, msg)
+                    }
+                }
+                Ok(Node::new(
+                    NodeBase::UnaryOp(Box::new(operand), UnaryOp::Delete),
+                    pos,
+                ))
+            }
             Kind::Keyword(Keyword::Void) => Ok(Node::new(
                 NodeBase::UnaryOp(Box::new(self.read_unary_expression()?), UnaryOp::Void),
                 pos,
@@ -526,6 +1614,16 @@ impl Parser {
                 NodeBase::UnaryOp(Box::new(self.read_unary_expression()?), UnaryOp::Not),
                 pos,
             )),
+            Kind::Identifier(ref name) if name == "await" && self.in_async_function => {
+                Ok(Node::new(
+                    NodeBase::UnaryOp(Box::new(self.read_unary_expression()?), UnaryOp::Await),
+                    pos,
+                ))
+            }
+            Kind::Identifier(ref name) if name == "await" && self.is_operand_ahead() => {
+                let msg = "await is only valid in async functions";
+                self.show_error_at(tok.pos, ErrorMsgKind::Normal, msg)
+            }
             _ => {
                 self.lexer.unget(&tok);
                 self.read_update_expression()
@@ -564,6 +1662,12 @@ impl Parser {
         let e = self.read_left_hand_side_expression()?;
         if let Ok(tok) = self.lexer.next() {
             match tok.kind {
+                // No line terminator is allowed before a postfix operator: `a\n++b` is `a; ++b`.
+                Kind::Symbol(Symbol::Inc) | Kind::Symbol(Symbol::Dec)
+                    if self.starts_line(tok.pos) =>
+                {
+                    self.lexer.unget(&tok)
+                }
                 Kind::Symbol(Symbol::Inc) => {
                     return Ok(Node::new(
                         NodeBase::UnaryOp(Box::new(e), UnaryOp::PoInc),
@@ -593,28 +1697,89 @@ impl Parser {
 
     /// https://tc39.github.io/ecma262/#prod-NewExpression
     fn read_new_expression(&mut self) -> Result<Node, Error> {
+        self.read_call_expression()
+    }
+
+    /// https://tc39.github.io/ecma262/#prod-MemberExpression
+    /// Reads a primary expression and the properties read from it, or a `new` expression. The
+    /// arguments of `new` are the first ones after its constructor, so `new f(a).b` reads b of
+    /// the object made.
+    fn read_member_expression(&mut self) -> Result<Node, Error> {
+        self.nested(Parser::read_member_expression_inner)
+    }
+
+    fn read_member_expression_inner(&mut self) -> Result<Node, Error> {
         token_start_pos!(pos, self.lexer);
-        if self.lexer.skip(Kind::Keyword(Keyword::New)) {
-            Ok(Node::new(
-                NodeBase::New(Box::new(self.read_new_expression()?)),
-                pos,
-            ))
+        let mut lhs = if self.lexer.skip(Kind::Keyword(Keyword::New)) {
+            let callee = self.read_member_expression()?;
+            let expr = if self.lexer.skip(Kind::Symbol(Symbol::OpeningParen)) {
+                let args = self.read_arguments()?;
+                Node::new(NodeBase::Call(Box::new(callee), args), pos)
+            } else if let NodeBase::Call(_, _) = callee.base {
+                // `new (f())` constructs what f returns, unlike `new f()`.
+                Node::new(NodeBase::Call(Box::new(callee), vec![]), pos)
+            } else {
+                // new F is new F()
+                callee
+            };
+            Node::new(NodeBase::New(Box::new(expr)), pos)
         } else {
-            self.read_call_expression()
+            self.read_primary_expression()?
+        };
+
+        while let Ok(tok) = self.lexer.next() {
+            let pos_ = self.lexer.pos;
+            match tok.kind {
+                Kind::Symbol(Symbol::Point) => match self.lexer.next()?.kind {
+                    Kind::Identifier(name) => {
+                        lhs = Node::new(NodeBase::Member(Box::new(lhs), name), pos)
+                    }
+                    // Reserved words name properties too, e.g. promise.catch.
+                    Kind::Keyword(keyword) => {
+                        let name = keyword.as_str().to_string();
+                        lhs = Node::new(NodeBase::Member(Box::new(lhs), name), pos)
+                    }
+                    _ => self.show_error_at(pos_, ErrorMsgKind::Normal, "expect identifier"),
+                },
+                Kind::Symbol(Symbol::OpeningBoxBracket) => {
+                    let idx = self.read_expression()?;
+                    if !self.lexer.skip(Kind::Symbol(Symbol::ClosingBoxBracket)) {
+                        self.show_error_at(self.lexer.pos, ErrorMsgKind::Normal, "expect ']'");
+                    }
+                    lhs = Node::new(NodeBase::Index(Box::new(lhs), Box::new(idx)), pos);
+                }
+                Kind::Template(cooked, raw, tail) if self.begins_template(tok.pos) => {
+                    let (strings, substitutions) = self.read_template((cooked, raw, tail))?;
+                    let template = NodeBase::TaggedTemplate(Box::new(lhs), strings, substitutions);
+                    lhs = Node::new(template, pos);
+                }
+                _ => {
+                    self.lexer.unget(&tok);
+                    break;
+                }
+            }
         }
+
+        Ok(lhs)
     }
 
     /// https://tc39.github.io/ecma262/#prod-CallExpression
     // TODO: Implement all features.
     fn read_call_expression(&mut self) -> Result<Node, Error> {
         token_start_pos!(pos, self.lexer);
-        let mut lhs = self.read_primary_expression()?;
+        let mut lhs = self.read_member_expression()?;
 
         while let Ok(tok) = self.lexer.next() {
             let pos_ = self.lexer.pos;
 
             match tok.kind {
                 Kind::Symbol(Symbol::OpeningParen) => {
+                    if self.starts_line(tok.pos) {
+                        let msg = "this '(' calls the expression before the line break; \
+                                   start the line with ';' if it begins a new statement";
+                        let warning = Diagnostic::warning(msg, self.lexer.code.as_str(), tok.pos);
+                        self.warnings.push(warning);
+                    }
                     let args = self.read_arguments()?;
                     lhs = Node::new(NodeBase::Call(Box::new(lhs), args), pos)
                 }
@@ -622,6 +1787,11 @@ impl Parser {
                     Kind::Identifier(name) => {
                         lhs = Node::new(NodeBase::Member(Box::new(lhs), name), pos)
                     }
+                    // Reserved words name properties too, e.g. promise.catch.
+                    Kind::Keyword(keyword) => {
+                        let name = keyword.as_str().to_string();
+                        lhs = Node::new(NodeBase::Member(Box::new(lhs), name), pos)
+                    }
                     _ => self.show_error_at(pos_, ErrorMsgKind::Normal, "expect identifier"),
                 },
                 Kind::Symbol(Symbol::OpeningBoxBracket) => {
@@ -631,6 +1801,11 @@ impl Parser {
                     }
                     lhs = Node::new(NodeBase::Index(Box::new(lhs), Box::new(idx)), pos);
                 }
+                Kind::Template(cooked, raw, tail) if self.begins_template(tok.pos) => {
+                    let (strings, substitutions) = self.read_template((cooked, raw, tail))?;
+                    let template = NodeBase::TaggedTemplate(Box::new(lhs), strings, substitutions);
+                    lhs = Node::new(template, pos);
+                }
                 _ => {
                     self.lexer.unget(&tok);
                     break;
@@ -641,6 +1816,61 @@ impl Parser {
         Ok(lhs)
     }
 
+    /// https://tc39.github.io/ecma262/#prod-TemplateLiteral
+    /// Reads the substitutions and the rest of a template literal whose first part, the cooked
+    /// and raw strings and whether it is the last part, has been read.
+    fn read_template(
+        &mut self,
+        part: (Option<String>, String, bool),
+    ) -> Result<(Vec<(Option<String>, String)>, Vec<Node>), Error> {
+        let (mut cooked, mut raw, mut tail) = part;
+        let mut strings = vec![];
+        let mut substitutions = vec![];
+        loop {
+            strings.push((cooked, raw));
+            if tail {
+                return Ok((strings, substitutions));
+            }
+            substitutions.push(self.read_expression()?);
+            let tok = self.lexer.next()?;
+            match tok.kind {
+                Kind::Template(cooked_, raw_, tail_) => {
+                    cooked = cooked_;
+                    raw = raw_;
+                    tail = tail_;
+                }
+                _ => self.show_error_at(tok.pos, ErrorMsgKind::Normal, "expect '}'"),
+            }
+        }
+    }
+
+    /// Reads an untagged template literal, which concatenates its strings and substitutions.
+    fn read_template_literal(
+        &mut self,
+        part: (Option<String>, String, bool),
+        pos: usize,
+    ) -> Result<Node, Error> {
+        let (strings, substitutions) = self.read_template(part)?;
+        let mut strings = strings.into_iter().map(|(cooked, _)| match cooked {
+            Some(cooked) => Node::new(NodeBase::String(cooked), pos),
+            None => self.show_error_at(pos, ErrorMsgKind::Normal, "invalid escape sequence"),
+        });
+        let mut node = strings.next().unwrap();
+        for (substitution, string) in substitutions.into_iter().zip(strings) {
+            node = Node::new(
+                NodeBase::BinaryOp(Box::new(node), Box::new(substitution), BinOp::Add),
+                pos,
+            );
+            if string.base != NodeBase::String("".to_string()) {
+                node = Node::new(
+                    NodeBase::BinaryOp(Box::new(node), Box::new(string), BinOp::Add),
+                    pos,
+                );
+            }
+        }
+        Ok(node)
+    }
+
     fn read_arguments(&mut self) -> Result<Vec<Node>, Error> {
         let tok = self.lexer.next()?;
         match tok.kind {
@@ -662,7 +1892,7 @@ impl Parser {
                 Err(_) => self.show_error_at(pos, ErrorMsgKind::LastToken, "reach unexpected EOF"),
             }
 
-            args.push(self.read_assignment_expression()?);
+            args.push(self.read_spread_or_assignment_expression()?);
 
             match self.lexer.next() {
                 Ok(ref tok) if tok.kind == Kind::Symbol(Symbol::Comma) => pos = tok.pos,
@@ -677,20 +1907,45 @@ impl Parser {
         Ok(args)
     }
 
+    /// Reads an element of an array literal or an argument, either of which may be spread.
+    fn read_spread_or_assignment_expression(&mut self) -> Result<Node, Error> {
+        token_start_pos!(pos, self.lexer);
+        if self.lexer.skip(Kind::Symbol(Symbol::Rest)) {
+            let expr = self.read_assignment_expression()?;
+            return Ok(Node::new(NodeBase::Spread(Box::new(expr)), pos));
+        }
+        self.read_assignment_expression()
+    }
+
     /// https://tc39.github.io/ecma262/#prod-PrimaryExpression
     fn read_primary_expression(&mut self) -> Result<Node, Error> {
         let tok = self.lexer.next()?;
         match tok.kind {
-            Kind::Keyword(Keyword::This) => Ok(Node::new(NodeBase::This, tok.pos)),
+            Kind::Keyword(Keyword::Arguments) if self.in_arrow_function => {
+                self.arrow_arguments = true;
+                Ok(identifier_node(ARROW_ARGUMENTS, tok.pos))
+            }
+            Kind::Keyword(Keyword::This) => Ok(self.read_this(tok.pos)),
             Kind::Keyword(Keyword::Arguments) => Ok(Node::new(NodeBase::Arguments, tok.pos)),
-            Kind::Keyword(Keyword::Function) => self.read_function_expression(),
-            Kind::Symbol(Symbol::Semicolon) => Ok(Node::new(NodeBase::Nope, tok.pos)),
+            Kind::Keyword(Keyword::Function) => self.read_function_expression(tok.pos, false),
+            Kind::Identifier(ref i) if i == "async" && self.is_async_function_ahead() => {
+                self.read_async_function_expression(tok.pos)
+            }
+            Kind::Identifier(ref i) if i == "class" => {
+                Ok(self.read_class(false, tok.pos)?.1)
+            }
+            Kind::Identifier(ref i) if i == "super" => self.read_super(tok.pos),
             // Kind::Symbol(Symbol::ClosingParen) => {
             //     self.lexer.unget(&tok);
             //     Ok(Node::new(NodeBase::Nope, tok.pos))
             // }
+            Kind::Symbol(Symbol::OpeningParen) if self.is_arrow_function_ahead() => {
+                self.read_arrow_function(tok.pos, false)
+            }
             Kind::Symbol(Symbol::OpeningParen) => {
+                let outer_no_in = ::std::mem::replace(&mut self.no_in, false);
                 let x = self.read_expression();
+                self.no_in = outer_no_in;
                 if !self.lexer.skip(Kind::Symbol(Symbol::ClosingParen)) {
                     self.show_error_at(
                         self.lexer.pos_line_list.last().unwrap().0,
@@ -708,9 +1963,36 @@ impl Parser {
             Kind::Identifier(ref i) if i == "false" => {
                 Ok(Node::new(NodeBase::Boolean(false), tok.pos))
             }
-            Kind::Identifier(ident) => Ok(Node::new(NodeBase::Identifier(ident), tok.pos)),
+            Kind::Identifier(ref i) if i == "null" => Ok(Node::new(NodeBase::Null, tok.pos)),
+            Kind::Identifier(ident) => {
+                self.check_identifier_reference(ident.as_str(), tok.pos);
+                if let Ok(next) = self.lexer.next() {
+                    // No line terminator is allowed before '=>'.
+                    if next.kind == Kind::Symbol(Symbol::Arrow) && !self.starts_line(next.pos) {
+                        let params = vec![FormalParameter::new(ident, None, false)];
+                        return self.read_arrow_function_body(params, vec![], false, tok.pos);
+                    }
+                    self.lexer.unget(&next);
+                }
+                Ok(Node::new(NodeBase::Identifier(ident), tok.pos))
+            }
             Kind::String(s) => Ok(Node::new(NodeBase::String(s), tok.pos)),
-            Kind::Number(num) => Ok(Node::new(NodeBase::Number(num), tok.pos)),
+            Kind::Template(cooked, raw, tail) if self.begins_template(tok.pos) => {
+                self.read_template_literal((cooked, raw, tail), tok.pos)
+            }
+            Kind::Number(num) => {
+                let mut digits = self.lexer.code[tok.pos..].chars();
+                let is_legacy_octal = digits.next() == Some('0')
+                    && digits.next().map_or(false, |c| c.is_digit(10));
+                if self.strict && is_legacy_octal {
+                    self.show_error_at(
+                        tok.pos,
+                        ErrorMsgKind::Normal,
+                        "octal literals are not allowed in strict mode",
+                    )
+                }
+                Ok(Node::new(NodeBase::Number(num), tok.pos))
+            }
             Kind::LineTerminator => self.read_primary_expression(),
             _ => self.show_error_at(
                 tok.pos,
@@ -747,8 +2029,78 @@ impl Parser {
         }
     }
 
+    /// Returns `this` of the function being read. Arrow functions use that of the enclosing
+    /// function through a variable.
+    fn read_this(&mut self, pos: usize) -> Node {
+        if self.in_arrow_function {
+            self.arrow_this = true;
+            identifier_node(ARROW_THIS, pos)
+        } else {
+            Node::new(NodeBase::This, pos)
+        }
+    }
+
+    /// Reads a use of `super` whose 'super' at `pos` has been read. `super(...)` calls the
+    /// superclass with this of the constructor. `super.m` reads m from the prototype of the
+    /// object the method is installed on, and `super.m(...)` calls it with this of the method.
+    /// The class is found through a variable the methods capture.
+    fn read_super(&mut self, pos: usize) -> Result<Node, Error> {
+        let (class_var, kind) = match self.home_object.clone() {
+            Some(home_object) => home_object,
+            None => self.show_error_at(pos, ErrorMsgKind::Normal, "'super' outside of a method"),
+        };
+        self.uses_super = true;
+        let member = |parent, name: &str| {
+            Node::new(NodeBase::Member(Box::new(parent), name.to_string()), pos)
+        };
+        let class = identifier_node(class_var.as_str(), pos);
+
+        let tok = self.lexer.next()?;
+        let home_proto = match kind {
+            MethodKind::StaticMethod => member(class, "__proto__"),
+            MethodKind::DerivedConstructor if tok.kind == Kind::Symbol(Symbol::OpeningParen) => {
+                let args = self.read_arguments()?;
+                let this = self.read_this(pos);
+                return Ok(super_call(class_var.as_str(), this, args, pos));
+            }
+            _ => member(member(class, "prototype"), "__proto__"),
+        };
+        let prop = match tok.kind {
+            Kind::Symbol(Symbol::Point) => match self.lexer.next()?.kind {
+                Kind::Identifier(name) => member(home_proto, name.as_str()),
+                _ => self.show_error_at(self.lexer.pos, ErrorMsgKind::Normal, "expect identifier"),
+            },
+            Kind::Symbol(Symbol::OpeningBoxBracket) => {
+                let idx = self.read_expression()?;
+                if !self.lexer.skip(Kind::Symbol(Symbol::ClosingBoxBracket)) {
+                    self.show_error_at(self.lexer.pos, ErrorMsgKind::Normal, "expect ']'");
+                }
+                Node::new(NodeBase::Index(Box::new(home_proto), Box::new(idx)), pos)
+            }
+            Kind::Symbol(Symbol::OpeningParen) => self.show_error_at(
+                tok.pos,
+                ErrorMsgKind::Normal,
+                "'super()' is only allowed in the constructor of a derived class",
+            ),
+            _ => {
+                let msg = "expect '.' or '[' after 'super'";
+                self.show_error_at(tok.pos, ErrorMsgKind::Normal, msg)
+            }
+        };
+
+        if self.lexer.skip(Kind::Symbol(Symbol::OpeningParen)) {
+            let mut args = self.read_arguments()?;
+            args.insert(0, self.read_this(pos));
+            let callee = member(prop, "call");
+            return Ok(Node::new(NodeBase::Call(Box::new(callee), args), pos));
+        }
+        Ok(prop)
+    }
+
     /// https://tc39.github.io/ecma262/#prod-FunctionDeclaration
-    fn read_function_expression(&mut self) -> Result<Node, Error> {
+    /// Reads a function expression whose 'function', or 'async' if it is async, at `start` has
+    /// been read.
+    fn read_function_expression(&mut self, start: usize, is_async: bool) -> Result<Node, Error> {
         token_start_pos!(pos, self.lexer);
         let name = if let Kind::Identifier(name) = self.lexer.peek()?.kind {
             self.lexer.next()?;
@@ -757,14 +2109,124 @@ impl Parser {
             None
         };
 
-        assert!(self.lexer.skip(Kind::Symbol(Symbol::OpeningParen)));
-        let params = self.read_formal_parameters()?;
+        let (params, body) =
+            self.read_function_params_and_body(name.as_ref(), None, is_async, pos)?;
+        let span = Span::new(start, self.read_pos());
+
+        Ok(Node::new(
+            NodeBase::FunctionExpr(name, params, Box::new(body), span, is_async),
+            pos,
+        ))
+    }
+
+    /// Returns true if the 'async' just read starts an async function or an async arrow function.
+    /// No line terminator is allowed after it.
+    fn is_async_function_ahead(&mut self) -> bool {
+        self.look_ahead(|lexer| match lexer.read_token().map(|tok| tok.kind) {
+            Ok(Kind::Keyword(Keyword::Function)) | Ok(Kind::Symbol(Symbol::OpeningParen)) => true,
+            Ok(Kind::Identifier(_)) => match lexer.read_token() {
+                Ok(tok) => tok.kind == Kind::Symbol(Symbol::Arrow),
+                Err(_) => false,
+            },
+            _ => false,
+        })
+    }
+
+    /// Returns true if the next token is on the same line and starts an operand, which cannot
+    /// come right after an identifier.
+    fn is_operand_ahead(&mut self) -> bool {
+        self.look_ahead(|lexer| match lexer.read_token().map(|tok| tok.kind) {
+            Ok(Kind::Identifier(_)) | Ok(Kind::Number(_)) | Ok(Kind::String(_)) => true,
+            Ok(Kind::Keyword(Keyword::This))
+            | Ok(Kind::Keyword(Keyword::New))
+            | Ok(Kind::Keyword(Keyword::Function)) => true,
+            _ => false,
+        })
+    }
+
+    /// https://tc39.github.io/ecma262/#prod-AsyncFunctionExpression
+    /// Reads an async function expression or an async arrow function whose 'async' at `start` has
+    /// been read. `async(...)` not followed by '=>' is a call of a function named async.
+    fn read_async_function_expression(&mut self, start: usize) -> Result<Node, Error> {
+        let tok = self.lexer.next()?;
+        match tok.kind {
+            Kind::Keyword(Keyword::Function) => self.read_function_expression(start, true),
+            Kind::Symbol(Symbol::OpeningParen) if self.is_arrow_function_ahead() => {
+                self.read_arrow_function(start, true)
+            }
+            Kind::Symbol(Symbol::OpeningParen) => {
+                self.lexer.unget(&tok);
+                Ok(Node::new(NodeBase::Identifier("async".to_string()), start))
+            }
+            Kind::Identifier(name) => {
+                self.lexer.next()?; // =>
+                let params = vec![FormalParameter::new(name, None, false)];
+                self.read_arrow_function_body(params, vec![], true, start)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Returns true if the parenthesized code after the '(' just read is the parameter list of
+    /// an arrow function.
+    fn is_arrow_function_ahead(&mut self) -> bool {
+        self.look_ahead(|lexer| {
+            let mut depth = 1;
+            while let Ok(tok) = lexer.next() {
+                match tok.kind {
+                    Kind::Symbol(Symbol::OpeningParen) => depth += 1,
+                    Kind::Symbol(Symbol::ClosingParen) if depth == 1 => {
+                        // No line terminator is allowed before '=>'.
+                        return match lexer.read_token() {
+                            Ok(tok) => tok.kind == Kind::Symbol(Symbol::Arrow),
+                            Err(_) => false,
+                        };
+                    }
+                    Kind::Symbol(Symbol::ClosingParen) => depth -= 1,
+                    _ => {}
+                }
+            }
+            false
+        })
+    }
+
+    /// https://tc39.github.io/ecma262/#prod-ArrowFunction
+    /// Reads an arrow function whose '(' has been read. It starts at `pos`, which is where its
+    /// 'async' is if it is async.
+    fn read_arrow_function(&mut self, pos: usize, is_async: bool) -> Result<Node, Error> {
+        let (params, prologue) = self.read_formal_parameters()?;
+        self.expect(Symbol::Arrow, "expect '=>'")?;
+        self.read_arrow_function_body(params, prologue, is_async, pos)
+    }
 
-        assert!(self.lexer.skip(Kind::Symbol(Symbol::OpeningBrace)));
-        let body = self.read_statement_list(true)?;
+    /// Reads the body of the arrow function at `pos`, after its '=>'. Its `this` and `arguments`
+    /// are those of the enclosing function.
+    fn read_arrow_function_body(
+        &mut self,
+        params: FormalParameters,
+        prologue: Vec<Node>,
+        is_async: bool,
+        pos: usize,
+    ) -> Result<Node, Error> {
+        let (mut body, strict) = if self.lexer.skip(Kind::Symbol(Symbol::OpeningBrace)) {
+            self.read_function_body(true, is_async)?
+        } else {
+            // A concise body is the value to return.
+            let outer_arrow = ::std::mem::replace(&mut self.in_arrow_function, true);
+            let outer_async = ::std::mem::replace(&mut self.in_async_function, is_async);
+            let val = self.read_assignment_expression()?;
+            self.in_arrow_function = outer_arrow;
+            self.in_async_function = outer_async;
+            let pos = val.pos;
+            let body = vec![Node::new(NodeBase::Return(Some(Box::new(val))), pos)];
+            (Node::new(NodeBase::StatementList(body), pos), self.strict)
+        };
+        self.check_function(None, &params, strict, pos);
+        prepend_statements(&mut body, prologue);
+        let span = Span::new(pos, self.read_pos());
 
         Ok(Node::new(
-            NodeBase::FunctionExpr(name, params, Box::new(body)),
+            NodeBase::FunctionExpr(None, params, Box::new(body), span, is_async),
             pos,
         ))
     }
@@ -784,9 +2246,7 @@ impl Parser {
                 break;
             }
 
-            if let Ok(elem) = self.read_assignment_expression() {
-                elements.push(elem);
-            }
+            elements.push(self.read_spread_or_assignment_expression()?);
 
             self.lexer.skip(Kind::Symbol(Symbol::Comma));
         }
@@ -803,8 +2263,11 @@ impl Parser {
             if self.lexer.skip(Kind::Symbol(Symbol::ClosingBrace)) {
                 break;
             }
-            if let Ok(elem) = self.read_property_definition() {
-                elements.push(elem);
+            match self.read_property_definition() {
+                Ok(elem) => elements.push(elem),
+                // Properties that cannot be parsed yet are left out.
+                Err(Error::UnsupportedFeature(_)) => {}
+                Err(e) => return Err(e),
             }
             self.lexer.skip(Kind::Symbol(Symbol::Comma));
         }
@@ -814,20 +2277,35 @@ impl Parser {
 
     /// https://tc39.github.io/ecma262/#prod-PropertyDefinition
     fn read_property_definition(&mut self) -> Result<PropertyDefinition, Error> {
-        fn to_string(kind: Kind) -> String {
+        fn to_string(kind: Kind) -> Option<String> {
             match kind {
-                Kind::Identifier(name) => name,
-                Kind::Number(n) => format!("{}", n),
-                Kind::String(s) => s,
-                _ => unimplemented!(),
+                Kind::Identifier(name) => Some(name),
+                Kind::Number(n) => Some(format!("{}", n)),
+                Kind::String(s) => Some(s),
+                _ => None,
             }
         }
 
+        if self.lexer.skip(Kind::Symbol(Symbol::Rest)) {
+            return Ok(PropertyDefinition::Spread(self.read_assignment_expression()?));
+        }
+
         let tok = self.lexer.next()?;
 
         if self.lexer.skip(Kind::Symbol(Symbol::Colon)) {
+            let name = to_string(tok.kind.clone()).ok_or(Error::UnexpectedToken(tok.pos))?;
             let val = self.read_assignment_expression()?;
-            return Ok(PropertyDefinition::Property(to_string(tok.kind), val));
+            return Ok(PropertyDefinition::Property(name, val));
+        }
+
+        // `get` and `set` start an accessor only if a property name follows.
+        if let Kind::Identifier(ref kind) = tok.kind {
+            if kind == "get" || kind == "set" {
+                if let Some(name) = to_string(self.lexer.peek()?.kind) {
+                    self.lexer.next()?;
+                    return self.read_accessor(kind == "get", name, tok.pos);
+                }
+            }
         }
 
         if let Kind::Identifier(name) = tok.kind {
@@ -837,6 +2315,35 @@ impl Parser {
         // TODO: Support all features.
         Err(Error::UnsupportedFeature(tok.pos))
     }
+
+    /// Reads the parameters and the body of the getter or the setter of the property `name`,
+    /// whose 'get' or 'set' at `pos` has been read.
+    fn read_accessor(
+        &mut self,
+        is_getter: bool,
+        name: String,
+        pos: usize,
+    ) -> Result<PropertyDefinition, Error> {
+        token_start_pos!(params_pos, self.lexer);
+        let (params, body) = self.read_function_params_and_body(None, None, false, pos)?;
+        if is_getter && !params.is_empty() {
+            let msg = "a getter must not have parameters";
+            self.show_error_at(params_pos, ErrorMsgKind::Normal, msg)
+        }
+        if !is_getter && (params.len() != 1 || params[0].is_rest_param) {
+            let msg = "a setter must have exactly one parameter";
+            self.show_error_at(params_pos, ErrorMsgKind::Normal, msg)
+        }
+
+        let span = Span::new(pos, self.read_pos());
+        let func = NodeBase::FunctionExpr(None, params, Box::new(body), span, false);
+        let func = Node::new(func, pos);
+        Ok(if is_getter {
+            PropertyDefinition::Getter(name, func)
+        } else {
+            PropertyDefinition::Setter(name, func)
+        })
+    }
 }
 
 impl Parser {
@@ -856,19 +2363,179 @@ impl Parser {
 
 impl Parser {
     fn is_declaration(&mut self) -> bool {
-        self.is_hoistable_declaration()
+        self.is_hoistable_declaration() || self.is_class_declaration()
+    }
+
+    /// https://tc39.github.io/ecma262/#prod-LexicalDeclaration
+    fn is_lexical_declaration(&mut self) -> bool {
+        self.look_ahead(|lexer| match lexer.next().map(|tok| tok.kind) {
+            Ok(Kind::Keyword(Keyword::Const)) => true,
+            // `let` is not a reserved word, so it starts a declaration only if a name or a
+            // pattern follows.
+            Ok(Kind::Identifier(ref name)) if name == "let" => {
+                match lexer.next().map(|tok| tok.kind) {
+                    Ok(Kind::Identifier(_))
+                    | Ok(Kind::Symbol(Symbol::OpeningBoxBracket))
+                    | Ok(Kind::Symbol(Symbol::OpeningBrace)) => true,
+                    _ => false,
+                }
+            }
+            _ => false,
+        })
+    }
+
+    /// Reads the `let` or the `const` that starts a lexical declaration.
+    fn read_lexical_kind(&mut self) -> Result<LexicalKind, Error> {
+        let tok = self.lexer.next()?;
+        if tok.kind == Kind::Keyword(Keyword::Const) {
+            Ok(LexicalKind::Const)
+        } else {
+            Ok(LexicalKind::Let)
+        }
     }
 
     fn read_declaration(&mut self) -> Result<Node, Error> {
         let tok = self.lexer.next()?;
         match tok.kind {
-            Kind::Keyword(Keyword::Function) => self.read_function_declaration(),
+            Kind::Keyword(Keyword::Function) => self.read_function_declaration(tok.pos, false),
+            Kind::Identifier(ref name) if name == "async" => {
+                self.lexer.next()?; // function
+                self.read_function_declaration(tok.pos, true)
+            }
+            Kind::Identifier(ref name) if name == "class" => self.read_class_declaration(tok.pos),
             _ => unreachable!(),
         }
     }
 
+    /// https://tc39.github.io/ecma262/#prod-ClassDeclaration
+    /// Reads a class declaration whose 'class' at `start` has been read. The class is bound to
+    /// its name like a variable declared with let.
+    fn read_class_declaration(&mut self, start: usize) -> Result<Node, Error> {
+        token_start_pos!(pos, self.lexer);
+        let (name, class) = self.read_class(true, start)?;
+        let decl = NodeBase::LexicalDecl(name.unwrap(), Some(Box::new(class)), LexicalKind::Let);
+        Ok(Node::new(decl, pos))
+    }
+
+    /// https://tc39.github.io/ecma262/#prod-ClassExpression
+    /// Reads a class whose 'class' at `start` has been read, and returns its name and the class.
+    /// The constructor is a function named after the class, and the other methods are function
+    /// expressions. Class bodies are strict mode code. A class whose methods use super is assigned
+    /// to a hidden variable of the enclosing function, through which they find the superclass.
+    ///
+    /// TODO: Calling a class without new should be a TypeError.
+    fn read_class(
+        &mut self,
+        needs_name: bool,
+        start: usize,
+    ) -> Result<(Option<String>, Node), Error> {
+        token_start_pos!(pos, self.lexer);
+        let name = match self.lexer.peek()?.kind {
+            // An anonymous class expression may be followed by its heritage.
+            Kind::Identifier(name) if name != "extends" => {
+                self.lexer.next()?;
+                self.check_binding_identifier(name.as_str(), pos);
+                Some(name)
+            }
+            _ if needs_name => self.show_error_at(pos, ErrorMsgKind::Normal, "expect class name"),
+            _ => None,
+        };
+
+        // The class body is strict mode code, and so is the heritage.
+        let outer_strict = ::std::mem::replace(&mut self.strict, true);
+        let heritage = if self.lexer.skip(Kind::Identifier("extends".to_string())) {
+            Some(Box::new(self.read_left_hand_side_expression()?))
+        } else {
+            None
+        };
+        self.expect(Symbol::OpeningBrace, "expect '{'")?;
+
+        let class_var = format!("%class{}", self.num_class_vars);
+        self.num_class_vars += 1;
+        let outer_uses_super = ::std::mem::replace(&mut self.uses_super, false);
+        let mut constructor = None;
+        let mut methods = vec![];
+        loop {
+            let mut tok = self.lexer.next()?;
+            let is_static = match tok.kind {
+                Kind::Symbol(Symbol::ClosingBrace) => break,
+                Kind::Symbol(Symbol::Semicolon) => continue,
+                // A method may be named static.
+                Kind::Identifier(ref name) if name == "static" => {
+                    let next = self.lexer.peek()?;
+                    next.kind != Kind::Symbol(Symbol::OpeningParen)
+                }
+                _ => false,
+            };
+            if is_static {
+                tok = self.lexer.next()?;
+            }
+            let method_name = match tok.kind {
+                Kind::Identifier(name) | Kind::String(name) => name,
+                Kind::Number(num) => format!("{}", num),
+                _ => self.show_error_at(tok.pos, ErrorMsgKind::Normal, "expect method name"),
+            };
+            let is_constructor = method_name == "constructor" && !is_static;
+            let kind = match (is_static, is_constructor, heritage.is_some()) {
+                (true, _, _) => MethodKind::StaticMethod,
+                (_, true, true) => MethodKind::DerivedConstructor,
+                (_, true, false) => MethodKind::Constructor,
+                _ => MethodKind::Method,
+            };
+            let home_object = Some((class_var.clone(), kind));
+            let (params, body) =
+                self.read_function_params_and_body(None, home_object, false, tok.pos)?;
+            if is_constructor {
+                if constructor.is_some() {
+                    self.show_error_at(
+                        tok.pos,
+                        ErrorMsgKind::Normal,
+                        "a class may only have one constructor",
+                    )
+                }
+                constructor = Some((params, body));
+            } else {
+                let span = Span::new(tok.pos, self.read_pos());
+                let method = NodeBase::FunctionExpr(None, params, Box::new(body), span, false);
+                methods.push((method_name, Node::new(method, tok.pos), is_static));
+            }
+        }
+        self.strict = outer_strict;
+
+        // The source of the constructor is the whole class, as Function.prototype.toString shows.
+        let span = Span::new(start, self.read_pos());
+        let (params, body) = match constructor {
+            Some(constructor) => constructor,
+            // constructor(...args) { super(...args); }
+            None if heritage.is_some() => {
+                self.uses_super = true;
+                let args = identifier_node(DEFAULT_CONSTRUCTOR_ARGS, pos);
+                let args = vec![Node::new(NodeBase::Spread(Box::new(args)), pos)];
+                let this = Node::new(NodeBase::This, pos);
+                let call = super_call(class_var.as_str(), this, args, pos);
+                let param = FormalParameter::new(DEFAULT_CONSTRUCTOR_ARGS.to_string(), None, true);
+                (vec![param], Node::new(NodeBase::StatementList(vec![call]), pos))
+            }
+            None => (vec![], Node::new(NodeBase::StatementList(vec![]), pos)),
+        };
+        let constructor =
+            NodeBase::FunctionExpr(name.clone(), params, Box::new(body), span, false);
+        let class = NodeBase::Class(Box::new(Node::new(constructor, pos)), heritage, methods);
+        let mut class = Node::new(class, pos);
+
+        // The methods find the class through a variable of the enclosing function.
+        if ::std::mem::replace(&mut self.uses_super, outer_uses_super) {
+            let var = identifier_node(class_var.as_str(), pos);
+            class = Node::new(NodeBase::Assign(Box::new(var), Box::new(class)), pos);
+            self.class_vars.push(class_var);
+        }
+        Ok((name, class))
+    }
+
     /// https://tc39.github.io/ecma262/#prod-FunctionDeclaration
-    fn read_function_declaration(&mut self) -> Result<Node, Error> {
+    /// Reads a function declaration whose 'function', or 'async function' if it is async, at
+    /// `start` has been read.
+    fn read_function_declaration(&mut self, start: usize, is_async: bool) -> Result<Node, Error> {
         token_start_pos!(pos, self.lexer);
         let name = if let Kind::Identifier(name) = self.lexer.next()?.kind {
             name
@@ -876,11 +2543,9 @@ impl Parser {
             self.show_error_at(pos, ErrorMsgKind::Normal, "expect function name")
         };
 
-        assert!(self.lexer.skip(Kind::Symbol(Symbol::OpeningParen)));
-        let params = self.read_formal_parameters()?;
-
-        assert!(self.lexer.skip(Kind::Symbol(Symbol::OpeningBrace)));
-        let body = self.read_statement_list(true)?;
+        let (params, body) =
+            self.read_function_params_and_body(Some(&name), None, is_async, pos)?;
+        let span = Span::new(start, self.read_pos());
 
         Ok(Node::new(
             NodeBase::FunctionDecl(FunctionDeclNode {
@@ -890,48 +2555,70 @@ impl Parser {
                 fv: HashSet::new(),
                 params: params,
                 body: Box::new(body),
+                span: span,
+                is_async: is_async,
+                in_with: false,
             }),
             pos,
         ))
     }
 
-    fn read_formal_parameters(&mut self) -> Result<FormalParameters, Error> {
+    /// Reads the parameters of a function. Also returns the statements that give them their
+    /// default values and bind their patterns, which go at the start of the body.
+    fn read_formal_parameters(&mut self) -> Result<(FormalParameters, Vec<Node>), Error> {
+        let mut params = vec![];
+        let mut prologue = vec![];
+
         if self.lexer.skip(Kind::Symbol(Symbol::ClosingParen)) {
-            return Ok(vec![]);
+            return Ok((params, prologue));
         }
 
-        let mut params = vec![];
-
         loop {
             params.push(if self.lexer.skip(Kind::Symbol(Symbol::Rest)) {
                 self.read_function_rest_parameter()?
             } else {
-                self.read_formal_parameter()?
+                self.read_formal_parameter(&mut prologue)?
             });
 
             if self.lexer.skip(Kind::Symbol(Symbol::ClosingParen)) {
                 break;
             }
 
-            assert!(self.lexer.skip(Kind::Symbol(Symbol::Comma)))
+            self.expect(Symbol::Comma, "expect ','")?;
         }
 
-        Ok(params)
+        Ok((params, prologue))
     }
 
-    // TODO: Support all features: https://tc39.github.io/ecma262/#prod-FormalParameter
-    fn read_formal_parameter(&mut self) -> Result<FormalParameter, Error> {
+    /// https://tc39.github.io/ecma262/#prod-FormalParameter
+    /// A parameter that is a pattern is passed in a variable of its own, which the statements
+    /// appended to `prologue` destructure.
+    fn read_formal_parameter(
+        &mut self,
+        prologue: &mut Vec<Node>,
+    ) -> Result<FormalParameter, Error> {
         token_start_pos!(pos, self.lexer);
-        let name = if let Kind::Identifier(name) = self.lexer.next()?.kind {
-            name
-        } else {
-            self.show_error_at(
+        let tok = self.lexer.next()?;
+        let (name, pattern_decls) = match tok.kind {
+            Kind::Identifier(name) => (name, vec![]),
+            Kind::Symbol(Symbol::OpeningBoxBracket) | Kind::Symbol(Symbol::OpeningBrace) => {
+                self.lexer.unget(&tok);
+                let var = self.new_pattern_var();
+                let mut pattern_decls = vec![];
+                self.read_binding_pattern(var.as_str(), &mut pattern_decls)?;
+                (var, pattern_decls)
+            }
+            _ => self.show_error_at(
                 pos,
                 ErrorMsgKind::Normal,
                 "expect identifier (unsupported feature)",
-            );
+            ),
         };
-        // TODO: Implement initializer.
+        if self.lexer.skip(Kind::Symbol(Symbol::Assign)) {
+            let default = self.read_assignment_expression()?;
+            prologue.push(assign_if_undefined(name.as_str(), default, tok.pos));
+        }
+        prologue.extend(pattern_decls);
         Ok(FormalParameter::new(name, None, false))
     }
 
@@ -958,14 +2645,28 @@ impl Parser {
     fn is_hoistable_declaration(&mut self) -> bool {
         self.is_function_declaration()
     }
+
+    /// https://tc39.github.io/ecma262/#prod-ClassDeclaration
+    fn is_class_declaration(&mut self) -> bool {
+        match self.lexer.peek() {
+            Ok(tok) => tok.kind == Kind::Identifier("class".to_string()),
+            Err(_) => false,
+        }
+    }
 }
 
 impl Parser {
     /// https://tc39.github.io/ecma262/#prod-FunctionDeclaration
+    /// https://tc39.github.io/ecma262/#prod-AsyncFunctionDeclaration
     fn is_function_declaration(&mut self) -> bool {
-        match self.lexer.peek() {
-            Ok(tok) => tok.is_the_keyword(Keyword::Function),
-            Err(_) => false,
+        let (tok, next) = self.look_ahead(|lexer| (lexer.next(), lexer.read_token()));
+        match (tok, next) {
+            (Ok(ref tok), _) if tok.is_the_keyword(Keyword::Function) => true,
+            // No line terminator is allowed between 'async' and 'function'.
+            (Ok(ref tok), Ok(ref next)) if tok.kind == Kind::Identifier("async".to_string()) => {
+                next.is_the_keyword(Keyword::Function)
+            }
+            _ => false,
         }
     }
 }
@@ -1006,6 +2707,15 @@ fn boolean() {
     );
 }
 
+#[test]
+fn null() {
+    let mut parser = Parser::new("null".to_string());
+    assert_eq!(
+        parser.parse_all(),
+        Node::new(NodeBase::StatementList(vec![Node::new(NodeBase::Null, 0)]), 0)
+    );
+}
+
 #[test]
 fn identifier() {
     let mut parser = Parser::new("variable".to_string());
@@ -1190,6 +2900,7 @@ fn simple_expr_rel() {
         ("1 + 2 > 3", BinOp::Gt, 8),
         ("1 + 2 <= 3", BinOp::Le, 9),
         ("1 + 2 >= 3", BinOp::Ge, 9),
+        ("1 + 2 instanceof 3", BinOp::InstanceOf, 17),
     ].iter()
     {
         let mut parser = Parser::new(input.to_string());
@@ -1422,7 +3133,7 @@ fn simple_expr_new() {
                         Box::new(Node::new(NodeBase::Identifier("f".to_string()), 4)),
                         vec![Node::new(NodeBase::Number(1.0), 6)],
                     ),
-                    5,
+                    3,
                 ))),
                 3,
             )]),
@@ -1485,6 +3196,88 @@ fn call() {
     }
 }
 
+#[test]
+fn arrow_function() {
+    let mut parser = Parser::new("(a) => a".to_string());
+    assert_eq!(
+        parser.parse_all(),
+        Node::new(
+            NodeBase::StatementList(vec![Node::new(
+                NodeBase::FunctionExpr(
+                    None,
+                    vec![FormalParameter::new("a".to_string(), None, false)],
+                    Box::new(Node::new(
+                        NodeBase::StatementList(vec![Node::new(
+                            NodeBase::Return(Some(Box::new(Node::new(
+                                NodeBase::Identifier("a".to_string()),
+                                7,
+                            )))),
+                            7,
+                        )]),
+                        7,
+                    )),
+                    Span::new(0, 8),
+                    false,
+                ),
+                0,
+            )]),
+            0
+        )
+    );
+}
+
+#[test]
+fn arrow_function_this() {
+    let mut parser = Parser::new("x => this".to_string());
+    assert_eq!(
+        parser.parse_all(),
+        Node::new(
+            NodeBase::StatementList(vec![
+                Node::new(
+                    NodeBase::VarDecl(
+                        "%this".to_string(),
+                        Some(Box::new(Node::new(NodeBase::This, 0))),
+                    ),
+                    0,
+                ),
+                Node::new(
+                    NodeBase::FunctionExpr(
+                        None,
+                        vec![FormalParameter::new("x".to_string(), None, false)],
+                        Box::new(Node::new(
+                            NodeBase::StatementList(vec![Node::new(
+                                NodeBase::Return(Some(Box::new(Node::new(
+                                    NodeBase::Identifier("%this".to_string()),
+                                    5,
+                                )))),
+                                5,
+                            )]),
+                            5,
+                        )),
+                        Span::new(0, 9),
+                        false,
+                    ),
+                    0,
+                ),
+            ]),
+            0
+        )
+    );
+}
+
+#[test]
+fn call_on_next_line() {
+    for (input, warnings) in vec![
+        ("var a = b\n(function() {})()", 1),
+        ("var a = b;\n(function() {})()", 0),
+        ("f(1,\n  (2))", 0),
+    ] {
+        let mut parser = Parser::new(input.to_string());
+        parser.parse_all();
+        assert_eq!(parser.warnings.len(), warnings, "{}", input);
+    }
+}
+
 #[test]
 fn member() {
     for (input, node) in [
@@ -1548,6 +3341,42 @@ fn var_decl() {
     );
 }
 
+#[test]
+fn lexical_decl() {
+    let mut parser = Parser::new("let a, b = 21; const c = a".to_string());
+    assert_eq!(
+        parser.parse_all(),
+        Node::new(
+            NodeBase::StatementList(vec![
+                Node::new(
+                    NodeBase::LexicalDecl("a".to_string(), None, LexicalKind::Let),
+                    3,
+                ),
+                Node::new(
+                    NodeBase::LexicalDecl(
+                        "b".to_string(),
+                        Some(Box::new(Node::new(NodeBase::Number(21.0), 11))),
+                        LexicalKind::Let,
+                    ),
+                    6,
+                ),
+                Node::new(
+                    NodeBase::LexicalDecl(
+                        "c".to_string(),
+                        Some(Box::new(Node::new(
+                            NodeBase::Identifier("a".to_string()),
+                            25,
+                        ))),
+                        LexicalKind::Const,
+                    ),
+                    20,
+                ),
+            ]),
+            0
+        )
+    );
+}
+
 #[test]
 fn block() {
     let mut parser = Parser::new("{ a=1 }".to_string());
@@ -1579,7 +3408,7 @@ fn break_() {
                 NodeBase::While(
                     Box::new(Node::new(NodeBase::Number(1.0), 6)),
                     Box::new(Node::new(
-                        NodeBase::StatementList(vec![Node::new(NodeBase::Break, 9)]),
+                        NodeBase::StatementList(vec![Node::new(NodeBase::Break(None), 9)]),
                         9,
                     )),
                 ),
@@ -1600,7 +3429,7 @@ fn continue_() {
                 NodeBase::While(
                     Box::new(Node::new(NodeBase::Number(1.0), 6)),
                     Box::new(Node::new(
-                        NodeBase::StatementList(vec![Node::new(NodeBase::Continue, 9)]),
+                        NodeBase::StatementList(vec![Node::new(NodeBase::Continue(None), 9)]),
                         9,
                     )),
                 ),
@@ -1611,6 +3440,46 @@ fn continue_() {
     );
 }
 
+#[test]
+fn labels() {
+    let mut parser = Parser::new("a: b: while(1){continue a\nbreak\nb}".to_string());
+    assert_eq!(
+        parser.parse_all(),
+        Node::new(
+            NodeBase::StatementList(vec![Node::new(
+                NodeBase::Label(
+                    "a".to_string(),
+                    Box::new(Node::new(
+                        NodeBase::Label(
+                            "b".to_string(),
+                            Box::new(Node::new(
+                                NodeBase::While(
+                                    Box::new(Node::new(NodeBase::Number(1.0), 12)),
+                                    Box::new(Node::new(
+                                        NodeBase::StatementList(vec![
+                                            Node::new(
+                                                NodeBase::Continue(Some("a".to_string())),
+                                                15,
+                                            ),
+                                            Node::new(NodeBase::Break(None), 26),
+                                            Node::new(NodeBase::Identifier("b".to_string()), 32),
+                                        ]),
+                                        15,
+                                    )),
+                                ),
+                                11,
+                            )),
+                        ),
+                        3,
+                    )),
+                ),
+                0,
+            )]),
+            0
+        )
+    );
+}
+
 #[test]
 fn return_() {
     for (input, node) in [
@@ -1740,6 +3609,9 @@ fn function_decl() {
                     fv: HashSet::new(),
                     params: vec![],
                     body: Box::new(Node::new(NodeBase::StatementList(vec![]), 14)),
+                    span: Span::new(0, 16),
+                    is_async: false,
+                    in_with: false,
                 }),
                 8,
             ),
@@ -1770,6 +3642,9 @@ fn function_decl() {
                         )]),
                         18,
                     )),
+                    span: Span::new(0, 33),
+                    is_async: false,
+                    in_with: false,
                 }),
                 8,
             ),
@@ -1783,3 +3658,437 @@ fn function_decl() {
         );
     }
 }
+
+#[test]
+fn use_strict() {
+    for (input, strict) in vec![
+        ("'use strict'; a", true),
+        ("// comment\n\"use strict\"\na", true),
+        ("\"use strict\" + 1", false),
+        ("a; 'use strict'", false),
+        ("function f() { 'use strict' }", false),
+    ] {
+        let mut parser = Parser::new(input.to_string());
+        parser.parse_all();
+        assert_eq!(parser.strict, strict, "{}", input);
+    }
+}
+
+#[test]
+fn recover() {
+    let mut parser = Parser::new("var = 1\nvar a = 2\nf(;\nvar b = 3\n".to_string());
+    parser.recover = true;
+    match parser.parse_all().base {
+        NodeBase::StatementList(ref items) => assert_eq!(items.len(), 2, "{:?}", items),
+        _ => unreachable!(),
+    }
+    assert_eq!(
+        parser
+            .errors
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<String>>(),
+        vec![
+            "parse error: expect a variable name".to_string(),
+            "parse error: unexpected token".to_string(),
+        ]
+    );
+}
+
+#[test]
+fn precedence() {
+    // Writes `node` with every operation in parentheses.
+    fn parenthesize(node: &Node) -> String {
+        let list = |nodes: &Vec<Node>| {
+            nodes
+                .iter()
+                .map(parenthesize)
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+        match node.base {
+            NodeBase::StatementList(ref nodes) => nodes
+                .iter()
+                .map(parenthesize)
+                .collect::<Vec<String>>()
+                .join("; "),
+            NodeBase::Identifier(ref name) => name.clone(),
+            NodeBase::Number(n) => format!("{}", n),
+            NodeBase::BinaryOp(ref lhs, ref rhs, ref op) => {
+                let op = match *op {
+                    BinOp::Add => "+",
+                    BinOp::Sub => "-",
+                    BinOp::Mul => "*",
+                    BinOp::Div => "/",
+                    BinOp::Rem => "%",
+                    BinOp::Exp => "**",
+                    BinOp::And => "&",
+                    BinOp::Or => "|",
+                    BinOp::Xor => "^",
+                    BinOp::LAnd => "&&",
+                    BinOp::LOr => "||",
+                    BinOp::Eq => "==",
+                    BinOp::Ne => "!=",
+                    BinOp::SEq => "===",
+                    BinOp::SNe => "!==",
+                    BinOp::Lt => "<",
+                    BinOp::Gt => ">",
+                    BinOp::Le => "<=",
+                    BinOp::Ge => ">=",
+                    BinOp::In => "in",
+                    BinOp::InstanceOf => "instanceof",
+                    BinOp::Shl => "<<",
+                    BinOp::Shr => ">>",
+                    BinOp::ZFShr => ">>>",
+                    BinOp::Comma => ",",
+                    BinOp::Assign => "=",
+                };
+                format!("({} {} {})", parenthesize(lhs), op, parenthesize(rhs))
+            }
+            NodeBase::UnaryOp(ref expr, UnaryOp::PoInc) => format!("({}++)", parenthesize(expr)),
+            NodeBase::UnaryOp(ref expr, UnaryOp::PoDec) => format!("({}--)", parenthesize(expr)),
+            NodeBase::UnaryOp(ref expr, ref op) => {
+                let op = match *op {
+                    UnaryOp::Delete => "delete ",
+                    UnaryOp::Void => "void ",
+                    UnaryOp::Typeof => "typeof ",
+                    UnaryOp::Await => "await ",
+                    UnaryOp::Plus => "+",
+                    UnaryOp::Minus => "-",
+                    UnaryOp::BitwiseNot => "~",
+                    UnaryOp::Not => "!",
+                    UnaryOp::PrInc => "++",
+                    UnaryOp::PrDec => "--",
+                    UnaryOp::PoInc | UnaryOp::PoDec => unreachable!(),
+                };
+                format!("({}{})", op, parenthesize(expr))
+            }
+            NodeBase::TernaryOp(ref cond, ref then, ref else_) => format!(
+                "({} ? {} : {})",
+                parenthesize(cond),
+                parenthesize(then),
+                parenthesize(else_)
+            ),
+            NodeBase::Assign(ref dst, ref src) => {
+                format!("({} = {})", parenthesize(dst), parenthesize(src))
+            }
+            NodeBase::CompoundAssign(ref dst, ref src, BinOp::Mul) => {
+                format!("({} *= {})", parenthesize(dst), parenthesize(src))
+            }
+            NodeBase::CompoundAssign(ref dst, ref src, BinOp::Rem) => {
+                format!("({} %= {})", parenthesize(dst), parenthesize(src))
+            }
+            NodeBase::New(ref expr) => format!("(new {})", parenthesize(expr)),
+            NodeBase::Call(ref callee, ref args) => {
+                format!("{}({})", parenthesize(callee), list(args))
+            }
+            NodeBase::Member(ref parent, ref name) => format!("{}.{}", parenthesize(parent), name),
+            NodeBase::Index(ref parent, ref idx) => {
+                format!("{}[{}]", parenthesize(parent), parenthesize(idx))
+            }
+            NodeBase::Array(ref elems) => format!("[{}]", list(elems)),
+            NodeBase::Spread(ref expr) => format!("...{}", parenthesize(expr)),
+            ref base => panic!("{:?}", base),
+        }
+    }
+
+    for (input, expected) in [
+        ("a || b || c", "((a || b) || c)"),
+        ("a || b && c", "(a || (b && c))"),
+        ("a || b | c", "(a || (b | c))"),
+        ("a || b ^ c", "(a || (b ^ c))"),
+        ("a || b & c", "(a || (b & c))"),
+        ("a || b == c", "(a || (b == c))"),
+        ("a || b < c", "(a || (b < c))"),
+        ("a || b << c", "(a || (b << c))"),
+        ("a || b + c", "(a || (b + c))"),
+        ("a || b * c", "(a || (b * c))"),
+        ("a || b ** c", "(a || (b ** c))"),
+        ("a && b || c", "((a && b) || c)"),
+        ("a && b && c", "((a && b) && c)"),
+        ("a && b | c", "(a && (b | c))"),
+        ("a && b ^ c", "(a && (b ^ c))"),
+        ("a && b & c", "(a && (b & c))"),
+        ("a && b == c", "(a && (b == c))"),
+        ("a && b < c", "(a && (b < c))"),
+        ("a && b << c", "(a && (b << c))"),
+        ("a && b + c", "(a && (b + c))"),
+        ("a && b * c", "(a && (b * c))"),
+        ("a && b ** c", "(a && (b ** c))"),
+        ("a | b || c", "((a | b) || c)"),
+        ("a | b && c", "((a | b) && c)"),
+        ("a | b | c", "((a | b) | c)"),
+        ("a | b ^ c", "(a | (b ^ c))"),
+        ("a | b & c", "(a | (b & c))"),
+        ("a | b == c", "(a | (b == c))"),
+        ("a | b < c", "(a | (b < c))"),
+        ("a | b << c", "(a | (b << c))"),
+        ("a | b + c", "(a | (b + c))"),
+        ("a | b * c", "(a | (b * c))"),
+        ("a | b ** c", "(a | (b ** c))"),
+        ("a ^ b || c", "((a ^ b) || c)"),
+        ("a ^ b && c", "((a ^ b) && c)"),
+        ("a ^ b | c", "((a ^ b) | c)"),
+        ("a ^ b ^ c", "((a ^ b) ^ c)"),
+        ("a ^ b & c", "(a ^ (b & c))"),
+        ("a ^ b == c", "(a ^ (b == c))"),
+        ("a ^ b < c", "(a ^ (b < c))"),
+        ("a ^ b << c", "(a ^ (b << c))"),
+        ("a ^ b + c", "(a ^ (b + c))"),
+        ("a ^ b * c", "(a ^ (b * c))"),
+        ("a ^ b ** c", "(a ^ (b ** c))"),
+        ("a & b || c", "((a & b) || c)"),
+        ("a & b && c", "((a & b) && c)"),
+        ("a & b | c", "((a & b) | c)"),
+        ("a & b ^ c", "((a & b) ^ c)"),
+        ("a & b & c", "((a & b) & c)"),
+        ("a & b == c", "(a & (b == c))"),
+        ("a & b < c", "(a & (b < c))"),
+        ("a & b << c", "(a & (b << c))"),
+        ("a & b + c", "(a & (b + c))"),
+        ("a & b * c", "(a & (b * c))"),
+        ("a & b ** c", "(a & (b ** c))"),
+        ("a == b || c", "((a == b) || c)"),
+        ("a == b && c", "((a == b) && c)"),
+        ("a == b | c", "((a == b) | c)"),
+        ("a == b ^ c", "((a == b) ^ c)"),
+        ("a == b & c", "((a == b) & c)"),
+        ("a == b == c", "((a == b) == c)"),
+        ("a == b < c", "(a == (b < c))"),
+        ("a == b << c", "(a == (b << c))"),
+        ("a == b + c", "(a == (b + c))"),
+        ("a == b * c", "(a == (b * c))"),
+        ("a == b ** c", "(a == (b ** c))"),
+        ("a < b || c", "((a < b) || c)"),
+        ("a < b && c", "((a < b) && c)"),
+        ("a < b | c", "((a < b) | c)"),
+        ("a < b ^ c", "((a < b) ^ c)"),
+        ("a < b & c", "((a < b) & c)"),
+        ("a < b == c", "((a < b) == c)"),
+        ("a < b < c", "((a < b) < c)"),
+        ("a < b << c", "(a < (b << c))"),
+        ("a < b + c", "(a < (b + c))"),
+        ("a < b * c", "(a < (b * c))"),
+        ("a < b ** c", "(a < (b ** c))"),
+        ("a << b || c", "((a << b) || c)"),
+        ("a << b && c", "((a << b) && c)"),
+        ("a << b | c", "((a << b) | c)"),
+        ("a << b ^ c", "((a << b) ^ c)"),
+        ("a << b & c", "((a << b) & c)"),
+        ("a << b == c", "((a << b) == c)"),
+        ("a << b < c", "((a << b) < c)"),
+        ("a << b << c", "((a << b) << c)"),
+        ("a << b + c", "(a << (b + c))"),
+        ("a << b * c", "(a << (b * c))"),
+        ("a << b ** c", "(a << (b ** c))"),
+        ("a + b || c", "((a + b) || c)"),
+        ("a + b && c", "((a + b) && c)"),
+        ("a + b | c", "((a + b) | c)"),
+        ("a + b ^ c", "((a + b) ^ c)"),
+        ("a + b & c", "((a + b) & c)"),
+        ("a + b == c", "((a + b) == c)"),
+        ("a + b < c", "((a + b) < c)"),
+        ("a + b << c", "((a + b) << c)"),
+        ("a + b + c", "((a + b) + c)"),
+        ("a + b * c", "(a + (b * c))"),
+        ("a + b ** c", "(a + (b ** c))"),
+        ("a * b || c", "((a * b) || c)"),
+        ("a * b && c", "((a * b) && c)"),
+        ("a * b | c", "((a * b) | c)"),
+        ("a * b ^ c", "((a * b) ^ c)"),
+        ("a * b & c", "((a * b) & c)"),
+        ("a * b == c", "((a * b) == c)"),
+        ("a * b < c", "((a * b) < c)"),
+        ("a * b << c", "((a * b) << c)"),
+        ("a * b + c", "((a * b) + c)"),
+        ("a * b * c", "((a * b) * c)"),
+        ("a * b ** c", "(a * (b ** c))"),
+        ("a ** b || c", "((a ** b) || c)"),
+        ("a ** b && c", "((a ** b) && c)"),
+        ("a ** b | c", "((a ** b) | c)"),
+        ("a ** b ^ c", "((a ** b) ^ c)"),
+        ("a ** b & c", "((a ** b) & c)"),
+        ("a ** b == c", "((a ** b) == c)"),
+        ("a ** b < c", "((a ** b) < c)"),
+        ("a ** b << c", "((a ** b) << c)"),
+        ("a ** b + c", "((a ** b) + c)"),
+        ("a ** b * c", "((a ** b) * c)"),
+        ("a ** b ** c", "(a ** (b ** c))"),
+        ("a - b + c", "((a - b) + c)"),
+        ("a + b - c", "((a + b) - c)"),
+        ("a / b * c", "((a / b) * c)"),
+        ("a % b / c", "((a % b) / c)"),
+        ("a * b % c", "((a * b) % c)"),
+        ("a === b !== c", "((a === b) !== c)"),
+        ("a != b == c", "((a != b) == c)"),
+        ("a >= b < c", "((a >= b) < c)"),
+        ("a <= b > c", "((a <= b) > c)"),
+        ("a >>> b >> c", "((a >>> b) >> c)"),
+        ("a << b >>> c", "((a << b) >>> c)"),
+        ("a % b << c >> d", "(((a % b) << c) >> d)"),
+        ("a | b || c === d", "((a | b) || (c === d))"),
+        ("a * b && c | d", "((a * b) && (c | d))"),
+        ("a < b / c >>> d", "(a < ((b / c) >>> d))"),
+        ("a && b - c < d", "(a && ((b - c) < d))"),
+        ("a & b < c && d", "((a & (b < c)) && d)"),
+        ("a >>> b % c >= d", "((a >>> (b % c)) >= d)"),
+        ("a - b % c ^ d", "((a - (b % c)) ^ d)"),
+        ("a !== b || c | d", "((a !== b) || (c | d))"),
+        ("a >> b === c % d", "((a >> b) === (c % d))"),
+        ("a >>> b / c != d", "((a >>> (b / c)) != d)"),
+        ("a || b == c >= d", "(a || (b == (c >= d)))"),
+        ("a | b == c != d", "(a | ((b == c) != d))"),
+        ("a || b >>> c >= d", "(a || ((b >>> c) >= d))"),
+        ("a !== b << c || d", "((a !== (b << c)) || d)"),
+        ("a !== b << c + d", "(a !== (b << (c + d)))"),
+        ("a << b << c >= d", "(((a << b) << c) >= d)"),
+        ("a || b - c * d", "(a || (b - (c * d)))"),
+        ("a % b != c / d", "((a % b) != (c / d))"),
+        ("a && b <= c != d", "(a && ((b <= c) != d))"),
+        ("a - b < c == d", "(((a - b) < c) == d)"),
+        ("a / b || c > d", "((a / b) || (c > d))"),
+        ("a < b >= c ** d", "((a < b) >= (c ** d))"),
+        ("a !== b != c + d", "((a !== b) != (c + d))"),
+        ("a >> b >>> c || d", "(((a >> b) >>> c) || d)"),
+        ("a != b & c >>> d", "((a != b) & (c >>> d))"),
+        ("a > b != c - d", "((a > b) != (c - d))"),
+        ("a != b << c != d", "((a != (b << c)) != d)"),
+        ("a >= b - c >> d", "(a >= ((b - c) >> d))"),
+        ("a & b > c ^ d", "((a & (b > c)) ^ d)"),
+        ("a ** b != c % d", "((a ** b) != (c % d))"),
+        ("a >= b && c > d", "((a >= b) && (c > d))"),
+        ("a | b > c != d", "(a | ((b > c) != d))"),
+        ("a !== b != c && d", "(((a !== b) != c) && d)"),
+        ("a && b * c >= d", "(a && ((b * c) >= d))"),
+        ("a / b << c / d", "((a / b) << (c / d))"),
+        ("a != b / c === d", "((a != (b / c)) === d)"),
+        ("a == b == c ** d", "((a == b) == (c ** d))"),
+        ("a > b >> c ^ d", "((a > (b >> c)) ^ d)"),
+        ("a & b >>> c * d", "(a & (b >>> (c * d)))"),
+        ("-a * b", "((-a) * b)"),
+        ("!a == b", "((!a) == b)"),
+        ("typeof a + b", "((typeof a) + b)"),
+        ("void a || b", "((void a) || b)"),
+        ("~a & b", "((~a) & b)"),
+        ("- -a", "(-(-a))"),
+        ("!!a", "(!(!a))"),
+        ("typeof typeof a", "(typeof (typeof a))"),
+        ("-a.b", "(-a.b)"),
+        ("!f()", "(!f())"),
+        ("typeof a.b(c)", "(typeof a.b(c))"),
+        ("delete a[b]", "(delete a[b])"),
+        ("a * -b", "(a * (-b))"),
+        ("a - -b", "(a - (-b))"),
+        ("a + +b", "(a + (+b))"),
+        ("a ** -b", "(a ** (-b))"),
+        ("(-a) ** b", "((-a) ** b)"),
+        ("!a++", "(!(a++))"),
+        ("-a++ * b", "((-(a++)) * b)"),
+        ("++a * b", "((++a) * b)"),
+        ("a-- - b", "((a--) - b)"),
+        ("a ** b++", "(a ** (b++))"),
+        ("a++ ** b", "((a++) ** b)"),
+        ("--a.b", "(--a.b)"),
+        ("a ? b : c ? d : e", "(a ? b : (c ? d : e))"),
+        ("a ? b ? c : d : e", "(a ? (b ? c : d) : e)"),
+        ("a || b ? c : d", "((a || b) ? c : d)"),
+        ("a ? b || c : d && e", "(a ? (b || c) : (d && e))"),
+        ("a = b ? c : d", "(a = (b ? c : d))"),
+        ("a ? b = c : d", "(a ? (b = c) : d)"),
+        ("a ? b : c = d", "(a ? b : (c = d))"),
+        ("a ? b : c ? d : e = f", "(a ? b : (c ? d : (e = f)))"),
+        ("a = b = c", "(a = (b = c))"),
+        ("a = b || c", "(a = (b || c))"),
+        ("a.b = c + d", "(a.b = (c + d))"),
+        ("a[b] = c = d", "(a[b] = (c = d))"),
+        ("a += b * c", "(a = (a + (b * c)))"),
+        ("a -= b -= c", "(a = (a - (b = (b - c))))"),
+        ("a.b *= c + d", "(a.b *= (c + d))"),
+        ("a[b] %= c ? d : e", "(a[b] %= (c ? d : e))"),
+        ("a = (b, c)", "(a = (b , c))"),
+        ("a, b = c", "(a , (b = c))"),
+        ("a = b, c", "((a = b) , c)"),
+        ("a ? b : c, d", "((a ? b : c) , d)"),
+        ("a ? (b, c) : d", "(a ? (b , c) : d)"),
+        ("a, b, c", "((a , b) , c)"),
+        ("new f", "(new f)"),
+        ("new f()", "(new f())"),
+        ("new f(a).b", "(new f(a)).b"),
+        ("new f(a)[b]", "(new f(a))[b]"),
+        ("new f(a).g(b)", "(new f(a)).g(b)"),
+        ("new f()()", "(new f())()"),
+        ("new f.g(a)", "(new f.g(a))"),
+        ("new f.g", "(new f.g)"),
+        ("new f[a](b)", "(new f[a](b))"),
+        ("new new f()()", "(new (new f())())"),
+        ("new new f", "(new (new f))"),
+        ("new (f())", "(new f()())"),
+        ("new (f())()", "(new f()())"),
+        ("new f(a) + b", "((new f(a)) + b)"),
+        ("-new f(a).b", "(-(new f(a)).b)"),
+        ("typeof new f", "(typeof (new f))"),
+        ("a.b.c", "a.b.c"),
+        ("a.b(c).d[e](f)", "a.b(c).d[e](f)"),
+        ("a(b)(c)", "a(b)(c)"),
+        ("a[b + c] * d", "(a[(b + c)] * d)"),
+        ("f(a, b = c)", "f(a, (b = c))"),
+        ("f(a ? b : c, d)", "f((a ? b : c), d)"),
+        ("[a, b + c][0]", "[a, (b + c)][0]"),
+        ("f(...a, b)", "f(...a, b)"),
+        ("[a, ...b || c]", "[a, ...(b || c)]"),
+        ("(a + b) * c", "((a + b) * c)"),
+        ("a * (b + c)", "(a * (b + c))"),
+        ("a - (b - c)", "(a - (b - c))"),
+        ("(a ** b) ** c", "((a ** b) ** c)"),
+        ("(a, b) + c", "((a , b) + c)"),
+        ("a\n- b", "(a - b)"),
+        ("a *\n-b", "(a * (-b))"),
+        ("a\n++b", "a; (++b)"),
+        ("a\n? b\n: c", "(a ? b : c)"),
+        ("a in b < c", "((a in b) < c)"),
+        ("a < b in c", "((a < b) in c)"),
+        ("a + b in c == d", "(((a + b) in c) == d)"),
+    ].iter()
+    {
+        let node = Parser::new(input.to_string()).parse();
+        assert_eq!(node.map(|node| parenthesize(&node)), Ok(expected.to_string()), "{}", input);
+    }
+
+    for input in [
+        "-a ** b",
+        "typeof a ** b",
+        "!a ** b",
+        "a + b = c",
+        "(a ? b : c) = d",
+        "a++ = b",
+        "a ** b = c",
+        "a ? b, c : d",
+        "1 = a",
+        "new f(a) = b",
+    ].iter()
+    {
+        assert!(Parser::new(input.to_string()).parse().is_err(), "{}", input);
+    }
+}
+
+#[test]
+fn tagged_template() {
+    let mut parser = Parser::new("t`a${x}\\xz`".to_string());
+    assert_eq!(
+        parser.parse_all(),
+        Node::new(
+            NodeBase::StatementList(vec![Node::new(
+                NodeBase::TaggedTemplate(
+                    Box::new(Node::new(NodeBase::Identifier("t".to_string()), 0)),
+                    vec![
+                        (Some("a".to_string()), "a".to_string()),
+                        (None, "\\xz".to_string()),
+                    ],
+                    vec![Node::new(NodeBase::Identifier("x".to_string()), 5)],
+                ),
+                1,
+            )]),
+            0
+        )
+    );
+}
@@ -0,0 +1,233 @@
+//! Promises, and the microtask queue that runs what waits for them. An async function returns a
+//! promise, and `await` suspends the function until the value it waits for is settled.
+//!
+//! TODO: Report rejections that nothing handles once the queue is empty, as Node does.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+use builtin;
+use vm::{object_addr, type_of, CallFrame, PropertyMap, Value, VM};
+
+/// The frame of an async function suspended by `await`, cut from the operand stack. The registers
+/// are relative to the start of the frame, where `this` is.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SuspendedFunction {
+    pub stack: Vec<Value>,
+    pub pc: isize, // Where to resume, after the `await`
+    pub bp: usize,
+    pub lp: usize,
+    pub argc: usize,
+    pub func_pos: usize,
+    pub env: Value,
+    pub promise: Value, // What the function returned, which is rejected if the awaited value is
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PromiseState {
+    Pending,
+    Fulfilled(Value),
+    Rejected(Value),
+}
+
+/// What to do once a promise is settled.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reaction {
+    Resume(SuspendedFunction), // An async function waits for the value
+    Resolve(Value),            // Another promise was resolved with this one
+    Then(Value, Value, Value), // The callbacks of then(), or undefined, and the promise it returned
+    Finally(Value, Value),     // The callback of finally(), and the promise it returned
+}
+
+/// A reaction run once the script, and every microtask queued before it, has finished. It is
+/// given the state its promise settled in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Microtask(pub Reaction, pub PromiseState);
+
+pub struct PromiseRecord {
+    pub object: Weak<RefCell<PropertyMap>>, // Keeps the address from being taken by other objects
+    pub state: PromiseState,
+    pub reactions: Vec<Reaction>,
+}
+
+/// The state of every promise alive, by the address of its object like IntegrityLevels.
+pub type Promises = HashMap<usize, PromiseRecord>;
+
+/// Returns the prototype that every promise shares.
+pub fn new_promise_prototype() -> Value {
+    let mut map = PropertyMap::new();
+    for &(name, id) in &[
+        ("then", builtin::PROMISE_PROTOTYPE_THEN),
+        ("catch", builtin::PROMISE_PROTOTYPE_CATCH),
+        ("finally", builtin::PROMISE_PROTOTYPE_FINALLY),
+    ] {
+        map.insert(
+            name.to_string(),
+            Value::NeedThis(Box::new(Value::BuiltinFunction(id))),
+        );
+    }
+    Value::Object(Rc::new(RefCell::new(map)))
+}
+
+impl VM {
+    pub fn new_promise(&mut self) -> Value {
+        let object = Rc::new(RefCell::new({
+            let mut map = PropertyMap::new();
+            map.insert("__proto__".to_string(), self.promise_prototype.clone());
+            map
+        }));
+        // Forget the promises that have been freed every time the table doubles.
+        if self.promises.len().is_power_of_two() {
+            self.promises
+                .retain(|_, record| record.object.upgrade().is_some());
+        }
+        let promise = Value::Object(object.clone());
+        self.promises.insert(
+            object_addr(&promise).unwrap(),
+            PromiseRecord {
+                object: Rc::downgrade(&object),
+                state: PromiseState::Pending,
+                reactions: vec![],
+            },
+        );
+        self.stats.allocations += 1;
+        promise
+    }
+
+    fn promise_record(&mut self, val: &Value) -> Option<&mut PromiseRecord> {
+        if self.promises.is_empty() {
+            return None;
+        }
+        let addr = object_addr(val)?;
+        self.promises.get_mut(&addr)
+    }
+
+    pub fn is_promise(&mut self, val: &Value) -> bool {
+        self.promise_record(val).is_some()
+    }
+
+    /// Resolves `promise` with `val`. A promise resolved with another promise follows it, and
+    /// one already settled is left as it is.
+    pub fn resolve_promise(&mut self, promise: &Value, val: Value) {
+        if self.is_promise(&val) {
+            if object_addr(&val) == object_addr(promise) {
+                let reason = builtin::new_string("chaining cycle detected for promise".to_string());
+                return self.reject_promise(promise, reason);
+            }
+            self.add_reaction(&val, Reaction::Resolve(promise.clone()));
+        } else {
+            self.settle(promise, PromiseState::Fulfilled(val))
+        }
+    }
+
+    pub fn reject_promise(&mut self, promise: &Value, reason: Value) {
+        self.settle(promise, PromiseState::Rejected(reason))
+    }
+
+    fn settle(&mut self, promise: &Value, state: PromiseState) {
+        let reactions = match self.promise_record(promise) {
+            Some(ref mut record) if record.state == PromiseState::Pending => {
+                record.state = state.clone();
+                ::std::mem::replace(&mut record.reactions, vec![])
+            }
+            _ => return,
+        };
+        for reaction in reactions {
+            self.microtasks
+                .push_back(Microtask(reaction, state.clone()));
+        }
+    }
+
+    /// Runs `reaction` once `promise` is settled, after the current microtask at the earliest.
+    pub fn add_reaction(&mut self, promise: &Value, reaction: Reaction) {
+        let state = {
+            let record = self.promise_record(promise).unwrap();
+            if record.state == PromiseState::Pending {
+                return record.reactions.push(reaction);
+            }
+            record.state.clone()
+        };
+        self.microtasks.push_back(Microtask(reaction, state));
+    }
+
+    /// Resumes `func` once `val` is settled, or right after the current microtask if `val` is not
+    /// a promise.
+    pub fn await_value(&mut self, func: SuspendedFunction, val: Value) {
+        if self.is_promise(&val) {
+            self.add_reaction(&val, Reaction::Resume(func))
+        } else {
+            let task = Microtask(Reaction::Resume(func), PromiseState::Fulfilled(val));
+            self.microtasks.push_back(task)
+        }
+    }
+
+    /// Runs the queued microtasks, and those they queue, until none is left.
+    pub fn run_microtasks(&mut self) {
+        while let Some(Microtask(reaction, state)) = self.microtasks.pop_front() {
+            self.react(reaction, state)
+        }
+    }
+
+    fn react(&mut self, reaction: Reaction, state: PromiseState) {
+        match (reaction, state) {
+            (Reaction::Resume(func), PromiseState::Fulfilled(val)) => self.resume(func, val),
+            // Errors cannot be caught yet, so the function gives up and rejects its promise.
+            (Reaction::Resume(func), PromiseState::Rejected(reason)) => {
+                self.reject_promise(&func.promise, reason)
+            }
+            (Reaction::Then(on_fulfilled, on_rejected, promise), state) => {
+                let (callback, val) = match state {
+                    PromiseState::Fulfilled(ref val) => (on_fulfilled, val.clone()),
+                    PromiseState::Rejected(ref reason) => (on_rejected, reason.clone()),
+                    PromiseState::Pending => unreachable!(),
+                };
+                // Without a callback, the promise of then() settles as the one it was called on.
+                if type_of(&callback) == "function" {
+                    let val = self.call_value(callback, vec![val]);
+                    self.resolve_promise(&promise, val)
+                } else {
+                    self.react(Reaction::Resolve(promise), state)
+                }
+            }
+            // The promise of finally() settles as the one it was called on.
+            (Reaction::Finally(callback, promise), state) => {
+                if type_of(&callback) == "function" {
+                    self.call_value(callback, vec![]);
+                }
+                self.react(Reaction::Resolve(promise), state)
+            }
+            (Reaction::Resolve(promise), PromiseState::Fulfilled(val)) => {
+                self.resolve_promise(&promise, val)
+            }
+            (Reaction::Resolve(promise), PromiseState::Rejected(reason)) => {
+                self.reject_promise(&promise, reason)
+            }
+            (_, PromiseState::Pending) => unreachable!(),
+        }
+    }
+
+    /// Runs `func` with `val` as the value of its `await`, until it returns or awaits again.
+    fn resume(&mut self, func: SuspendedFunction, val: Value) {
+        let sp = self.state.stack.len();
+        self.state.history.push(CallFrame {
+            bp: self.state.bp,
+            lp: self.state.lp,
+            argc: self.state.argc,
+            sp: sp,
+            return_pc: self.state.pc,
+            func_pos: func.func_pos,
+            new_this: None,
+            env: func.env,
+        });
+        self.state.stack.extend(func.stack);
+        self.state.stack.push(val);
+        self.state.bp = sp + func.bp;
+        self.state.lp = sp + func.lp;
+        self.state.argc = func.argc;
+        self.state.pc = func.pc;
+        self.do_run();
+        // The promise of the function, which it returned or awaited with.
+        self.state.pop();
+    }
+}
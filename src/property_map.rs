@@ -0,0 +1,265 @@
+//! The properties of an object. Properties named by array indices are kept apart, in a vector
+//! indexed by them like the elements of an array, so that an object used as an array neither
+//! formats nor hashes its indices.
+
+use std::iter::FromIterator;
+use std::ops::Index;
+
+use indexmap::IndexMap;
+
+use vm::{array_index, Value};
+
+/// An index further than this beyond the last element is kept with the named properties, so that
+/// a large index does not allocate every element before it.
+const MAX_ELEMENT_GAP: usize = 1024;
+
+/// The properties of an object, elements first in the order of their indices and then the others
+/// in the order they were added.
+///
+/// The other properties also have positions, by which the VM reaches the global variables without
+/// hashing their names. Removing one of them moves those after it.
+#[derive(Clone, Debug, Default)]
+pub struct PropertyMap {
+    elements: Vec<Option<Value>>, // None where there is no element
+    element_count: usize,
+    props: IndexMap<String, Value>,
+    sparse_indices: usize, // The number of array indices in props
+    removals: usize,       // The number of times props has lost a property
+}
+
+impl PropertyMap {
+    pub fn new() -> PropertyMap {
+        PropertyMap::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.element_count + self.props.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match array_index(key) {
+            Some(index) => self.get_index(index),
+            None => self.props.get(key),
+        }
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        match array_index(key) {
+            Some(index) if (index as usize) < self.elements.len() => {
+                self.elements[index as usize].as_mut()
+            }
+            _ => self.props.get_mut(key),
+        }
+    }
+
+    /// Returns the element `index`, the property named by it.
+    pub fn get_index(&self, index: u32) -> Option<&Value> {
+        match self.elements.get(index as usize) {
+            Some(elem) => elem.as_ref(),
+            None if self.sparse_indices > 0 => self.props.get(&index.to_string()),
+            None => None,
+        }
+    }
+
+    /// Returns the position of the property `key`, which is not an element.
+    pub fn position(&self, key: &str) -> Option<usize> {
+        self.props.get_index_of(key)
+    }
+
+    pub fn get_position(&self, position: usize) -> Option<&Value> {
+        self.props.get_index(position).map(|(_, val)| val)
+    }
+
+    pub fn get_position_mut(&mut self, position: usize) -> Option<&mut Value> {
+        self.props.get_index_mut(position).map(|(_, val)| val)
+    }
+
+    /// Returns how many times a property that is not an element has been removed. The positions
+    /// stay the same as long as this does.
+    pub fn removals(&self) -> usize {
+        self.removals
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Returns the property `key`, which is added as undefined if there is none.
+    pub fn get_or_insert_undefined(&mut self, key: String) -> &mut Value {
+        if !self.contains_key(key.as_str()) {
+            self.insert(key.clone(), Value::Undefined);
+        }
+        self.get_mut(key.as_str()).unwrap()
+    }
+
+    /// Sets the property `key`, which keeps its place if there is one already.
+    pub fn insert(&mut self, key: String, val: Value) -> Option<Value> {
+        match array_index(key.as_str()) {
+            Some(index) => self.insert_index(index, val),
+            None => self.props.insert(key, val),
+        }
+    }
+
+    /// Sets the element `index`.
+    pub fn insert_index(&mut self, index: u32, val: Value) -> Option<Value> {
+        let i = index as usize;
+        if i >= self.elements.len() {
+            if i - self.elements.len() > MAX_ELEMENT_GAP {
+                let old = self.props.insert(index.to_string(), val);
+                if old.is_none() {
+                    self.sparse_indices += 1;
+                }
+                return old;
+            }
+            self.elements.resize(i + 1, None);
+            self.take_sparse_indices();
+        }
+        let old = ::std::mem::replace(&mut self.elements[i], Some(val));
+        if old.is_none() {
+            self.element_count += 1;
+        }
+        old
+    }
+
+    /// Moves the indices in props that the elements now reach into the elements.
+    fn take_sparse_indices(&mut self) {
+        if self.sparse_indices == 0 {
+            return;
+        }
+        let len = self.elements.len();
+        let reached = self
+            .props
+            .keys()
+            .filter(|key| array_index(key).map_or(false, |index| (index as usize) < len))
+            .cloned()
+            .collect::<Vec<String>>();
+        for key in reached {
+            let val = self.props.shift_remove(&key).unwrap();
+            self.removals += 1;
+            self.elements[array_index(key.as_str()).unwrap() as usize] = Some(val);
+            self.element_count += 1;
+            self.sparse_indices -= 1;
+        }
+    }
+
+    /// Removes the property `key`. The others keep their order.
+    pub fn remove(&mut self, key: &str) -> Option<Value> {
+        match array_index(key) {
+            Some(index) if (index as usize) < self.elements.len() => {
+                let old = self.elements[index as usize].take();
+                if old.is_some() {
+                    self.element_count -= 1;
+                }
+                while let Some(&None) = self.elements.last() {
+                    self.elements.pop();
+                }
+                old
+            }
+            Some(_) => {
+                let old = self.props.shift_remove(key);
+                if old.is_some() {
+                    self.sparse_indices -= 1;
+                    self.removals += 1;
+                }
+                old
+            }
+            None => {
+                let old = self.props.shift_remove(key);
+                if old.is_some() {
+                    self.removals += 1;
+                }
+                old
+            }
+        }
+    }
+
+    /// Returns the keys and values of the properties. The keys of elements are made here.
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (String, &'a Value)> + 'a {
+        self.elements
+            .iter()
+            .enumerate()
+            .filter_map(|(i, elem)| elem.as_ref().map(|val| (i.to_string(), val)))
+            .chain(self.props.iter().map(|(key, val)| (key.clone(), val)))
+    }
+
+    pub fn keys<'a>(&'a self) -> impl Iterator<Item = String> + 'a {
+        self.iter().map(|(key, _)| key)
+    }
+}
+
+// Maps with the same properties are equal, however they came to have them.
+impl PartialEq for PropertyMap {
+    fn eq(&self, other: &PropertyMap) -> bool {
+        self.elements == other.elements && self.props == other.props
+    }
+}
+
+impl<'a, K: AsRef<str> + ?Sized> Index<&'a K> for PropertyMap {
+    type Output = Value;
+
+    fn index(&self, key: &'a K) -> &Value {
+        self.get(key.as_ref()).expect("no such property")
+    }
+}
+
+impl Extend<(String, Value)> for PropertyMap {
+    fn extend<I: IntoIterator<Item = (String, Value)>>(&mut self, iter: I) {
+        for (key, val) in iter {
+            self.insert(key, val);
+        }
+    }
+}
+
+impl FromIterator<(String, Value)> for PropertyMap {
+    fn from_iter<I: IntoIterator<Item = (String, Value)>>(iter: I) -> PropertyMap {
+        let mut map = PropertyMap::new();
+        map.extend(iter);
+        map
+    }
+}
+
+impl IntoIterator for PropertyMap {
+    type Item = (String, Value);
+    type IntoIter = Box<dyn Iterator<Item = (String, Value)>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Box::new(
+            self.elements
+                .into_iter()
+                .enumerate()
+                .filter_map(|(i, elem)| elem.map(|val| (i.to_string(), val)))
+                .chain(self.props.into_iter()),
+        )
+    }
+}
+
+#[test]
+fn elements_and_sparse_indices() {
+    let mut map = PropertyMap::new();
+    map.insert("a".to_string(), Value::Number(0.0));
+    map.insert_index(1, Value::Number(1.0));
+    map.insert("2000".to_string(), Value::Number(2000.0));
+    assert_eq!(map.len(), 3);
+    assert_eq!(map.elements.len(), 2);
+    assert_eq!(map.get("1"), Some(&Value::Number(1.0)));
+    assert_eq!(map.get_index(2000), Some(&Value::Number(2000.0)));
+    assert_eq!(map.get("0"), None);
+
+    // Elements that reach the sparse index take it in.
+    map.insert_index(1000, Value::Undefined);
+    assert_eq!(map.sparse_indices, 1);
+    map.insert_index(2010, Value::Undefined);
+    assert_eq!(map.sparse_indices, 0);
+    assert_eq!(map.get("2000"), Some(&Value::Number(2000.0)));
+    assert_eq!(map.len(), 5);
+
+    assert_eq!(map.remove("1000"), Some(Value::Undefined));
+    assert_eq!(map.remove("2010"), Some(Value::Undefined));
+    assert_eq!(map.remove("2000"), Some(Value::Number(2000.0)));
+    assert_eq!(map.elements.len(), 2);
+    assert_eq!(map.keys().collect::<Vec<String>>(), vec!["1", "a"]);
+}
@@ -0,0 +1,169 @@
+//! Converts between vm::Value and serde data, e.g. to pass configuration from the host to
+//! scripts and results back.
+//!
+//! Values are serialized as JSON.stringify does:
+//! - undefined, functions and arguments become null in arrays and at the top level, and are
+//!   left out of objects.
+//! - NaN and Infinity become null.
+//! - Properties of the prototype (__proto__) are not serialized.
+//!
+//! JavaScript has no null value yet, so null is deserialized as undefined.
+
+use vm::{own_keys, ArrayValue, PropertyMap, Value};
+
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeMap, SerializeSeq, Serializer};
+use serde_json;
+
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::fmt;
+use std::rc::Rc;
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            &Value::Bool(b) => serializer.serialize_bool(b),
+            // Integers are serialized as integers so that they are not shown as 1.0.
+            &Value::Number(n) if n.fract() == 0.0 && n.abs() < 9007199254740992.0 => {
+                serializer.serialize_i64(n as i64)
+            }
+            &Value::Number(n) if n.is_finite() => serializer.serialize_f64(n),
+            &Value::String(ref s) => serializer.serialize_str(s.to_str().unwrap()),
+            &Value::Object(ref map) => {
+                let map = map.borrow();
+                let keys = own_keys(&map)
+                    .into_iter()
+                    .filter(|key| is_serializable(&map[key]))
+                    .collect::<Vec<String>>();
+                let mut serializer = serializer.serialize_map(Some(keys.len()))?;
+                for key in keys {
+                    serializer.serialize_entry(&key, &map[&key])?;
+                }
+                serializer.end()
+            }
+            &Value::Array(ref array) => {
+                let array = array.borrow();
+                let mut serializer = serializer.serialize_seq(Some(array.length))?;
+                for elem in &array.elems[..array.length] {
+                    serializer.serialize_element(elem)?;
+                }
+                serializer.end()
+            }
+            _ => serializer.serialize_unit(),
+        }
+    }
+}
+
+fn is_serializable(val: &Value) -> bool {
+    match val {
+        &Value::Undefined
+        | &Value::Function(_, _)
+        | &Value::NeedThis(_)
+        | &Value::WithThis(_)
+        | &Value::BuiltinFunction(_)
+        | &Value::Arguments => false,
+        _ => true,
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Value, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a JSON value")
+    }
+
+    fn visit_unit<E>(self) -> Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_none<E>(self) -> Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        Value::deserialize(deserializer)
+    }
+
+    fn visit_bool<E>(self, b: bool) -> Result<Value, E> {
+        Ok(Value::Bool(b))
+    }
+
+    fn visit_i64<E>(self, n: i64) -> Result<Value, E> {
+        Ok(Value::Number(n as f64))
+    }
+
+    fn visit_u64<E>(self, n: u64) -> Result<Value, E> {
+        Ok(Value::Number(n as f64))
+    }
+
+    fn visit_f64<E>(self, n: f64) -> Result<Value, E> {
+        Ok(Value::Number(n))
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<Value, E> {
+        match CString::new(s) {
+            Ok(s) => Ok(Value::String(s)),
+            Err(_) => Err(E::custom("strings containing NUL are not supported")),
+        }
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut elems = vec![];
+        while let Some(elem) = seq.next_element()? {
+            elems.push(elem)
+        }
+        Ok(Value::Array(Rc::new(RefCell::new(ArrayValue::new(elems)))))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let mut props = PropertyMap::new();
+        while let Some((key, val)) = map.next_entry()? {
+            props.insert(key, val);
+        }
+        Ok(Value::Object(Rc::new(RefCell::new(props))))
+    }
+}
+
+/// Converts `val` to JSON. See the module documentation for how values without a JSON
+/// counterpart are converted.
+pub fn to_json(val: &Value) -> serde_json::Value {
+    serde_json::to_value(val).unwrap()
+}
+
+pub fn from_json(json: &serde_json::Value) -> Result<Value, serde_json::Error> {
+    Value::deserialize(json)
+}
+
+#[test]
+fn json_round_trip() {
+    let json = serde_json::from_str::<serde_json::Value>(
+        r#"{"name": "rapidus", "version": 1, "ratio": 0.5, "tags": ["js", null, true]}"#,
+    ).unwrap();
+    let val = from_json(&json).unwrap();
+    assert_eq!(
+        val.inspect(),
+        "{ name: 'rapidus', ratio: 0.5, tags: [ 'js', null, true ], version: 1 }"
+    );
+    assert_eq!(to_json(&val), json);
+
+    if let Value::Object(ref map) = val {
+        let mut map = map.borrow_mut();
+        map.insert("f".to_string(), Value::BuiltinFunction(0));
+        map.insert("u".to_string(), Value::Undefined);
+    }
+    assert_eq!(to_json(&val), json);
+    assert_eq!(
+        serde_json::to_string(&Value::Number(::std::f64::NAN)).unwrap(),
+        "null"
+    );
+}
@@ -0,0 +1,269 @@
+use builtin;
+use vm::{PropertyMap, Value, VM};
+
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// The metadata in the /*--- ---*/ block at the top of a test262 test.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TestMetadata {
+    pub includes: Vec<String>,
+    pub flags: Vec<String>,
+    pub negative: Option<Negative>,
+}
+
+/// A test that is expected to fail with an error of `error_type` in `phase`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Negative {
+    pub phase: Phase,
+    pub error_type: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Phase {
+    Parse,
+    Resolution,
+    Runtime,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    Pass,
+    Fail,
+    Skip(String), // reason
+}
+
+impl TestMetadata {
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|f| f == flag)
+    }
+
+    /// Returns why the test cannot be run, if it cannot.
+    pub fn unsupported(&self) -> Option<String> {
+        for flag in &["module", "async", "onlyStrict", "CanBlockIsTrue"] {
+            if self.has_flag(flag) {
+                return Some(format!("flag {}", flag));
+            }
+        }
+        None
+    }
+
+    /// Decides the outcome of a run from how far the test got: whether it parsed, and whether it
+    /// ran to the end without errors.
+    pub fn outcome(&self, parsed: bool, completed: bool) -> Outcome {
+        let passed = match self.negative {
+            Some(Negative {
+                phase: Phase::Parse,
+                ..
+            }) => !parsed,
+            Some(_) => parsed && !completed,
+            None => completed,
+        };
+        if passed {
+            Outcome::Pass
+        } else {
+            Outcome::Fail
+        }
+    }
+}
+
+pub fn parse_metadata(source: &str) -> TestMetadata {
+    let mut metadata = TestMetadata {
+        includes: vec![],
+        flags: vec![],
+        negative: None,
+    };
+
+    let yaml = match (source.find("/*---"), source.find("---*/")) {
+        (Some(start), Some(end)) if start < end => &source[start + 5..end],
+        _ => return metadata,
+    };
+
+    let (mut phase, mut error_type) = (None, None);
+    let mut key = "";
+    for line in yaml.lines() {
+        let indented = line.starts_with(' ') || line.starts_with('\t');
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if !indented {
+            let mut kv = line.splitn(2, ':');
+            key = kv.next().unwrap_or("").trim();
+            let value = kv.next().unwrap_or("").trim();
+            match key {
+                "includes" => metadata.includes.append(&mut parse_list(value)),
+                "flags" => metadata.flags.append(&mut parse_list(value)),
+                _ => {}
+            }
+            continue;
+        }
+
+        match key {
+            "includes" | "flags" if line.starts_with('-') => {
+                let item = line[1..].trim().to_string();
+                if key == "includes" {
+                    metadata.includes.push(item)
+                } else {
+                    metadata.flags.push(item)
+                }
+            }
+            "negative" => {
+                let mut kv = line.splitn(2, ':');
+                match (kv.next().map(|k| k.trim()), kv.next().map(|v| v.trim())) {
+                    (Some("phase"), Some(value)) => {
+                        phase = match value {
+                            "parse" | "early" => Some(Phase::Parse),
+                            "resolution" => Some(Phase::Resolution),
+                            _ => Some(Phase::Runtime),
+                        }
+                    }
+                    (Some("type"), Some(value)) => error_type = Some(value.to_string()),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(phase) = phase {
+        metadata.negative = Some(Negative {
+            phase: phase,
+            error_type: error_type.unwrap_or("".to_string()),
+        });
+    }
+
+    metadata
+}
+
+// Parses a flow sequence like "[a.js, b.js]".
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .trim_matches(|c| c == '[' || c == ']')
+        .split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Returns the harness files to run before the test, in order.
+pub fn harness_files(harness_dir: &Path, metadata: &TestMetadata) -> Vec<PathBuf> {
+    let mut files = vec![];
+    if !metadata.has_flag("raw") {
+        files.push(harness_dir.join("assert.js"));
+        files.push(harness_dir.join("sta.js"));
+    }
+    for include in &metadata.includes {
+        files.push(harness_dir.join(include));
+    }
+    files
+}
+
+/// Returns the source to run for a test: the harness files followed by the test itself.
+pub fn test_source(harness_dir: &Path, metadata: &TestMetadata, test: &str) -> io::Result<String> {
+    let mut source = String::new();
+    for file in harness_files(harness_dir, metadata) {
+        source += fs::read_to_string(file)?.as_str();
+        source.push('\n');
+    }
+    Ok(source + test)
+}
+
+/// Returns the test files in `path`, searching directories recursively. Fixtures, which are only
+/// used by other tests, are skipped.
+pub fn find_tests(path: &Path) -> Vec<PathBuf> {
+    let mut tests = vec![];
+    if path.is_dir() {
+        let mut entries = match fs::read_dir(path) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .collect::<Vec<PathBuf>>(),
+            Err(_) => return tests,
+        };
+        entries.sort();
+        for entry in entries {
+            if entry.is_dir() {
+                tests.append(&mut find_tests(&entry));
+            } else if is_test(&entry) {
+                tests.push(entry);
+            }
+        }
+    } else {
+        tests.push(path.to_path_buf());
+    }
+    tests
+}
+
+fn is_test(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.ends_with(".js") && !name.contains("_FIXTURE"))
+        .unwrap_or(false)
+}
+
+/// Finds the harness directory of the test262 checkout that `path` is in.
+pub fn find_harness_dir(path: &Path) -> Option<PathBuf> {
+    let path = fs::canonicalize(path).ok()?;
+    path.ancestors()
+        .map(|dir| dir.join("harness"))
+        .find(|dir| dir.join("assert.js").is_file())
+}
+
+/// Creates a realm, with its own global object and $262, to run a test in.
+pub fn create_realm() -> VM {
+    let mut vm = VM::new();
+    define_host_object(&mut vm);
+    vm
+}
+
+/// Defines $262, the object through which tests reach the host.
+pub fn define_host_object(vm: &mut VM) {
+    let mut map = PropertyMap::new();
+    map.insert(
+        "global".to_string(),
+        Value::Object(vm.global_objects.clone()),
+    );
+    map.insert("gc".to_string(), Value::BuiltinFunction(builtin::HOST_GC));
+    map.insert(
+        "createRealm".to_string(),
+        Value::BuiltinFunction(builtin::HOST_CREATE_REALM),
+    );
+    map.insert(
+        "evalScript".to_string(),
+        Value::BuiltinFunction(builtin::HOST_EVAL_SCRIPT),
+    );
+    vm.set_global("$262".to_string(), Value::Object(Rc::new(RefCell::new(map))));
+}
+
+#[test]
+fn metadata() {
+    let metadata = parse_metadata(
+        "// Copyright\n/*---\nesid: sec-foo\ndescription: >\n  a test\nincludes: [compareArray.js, \
+         propertyHelper.js]\nflags:\n  - noStrict\nnegative:\n  phase: parse\n  type: SyntaxError\n\
+         ---*/\nvar x;\n",
+    );
+    assert_eq!(
+        metadata.includes,
+        vec!["compareArray.js".to_string(), "propertyHelper.js".to_string()]
+    );
+    assert_eq!(metadata.flags, vec!["noStrict".to_string()]);
+    assert_eq!(
+        metadata.negative,
+        Some(Negative {
+            phase: Phase::Parse,
+            error_type: "SyntaxError".to_string(),
+        })
+    );
+    assert_eq!(metadata.outcome(false, false), Outcome::Pass);
+    assert_eq!(metadata.outcome(true, true), Outcome::Fail);
+
+    let metadata = parse_metadata("/*---\nflags: [raw, async]\n---*/");
+    assert_eq!(metadata.negative, None);
+    assert_eq!(metadata.unsupported(), Some("flag async".to_string()));
+    assert_eq!(harness_files(Path::new("h"), &metadata), Vec::<PathBuf>::new());
+    assert_eq!(metadata.outcome(true, true), Outcome::Pass);
+}
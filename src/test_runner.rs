@@ -0,0 +1,66 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The outcome of the tests in one file, filled in by the test(), assert() and expect() builtins.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TestResults {
+    pub passed: usize,
+    pub failed: usize,
+    pub current_failures: Option<Vec<String>>, // Failures in the test being run, if any
+}
+
+impl TestResults {
+    pub fn new() -> TestResults {
+        TestResults {
+            passed: 0,
+            failed: 0,
+            current_failures: None,
+        }
+    }
+}
+
+pub fn is_test_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.ends_with(".test.js"))
+        .unwrap_or(false)
+}
+
+/// Returns the test files in `path`, searching directories recursively. Hidden directories and
+/// `target` are skipped.
+pub fn find_test_files(path: &Path) -> Vec<PathBuf> {
+    let mut files = vec![];
+    if path.is_dir() {
+        let mut entries = match fs::read_dir(path) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .collect::<Vec<PathBuf>>(),
+            Err(_) => return files,
+        };
+        entries.sort();
+        for entry in entries {
+            let name = entry
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or("")
+                .to_string();
+            if entry.is_dir() {
+                if !name.starts_with('.') && name != "target" {
+                    files.append(&mut find_test_files(&entry));
+                }
+            } else if is_test_file(&entry) {
+                files.push(entry);
+            }
+        }
+    } else {
+        files.push(path.to_path_buf());
+    }
+    files
+}
+
+#[test]
+fn test_file_name() {
+    assert!(is_test_file(Path::new("a/b.test.js")));
+    assert!(!is_test_file(Path::new("a/b.js")));
+    assert!(!is_test_file(Path::new("a/test.js")));
+}
@@ -13,6 +13,7 @@ pub enum Kind {
     Number(f64),
     String(String),
     Symbol(Symbol),
+    Template(Option<String>, String, bool), // Cooked, raw, whether it ends the template
     LineTerminator,
 }
 
@@ -23,6 +24,7 @@ pub enum Keyword {
     Break,
     Case,
     Catch,
+    Const,
     Continue,
     Debugger,
     Default,
@@ -134,6 +136,13 @@ impl Token {
         }
     }
 
+    pub fn new_template(cooked: Option<String>, raw: String, tail: bool, pos: usize) -> Token {
+        Token {
+            kind: Kind::Template(cooked, raw, tail),
+            pos: pos,
+        }
+    }
+
     pub fn new_symbol(symbol: Symbol, pos: usize) -> Token {
         Token {
             kind: Kind::Symbol(symbol),
@@ -159,6 +168,42 @@ impl Token {
     }
 }
 
+impl Keyword {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Keyword::Abstract => "abstract",
+            Keyword::Arguments => "arguments",
+            Keyword::Break => "break",
+            Keyword::Case => "case",
+            Keyword::Catch => "catch",
+            Keyword::Const => "const",
+            Keyword::Continue => "continue",
+            Keyword::Debugger => "debugger",
+            Keyword::Default => "default",
+            Keyword::Delete => "delete",
+            Keyword::Do => "do",
+            Keyword::Else => "else",
+            Keyword::Finally => "finally",
+            Keyword::For => "for",
+            Keyword::Function => "function",
+            Keyword::If => "if",
+            Keyword::In => "in",
+            Keyword::Instanceof => "instanceof",
+            Keyword::New => "new",
+            Keyword::Return => "return",
+            Keyword::Switch => "switch",
+            Keyword::This => "this",
+            Keyword::Throw => "throw",
+            Keyword::Try => "try",
+            Keyword::Typeof => "typeof",
+            Keyword::Var => "var",
+            Keyword::Void => "void",
+            Keyword::While => "while",
+            Keyword::With => "with",
+        }
+    }
+}
+
 impl Symbol {
     pub fn as_binop(&self) -> Option<BinOp> {
         match self {
@@ -197,6 +242,7 @@ pub fn convert_reserved_keyword(keyword: &str) -> Option<Keyword> {
         "break" => Some(Keyword::Break),
         "case" => Some(Keyword::Case),
         "catch" => Some(Keyword::Catch),
+        "const" => Some(Keyword::Const),
         "continue" => Some(Keyword::Continue),
         "debugger" => Some(Keyword::Debugger),
         "default" => Some(Keyword::Default),
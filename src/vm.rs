@@ -1,19 +1,35 @@
+use std::any::{Any, TypeId};
 use std::boxed::Box;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::ffi::CString;
-use std::rc::Rc;
+use std::fmt;
+use std::panic;
+use std::rc::{Rc, Weak};
 
 use libc;
 // use cpuprofiler::PROFILER;
 
 use builtin;
 use bytecode_gen::ByteCode;
+use coverage::Coverage;
+use debugger::Debugger;
+use diagnostic::{Diagnostic, SourceInfo};
+use error::{ErrorKind, RuntimeError, Span};
 use jit::TracingJit;
+use module::{FileResolver, ModuleResolver};
 use node::BinOp;
+use promise::{new_promise_prototype, Microtask, Promises, SuspendedFunction};
+use test_runner::TestResults;
 
 pub type RawStringPtr = *mut libc::c_char;
 
+pub use property_map::PropertyMap;
+
+/// A function implemented in Rust. It is passed the arguments, with `this` first if the function
+/// needs it, and must push its result onto the stack of the VM.
+pub type NativeFunction = unsafe fn(Vec<Value>, &mut VM);
+
 pub unsafe fn alloc_rawstring(s: &str) -> RawStringPtr {
     let p = libc::calloc(1, s.len() + 2) as RawStringPtr;
     libc::strncpy(p, s.as_ptr() as *const i8, s.len());
@@ -23,10 +39,13 @@ pub unsafe fn alloc_rawstring(s: &str) -> RawStringPtr {
 #[derive(Clone, Debug, PartialEq)]
 pub struct ArrayValue {
     pub elems: Vec<Value>,
-    pub length: usize,
-    pub obj: HashMap<String, Value>,
+    pub length: usize, // Always elems.len()
+    pub obj: PropertyMap,
 }
 
+// Arrays are not sparse, so a large index would allocate all the elements before it.
+pub const MAX_ARRAY_LENGTH: usize = 1 << 24;
+
 impl ArrayValue {
     pub fn new(arr: Vec<Value>) -> ArrayValue {
         let len = arr.len();
@@ -34,15 +53,23 @@ impl ArrayValue {
             elems: arr,
             length: len,
             obj: {
-                let mut hm = HashMap::new();
+                let mut hm = PropertyMap::new();
                 hm.insert(
                     "__proto__".to_string(),
                     Value::Object(Rc::new(RefCell::new({
-                        let mut hm = HashMap::new();
+                        let mut hm = PropertyMap::new();
                         hm.insert(
                             "push".to_string(),
                             Value::NeedThis(Box::new(Value::BuiltinFunction(builtin::ARRAY_PUSH))),
                         );
+                        hm.insert(
+                            "slice".to_string(),
+                            Value::NeedThis(Box::new(Value::BuiltinFunction(builtin::ARRAY_SLICE))),
+                        );
+                        hm.insert(
+                            "map".to_string(),
+                            Value::NeedThis(Box::new(Value::BuiltinFunction(builtin::ARRAY_MAP))),
+                        );
                         hm
                     }))),
                 );
@@ -50,42 +77,219 @@ impl ArrayValue {
             },
         }
     }
+
+    /// Sets the length as assigning to `length` does: elements past it are removed, and added
+    /// elements are undefined.
+    pub fn set_length(&mut self, len: usize) {
+        self.elems.resize(len, Value::Undefined);
+        self.length = len;
+    }
 }
 
+// Objects are reference counted, so host code can keep a Value (e.g. a callback) across runs of
+// the VM just by cloning it.
+// TODO: Once there is a tracing GC, values held by host code are no longer roots. Add
+// Persistent<T> handles and a HandleScope then.
 #[derive(Clone, Debug, PartialEq)]
 pub enum Value {
     Undefined,
+    Null,
     Bool(bool),
     Number(f64),
     String(CString),
-    Function(usize, Rc<RefCell<HashMap<String, Value>>>),
+    Function(usize, Rc<RefCell<PropertyMap>>),
     NeedThis(Box<Value>),
     WithThis(Box<(Value, Value)>),               // Function, This
     BuiltinFunction(usize), // unknown if usize == 0; specific function if usize > 0
-    Object(Rc<RefCell<HashMap<String, Value>>>), // Object(HashMap<String, Value>),
+    Object(Rc<RefCell<PropertyMap>>),
     Array(Rc<RefCell<ArrayValue>>),
     Arguments,
+    Accessor(Box<(Value, Value)>), // Getter, Setter of an accessor property; never on the stack
 }
 
 impl Value {
-    pub fn to_string(self) -> String {
+    /// Returns the value as console.log shows it: strings are quoted, and the contents of
+    /// objects and arrays are shown.
+    pub fn inspect(&self) -> String {
+        inspect(self, 0)
+    }
+}
+
+/// Shows the value as `String(value)` does in JavaScript.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Value::String(name) => name.into_string().unwrap(),
-            Value::Number(n) => format!("{}", n),
-            e => unimplemented!("{:?}", e),
+            &Value::Undefined => write!(f, "undefined"),
+            &Value::Null => write!(f, "null"),
+            &Value::Bool(b) => write!(f, "{}", b),
+            &Value::Number(n) => write!(f, "{}", number_to_string(n)),
+            &Value::String(ref s) => write!(f, "{}", s.to_str().unwrap()),
+            // VM::value_to_string shows the source code of the functions of the script.
+            &Value::Function(_, _) => write!(f, "function () {{ [native code] }}"),
+            &Value::NeedThis(ref callee) => write!(f, "{}", callee),
+            &Value::WithThis(box (ref callee, _)) => write!(f, "{}", callee),
+            &Value::BuiltinFunction(id) => {
+                let name = builtin::NAMES.get(id).unwrap_or(&"");
+                write!(f, "function {}() {{ [native code] }}", name)
+            }
+            &Value::Object(_) => write!(f, "[object Object]"),
+            &Value::Arguments => write!(f, "[object Arguments]"),
+            &Value::Accessor(_) => write!(f, "{}", inspect(self, 0)),
+            &Value::Array(ref array) => {
+                let array = array.borrow();
+                for (i, elem) in array.elems[..array.length].iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    // undefined and null elements are shown as empty strings.
+                    if *elem != Value::Undefined && *elem != Value::Null {
+                        write!(f, "{}", elem)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Converts a number to a string as JavaScript does, e.g. 1e+21 and Infinity.
+pub fn number_to_string(n: f64) -> String {
+    if n.is_nan() {
+        "NaN".to_string()
+    } else if n.is_infinite() {
+        if n > 0.0 {
+            "Infinity".to_string()
+        } else {
+            "-Infinity".to_string()
+        }
+    } else if n == 0.0 {
+        // Including -0
+        "0".to_string()
+    } else if n.abs() >= 1e21 || n.abs() < 1e-6 {
+        let s = format!("{:e}", n);
+        if s.contains("e-") {
+            s
+        } else {
+            s.replace("e", "e+")
         }
+    } else {
+        n.to_string()
+    }
+}
+
+// Objects and arrays nested deeper than this are not shown.
+const INSPECT_DEPTH: usize = 2;
+
+/// Strings longer than this are cut short where console.log shows them quoted.
+pub const MAX_QUOTED_STRING_LEN: usize = 10000;
+
+/// Quotes `s` as console.log does: in single quotes, or in double quotes or backquotes if that
+/// saves escaping, with control characters escaped. Only the first `max_len` characters are
+/// shown, followed by how many are left out.
+pub fn quote_string(s: &str, max_len: usize) -> String {
+    let quote = if !s.contains('\'') {
+        '\''
+    } else if !s.contains('"') {
+        '"'
+    } else if !s.contains('`') {
+        '`'
+    } else {
+        '\''
+    };
+    let mut quoted = quote.to_string();
+    for c in s.chars().take(max_len) {
+        match c {
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            '\u{8}' => quoted.push_str("\\b"),
+            '\u{c}' => quoted.push_str("\\f"),
+            '\\' => quoted.push_str("\\\\"),
+            c if c == quote => {
+                quoted.push('\\');
+                quoted.push(c)
+            }
+            c if c < ' ' || c == '\u{7f}' => {
+                quoted.push_str(format!("\\x{:02X}", c as u32).as_str())
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push(quote);
+    match s.chars().count().saturating_sub(max_len) {
+        0 => quoted,
+        1 => quoted + "... 1 more character",
+        rest => quoted + format!("... {} more characters", rest).as_str(),
+    }
+}
+
+fn inspect(val: &Value, depth: usize) -> String {
+    match val {
+        &Value::String(ref s) => quote_string(s.to_str().unwrap(), MAX_QUOTED_STRING_LEN),
+        &Value::Function(_, _) | &Value::BuiltinFunction(_) => "[Function]".to_string(),
+        &Value::NeedThis(ref callee) => inspect(callee, depth),
+        &Value::WithThis(box (ref callee, _)) => inspect(callee, depth),
+        &Value::Arguments => "[Arguments]".to_string(),
+        &Value::Accessor(box (_, Value::Undefined)) => "[Getter]".to_string(),
+        &Value::Accessor(box (Value::Undefined, _)) => "[Setter]".to_string(),
+        &Value::Accessor(_) => "[Getter/Setter]".to_string(),
+        &Value::Object(_) if depth > INSPECT_DEPTH => "[Object]".to_string(),
+        &Value::Array(_) if depth > INSPECT_DEPTH => "[Array]".to_string(),
+        &Value::Object(ref map) => {
+            let map = map.borrow();
+            let keys = own_keys(&map);
+            if keys.is_empty() {
+                return "{}".to_string();
+            }
+            let props = keys.iter()
+                .map(|key| format!("{}: {}", key, inspect(&map[key], depth + 1)))
+                .collect::<Vec<String>>();
+            format!("{{ {} }}", props.join(", "))
+        }
+        &Value::Array(ref array) => {
+            let array = array.borrow();
+            if array.length == 0 {
+                return "[]".to_string();
+            }
+            let elems = array.elems[..array.length]
+                .iter()
+                .map(|elem| inspect(elem, depth + 1))
+                .collect::<Vec<String>>();
+            format!("[ {} ]", elems.join(", "))
+        }
+        val => val.to_string(),
+    }
+}
+
+/// Returns Object.prototype. Objects do not inherit from it yet, so they find its methods through
+/// primitive_method instead; it is there for calls like Object.prototype.toString.call(x).
+pub fn new_object_prototype() -> Value {
+    let mut map = PropertyMap::new();
+    for &(name, id) in &[
+        ("toString", builtin::OBJECT_PROTOTYPE_TO_STRING),
+        ("hasOwnProperty", builtin::OBJECT_PROTOTYPE_HAS_OWN_PROPERTY),
+        (
+            "propertyIsEnumerable",
+            builtin::OBJECT_PROTOTYPE_PROPERTY_IS_ENUMERABLE,
+        ),
+    ] {
+        map.insert(
+            name.to_string(),
+            Value::NeedThis(Box::new(Value::BuiltinFunction(id))),
+        );
     }
+    Value::Object(Rc::new(RefCell::new(map)))
 }
 
 pub fn new_value_function(pos: usize) -> Value {
     let mut val = Value::Function(
         pos,
         Rc::new(RefCell::new({
-            let mut hm = HashMap::new();
+            let mut hm = PropertyMap::new();
             hm.insert(
                 "prototype".to_string(),
                 Value::Object(Rc::new(RefCell::new({
-                    let mut hm = HashMap::new();
+                    let mut hm = PropertyMap::new();
                     // hm.insert("call".to_string(), Value::NeedThis(Box::new(Value::BuiltinFunction(6))));
                     hm
                 }))),
@@ -93,11 +297,29 @@ pub fn new_value_function(pos: usize) -> Value {
             hm.insert(
                 "__proto__".to_string(),
                 Value::Object(Rc::new(RefCell::new({
-                    let mut hm = HashMap::new();
+                    let mut hm = PropertyMap::new();
                     hm.insert(
                         "call".to_string(),
                         Value::NeedThis(Box::new(Value::BuiltinFunction(builtin::FUNCTION_PROTOTYPE_CALL))),
                     );
+                    hm.insert(
+                        "toString".to_string(),
+                        Value::NeedThis(Box::new(Value::BuiltinFunction(
+                            builtin::FUNCTION_PROTOTYPE_TO_STRING,
+                        ))),
+                    );
+                    hm.insert(
+                        "hasOwnProperty".to_string(),
+                        Value::NeedThis(Box::new(Value::BuiltinFunction(
+                            builtin::OBJECT_PROTOTYPE_HAS_OWN_PROPERTY,
+                        ))),
+                    );
+                    hm.insert(
+                        "propertyIsEnumerable".to_string(),
+                        Value::NeedThis(Box::new(Value::BuiltinFunction(
+                            builtin::OBJECT_PROTOTYPE_PROPERTY_IS_ENUMERABLE,
+                        ))),
+                    );
                     hm
                 }))),
             );
@@ -118,6 +340,7 @@ pub fn new_value_function(pos: usize) -> Value {
 pub struct ConstantTable {
     pub value: Vec<Value>,
     pub string: Vec<String>,
+    pub object_templates: Vec<ObjectTemplate>,
 }
 
 impl ConstantTable {
@@ -125,75 +348,313 @@ impl ConstantTable {
         ConstantTable {
             value: vec![],
             string: vec![],
+            object_templates: vec![],
+        }
+    }
+}
+
+/// The shape of an object literal. Properties whose values are primitive literals are stored
+/// in `props` and cloned as-is; the values of `dynamic_keys` are taken from the stack. `props`
+/// has every key, in the order they were written.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectTemplate {
+    pub props: PropertyMap,
+    pub dynamic_keys: Vec<(String, PropertyKind)>,
+}
+
+/// What a value taken from the stack for an object literal is to its property.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PropertyKind {
+    Value,
+    Getter,
+    Setter,
+}
+
+impl ObjectTemplate {
+    pub fn new() -> ObjectTemplate {
+        ObjectTemplate {
+            props: PropertyMap::new(),
+            dynamic_keys: vec![],
         }
     }
 }
 
-pub const END: u8 = 0x00;
-pub const CREATE_CONTEXT: u8 = 0x01;
-pub const CONSTRUCT: u8 = 0x02;
-pub const CREATE_OBJECT: u8 = 0x03;
-pub const CREATE_ARRAY: u8 = 0x04;
-pub const PUSH_INT8: u8 = 0x05;
-pub const PUSH_INT32: u8 = 0x06;
-pub const PUSH_FALSE: u8 = 0x07;
-pub const PUSH_TRUE: u8 = 0x08;
-pub const PUSH_CONST: u8 = 0x09;
-pub const PUSH_THIS: u8 = 0x0a;
-pub const PUSH_ARGUMENTS: u8 = 0x0b;
-pub const NEG: u8 = 0x0c;
-pub const ADD: u8 = 0x0d;
-pub const SUB: u8 = 0x0e;
-pub const MUL: u8 = 0x0f;
-pub const DIV: u8 = 0x10;
-pub const REM: u8 = 0x11;
-pub const LT: u8 = 0x12;
-pub const GT: u8 = 0x13;
-pub const LE: u8 = 0x14;
-pub const GE: u8 = 0x15;
-pub const EQ: u8 = 0x16;
-pub const NE: u8 = 0x17;
-pub const SEQ: u8 = 0x18;
-pub const SNE: u8 = 0x19;
-pub const GET_MEMBER: u8 = 0x1a;
-pub const SET_MEMBER: u8 = 0x1b;
-pub const GET_GLOBAL: u8 = 0x1c;
-pub const SET_GLOBAL: u8 = 0x1d;
-pub const GET_LOCAL: u8 = 0x1e;
-pub const SET_LOCAL: u8 = 0x1f;
-pub const GET_ARG_LOCAL: u8 = 0x20;
-pub const SET_ARG_LOCAL: u8 = 0x21;
-pub const JMP_IF_FALSE: u8 = 0x22;
-pub const JMP: u8 = 0x23;
-pub const CALL: u8 = 0x24;
-pub const RETURN: u8 = 0x25;
-pub const ASG_FREST_PARAM: u8 = 0x26;
+/// How the operands that follow an opcode are laid out. Every operand is little-endian.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operands {
+    None,
+    Int8,
+    Int32,
+    Int32Int32,
+    /// The smallest case, the number of cases and the default offset, then an offset per case.
+    JmpTable,
+}
+
+/// What the code generator, the disassembler, the interpreter and the JIT know about an
+/// instruction.
+pub struct Instruction {
+    pub op: u8,
+    pub name: &'static str, // As the disassembler shows it
+    pub operands: Operands,
+    pub run: fn(&mut VM),
+}
+
+macro_rules! instructions {
+    ($( $op:ident = $byte:expr, $name:expr, $operands:ident, $run:ident; )*) => {
+        $( pub const $op: u8 = $byte; )*
+
+        /// Every instruction, indexed by its opcode.
+        pub const INSTRUCTIONS: &[Instruction] = &[$(
+            Instruction { op: $op, name: $name, operands: Operands::$operands, run: $run },
+        )*];
+    };
+}
+
+instructions! {
+    END = 0x00, "End", None, end;
+    CREATE_CONTEXT = 0x01, "CreateContext", Int32Int32, create_context;
+    CONSTRUCT = 0x02, "Construct", Int32, construct;
+    CREATE_OBJECT = 0x03, "CreateObject", Int32, create_object;
+    CREATE_ARRAY = 0x04, "CreateArray", Int32, create_array;
+    PUSH_INT8 = 0x05, "PushInt8", Int8, push_int8;
+    PUSH_INT32 = 0x06, "PushInt32", Int32, push_int32;
+    PUSH_FALSE = 0x07, "PushFalse", None, push_false;
+    PUSH_TRUE = 0x08, "PushTrue", None, push_true;
+    PUSH_CONST = 0x09, "PushConst", Int32, push_const;
+    PUSH_THIS = 0x0a, "PushThis", None, push_this;
+    PUSH_ARGUMENTS = 0x0b, "PushArguments", None, push_arguments;
+    NEG = 0x0c, "Neg", None, neg;
+    ADD = 0x0d, "Add", None, add;
+    SUB = 0x0e, "Sub", None, sub;
+    MUL = 0x0f, "Mul", None, mul;
+    DIV = 0x10, "Div", None, div;
+    REM = 0x11, "Rem", None, rem;
+    LT = 0x12, "Lt", None, lt;
+    GT = 0x13, "Gt", None, gt;
+    LE = 0x14, "Le", None, le;
+    GE = 0x15, "Ge", None, ge;
+    EQ = 0x16, "Eq", None, eq;
+    NE = 0x17, "Ne", None, ne;
+    SEQ = 0x18, "SEq", None, seq;
+    SNE = 0x19, "SNe", None, sne;
+    GET_MEMBER = 0x1a, "GetMember", None, get_member;
+    SET_MEMBER = 0x1b, "SetMember", None, set_member;
+    GET_GLOBAL = 0x1c, "GetGlobal", Int32, get_global;
+    SET_GLOBAL = 0x1d, "SetGlobal", Int32, set_global;
+    GET_LOCAL = 0x1e, "GetLocal", Int32, get_local;
+    SET_LOCAL = 0x1f, "SetLocal", Int32, set_local;
+    GET_ARG_LOCAL = 0x20, "GetArgLocal", Int32, get_arg_local;
+    SET_ARG_LOCAL = 0x21, "SetArgLocal", Int32, set_arg_local;
+    JMP_IF_FALSE = 0x22, "JmpIfFalse", Int32, jmp_if_false;
+    JMP = 0x23, "Jmp", Int32, jmp;
+    CALL = 0x24, "Call", Int32, call;
+    RETURN = 0x25, "Return", None, return_;
+    ASG_FREST_PARAM = 0x26, "AssignFunctionRestParam", Int32Int32, assign_func_rest_param;
+    CALL_DIRECT = 0x27, "CallDirect", Int32Int32, call_direct;
+    POP = 0x28, "Pop", None, pop;
+    NOT = 0x29, "Not", None, not;
+    TYPEOF = 0x2a, "Typeof", None, typeof_;
+    CALL_METHOD = 0x2b, "CallMethod", Int32Int32, call_method;
+    OBJECT_SPREAD = 0x2c, "ObjectSpread", None, object_spread;
+    GET_ITERATOR = 0x2d, "GetIterator", None, get_iterator;
+    ITER_NEXT = 0x2e, "IterNext", Int32, iter_next;
+    GET_KEY_ITERATOR = 0x2f, "GetKeyIterator", None, get_key_iterator;
+    IN = 0x30, "In", None, in_;
+    ARRAY_SPREAD = 0x31, "ArraySpread", None, array_spread;
+    CALL_SPREAD = 0x32, "CallSpread", None, call_spread;
+    JMP_TABLE = 0x33, "JmpTable", JmpTable, jmp_table;
+    GET_GLOBAL_OR_UNDEFINED = 0x34, "GetGlobalOrUndefined", Int32, get_global_or_undefined;
+    CHECK_INITIALIZED = 0x35, "CheckInitialized", Int32, check_initialized;
+    ASSIGN_TO_CONST = 0x36, "AssignToConst", Int32, assign_to_const;
+    CONSTRUCT_SPREAD = 0x37, "ConstructSpread", None, construct_spread;
+    NEW_PROMISE = 0x38, "NewPromise", None, new_promise;
+    AWAIT = 0x39, "Await", Int32, await_;
+    RESOLVE_PROMISE = 0x3a, "ResolvePromise", None, resolve_promise;
+    INSTANCE_OF = 0x3b, "InstanceOf", None, instance_of;
+    DELETE_MEMBER = 0x3c, "DeleteMember", None, delete_member;
+    GET_ENV = 0x3d, "GetEnv", None, get_env;
+    NEW_ENV = 0x3e, "NewEnv", None, new_env;
+    COPY_ENV = 0x3f, "CopyEnv", None, copy_env;
+    GET_ENV_VAR = 0x40, "GetEnvVar", Int32, get_env_var;
+    SET_ENV_VAR = 0x41, "SetEnvVar", Int32, set_env_var;
+    MAKE_CLOSURE = 0x42, "MakeClosure", None, make_closure;
+    GET_WITH_OBJECT = 0x43, "GetWithObject", Int32, get_with_object;
+}
+
+/// Returns the length of the instruction at `pc`, operands included.
+pub fn inst_len(code: &[u8], pc: usize) -> usize {
+    let int32_at = |i: usize| {
+        code[i] as usize
+            + ((code[i + 1] as usize) << 8)
+            + ((code[i + 2] as usize) << 16)
+            + ((code[i + 3] as usize) << 24)
+    };
+    match INSTRUCTIONS[code[pc] as usize].operands {
+        Operands::None => 1,
+        Operands::Int8 => 2,
+        Operands::Int32 => 5,
+        Operands::Int32Int32 => 9,
+        Operands::JmpTable => 13 + 4 * int32_at(pc + 5),
+    }
+}
 
 pub struct VM {
-    pub global_objects: Rc<RefCell<HashMap<String, Value>>>,
+    pub global_objects: Rc<RefCell<PropertyMap>>,
+    pub global_slot_cache: Vec<Option<usize>>, // indexed by the operand of GET/SET_GLOBAL
+    pub global_slot_removals: usize, // The removals of the global object when the cache was filled
+    pub integrity_levels: IntegrityLevels,
+    pub closure_envs: ClosureEnvs,
+    pub promises: Promises,
+    pub microtasks: VecDeque<Microtask>, // Run once the script has finished
+    pub promise_prototype: Value,
     pub jit: TracingJit,
     pub state: VMState,
     pub const_table: ConstantTable,
     pub insts: ByteCode,
     pub loop_bgn_end: HashMap<isize, isize>,
-    pub op_table: [fn(&mut VM); 39],
-    pub builtin_functions: [unsafe fn(Vec<Value>, &mut VM); 7],
+    pub builtin_functions: Vec<NativeFunction>, // indexed by Value::BuiltinFunction
+    pub native_modules: HashMap<String, Value>, // The exports of each native module
+    pub embedder_data: HashMap<TypeId, Box<dyn Any>>, // One value of each type, set by the host
+    pub coverage: Option<Coverage>,
+    pub debugger: Option<Debugger>,
+    pub source: Option<SourceInfo>,
+    pub tests: TestResults,
+    pub error_count: usize, // Number of runtime errors reported
+    pub last_error: Option<RuntimeError>,
+    pub max_call_depth: Option<usize>,
+    pub stack_limit: Option<usize>, // Values the operand stack may hold
+    pub strict: bool,                     // Assigning to undeclared variables is an error
+    pub stdout: Option<Rc<dyn Fn(&str)>>, // Receives what the script prints instead of stdout
+    pub module_resolver: Rc<dyn ModuleResolver>,
+    pub budget: Option<usize>, // Instructions left before run_for() pauses
+    pub instruction_limit: Option<usize>, // Instructions left before the script is stopped
+    pub stats: Stats,
+}
+
+/// How far Object.seal or Object.freeze has locked an object.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum IntegrityLevel {
+    Sealed, // Properties cannot be added
+    Frozen, // Properties cannot be added or changed
+}
+
+/// The integrity levels of the objects that have been sealed or frozen, by the address of their
+/// properties. The weak references keep the addresses from being taken by other objects.
+pub type IntegrityLevels = HashMap<usize, (IntegrityLevel, Weak<dyn Any>)>;
+
+/// The environments that closures made in loops capture, by the address of the properties of the
+/// closures like IntegrityLevels. An environment is an object holding the variables declared with
+/// let or const in one iteration, which inherits those of the enclosing scopes.
+pub type ClosureEnvs = HashMap<usize, (Value, Weak<RefCell<PropertyMap>>)>;
+
+/// Counts the work a VM has done, for embedders that meter how much scripts use. What code
+/// compiled by the JIT does is not counted, beyond the calls into it.
+///
+/// TODO: Count GC pauses once there is a GC. Values are reference counted for now.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub instructions: u64,       // Bytecode instructions executed
+    pub calls: u64,              // Calls of script and native functions, including `new`
+    pub allocations: u64,        // Objects and arrays created by the script
+    pub jit_compilations: usize, // Functions and loops compiled by the JIT
+    pub max_stack_len: usize,    // Most values the operand stack has held at once
 }
 
+/// Globals that scripts cannot assign to. Assignments to them are ignored, as in sloppy mode.
+pub const READ_ONLY_GLOBALS: [&str; 3] = ["Infinity", "NaN", "undefined"];
+
+/// What the VM panics with after reporting an error that it cannot continue from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FatalError;
+
+/// A function finds `this` right below its arguments on the stack, which start at `bp`. Its
+/// local variables start at `lp`, after the arguments and a slot for each parameter that was
+/// not passed.
 pub struct VMState {
     pub stack: Vec<Value>,
     pub bp: usize,
     pub lp: usize,
+    pub argc: usize, // The number of arguments passed to the function being run
     pub pc: isize,
-    pub history: Vec<(usize, usize, usize, isize)>, // bp, lp, sp, return_pc
+    pub history: Vec<CallFrame>,
+}
+
+/// The state of a caller, saved while the function it called runs. The first entry of the history
+/// is the frame of the top level code, which is not called by anything.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CallFrame {
+    pub bp: usize,
+    pub lp: usize,
+    pub argc: usize,
+    pub sp: usize, // Where `this` and the arguments of the callee start
+    pub return_pc: isize,
+    pub func_pos: usize,         // Where the code of the callee starts
+    pub new_this: Option<Value>, // The object being made if the callee was called with `new`
+    pub env: Value,              // The environment the callee closes over, or undefined
+}
+
+impl CallFrame {
+    pub fn new(
+        return_pc: isize,
+        func_pos: usize,
+        new_this: Option<Value>,
+        env: Value,
+    ) -> CallFrame {
+        CallFrame {
+            bp: 0,
+            lp: 0,
+            argc: 0,
+            sp: 0,
+            return_pc: return_pc,
+            func_pos: func_pos,
+            new_this: new_this,
+            env: env,
+        }
+    }
+}
+
+impl VMState {
+    /// Returns the state of the top level code about to run.
+    fn toplevel(global_objects: &Rc<RefCell<PropertyMap>>) -> VMState {
+        VMState {
+            stack: {
+                let mut stack = Vec::with_capacity(128);
+                // The top level code is run as a function called with no arguments.
+                stack.push(Value::Object(global_objects.clone()));
+                stack.push(Value::Number(0.0));
+                stack
+            },
+            history: {
+                let mut s = Vec::with_capacity(128);
+                s.push(CallFrame::new(0, 0, None, Value::Undefined));
+                s
+            },
+            bp: 0,
+            lp: 0,
+            argc: 0,
+            pc: 0isize,
+        }
+    }
+
+    /// Pops the value on top of the operand stack. The values below `lp` are the arguments of
+    /// the function being run and the frames of its callers, so the bytecode is broken if it
+    /// pops them.
+    pub fn pop(&mut self) -> Value {
+        debug_assert!(
+            self.stack.len() > self.lp,
+            "operand stack underflow at {:04x}",
+            self.pc
+        );
+        self.stack.pop().expect("operand stack underflow")
+    }
 }
 
 impl VM {
     pub fn new() -> VM {
-        let mut obj = HashMap::new();
+        let mut obj = PropertyMap::new();
 
         obj.insert("console".to_string(), {
-            let mut map = HashMap::new();
+            let mut map = PropertyMap::new();
             map.insert(
                 "log".to_string(),
                 Value::BuiltinFunction(builtin::CONSOLE_LOG),
@@ -202,20 +663,24 @@ impl VM {
         });
 
         obj.insert("process".to_string(), {
-            let mut map = HashMap::new();
+            let mut map = PropertyMap::new();
             map.insert("stdout".to_string(), {
-                let mut map = HashMap::new();
+                let mut map = PropertyMap::new();
                 map.insert(
                     "write".to_string(),
                     Value::BuiltinFunction(builtin::PROCESS_STDOUT_WRITE),
                 );
                 Value::Object(Rc::new(RefCell::new(map)))
             });
+            map.insert(
+                "exit".to_string(),
+                Value::BuiltinFunction(builtin::PROCESS_EXIT),
+            );
             Value::Object(Rc::new(RefCell::new(map)))
         });
 
         obj.insert("Math".to_string(), {
-            let mut map = HashMap::new();
+            let mut map = PropertyMap::new();
             map.insert(
                 "floor".to_string(),
                 Value::BuiltinFunction(builtin::MATH_FLOOR),
@@ -228,72 +693,47 @@ impl VM {
             Value::Object(Rc::new(RefCell::new(map)))
         });
 
+        obj.insert("Object".to_string(), {
+            let mut map = PropertyMap::new();
+            map.insert("keys".to_string(), Value::BuiltinFunction(builtin::OBJECT_KEYS));
+            map.insert("prototype".to_string(), new_object_prototype());
+            map.insert("freeze".to_string(), Value::BuiltinFunction(builtin::OBJECT_FREEZE));
+            map.insert("seal".to_string(), Value::BuiltinFunction(builtin::OBJECT_SEAL));
+            map.insert("isFrozen".to_string(), Value::BuiltinFunction(builtin::OBJECT_IS_FROZEN));
+            map.insert("isSealed".to_string(), Value::BuiltinFunction(builtin::OBJECT_IS_SEALED));
+            Value::Object(Rc::new(RefCell::new(map)))
+        });
+
+        obj.insert("Array".to_string(), {
+            let mut map = PropertyMap::new();
+            map.insert("from".to_string(), Value::BuiltinFunction(builtin::ARRAY_FROM));
+            Value::Object(Rc::new(RefCell::new(map)))
+        });
+
+        obj.insert("String".to_string(), Value::BuiltinFunction(builtin::STRING));
+        obj.insert("Promise".to_string(), Value::BuiltinFunction(builtin::PROMISE));
+
+        obj.insert("Infinity".to_string(), Value::Number(::std::f64::INFINITY));
+        obj.insert("NaN".to_string(), Value::Number(::std::f64::NAN));
+        obj.insert("undefined".to_string(), Value::Undefined);
+
         let global_objects = Rc::new(RefCell::new(obj));
 
         VM {
             global_objects: global_objects.clone(),
+            global_slot_cache: vec![],
+            global_slot_removals: 0,
+            integrity_levels: HashMap::new(),
+            closure_envs: HashMap::new(),
+            promises: HashMap::new(),
+            microtasks: VecDeque::new(),
+            promise_prototype: new_promise_prototype(),
             jit: unsafe { TracingJit::new() },
-            state: VMState {
-                stack: {
-                    let mut stack = Vec::with_capacity(128);
-                    stack.push(Value::Object(global_objects.clone()));
-                    stack.push(Value::Number(1.0));
-                    stack
-                },
-                history: {
-                    let mut s = Vec::with_capacity(128);
-                    s.push((0, 0, 0, 0));
-                    s
-                },
-                bp: 0,
-                lp: 0,
-                pc: 0isize,
-            },
+            state: VMState::toplevel(&global_objects),
             const_table: ConstantTable::new(),
             insts: vec![],
             loop_bgn_end: HashMap::new(),
-            op_table: [
-                end,
-                create_context,
-                construct,
-                create_object,
-                create_array,
-                push_int8,
-                push_int32,
-                push_false,
-                push_true,
-                push_const,
-                push_this,
-                push_arguments,
-                neg,
-                add,
-                sub,
-                mul,
-                div,
-                rem,
-                lt,
-                gt,
-                le,
-                ge,
-                eq,
-                ne,
-                seq,
-                sne,
-                get_member,
-                set_member,
-                get_global,
-                set_global,
-                get_local,
-                set_local,
-                get_arg_local,
-                set_arg_local,
-                jmp_if_false,
-                jmp,
-                call,
-                return_,
-                assign_func_rest_param,
-            ],
-            builtin_functions: [
+            builtin_functions: vec![
                 builtin::console_log,
                 builtin::process_stdout_write,
                 builtin::array_push,
@@ -301,14 +741,231 @@ impl VM {
                 builtin::math_random,
                 builtin::math_pow,
                 builtin::function_prototype_call,
+                builtin::test,
+                builtin::assert,
+                builtin::expect,
+                builtin::expect_to_be,
+                builtin::expect_to_equal,
+                builtin::host_gc,
+                builtin::host_create_realm,
+                builtin::host_eval_script,
+                builtin::process_exit,
+                builtin::object_keys,
+                builtin::string_char_at,
+                builtin::string_index_of,
+                builtin::string_slice,
+                builtin::string_to_upper_case,
+                builtin::string_to_lower_case,
+                builtin::number_to_fixed,
+                builtin::primitive_to_string,
+                builtin::function_prototype_to_string,
+                builtin::string,
+                builtin::array_slice,
+                builtin::object_prototype_has_own_property,
+                builtin::object_prototype_property_is_enumerable,
+                builtin::array_from,
+                builtin::object_freeze,
+                builtin::object_seal,
+                builtin::object_is_frozen,
+                builtin::object_is_sealed,
+                builtin::object_prototype_to_string,
+                builtin::promise,
+                builtin::promise_resolve,
+                builtin::promise_reject,
+                builtin::promise_prototype_then,
+                builtin::promise_prototype_catch,
+                builtin::promise_prototype_finally,
+                builtin::promise_resolve_function,
+                builtin::promise_reject_function,
+                builtin::string_normalize,
+                builtin::string_locale_compare,
+                builtin::array_map,
             ],
+            native_modules: HashMap::new(),
+            embedder_data: HashMap::new(),
+            coverage: None,
+            debugger: None,
+            source: None,
+            tests: TestResults::new(),
+            error_count: 0,
+            last_error: None,
+            max_call_depth: None,
+            stack_limit: None,
+            strict: false,
+            stdout: None,
+            module_resolver: Rc::new(FileResolver),
+            budget: None,
+            instruction_limit: None,
+            stats: Stats::default(),
         }
     }
 }
 
 impl VM {
-    pub fn run(&mut self, insts: ByteCode) {
+    /// Returns the slot of the global variable `name`, its position in the global object. Global
+    /// variables are properties of the global object, so scripts see them through `this` too.
+    pub fn global_slot(&self, name: &str) -> Option<usize> {
+        (*self.global_objects).borrow().position(name)
+    }
+
+    /// Returns the slot of the global variable named by the string constant `id`. The slots are
+    /// cached until the global object loses a property, which moves the ones after it.
+    fn cached_global_slot(&mut self, id: usize) -> Option<usize> {
+        let removals = (*self.global_objects).borrow().removals();
+        if removals != self.global_slot_removals {
+            self.global_slot_cache.clear();
+            self.global_slot_removals = removals;
+        }
+        if let Some(&Some(slot)) = self.global_slot_cache.get(id) {
+            return Some(slot);
+        }
+        let slot = self.global_slot(self.const_table.string[id].as_str())?;
+        if self.global_slot_cache.len() <= id {
+            self.global_slot_cache.resize(id + 1, None);
+        }
+        self.global_slot_cache[id] = Some(slot);
+        Some(slot)
+    }
+
+    /// Returns the value of the global variable `name`, e.g. a callback defined by the script.
+    pub fn get_global(&mut self, name: &str) -> Option<Value> {
+        (*self.global_objects).borrow().get(name).cloned()
+    }
+
+    pub fn set_global(&mut self, name: String, val: Value) {
+        (*self.global_objects).borrow_mut().insert(name, val);
+    }
+
+    /// Sets process.argv, the command line arguments seen by the script.
+    pub fn set_argv(&mut self, argv: Vec<String>) {
+        let argv = argv
+            .into_iter()
+            .map(|arg| Value::String(CString::new(arg).unwrap()))
+            .collect();
+        if let Some(Value::Object(process)) = self.get_global("process") {
+            process.borrow_mut().insert(
+                "argv".to_string(),
+                Value::Array(Rc::new(RefCell::new(ArrayValue::new(argv)))),
+            );
+        }
+    }
+
+    /// Returns how far the object `val` has been locked by Object.seal or Object.freeze.
+    pub fn integrity_level(&self, val: &Value) -> Option<IntegrityLevel> {
+        if self.integrity_levels.is_empty() {
+            return None;
+        }
+        let addr = object_addr(val)?;
+        self.integrity_levels.get(&addr).map(|&(level, _)| level)
+    }
+
+    /// Locks the object `val` to `level`. Objects cannot be unlocked, so a lower level than the
+    /// current one is ignored. Primitives are left as they are.
+    pub fn set_integrity_level(&mut self, val: &Value, level: IntegrityLevel) {
+        let weak: Weak<dyn Any> = match *val {
+            Value::Object(ref map)
+            | Value::Function(_, ref map)
+            | Value::NeedThis(box Value::Function(_, ref map)) => Rc::downgrade(map) as Weak<_>,
+            Value::Array(ref arr) => Rc::downgrade(arr) as Weak<_>,
+            _ => return,
+        };
+        // Forget the objects that have been freed every time the table doubles.
+        if self.integrity_levels.len().is_power_of_two() {
+            self.integrity_levels
+                .retain(|_, &mut (_, ref weak)| weak.upgrade().is_some());
+        }
+        let entry = self.integrity_levels
+            .entry(object_addr(val).unwrap())
+            .or_insert((level, weak));
+        if entry.0 < level {
+            entry.0 = level;
+        }
+    }
+
+    /// Returns the environment that the function whose properties are `map` closes over, or
+    /// undefined if it is not a closure made in a loop.
+    pub fn closure_env(&self, map: &Rc<RefCell<PropertyMap>>) -> Value {
+        if self.closure_envs.is_empty() {
+            return Value::Undefined;
+        }
+        let addr = &**map as *const RefCell<PropertyMap> as usize;
+        match self.closure_envs.get(&addr) {
+            Some(&(ref env, _)) => env.clone(),
+            None => Value::Undefined,
+        }
+    }
+
+    /// Attaches `data` to the VM, so that native functions can reach the state of the host
+    /// through the VM they are passed. The VM keeps one value of each type; setting another one
+    /// replaces it.
+    pub fn set_embedder_data<T: 'static>(&mut self, data: T) {
+        self.embedder_data.insert(TypeId::of::<T>(), Box::new(data));
+    }
+
+    pub fn get_embedder_data<T: 'static>(&self) -> Option<&T> {
+        self.embedder_data
+            .get(&TypeId::of::<T>())
+            .and_then(|data| data.downcast_ref())
+    }
+
+    pub fn get_embedder_data_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.embedder_data
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|data| data.downcast_mut())
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            jit_compilations: self.jit.compilations,
+            ..self.stats.clone()
+        }
+    }
+}
+
+impl VM {
+    /// Loads `insts` to be run with run_for().
+    pub fn load(&mut self, insts: ByteCode) {
         self.insts = insts;
+    }
+
+    /// Runs the loaded program for about `budget` instructions, so that a host with its own
+    /// event loop (e.g. a game or a GUI) can run a script a bit at a time. Returns the completion
+    /// value once the program has finished, and the error message if an error occurs.
+    ///
+    /// Functions of the script can be paused too, but not while they are called back by a native
+    /// function (e.g. Function.prototype.call): that call runs to its end once it is made, and so
+    /// does a loop compiled by the JIT.
+    ///
+    /// The microtasks queued by the program are run once it has finished, in the same call.
+    ///
+    /// TODO: Add poll_event_loop() once there are timers to run.
+    pub fn run_for(&mut self, budget: usize) -> Result<Option<Value>, Value> {
+        let error_count = self.error_count;
+        self.budget = Some(budget);
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            self.run_frames(1);
+            if self.insts[self.state.pc as usize] == END {
+                self.run_microtasks();
+            }
+        }));
+        self.budget = None;
+        if let Err(payload) = result {
+            if !payload.is::<FatalError>() {
+                panic::resume_unwind(payload)
+            }
+        } else if self.error_count == error_count {
+            return Ok(if self.insts[self.state.pc as usize] == END {
+                Some(self.state.stack.pop().unwrap_or(Value::Undefined))
+            } else {
+                None
+            });
+        }
+        Err(self.last_error_message())
+    }
+
+    /// Runs `insts` and returns the completion value of the program.
+    pub fn run(&mut self, insts: ByteCode) -> Value {
+        self.load(insts);
         // Unlock the mutex and start the profiler
         // PROFILER
         //     .lock()
@@ -317,14 +974,58 @@ impl VM {
         //     .expect("Couldn't start");
 
         self.do_run();
+        self.run_microtasks();
 
         // Unwrap the mutex and stop the profiler
         // PROFILER.lock().unwrap().stop().expect("Couldn't stop");
+
+        self.state.stack.pop().unwrap_or(Value::Undefined)
     }
 
+    /// Runs the top level code appended to the program at `pc`, and returns its completion value.
+    /// It starts from a new top level frame, even if the code run before stopped with an error.
+    pub fn run_more(&mut self, pc: usize) -> Value {
+        self.state = VMState::toplevel(&self.global_objects);
+        self.state.pc = pc as isize;
+        self.do_run();
+        self.run_microtasks();
+        self.state.stack.pop().unwrap_or(Value::Undefined)
+    }
+
+    /// Runs the function whose frame was pushed last until it returns, or the top level code
+    /// until its end. Native functions call this to call back into the script.
     pub fn do_run(&mut self) {
+        let depth = self.state.history.len();
+        self.run_frames(depth)
+    }
+
+    /// Runs the code until the function called with `depth` frames in the history returns, or
+    /// until the end of the program. Calls and returns between functions of the script push and
+    /// pop frames in this loop; only native functions that call back into the script start
+    /// another one.
+    fn run_frames(&mut self, depth: usize) {
         loop {
-            if let Some(end) = self.loop_bgn_end.get(&self.state.pc) {
+            if let Some(budget) = self.budget {
+                // A nested loop cannot be paused, since the native function that started it
+                // is waiting for it on the Rust stack.
+                if budget == 0 && depth == 1 {
+                    break;
+                }
+                self.budget = Some(budget.saturating_sub(1));
+            }
+            if let Some(limit) = self.instruction_limit {
+                if limit == 0 {
+                    self.fatal_error(ErrorKind::Error, "too many instructions were run")
+                }
+                self.instruction_limit = Some(limit - 1);
+            }
+            if let Some(mut debugger) = self.debugger.take() {
+                debugger.on_instruction(self);
+                self.debugger = Some(debugger);
+            }
+            if let Some(ref mut coverage) = self.coverage {
+                coverage.hit(self.state.pc as usize);
+            } else if let Some(end) = self.loop_bgn_end.get(&self.state.pc) {
                 unsafe {
                     // println!("range: [{:x}, {:x})", self.state.pc, end);
                     if let Some(pc) = self.jit.can_loop_jit(
@@ -338,9 +1039,15 @@ impl VM {
                     }
                 }
             }
+            if self.state.stack.len() > self.stats.max_stack_len {
+                self.grow_stack();
+            }
             let code = self.insts[self.state.pc as usize];
-            self.op_table[code as usize](self);
-            if code == RETURN || code == END {
+            self.stats.instructions += 1;
+            (INSTRUCTIONS[code as usize].run)(self);
+            // Awaiting returns from the function like `return` does.
+            if code == END || (code == RETURN || code == AWAIT) && self.state.history.len() < depth
+            {
                 break;
             }
             // println!("stack trace: {:?} - {}", self.stack, *pc);
@@ -348,6 +1055,165 @@ impl VM {
     }
 }
 
+impl VM {
+    /// Calls a function value from native code and returns its result.
+    pub fn call_value(&mut self, callee: Value, args: Vec<Value>) -> Value {
+        let argc = args.len();
+        let depth = self.state.history.len();
+        self.state.stack.extend(args);
+        call_with_args_on_stack(self, callee, argc);
+        // A function of the script has only been entered. Run it until it returns.
+        if self.state.history.len() > depth {
+            self.run_frames(depth + 1);
+        }
+        self.state.pop()
+    }
+
+    /// Calls `function` with `this` from host code, after the script has been run. If an error
+    /// occurs in the function, its message is returned as the error.
+    pub fn call(&mut self, function: Value, this: Value, args: &[Value]) -> Result<Value, Value> {
+        let callee = match function {
+            Value::NeedThis(box function) => Value::WithThis(Box::new((function, this))),
+            function => function,
+        };
+        let error_count = self.error_count;
+        let (pc, bp, lp, argc) = (
+            self.state.pc,
+            self.state.bp,
+            self.state.lp,
+            self.state.argc,
+        );
+        let (sp, depth) = (self.state.stack.len(), self.state.history.len());
+
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            self.call_value(callee, args.to_vec())
+        }));
+        match result {
+            Ok(val) => if self.error_count == error_count {
+                return Ok(val);
+            },
+            Err(payload) => {
+                if !payload.is::<FatalError>() {
+                    panic::resume_unwind(payload)
+                }
+                // The function stopped in the middle, so go back to where the call was made.
+                self.state.stack.truncate(sp);
+                self.state.history.truncate(depth);
+                self.state.pc = pc;
+                self.state.bp = bp;
+                self.state.lp = lp;
+                self.state.argc = argc;
+            }
+        }
+        Err(self.last_error_message())
+    }
+
+    fn last_error_message(&self) -> Value {
+        let msg = self.last_error.as_ref().unwrap().message.as_str();
+        Value::String(CString::new(msg).unwrap())
+    }
+
+    /// Converts `val` to a string as String(val) does. Unlike Display, this shows the source code
+    /// of the functions of the script.
+    pub fn value_to_string(&self, val: &Value) -> String {
+        if let &Value::Function(pos, _) = callee_of(val) {
+            if let Some(source) = self.source.as_ref().and_then(|info| info.function_source(pos)) {
+                return source.to_string();
+            }
+        }
+        val.to_string()
+    }
+
+    /// Returns "file:line:column" of the instruction being executed.
+    pub fn current_location(&self) -> Option<String> {
+        let info = self.source.as_ref()?;
+        Some(info.location(info.pos_at(self.state.pc as usize)))
+    }
+
+    /// Records that the operand stack has grown past its largest size so far, and stops the
+    /// script if it has grown past the limit.
+    fn grow_stack(&mut self) {
+        let len = self.state.stack.len();
+        self.stats.max_stack_len = len;
+        if let Some(limit) = self.stack_limit {
+            if len > limit {
+                self.fatal_error(ErrorKind::RangeError, "maximum operand stack size exceeded")
+            }
+        }
+    }
+
+    /// Saves the state of the caller before entering the function at `func_pos`. `new_this` is
+    /// the object being made if the function is called with `new`, and `env` the environment
+    /// that the function closes over.
+    pub fn push_frame(&mut self, func_pos: usize, new_this: Option<Value>, env: Value) {
+        if let Some(max_call_depth) = self.max_call_depth {
+            // The first entry is the frame of the top level code.
+            if self.state.history.len() > max_call_depth {
+                self.fatal_error(ErrorKind::RangeError, "maximum call stack size exceeded")
+            }
+        }
+        let frame = CallFrame::new(self.state.pc, func_pos, new_this, env);
+        self.state.history.push(frame);
+    }
+
+    /// Reports an error at the instruction being executed, after which the script goes on.
+    pub fn report_error(&mut self, msg: &str) {
+        self.report(ErrorKind::Error, msg)
+    }
+
+    /// Reports an error of `kind` at the instruction being executed, and stops the script by
+    /// unwinding with FatalError. There is no try statement, so scripts cannot catch the error,
+    /// and no error object is made for it.
+    pub fn fatal_error(&mut self, kind: ErrorKind, msg: &str) -> ! {
+        self.report(kind, msg);
+        panic::resume_unwind(Box::new(FatalError))
+    }
+
+    fn report(&mut self, kind: ErrorKind, msg: &str) {
+        self.error_count += 1;
+        let mut error = RuntimeError {
+            kind: kind,
+            message: msg.to_string(),
+            span: None,
+            stack_trace: vec![],
+        };
+        let text = error.to_string();
+        match self.source {
+            Some(ref info) => {
+                let pos = info.pos_at(self.state.pc as usize);
+                let (file_name, source, pos_in_file) = info.locate(pos);
+                let diagnostic = Diagnostic::new(text.as_str(), source, pos_in_file);
+                eprint!("{}", diagnostic.render(file_name, source));
+                error.span = Some(Span::new(pos, pos + diagnostic.len));
+                // The first entry of the history is the frame of the top level code.
+                for frame in self.state.history.iter().skip(1).rev() {
+                    let pos = info.pos_at(frame.return_pc as usize);
+                    error.stack_trace.push(Span::new(pos, pos + 1));
+                }
+            }
+            None => eprintln!("error: {}", text),
+        }
+        self.last_error = Some(error);
+    }
+}
+
+pub fn describe_value(val: &Value) -> String {
+    match val {
+        &Value::Undefined => "undefined".to_string(),
+        &Value::Null => "null".to_string(),
+        &Value::Bool(b) => b.to_string(),
+        &Value::Number(n) => number_to_string(n),
+        &Value::String(ref s) => format!("{:?}", s.to_str().unwrap()),
+        &Value::Function(_, _)
+        | &Value::NeedThis(_)
+        | &Value::WithThis(_)
+        | &Value::BuiltinFunction(_) => "function".to_string(),
+        &Value::Object(_) | &Value::Arguments => "object".to_string(),
+        &Value::Array(_) => "array".to_string(),
+        &Value::Accessor(_) => "accessor".to_string(),
+    }
+}
+
 macro_rules! get_int8 {
     ($self:ident, $var:ident, $ty:ty) => {
         let $var = $self.insts[$self.state.pc as usize] as $ty;
@@ -370,24 +1236,30 @@ fn end(_self: &mut VM) {}
 fn create_context(self_: &mut VM) {
     self_.state.pc += 1; // create_context
     get_int32!(self_, num_local_var, usize);
-    let argc = if let Value::Number(argc) = self_.state.stack.pop().unwrap() {
+    get_int32!(self_, num_params, usize);
+    let argc = if let Value::Number(argc) = self_.state.pop() {
         argc as usize
     } else {
         unreachable!()
     };
 
     let stack_len = self_.state.stack.len();
-    if let Some((ref mut bp, ref mut lp, ref mut sp, ref mut _return_pc)) =
-        self_.state.history.last_mut()
     {
-        *bp = self_.state.bp;
-        *lp = self_.state.lp;
-        *sp = stack_len - argc;
-    } else {
-        unreachable!()
-    };
+        let frame = self_.state.history.last_mut().unwrap();
+        frame.bp = self_.state.bp;
+        frame.lp = self_.state.lp;
+        frame.argc = self_.state.argc;
+        frame.sp = stack_len - argc - 1; // this
+    }
     self_.state.bp = stack_len - argc;
-    self_.state.lp = stack_len;
+    self_.state.argc = argc;
+
+    // Parameters without an argument are undefined. Extra arguments are kept for the rest
+    // parameter and `arguments`.
+    for _ in argc..num_params {
+        self_.state.stack.push(Value::Undefined);
+    }
+    self_.state.lp = self_.state.stack.len();
 
     // This code is slower -> self_.state.stack.resize(stack_len + n, Value::Undefined);
     for _ in 0..num_local_var {
@@ -398,18 +1270,34 @@ fn create_context(self_: &mut VM) {
 fn construct(self_: &mut VM) {
     self_.state.pc += 1; // construct
     get_int32!(self_, argc, usize);
+    let callee = self_.state.pop();
+    construct_with_args_on_stack(self_, callee, argc);
+}
 
-    let mut callee = self_.state.stack.pop().unwrap();
+/// Like construct, but takes the constructor and the array of its arguments.
+fn construct_spread(self_: &mut VM) {
+    self_.state.pc += 1; // construct_spread
+    let callee = self_.state.pop();
+    let args = match self_.state.pop() {
+        Value::Array(arr) => arr.borrow().elems.clone(),
+        _ => unreachable!(),
+    };
+    let argc = args.len();
+    self_.state.stack.extend(args);
+    construct_with_args_on_stack(self_, callee, argc);
+}
 
+fn construct_with_args_on_stack(self_: &mut VM, mut callee: Value, argc: usize) {
     loop {
         match callee {
             Value::Function(dst, obj) => {
-                self_.state.history.push((0, 0, 0, self_.state.pc));
+                self_.stats.calls += 1;
+                self_.stats.allocations += 1;
 
                 // insert new 'this'
                 let pos = self_.state.stack.len() - argc;
                 let new_this = {
-                    let mut map = HashMap::new();
+                    let mut map = PropertyMap::new();
                     map.insert(
                         "__proto__".to_string(),
                         (*obj)
@@ -425,18 +1313,15 @@ fn construct(self_: &mut VM) {
                     .stack
                     .insert(pos, Value::Object(new_this.clone()));
 
+                let env = self_.closure_env(&obj);
+                self_.push_frame(dst, Some(Value::Object(new_this)), env);
                 self_.state.pc = dst as isize;
-                self_.state.stack.push(Value::Number(argc as f64 + 1.0));
-
-                self_.do_run();
-
-                match self_.state.stack.last_mut().unwrap() {
-                    &mut Value::Object(_)
-                    | &mut Value::Array(_)
-                    | &mut Value::Function(_, _)
-                    | &mut Value::BuiltinFunction(_) => {}
-                    others => *others = Value::Object(new_this),
-                };
+                self_.state.stack.push(Value::Number(argc as f64));
+                break;
+            }
+            // Promise makes its object itself.
+            Value::BuiltinFunction(builtin::PROMISE) => {
+                call_with_args_on_stack(self_, callee, argc);
                 break;
             }
             Value::NeedThis(callee_) => {
@@ -446,43 +1331,315 @@ fn construct(self_: &mut VM) {
                 callee = callee_;
             }
             c => {
-                println!("Constract: err: {:?}, pc = {}", c, self_.state.pc);
-                break;
+                let msg = format!("{} is not a constructor", describe_value(&c));
+                self_.fatal_error(ErrorKind::TypeError, msg.as_str())
             }
         }
     }
 }
 
 fn create_object(self_: &mut VM) {
-    self_.state.pc += 1; // create_context
-    get_int32!(self_, len, usize);
+    self_.state.pc += 1; // create_object
+    get_int32!(self_, id, usize);
 
-    let mut map = HashMap::new();
-    for _ in 0..len {
-        let name = if let Value::String(name) = self_.state.stack.pop().unwrap() {
-            name.into_string().unwrap()
-        } else {
-            panic!()
+    let template = &self_.const_table.object_templates[id];
+    let mut map = template.props.clone();
+    let len = self_.state.stack.len();
+    for (&(ref name, kind), val) in template
+        .dynamic_keys
+        .iter()
+        .zip(self_.state.stack.drain(len - template.dynamic_keys.len()..))
+    {
+        if kind == PropertyKind::Value {
+            map.insert(name.clone(), val);
+            continue;
+        }
+        // The getter and the setter of a property make one accessor.
+        let prop = map.get_or_insert_undefined(name.clone());
+        let (mut getter, mut setter) = match prop.clone() {
+            Value::Accessor(box accessor) => accessor,
+            _ => (Value::Undefined, Value::Undefined),
         };
-        let val = self_.state.stack.pop().unwrap();
-        map.insert(name, val.clone());
+        match kind {
+            PropertyKind::Getter => getter = val,
+            _ => setter = val,
+        }
+        *prop = Value::Accessor(Box::new((getter, setter)));
     }
+    self_.stats.allocations += 1;
     self_
         .state
         .stack
         .push(Value::Object(Rc::new(RefCell::new(map))));
 }
 
+/// Copies the own enumerable properties of the value on top of the stack to the object under it,
+/// as `{...val}` does.
+fn object_spread(self_: &mut VM) {
+    self_.state.pc += 1; // object_spread
+    let src = self_.state.pop();
+    let props = own_enumerable_props(self_, src.clone())
+        .into_iter()
+        .map(|(key, val)| match val {
+            // The value of an accessor property is copied.
+            Value::Accessor(box (getter, _)) => {
+                (key, call_accessor(self_, getter, src.clone(), vec![]))
+            }
+            val => (key, val),
+        })
+        .collect::<Vec<_>>();
+    match self_.state.stack.last() {
+        Some(Value::Object(map)) => map.borrow_mut().extend(props),
+        _ => unreachable!(),
+    }
+}
+
+/// Returns whether the property `key` of `val` is enumerable if it is an own property of `val`,
+/// and None otherwise. Elements are enumerable; the length of arrays, strings and arguments and
+/// the prototype of functions are not.
+pub fn own_property(self_: &mut VM, val: &Value, key: &str) -> Option<bool> {
+    let index = array_index(key).map(|index| index as usize);
+    match *val {
+        _ if key == "__proto__" => None,
+        Value::Object(ref map) => map.borrow().get(key).map(|_| true),
+        Value::Function(_, ref map) | Value::NeedThis(box Value::Function(_, ref map)) => {
+            map.borrow().get(key).map(|_| key != "prototype")
+        }
+        Value::Array(ref arr) => {
+            let arr = arr.borrow();
+            match index {
+                Some(index) if index < arr.length => Some(true),
+                _ if key == "length" => Some(false),
+                _ => arr.obj.get(key).map(|_| true),
+            }
+        }
+        Value::String(ref s) => match index {
+            Some(index) if index < s.to_str().unwrap().chars().count() => Some(true),
+            _ if key == "length" => Some(false),
+            _ => None,
+        },
+        Value::Arguments => match index {
+            Some(index) if index < self_.state.argc => Some(true),
+            _ if key == "length" => Some(false),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Whether `val` or its prototypes have the property `key`, as `in` finds it. The methods that
+/// primitive_method gives count as inherited properties.
+fn has_property(self_: &mut VM, val: &Value, key: &str) -> bool {
+    if own_property(self_, val, key).is_some() || primitive_method(val, key).is_some() {
+        return true;
+    }
+    let proto = match *val {
+        Value::Object(ref map)
+        | Value::Function(_, ref map)
+        | Value::NeedThis(box Value::Function(_, ref map)) => {
+            map.borrow().get("__proto__").cloned()
+        }
+        Value::Array(ref arr) => arr.borrow().obj.get("__proto__").cloned(),
+        _ => None,
+    };
+    match proto {
+        Some(proto @ Value::Object(_)) => has_property(self_, &proto, key),
+        _ => false,
+    }
+}
+
+/// Returns the own enumerable properties of `val`, which spreading copies and for-in visits.
+fn own_enumerable_props(self_: &VM, val: Value) -> Vec<(String, Value)> {
+    let own_props = |map: &PropertyMap| -> Vec<(String, Value)> {
+        own_keys(map)
+            .into_iter()
+            .map(|key| {
+                let val = map[&key].clone();
+                (key, val)
+            })
+            .collect()
+    };
+    match val {
+        Value::Object(map) => own_object_keys(self_, &map)
+            .into_iter()
+            .map(|key| {
+                let val = map.borrow()[&key].clone();
+                (key, val)
+            })
+            .collect(),
+        // The prototype of a function is not enumerable.
+        Value::Function(_, map) => own_props(&*map.borrow())
+            .into_iter()
+            .filter(|(key, _)| key != "prototype")
+            .collect(),
+        Value::Array(map) => {
+            let map = map.borrow();
+            let mut props: Vec<(String, Value)> = map
+                .elems
+                .iter()
+                .enumerate()
+                .map(|(i, val)| (i.to_string(), val.clone()))
+                .collect();
+            props.extend(own_props(&map.obj));
+            props
+        }
+        Value::String(s) => s
+            .to_str()
+            .unwrap()
+            .chars()
+            .enumerate()
+            .map(|(i, c)| (i.to_string(), Value::String(CString::new(c.to_string()).unwrap())))
+            .collect(),
+        Value::Arguments => (0..self_.state.argc)
+            .map(|i| (i.to_string(), self_.state.stack[self_.state.bp + i].clone()))
+            .collect(),
+        // Other primitives, null and undefined have no own enumerable properties.
+        _ => vec![],
+    }
+}
+
+/// Returns the elements a for-of loop over `val` visits, or None if `val` is not iterable. Arrays,
+/// strings and arguments are copied out at once instead of being stepped through.
+// TODO: Iterate other objects through their Symbol.iterator method once there are symbols, and
+// keep this fast path for arrays and strings.
+pub fn iterable_elems(self_: &VM, val: &Value) -> Option<Vec<Value>> {
+    match *val {
+        Value::Array(ref arr) => Some(arr.borrow().elems.clone()),
+        Value::String(ref s) => Some(
+            s.to_str()
+                .unwrap()
+                .chars()
+                .map(|c| Value::String(CString::new(c.to_string()).unwrap()))
+                .collect(),
+        ),
+        Value::Arguments => {
+            let bp = self_.state.bp;
+            Some(self_.state.stack[bp..bp + self_.state.argc].to_vec())
+        }
+        _ => None,
+    }
+}
+
+/// Returns the elements spreading `val` gives.
+fn spread_elems(self_: &mut VM, val: Value) -> Vec<Value> {
+    match iterable_elems(self_, &val) {
+        Some(elems) => elems,
+        None => {
+            let msg = format!("{} is not iterable", describe_value(&val));
+            self_.fatal_error(ErrorKind::TypeError, msg.as_str())
+        }
+    }
+}
+
+/// Appends the elements of the value on top of the stack to the array under it, as `[...val]`
+/// does.
+fn array_spread(self_: &mut VM) {
+    self_.state.pc += 1; // array_spread
+    let val = self_.state.pop();
+    let elems = spread_elems(self_, val);
+    match self_.state.stack.last() {
+        Some(Value::Array(arr)) => {
+            let mut arr = arr.borrow_mut();
+            arr.elems.extend(elems);
+            arr.length = arr.elems.len();
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Calls the function on top of the stack with the elements of the array under it, as
+/// `f(...args)` does.
+fn call_spread(self_: &mut VM) {
+    self_.state.pc += 1; // call_spread
+    let callee = self_.state.pop();
+    let args = match self_.state.pop() {
+        Value::Array(arr) => arr.borrow().elems.clone(),
+        _ => unreachable!(),
+    };
+    let argc = args.len();
+    self_.state.stack.extend(args);
+    call_with_args_on_stack(self_, callee, argc);
+}
+
+/// Starts a for-of loop over the value on top of the stack. The value stays there, with the
+/// position of its next element above it, until the loop ends.
+fn get_iterator(self_: &mut VM) {
+    self_.state.pc += 1; // get_iterator
+    let iterable = self_.state.pop();
+    match iterable {
+        Value::Array(_) | Value::String(_) | Value::Arguments => {}
+        val => {
+            let msg = format!("{} is not iterable", describe_value(&val));
+            self_.fatal_error(ErrorKind::TypeError, msg.as_str())
+        }
+    }
+    self_.state.stack.push(iterable);
+    self_.state.stack.push(Value::Number(0.0));
+}
+
+/// Starts a for-in loop over the keys the value on top of the stack has now. They are iterated
+/// as an array, which takes the place of the value.
+fn get_key_iterator(self_: &mut VM) {
+    self_.state.pc += 1; // get_key_iterator
+    let val = self_.state.pop();
+    let keys = own_enumerable_props(self_, val)
+        .into_iter()
+        .map(|(key, _)| Value::String(CString::new(key).unwrap()))
+        .collect();
+    self_
+        .state
+        .stack
+        .push(Value::Array(Rc::new(RefCell::new(ArrayValue::new(keys)))));
+    self_.state.stack.push(Value::Number(0.0));
+}
+
+/// Pushes the next element of the value a for-in or a for-of loop iterates, or jumps out of the
+/// loop if there are no more. The elements of a string are its characters, and its positions are
+/// byte offsets.
+fn iter_next(self_: &mut VM) {
+    self_.state.pc += 1; // iter_next
+    get_int32!(self_, dst, i32);
+    let len = self_.state.stack.len();
+    let index = match self_.state.stack[len - 1] {
+        Value::Number(index) => index as usize,
+        _ => unreachable!(),
+    };
+    let next = match self_.state.stack[len - 2] {
+        Value::Array(ref arr) => arr
+            .borrow()
+            .elems
+            .get(index)
+            .map(|val| (val.clone(), index + 1)),
+        Value::String(ref s) => s.to_str().unwrap()[index..].chars().next().map(|c| {
+            let val = Value::String(CString::new(c.to_string()).unwrap());
+            (val, index + c.len_utf8())
+        }),
+        Value::Arguments if index < self_.state.argc => {
+            Some((self_.state.stack[self_.state.bp + index].clone(), index + 1))
+        }
+        Value::Arguments => None,
+        _ => unreachable!(),
+    };
+    match next {
+        Some((val, next_index)) => {
+            self_.state.stack[len - 1] = Value::Number(next_index as f64);
+            self_.state.stack.push(val);
+        }
+        None => self_.state.pc += dst as isize,
+    }
+}
+
 fn create_array(self_: &mut VM) {
     self_.state.pc += 1; // create_context
     get_int32!(self_, len, usize);
 
     let mut arr = vec![];
     for _ in 0..len {
-        let val = self_.state.stack.pop().unwrap();
+        let val = self_.state.pop();
         arr.push(val);
     }
 
+    self_.stats.allocations += 1;
     self_
         .state
         .stack
@@ -519,7 +1676,7 @@ fn push_const(self_: &mut VM) {
 
 fn push_this(self_: &mut VM) {
     self_.state.pc += 1; // push_this
-    let val = self_.state.stack[self_.state.bp].clone();
+    let val = self_.state.stack[self_.state.bp - 1].clone();
     self_.state.stack.push(val);
 }
 
@@ -528,15 +1685,105 @@ fn push_arguments(self_: &mut VM) {
     self_.state.stack.push(Value::Arguments);
 }
 
+/// https://tc39.github.io/ecma262/#sec-unary-minus-operator
 fn neg(self_: &mut VM) {
     self_.state.pc += 1; // neg
-    let expr = self_.state.stack.last_mut().unwrap();
-    match expr {
-        &mut Value::Number(ref mut n) => *n = -*n,
-        _ => unimplemented!(),
+    let val = self_.state.pop();
+    let n = to_number(&to_primitive(self_, &val, Hint::Number));
+    self_.state.stack.push(Value::Number(-n));
+}
+
+fn not(self_: &mut VM) {
+    self_.state.pc += 1; // not
+    let val = self_.state.pop();
+    self_.state.stack.push(Value::Bool(!to_boolean(&val)));
+}
+
+// TODO: `typeof x` of an undeclared x should be "undefined" instead of an error.
+fn in_(self_: &mut VM) {
+    self_.state.pc += 1; // in
+    let obj = self_.state.pop();
+    let key = self_.state.pop().to_string();
+    match obj {
+        Value::Object(_)
+        | Value::Function(_, _)
+        | Value::NeedThis(_)
+        | Value::Array(_)
+        | Value::Arguments => {}
+        _ => {
+            let msg = format!(
+                "cannot use 'in' operator to search for '{}' in {}",
+                key,
+                describe_value(&obj)
+            );
+            self_.fatal_error(ErrorKind::TypeError, msg.as_str())
+        }
+    }
+    let found = has_property(self_, &obj, key.as_str());
+    self_.state.stack.push(Value::Bool(found));
+}
+
+fn instance_of(self_: &mut VM) {
+    self_.state.pc += 1; // instance_of
+    let ctor = self_.state.pop();
+    let val = self_.state.pop();
+    let is = is_instance_of(self_, &val, &ctor);
+    self_.state.stack.push(Value::Bool(is));
+}
+
+/// Whether `ctor.prototype` is in the prototype chain of `val`, as `instanceof` finds it.
+fn is_instance_of(self_: &mut VM, val: &Value, ctor: &Value) -> bool {
+    let prototype = match *ctor {
+        Value::Function(_, ref map) | Value::NeedThis(box Value::Function(_, ref map)) => {
+            map.borrow().get("prototype").cloned()
+        }
+        // A bound function checks for the function it calls.
+        Value::WithThis(box (ref callee, _)) => return is_instance_of(self_, val, callee),
+        // Promise has no prototype property, but knows its objects.
+        Value::BuiltinFunction(builtin::PROMISE) => return self_.is_promise(val),
+        Value::BuiltinFunction(_) | Value::NeedThis(_) => return false,
+        _ => {
+            self_.fatal_error(
+                ErrorKind::TypeError,
+                "right-hand side of 'instanceof' is not callable"
+            )
+        }
+    };
+    let prototype = match prototype {
+        Some(Value::Object(prototype)) => prototype,
+        _ => {
+            self_.fatal_error(
+                ErrorKind::TypeError,
+                "function has non-object prototype in 'instanceof' check"
+            )
+        }
+    };
+    let mut val = val.clone();
+    loop {
+        let proto = match val {
+            Value::Object(ref map)
+            | Value::Function(_, ref map)
+            | Value::NeedThis(box Value::Function(_, ref map)) => {
+                map.borrow().get("__proto__").cloned()
+            }
+            Value::Array(ref arr) => arr.borrow().obj.get("__proto__").cloned(),
+            _ => None,
+        };
+        match proto {
+            Some(Value::Object(ref proto)) if Rc::ptr_eq(proto, &prototype) => return true,
+            Some(proto @ Value::Object(_)) => val = proto,
+            _ => return false,
+        }
     }
 }
 
+fn typeof_(self_: &mut VM) {
+    self_.state.pc += 1; // typeof
+    let val = self_.state.pop();
+    let type_ = CString::new(type_of(&val)).unwrap();
+    self_.state.stack.push(Value::String(type_));
+}
+
 macro_rules! bin_op {
     ($name:ident, $binop:ident) => {
         fn $name(self_: &mut VM) {
@@ -562,68 +1809,298 @@ bin_op!(sne, SNe);
 
 #[inline]
 fn binary(self_: &mut VM, op: &BinOp) {
-    let rhs = self_.state.stack.pop().unwrap();
-    let lhs = self_.state.stack.pop().unwrap();
-    match (lhs, rhs) {
-        (Value::Number(n1), Value::Number(n2)) => self_.state.stack.push(match op {
-            &BinOp::Add => Value::Number(n1 + n2),
-            &BinOp::Sub => Value::Number(n1 - n2),
-            &BinOp::Mul => Value::Number(n1 * n2),
-            &BinOp::Div => Value::Number(n1 / n2),
-            &BinOp::Rem => Value::Number((n1 as i64 % n2 as i64) as f64),
-            &BinOp::Lt => Value::Bool(n1 < n2),
-            &BinOp::Gt => Value::Bool(n1 > n2),
-            &BinOp::Le => Value::Bool(n1 <= n2),
-            &BinOp::Ge => Value::Bool(n1 >= n2),
-            &BinOp::Eq => Value::Bool(n1 == n2),
-            &BinOp::Ne => Value::Bool(n1 != n2),
-            &BinOp::SEq => Value::Bool(n1 == n2),
-            &BinOp::SNe => Value::Bool(n1 != n2),
-            _ => panic!(),
-        }),
-        (Value::String(s1), Value::Number(n2)) => self_.state.stack.push(match op {
-            &BinOp::Add => {
-                let concat = format!("{}{}", s1.to_str().unwrap(), n2);
-                Value::String(CString::new(concat).unwrap())
+    let rhs = self_.state.pop();
+    let lhs = self_.state.pop();
+    let result = match (&lhs, &rhs) {
+        (&Value::Number(n1), &Value::Number(n2)) => number_op(op, n1, n2),
+        _ => match op {
+            &BinOp::SEq => Value::Bool(strict_equals(&lhs, &rhs)),
+            &BinOp::SNe => Value::Bool(!strict_equals(&lhs, &rhs)),
+            &BinOp::Eq => Value::Bool(loose_equals(self_, &lhs, &rhs)),
+            &BinOp::Ne => Value::Bool(!loose_equals(self_, &lhs, &rhs)),
+            &BinOp::Add => add_values(self_, &lhs, &rhs),
+            &BinOp::Lt | &BinOp::Gt | &BinOp::Le | &BinOp::Ge => {
+                // The left operand is converted first whichever way the comparison goes.
+                let lhs = to_primitive(self_, &lhs, Hint::Number);
+                let rhs = to_primitive(self_, &rhs, Hint::Number);
+                // Comparisons with NaN are false both ways.
+                Value::Bool(match op {
+                    &BinOp::Lt => less_than(&lhs, &rhs) == Some(true),
+                    &BinOp::Gt => less_than(&rhs, &lhs) == Some(true),
+                    &BinOp::Le => less_than(&rhs, &lhs) == Some(false),
+                    _ => less_than(&lhs, &rhs) == Some(false),
+                })
             }
-            _ => panic!(),
-        }),
-        (Value::Number(n1), Value::String(s2)) => self_.state.stack.push(match op {
-            &BinOp::Add => {
-                let concat = format!("{}{}", n1, s2.to_str().unwrap());
-                Value::String(CString::new(concat).unwrap())
+            _ => {
+                let n1 = to_number(&to_primitive(self_, &lhs, Hint::Number));
+                let n2 = to_number(&to_primitive(self_, &rhs, Hint::Number));
+                number_op(op, n1, n2)
             }
-            _ => panic!(),
-        }),
-        (Value::String(s1), Value::String(s2)) => self_.state.stack.push(match op {
-            &BinOp::Add => {
-                let concat = format!("{}{}", s1.to_str().unwrap(), s2.to_str().unwrap());
-                Value::String(CString::new(concat).unwrap())
+        },
+    };
+    self_.state.stack.push(result)
+}
+
+fn number_op(op: &BinOp, n1: f64, n2: f64) -> Value {
+    match op {
+        &BinOp::Add => Value::Number(n1 + n2),
+        &BinOp::Sub => Value::Number(n1 - n2),
+        &BinOp::Mul => Value::Number(n1 * n2),
+        &BinOp::Div => Value::Number(n1 / n2),
+        // The remainder of the truncating division, like C's fmod, rather than an IEEE 754
+        // remainder. Its sign is that of the dividend.
+        &BinOp::Rem => Value::Number(n1 % n2),
+        &BinOp::Lt => Value::Bool(n1 < n2),
+        &BinOp::Gt => Value::Bool(n1 > n2),
+        &BinOp::Le => Value::Bool(n1 <= n2),
+        &BinOp::Ge => Value::Bool(n1 >= n2),
+        &BinOp::Eq | &BinOp::SEq => Value::Bool(n1 == n2),
+        &BinOp::Ne | &BinOp::SNe => Value::Bool(n1 != n2),
+        _ => unreachable!(),
+    }
+}
+
+/// https://tc39.github.io/ecma262/#sec-addition-operator-plus
+fn add_values(self_: &mut VM, lhs: &Value, rhs: &Value) -> Value {
+    let lhs = to_primitive(self_, lhs, Hint::Default);
+    let rhs = to_primitive(self_, rhs, Hint::Default);
+    match (&lhs, &rhs) {
+        (&Value::String(_), _) | (_, &Value::String(_)) => {
+            Value::String(CString::new(format!("{}{}", lhs, rhs)).unwrap())
+        }
+        _ => Value::Number(to_number(&lhs) + to_number(&rhs)),
+    }
+}
+
+/// https://tc39.github.io/ecma262/#sec-strict-equality-comparison
+fn strict_equals(x: &Value, y: &Value) -> bool {
+    match (callee_of(x), callee_of(y)) {
+        (&Value::Undefined, &Value::Undefined) | (&Value::Null, &Value::Null) => true,
+        (&Value::Bool(b1), &Value::Bool(b2)) => b1 == b2,
+        (&Value::Number(n1), &Value::Number(n2)) => n1 == n2,
+        (&Value::String(ref s1), &Value::String(ref s2)) => s1 == s2,
+        (&Value::Function(_, ref m1), &Value::Function(_, ref m2)) => Rc::ptr_eq(m1, m2),
+        (&Value::BuiltinFunction(id1), &Value::BuiltinFunction(id2)) => id1 == id2,
+        (&Value::Object(ref m1), &Value::Object(ref m2)) => Rc::ptr_eq(m1, m2),
+        (&Value::Array(ref a1), &Value::Array(ref a2)) => Rc::ptr_eq(a1, a2),
+        (&Value::Arguments, &Value::Arguments) => true,
+        _ => false,
+    }
+}
+
+/// Returns the function that `val` calls if it is a function, e.g. the method of `obj.method`.
+fn callee_of(val: &Value) -> &Value {
+    match val {
+        &Value::NeedThis(ref callee) => callee_of(callee),
+        &Value::WithThis(ref callee_and_this) => callee_of(&callee_and_this.0),
+        val => val,
+    }
+}
+
+/// https://tc39.github.io/ecma262/#sec-abstract-equality-comparison
+fn loose_equals(self_: &mut VM, x: &Value, y: &Value) -> bool {
+    match (x, y) {
+        (&Value::Undefined, _) | (&Value::Null, _) => is_nullish(y),
+        (_, &Value::Undefined) | (_, &Value::Null) => false,
+        (&Value::Number(n), &Value::String(_)) => n == to_number(y),
+        (&Value::String(_), &Value::Number(n)) => to_number(x) == n,
+        (&Value::Bool(_), _) => loose_equals(self_, &Value::Number(to_number(x)), y),
+        (_, &Value::Bool(_)) => loose_equals(self_, x, &Value::Number(to_number(y))),
+        (&Value::Number(_), _) | (&Value::String(_), _) if is_object(y) => {
+            let y = to_primitive(self_, y, Hint::Default);
+            loose_equals(self_, x, &y)
+        }
+        (_, &Value::Number(_)) | (_, &Value::String(_)) if is_object(x) => {
+            let x = to_primitive(self_, x, Hint::Default);
+            loose_equals(self_, &x, y)
+        }
+        _ => strict_equals(x, y),
+    }
+}
+
+/// https://tc39.github.io/ecma262/#sec-abstract-relational-comparison
+/// Compares primitives, so objects must go through `to_primitive` first.
+/// Returns None if either operand is NaN.
+fn less_than(x: &Value, y: &Value) -> Option<bool> {
+    match (x, y) {
+        (&Value::String(ref s1), &Value::String(ref s2)) => {
+            let (s1, s2) = (s1.to_str().unwrap(), s2.to_str().unwrap());
+            Some(s1.encode_utf16().lt(s2.encode_utf16()))
+        }
+        (x, y) => {
+            let (n1, n2) = (to_number(x), to_number(y));
+            if n1.is_nan() || n2.is_nan() {
+                None
+            } else {
+                Some(n1 < n2)
             }
-            _ => panic!(),
-        }),
+        }
+    }
+}
+
+fn is_object(val: &Value) -> bool {
+    match val {
+        &Value::Undefined
+        | &Value::Null
+        | &Value::Bool(_)
+        | &Value::Number(_)
+        | &Value::String(_) => false,
+        _ => true,
+    }
+}
+
+fn is_nullish(val: &Value) -> bool {
+    match val {
+        &Value::Undefined | &Value::Null => true,
+        _ => false,
+    }
+}
+
+/// The preferred type passed to `to_primitive`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hint {
+    Default,
+    Number,
+    String,
+}
+
+/// https://tc39.github.io/ecma262/#sec-toprimitive
+// TODO: Call Symbol.toPrimitive first once there are symbols.
+pub fn to_primitive(self_: &mut VM, val: &Value, hint: Hint) -> Value {
+    if !is_object(val) {
+        return val.clone();
+    }
+    // https://tc39.github.io/ecma262/#sec-ordinarytoprimitive
+    let methods = if hint == Hint::String {
+        ["toString", "valueOf"]
+    } else {
+        ["valueOf", "toString"]
+    };
+    for name in methods.iter() {
+        push_member(self_, val.clone(), Value::String(CString::new(*name).unwrap()));
+        let method = self_.state.pop();
+        if type_of(&method) == "function" {
+            let result = self_.call_value(method, vec![]);
+            if !is_object(&result) {
+                return result;
+            }
+        }
+    }
+    // Builtin functions, bound methods and arguments objects have no prototype to look the
+    // methods up on yet.
+    match callee_of(val) {
+        &Value::BuiltinFunction(_) | &Value::Function(_, _) | &Value::Arguments => {
+            return Value::String(CString::new(self_.value_to_string(val)).unwrap())
+        }
         _ => {}
     }
+    self_.fatal_error(ErrorKind::TypeError, "cannot convert object to primitive value")
+}
+
+/// https://tc39.github.io/ecma262/#sec-typeof-operator
+pub fn type_of(val: &Value) -> &'static str {
+    match val {
+        &Value::Undefined => "undefined",
+        &Value::Null => "object",
+        &Value::Bool(_) => "boolean",
+        &Value::Number(_) => "number",
+        &Value::String(_) => "string",
+        &Value::Function(_, _)
+        | &Value::NeedThis(_)
+        | &Value::WithThis(_)
+        | &Value::BuiltinFunction(_) => "function",
+        &Value::Object(_) | &Value::Array(_) | &Value::Arguments => "object",
+        // Accessor properties are read through their getters, so they are never operands.
+        &Value::Accessor(_) => unreachable!(),
+    }
+}
+
+/// https://tc39.github.io/ecma262/#sec-toboolean
+pub fn to_boolean(val: &Value) -> bool {
+    match val {
+        &Value::Undefined | &Value::Null => false,
+        &Value::Bool(b) => b,
+        &Value::Number(n) => n != 0.0 && !n.is_nan(),
+        &Value::String(ref s) => !s.as_bytes().is_empty(),
+        _ => true,
+    }
+}
+
+/// https://tc39.github.io/ecma262/#sec-tonumber
+/// Converts a primitive to a number.
+fn to_number(val: &Value) -> f64 {
+    match val {
+        &Value::Undefined => ::std::f64::NAN,
+        &Value::Null => 0.0,
+        &Value::Bool(b) => if b { 1.0 } else { 0.0 },
+        &Value::Number(n) => n,
+        &Value::String(ref s) => string_to_number(s.to_str().unwrap()),
+        // Objects go through to_primitive first, which needs the VM to call their methods.
+        _ => unreachable!(),
+    }
+}
+
+/// https://tc39.github.io/ecma262/#sec-tonumber-applied-to-the-string-type
+fn string_to_number(s: &str) -> f64 {
+    let s = s.trim();
+    let radix = match s.get(..2) {
+        Some("0x") | Some("0X") => 16,
+        Some("0o") | Some("0O") => 8,
+        Some("0b") | Some("0B") => 2,
+        _ => 10,
+    };
+    if radix != 10 {
+        let digits = &s[2..];
+        if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+            return ::std::f64::NAN;
+        }
+        return digits.chars().fold(0.0, |n, c| {
+            n * radix as f64 + c.to_digit(radix).unwrap() as f64
+        });
+    }
+    let unsigned = if s.starts_with('+') || s.starts_with('-') {
+        &s[1..]
+    } else {
+        s
+    };
+    match unsigned {
+        "" if s.is_empty() => 0.0,
+        "Infinity" if s.starts_with('-') => ::std::f64::NEG_INFINITY,
+        "Infinity" => ::std::f64::INFINITY,
+        // Rust also reads "inf" and "NaN", which JavaScript does not.
+        digits if digits.chars().all(|c| c.is_digit(10) || "eE.+-".contains(c)) => {
+            s.parse().unwrap_or(::std::f64::NAN)
+        }
+        _ => ::std::f64::NAN,
+    }
 }
 
 fn get_member(self_: &mut VM) {
     self_.state.pc += 1; // get_global
-    let member = self_.state.stack.pop().unwrap();
-    let parent = self_.state.stack.pop().unwrap();
+    let member = self_.state.pop();
+    let parent = self_.state.pop();
+    push_member(self_, parent, member)
+}
+
+/// Pushes the property `member` of `parent`. A method that uses `this` is pushed bound to
+/// `parent`.
+fn push_member(self_: &mut VM, parent: Value, member: Value) {
+    let member = index_key(member);
     match parent.clone() {
         Value::String(s) => {
             match member {
                 // Index
-                Value::Number(n) if n - n.floor() == 0.0 => self_.state.stack.push(Value::String(
-                    CString::new(
-                        s.to_str()
-                            .unwrap()
-                            .chars()
-                            .nth(n as usize)
-                            .unwrap()
-                            .to_string(),
-                    ).unwrap(),
-                )),
+                Value::Number(n) if n - n.floor() == 0.0 => {
+                    let c = if n < 0.0 {
+                        None
+                    } else {
+                        s.to_str().unwrap().chars().nth(n as usize)
+                    };
+                    self_.state.stack.push(match c {
+                        Some(c) => Value::String(CString::new(c.to_string()).unwrap()),
+                        None => Value::Undefined,
+                    })
+                }
                 Value::String(ref member) if member.to_str().unwrap() == "length" => {
                     self_.state.stack.push(Value::Number(
                         s.to_str()
@@ -632,23 +2109,40 @@ fn get_member(self_: &mut VM) {
                             .fold(0, |x, c| x + c.len_utf16()) as f64,
                     ));
                 }
-                // TODO: Support all features.
-                _ => self_.state.stack.push(Value::Undefined),
+                _ => push_primitive_method(self_, parent, &member),
+            }
+        }
+        Value::Object(map) => {
+            let val = find_member(&*map.borrow(), &member);
+            match val {
+                Value::NeedThis(callee) => self_.state.stack.push(Value::WithThis(Box::new((
+                    *callee,
+                    Value::Object(map.clone()),
+                )))),
+                Value::Accessor(box (getter, _)) => {
+                    let val = call_accessor(self_, getter, Value::Object(map.clone()), vec![]);
+                    self_.state.stack.push(val)
+                }
+                // Objects do not inherit from Object.prototype yet, so toString is looked up as a
+                // primitive's is.
+                Value::Undefined => {
+                    push_primitive_method(self_, Value::Object(map.clone()), &member)
+                }
+                val => self_.state.stack.push(val),
             }
         }
-        Value::Object(map) => match obj_find_val(&*map.borrow(), member.to_string().as_str()) {
-            Value::NeedThis(callee) => self_.state.stack.push(Value::WithThis(Box::new((
-                *callee,
-                Value::Object(map.clone()),
-            )))),
-            val => self_.state.stack.push(val),
-        },
         Value::Function(pos, map) | Value::NeedThis(box Value::Function(pos, map)) => {
-            match obj_find_val(&*map.borrow(), member.to_string().as_str()) {
+            let val = obj_find_val(&*map.borrow(), member.to_string().as_str());
+            match val {
                 Value::NeedThis(callee) => self_.state.stack.push(Value::WithThis(Box::new((
                     *callee,
                     Value::Function(pos, map.clone()),
                 )))),
+                Value::Accessor(box (getter, _)) => {
+                    let this = Value::Function(pos, map.clone());
+                    let val = call_accessor(self_, getter, this, vec![]);
+                    self_.state.stack.push(val)
+                }
                 val => self_.state.stack.push(val),
             }
         }
@@ -657,11 +2151,10 @@ fn get_member(self_: &mut VM) {
             match member {
                 // Index
                 Value::Number(n) if n - n.floor() == 0.0 => {
-                    let arr = &map.elems;
-                    if n as usize >= map.length {
+                    if n < 0.0 || n as usize >= map.length {
                         self_.state.stack.push(Value::Undefined);
                     } else {
-                        self_.state.stack.push(arr[n as usize].clone())
+                        self_.state.stack.push(map.elems[n as usize].clone())
                     }
                 }
                 Value::String(ref s) if s.to_str().unwrap() == "length" => {
@@ -672,6 +2165,7 @@ fn get_member(self_: &mut VM) {
                         .state
                         .stack
                         .push(Value::WithThis(Box::new((*callee, parent)))),
+                    Value::Undefined => push_primitive_method(self_, parent, &member),
                     val => self_.state.stack.push(val),
                 },
             }
@@ -680,69 +2174,275 @@ fn get_member(self_: &mut VM) {
             match member {
                 // Index
                 Value::Number(n) if n - n.floor() == 0.0 => {
-                    let idx = self_.state.bp + n as usize;
-                    if idx < self_.state.lp {
-                        let val = self_.state.stack[idx].clone();
+                    if n >= 0.0 && (n as usize) < self_.state.argc {
+                        let val = self_.state.stack[self_.state.bp + n as usize].clone();
                         self_.state.stack.push(val);
+                    } else {
+                        self_.state.stack.push(Value::Undefined);
                     }
                 }
                 Value::String(ref s) if s.to_str().unwrap() == "length" => {
                     self_
                         .state
                         .stack
-                        .push(Value::Number(self_.state.lp as f64 - self_.state.bp as f64));
+                        .push(Value::Number(self_.state.argc as f64));
                 }
                 _ => self_.state.stack.push(Value::Undefined),
             }
         }
-        e => unreachable!("{:?}", e),
+        // A method read from an object, such as the superclass read from a constructor, has the
+        // properties of the function.
+        Value::WithThis(box (callee @ Value::Function(_, _), _)) => {
+            push_member(self_, callee, member)
+        }
+        Value::Number(_)
+        | Value::Bool(_)
+        | Value::BuiltinFunction(_)
+        | Value::NeedThis(box Value::BuiltinFunction(_))
+        | Value::WithThis(_) => push_primitive_method(self_, parent, &member),
+        // TODO: Properties of functions other than Function(_, _).
+        Value::Undefined | Value::Null => {
+            let msg = format!("Cannot read properties of {} (reading '{}')", parent, member);
+            self_.fatal_error(ErrorKind::TypeError, msg.as_str())
+        }
+        _ => self_.state.stack.push(Value::Undefined),
+    }
+}
+
+/// Calls the getter or the setter `func` of an accessor property of `this`. A property without a
+/// getter reads as undefined.
+fn call_accessor(self_: &mut VM, func: Value, this: Value, args: Vec<Value>) -> Value {
+    let callee = match func {
+        Value::Undefined => return Value::Undefined,
+        Value::NeedThis(box func) => Value::WithThis(Box::new((func, this))),
+        func => func,
+    };
+    self_.call_value(callee, args)
+}
+
+/// Returns the address of the properties of the object `val`, which tells it apart from the other
+/// objects alive.
+pub fn object_addr(val: &Value) -> Option<usize> {
+    match *val {
+        Value::Object(ref map)
+        | Value::Function(_, ref map)
+        | Value::NeedThis(box Value::Function(_, ref map)) => {
+            Some(&**map as *const RefCell<PropertyMap> as usize)
+        }
+        Value::Array(ref arr) => Some(&**arr as *const RefCell<ArrayValue> as usize),
+        _ => None,
+    }
+}
+
+/// Returns the keys of the properties of `obj` in the order that they are enumerated: array
+/// indices in ascending order, then the other keys in the order they were added. The prototype
+/// (__proto__) is not a property.
+///
+/// TODO: JSON.stringify should enumerate properties in this order once it exists.
+pub fn own_keys(obj: &PropertyMap) -> Vec<String> {
+    let mut indices = vec![];
+    let mut others = vec![];
+    for key in obj.keys() {
+        match array_index(key.as_str()) {
+            Some(index) => indices.push((index, key)),
+            None if key != "__proto__" => others.push(key),
+            None => {}
+        }
+    }
+    indices.sort();
+    indices.into_iter().map(|(_, key)| key).chain(others).collect()
+}
+
+/// Returns the keys of the object `map` as own_keys() does. The global object leaves out the
+/// global variables that the AST passes name (e.g. `anonymous.1`), which scripts cannot declare.
+pub fn own_object_keys(self_: &VM, map: &Rc<RefCell<PropertyMap>>) -> Vec<String> {
+    let keys = own_keys(&map.borrow());
+    if !Rc::ptr_eq(map, &self_.global_objects) {
+        return keys;
+    }
+    let is_made_up = |key: &String| match key.rfind('.') {
+        Some(i) => i > 0 && i + 1 < key.len() && key[i + 1..].bytes().all(|b| b.is_ascii_digit()),
+        None => false,
+    };
+    keys.into_iter().filter(|key| !is_made_up(key)).collect()
+}
+
+/// Returns the index `key` names if it is an array index: an integer below 2^32 - 1 written
+/// without a sign or leading zeros.
+pub fn array_index(key: &str) -> Option<u32> {
+    let bytes = key.as_bytes();
+    match bytes.first() {
+        Some(&b'0') if bytes.len() == 1 => return Some(0),
+        Some(&b'1'...b'9') if bytes.len() <= 10 => {}
+        _ => return None,
+    }
+    let mut index: u64 = 0;
+    for &b in bytes {
+        if !b.is_ascii_digit() {
+            return None;
+        }
+        index = index * 10 + (b - b'0') as u64;
+    }
+    if index < ::std::u32::MAX as u64 {
+        Some(index as u32)
+    } else {
+        None
+    }
+}
+
+/// Returns the key `key` as a number if it is a string that is an array index, like the keys
+/// for-in gives. Both name the same element of an array.
+fn index_key(key: Value) -> Value {
+    if let Value::String(ref s) = key {
+        if let Some(index) = array_index(s.to_str().unwrap()) {
+            return Value::Number(index as f64);
+        }
+    }
+    key
+}
+
+/// Returns the builtin method `name` of a string, number or boolean. Primitives are not objects,
+/// so their methods are looked up here instead of on a wrapper object. Objects and arrays do not
+/// inherit from Object.prototype yet, so they find its methods here too, and builtin functions
+/// find call(). The static methods of Promise are found here too.
+fn primitive_method(val: &Value, name: &str) -> Option<usize> {
+    match (val, name) {
+        (&Value::BuiltinFunction(builtin::PROMISE), "resolve") => Some(builtin::PROMISE_RESOLVE),
+        (&Value::BuiltinFunction(builtin::PROMISE), "reject") => Some(builtin::PROMISE_REJECT),
+        (&Value::BuiltinFunction(_), "call")
+        | (&Value::NeedThis(box Value::BuiltinFunction(_)), "call")
+        | (&Value::WithThis(_), "call") => Some(builtin::FUNCTION_PROTOTYPE_CALL),
+        (&Value::String(_), "charAt") => Some(builtin::STRING_CHAR_AT),
+        (&Value::String(_), "indexOf") => Some(builtin::STRING_INDEX_OF),
+        (&Value::String(_), "slice") => Some(builtin::STRING_SLICE),
+        (&Value::String(_), "toUpperCase") | (&Value::String(_), "toLocaleUpperCase") => {
+            Some(builtin::STRING_TO_UPPER_CASE)
+        }
+        (&Value::String(_), "toLowerCase") | (&Value::String(_), "toLocaleLowerCase") => {
+            Some(builtin::STRING_TO_LOWER_CASE)
+        }
+        (&Value::String(_), "normalize") => Some(builtin::STRING_NORMALIZE),
+        (&Value::String(_), "localeCompare") => Some(builtin::STRING_LOCALE_COMPARE),
+        (&Value::Number(_), "toFixed") => Some(builtin::NUMBER_TO_FIXED),
+        (_, "toString") => Some(builtin::PRIMITIVE_TO_STRING),
+        (_, "hasOwnProperty") => Some(builtin::OBJECT_PROTOTYPE_HAS_OWN_PROPERTY),
+        (_, "propertyIsEnumerable") => Some(builtin::OBJECT_PROTOTYPE_PROPERTY_IS_ENUMERABLE),
+        _ => None,
     }
 }
 
-pub fn obj_find_val(obj: &HashMap<String, Value>, key: &str) -> Value {
+/// Pushes the method `member` of the primitive `parent`, bound to it, or undefined if there is
+/// no such method.
+fn push_primitive_method(self_: &mut VM, parent: Value, member: &Value) {
+    let val = match primitive_method(&parent, member.to_string().as_str()) {
+        Some(id) => Value::WithThis(Box::new((Value::BuiltinFunction(id), parent))),
+        None => Value::Undefined,
+    };
+    self_.state.stack.push(val)
+}
+
+/// Looks up `key` in `obj` and then along its prototype chain. The constructor of a derived
+/// class has the superclass, a function, as its prototype.
+pub fn obj_find_val(obj: &PropertyMap, key: &str) -> Value {
     match obj.get(key) {
         Some(addr) => addr.clone(),
         None => match obj.get("__proto__") {
-            Some(Value::Object(obj)) => obj_find_val(&*(*obj).borrow(), key),
+            Some(Value::Object(obj))
+            | Some(Value::Function(_, obj))
+            | Some(Value::NeedThis(box Value::Function(_, obj))) => {
+                obj_find_val(&*(*obj).borrow(), key)
+            }
             _ => Value::Undefined,
         },
     }
 }
 
+/// Looks up `member`, a key as index_key gives it, like obj_find_val does. An array index is
+/// looked up without making it a string, unless it has to be looked up in the prototypes.
+fn find_member(obj: &PropertyMap, member: &Value) -> Value {
+    if let Some(val) = member_index(member).and_then(|index| obj.get_index(index)) {
+        return val.clone();
+    }
+    obj_find_val(obj, member.to_string().as_str())
+}
+
+/// Returns the array index that `member`, a key as index_key gives it, is, if it is one.
+fn member_index(member: &Value) -> Option<u32> {
+    match *member {
+        Value::Number(n) if n >= 0.0 && n < ::std::u32::MAX as f64 && n.fract() == 0.0 => {
+            Some(n as u32)
+        }
+        _ => None,
+    }
+}
+
 fn set_member(self_: &mut VM) {
     self_.state.pc += 1; // get_global
-    let member = self_.state.stack.pop().unwrap();
-    let parent = self_.state.stack.pop().unwrap();
-    let val = self_.state.stack.pop().unwrap();
+    let member = index_key(self_.state.pop());
+    let parent = self_.state.pop();
+    let val = self_.state.pop();
+    if let Some(level) = self_.integrity_level(&parent) {
+        if let Some(msg) = locked_member_error(&parent, &member, &val, level) {
+            // Fails silently in sloppy mode.
+            if self_.strict {
+                self_.fatal_error(ErrorKind::TypeError, msg.as_str())
+            }
+            return;
+        }
+    }
     match parent {
-        Value::Object(map)
-        | Value::Function(_, map)
-        | Value::NeedThis(box Value::Function(_, map)) => {
-            *map.borrow_mut()
-                .entry(member.to_string())
-                .or_insert_with(|| Value::Undefined) = val;
+        Value::Object(ref map)
+        | Value::Function(_, ref map)
+        | Value::NeedThis(box Value::Function(_, ref map)) => {
+            // Assigning to an accessor property, whether own or inherited, calls its setter.
+            let prop = find_member(&*map.borrow(), &member);
+            match prop {
+                Value::Accessor(box (_, Value::Undefined)) => {
+                    // Fails silently in sloppy mode.
+                    if self_.strict {
+                        let msg = format!("cannot set '{}', which has only a getter", member);
+                        self_.fatal_error(ErrorKind::TypeError, msg.as_str())
+                    }
+                }
+                Value::Accessor(box (_, setter)) => {
+                    call_accessor(self_, setter, parent.clone(), vec![val]);
+                }
+                _ => match member_index(&member) {
+                    Some(index) => {
+                        map.borrow_mut().insert_index(index, val);
+                    }
+                    None => {
+                        map.borrow_mut().insert(member.to_string(), val);
+                    }
+                },
+            }
         }
         Value::Array(map) => {
             let mut map = map.borrow_mut();
             match member {
                 // Index
-                Value::Number(n) if n - n.floor() == 0.0 => {
-                    if n as usize >= map.length as usize {
-                        map.length = n as usize;
-                        unsafe {
-                            map.elems.set_len(n as usize);
-                        };
+                Value::Number(n) if n - n.floor() == 0.0 && n >= 0.0 => {
+                    if n as usize >= MAX_ARRAY_LENGTH {
+                        self_.report_error("invalid array length");
+                        return;
+                    }
+                    if n as usize >= map.length {
+                        map.set_length(n as usize + 1);
                     }
                     map.elems[n as usize] = val;
                 }
                 Value::String(ref s) if s.to_str().unwrap() == "length" => match val {
-                    Value::Number(n) if n - n.floor() == 0.0 => map.length = n as usize,
+                    Value::Number(n) if n - n.floor() == 0.0 && n >= 0.0 => {
+                        if n as usize > MAX_ARRAY_LENGTH {
+                            self_.report_error("invalid array length");
+                            return;
+                        }
+                        map.set_length(n as usize)
+                    }
                     _ => {}
                 },
                 _ => {
-                    *map.obj
-                        .entry(member.to_string())
-                        .or_insert_with(|| Value::Undefined) = val
+                    map.obj.insert(member.to_string(), val);
                 }
             }
         }
@@ -750,36 +2450,174 @@ fn set_member(self_: &mut VM) {
             match member {
                 // Index
                 Value::Number(n) if n - n.floor() == 0.0 => {
-                    let idx = self_.state.bp + n as usize;
-                    if idx < self_.state.lp {
-                        self_.state.stack[idx] = val;
+                    if n >= 0.0 && (n as usize) < self_.state.argc {
+                        self_.state.stack[self_.state.bp + n as usize] = val;
                     }
                 }
                 _ => {}
             }
         }
-        e => unreachable!("{:?}", e),
+        Value::Undefined | Value::Null => {
+            let msg = format!("Cannot set properties of {} (setting '{}')", parent, member);
+            self_.fatal_error(ErrorKind::TypeError, msg.as_str())
+        }
+        // Properties of primitives are not kept.
+        _ => {}
+    }
+}
+
+fn delete_member(self_: &mut VM) {
+    self_.state.pc += 1; // delete_member
+    let key = self_.state.pop().to_string();
+    let parent = self_.state.pop();
+    let deleted = delete_property(self_, &parent, key.as_str());
+    self_.state.stack.push(Value::Bool(deleted))
+}
+
+/// Removes the own property `key` of `val`, and returns whether it is gone. The length of arrays,
+/// strings and arguments, the characters of strings, the prototype of functions and the
+/// properties of sealed objects cannot be removed, which is a TypeError in strict mode.
+fn delete_property(self_: &mut VM, val: &Value, key: &str) -> bool {
+    if let Value::Undefined | Value::Null = *val {
+        let msg = format!(
+            "cannot delete properties of {} (deleting '{}')",
+            describe_value(val),
+            key
+        );
+        self_.fatal_error(ErrorKind::TypeError, msg.as_str())
+    }
+    let configurable = match own_property(self_, val, key) {
+        None => return true,
+        // The properties that are not enumerable cannot be removed either.
+        Some(false) => false,
+        // The arguments object cannot lose its elements yet.
+        Some(true) => match *val {
+            Value::String(_) | Value::Arguments => false,
+            _ => self_.integrity_level(val).is_none(),
+        },
+    };
+    if !configurable {
+        if self_.strict {
+            let msg = format!("cannot delete property '{}' of {}", key, describe_value(val));
+            self_.fatal_error(ErrorKind::TypeError, msg.as_str())
+        }
+        return false;
+    }
+    match *val {
+        Value::Object(ref map)
+        | Value::Function(_, ref map)
+        | Value::NeedThis(box Value::Function(_, ref map)) => {
+            map.borrow_mut().remove(key);
+        }
+        Value::Array(ref arr) => {
+            let mut arr = arr.borrow_mut();
+            match array_index(key) {
+                // Arrays are not sparse, so the element is left undefined instead of as a hole.
+                Some(index) if (index as usize) < arr.length => {
+                    arr.elems[index as usize] = Value::Undefined
+                }
+                _ => {
+                    arr.obj.remove(key);
+                }
+            }
+        }
+        _ => {}
+    }
+    true
+}
+
+/// Returns why `member` of `parent`, which is sealed or frozen to `level`, cannot be set to `val`,
+/// or None if it can.
+fn locked_member_error(
+    parent: &Value,
+    member: &Value,
+    val: &Value,
+    level: IntegrityLevel,
+) -> Option<String> {
+    let key = member.to_string();
+    let exists = match *parent {
+        Value::Object(ref map)
+        | Value::Function(_, ref map)
+        | Value::NeedThis(box Value::Function(_, ref map)) => map.borrow().contains_key(&key),
+        Value::Array(ref arr) => {
+            let arr = arr.borrow();
+            match (member, val) {
+                // The elements of a sealed array cannot be removed by shortening it.
+                (&Value::String(ref s), &Value::Number(n))
+                    if s.to_str().unwrap() == "length" && n < arr.length as f64 =>
+                {
+                    if level == IntegrityLevel::Sealed {
+                        return Some(format!(
+                            "cannot delete property '{}' of [object Array]",
+                            arr.length - 1
+                        ));
+                    }
+                    true
+                }
+                (&Value::String(ref s), _) if s.to_str().unwrap() == "length" => true,
+                (&Value::Number(n), _) if n - n.floor() == 0.0 && n >= 0.0 => {
+                    (n as usize) < arr.length
+                }
+                _ => arr.obj.contains_key(&key),
+            }
+        }
+        _ => return None,
+    };
+    if !exists {
+        Some(format!("cannot add property {}, object is not extensible", key))
+    } else if level == IntegrityLevel::Frozen {
+        Some(format!("cannot assign to read only property '{}' of object", key))
+    } else {
+        None
     }
 }
 
 fn get_global(self_: &mut VM) {
     self_.state.pc += 1; // get_global
     get_int32!(self_, n, usize);
-    let val = (*(*self_.global_objects)
-        .borrow()
-        .get(self_.const_table.string[n].as_str())
-        .unwrap())
-        .clone();
+    let val = match self_.cached_global_slot(n) {
+        Some(slot) => global_at(self_, slot),
+        None => {
+            let msg = format!("{} is not defined", self_.const_table.string[n]);
+            self_.fatal_error(ErrorKind::ReferenceError, msg.as_str())
+        }
+    };
+    self_.state.stack.push(val);
+}
+
+fn global_at(self_: &VM, slot: usize) -> Value {
+    (*self_.global_objects).borrow().get_position(slot).unwrap().clone()
+}
+
+/// Reads a global variable for typeof, which gives "undefined" for undeclared ones.
+fn get_global_or_undefined(self_: &mut VM) {
+    self_.state.pc += 1; // get_global_or_undefined
+    get_int32!(self_, n, usize);
+    let val = match self_.cached_global_slot(n) {
+        Some(slot) => global_at(self_, slot),
+        None => Value::Undefined,
+    };
     self_.state.stack.push(val);
 }
 
 fn set_global(self_: &mut VM) {
     self_.state.pc += 1; // set_global
     get_int32!(self_, n, usize);
-    *(*self_.global_objects)
-        .borrow_mut()
-        .entry(self_.const_table.string[n].clone())
-        .or_insert_with(|| Value::Undefined) = self_.state.stack.pop().unwrap();
+    let val = self_.state.pop();
+    match self_.cached_global_slot(n) {
+        Some(slot) => {
+            let mut global_objects = (*self_.global_objects).borrow_mut();
+            *global_objects.get_position_mut(slot).unwrap() = val
+        }
+        None => {
+            let name = self_.const_table.string[n].clone();
+            if self_.strict {
+                let msg = format!("{} is not defined", name);
+                self_.fatal_error(ErrorKind::ReferenceError, msg.as_str())
+            }
+            self_.set_global(name, val)
+        }
+    }
 }
 
 fn get_local(self_: &mut VM) {
@@ -792,10 +2630,182 @@ fn get_local(self_: &mut VM) {
 fn set_local(self_: &mut VM) {
     self_.state.pc += 1; // set_local
     get_int32!(self_, n, usize);
-    let val = self_.state.stack.pop().unwrap();
+    let val = self_.state.pop();
     self_.state.stack[self_.state.lp + n] = val;
 }
 
+/// Takes whether the declaration of a variable declared with let or const has run, and stops if
+/// it has not, as the variable cannot be used before.
+fn check_initialized(self_: &mut VM) {
+    self_.state.pc += 1; // check_initialized
+    get_int32!(self_, n, usize);
+    // A variable of a function that has not run is not initialized either.
+    if self_.state.pop() != Value::Bool(true) {
+        let msg = format!(
+            "cannot access '{}' before initialization",
+            self_.const_table.string[n]
+        );
+        self_.fatal_error(ErrorKind::ReferenceError, msg.as_str())
+    }
+}
+
+/// Takes the value being assigned to a variable declared with const, which cannot be assigned.
+fn assign_to_const(self_: &mut VM) {
+    self_.state.pc += 1; // assign_to_const
+    get_int32!(self_, n, usize);
+    self_.state.pop();
+    let msg = format!(
+        "assignment to constant variable '{}'",
+        self_.const_table.string[n]
+    );
+    self_.fatal_error(ErrorKind::TypeError, msg.as_str())
+}
+
+/// Pushes the environment that the function being run closes over.
+fn get_env(self_: &mut VM) {
+    self_.state.pc += 1; // get_env
+    let env = self_.state.history.last().unwrap().env.clone();
+    self_.state.stack.push(env);
+}
+
+/// Takes an environment, or undefined, and pushes a new one inheriting its variables.
+fn new_env(self_: &mut VM) {
+    self_.state.pc += 1; // new_env
+    let mut map = PropertyMap::new();
+    match self_.state.pop() {
+        Value::Undefined => {}
+        parent => {
+            map.insert("__proto__".to_string(), parent);
+        }
+    }
+    self_.state.stack.push(Value::Object(Rc::new(RefCell::new(map))));
+}
+
+/// Takes an environment and pushes a copy of it, which the next iteration of a loop runs in, so
+/// that the closures made in the last one keep their variables.
+fn copy_env(self_: &mut VM) {
+    self_.state.pc += 1; // copy_env
+    let map = match self_.state.pop() {
+        Value::Object(map) => map.borrow().clone(),
+        _ => unreachable!(),
+    };
+    self_.state.stack.push(Value::Object(Rc::new(RefCell::new(map))));
+}
+
+/// Takes an environment and pushes the variable named by the operand, which it has or inherits.
+fn get_env_var(self_: &mut VM) {
+    self_.state.pc += 1; // get_env_var
+    get_int32!(self_, n, usize);
+    let env = self_.state.pop();
+    let val = match env_holding(env, &self_.const_table.string[n]) {
+        Some(map) => map.borrow()[&self_.const_table.string[n]].clone(),
+        None => uninitialized_env_var(self_, n),
+    };
+    self_.state.stack.push(val);
+}
+
+/// Takes a value and an environment, and assigns the value to the variable named by the operand,
+/// which the environment has or inherits.
+fn set_env_var(self_: &mut VM) {
+    self_.state.pc += 1; // set_env_var
+    get_int32!(self_, n, usize);
+    let env = self_.state.pop();
+    let val = self_.state.pop();
+    match env_holding(env, &self_.const_table.string[n]) {
+        Some(map) => map
+            .borrow_mut()
+            .insert(self_.const_table.string[n].clone(), val),
+        None => uninitialized_env_var(self_, n),
+    };
+}
+
+/// Returns the properties of `env`, or of the environment it inherits from, that hold the
+/// variable `name`. A variable is added once its declaration has run.
+fn env_holding(env: Value, name: &str) -> Option<Rc<RefCell<PropertyMap>>> {
+    let mut env = env;
+    loop {
+        let parent = match env {
+            Value::Object(ref map) if map.borrow().contains_key(name) => return Some(map.clone()),
+            Value::Object(ref map) => map.borrow().get("__proto__").cloned()?,
+            _ => return None,
+        };
+        env = parent;
+    }
+}
+
+fn uninitialized_env_var(self_: &mut VM, n: usize) -> ! {
+    // Variables captured by closures are renamed to `name.N`.
+    let msg = format!(
+        "cannot access '{}' before initialization",
+        self_.const_table.string[n].split('.').next().unwrap()
+    );
+    self_.fatal_error(ErrorKind::ReferenceError, msg.as_str())
+}
+
+/// Takes a function and an environment, and pushes a closure over the environment: a new
+/// function object running the same code. A function made where there is no environment is
+/// pushed as it is.
+fn make_closure(self_: &mut VM) {
+    self_.state.pc += 1; // make_closure
+    let env = self_.state.pop();
+    let func = self_.state.pop();
+    if env == Value::Undefined {
+        return self_.state.stack.push(func);
+    }
+    let closure = match func {
+        Value::Function(pos, _) => new_value_function(pos),
+        Value::NeedThis(box Value::Function(pos, _)) => {
+            Value::NeedThis(Box::new(new_value_function(pos)))
+        }
+        _ => unreachable!(),
+    };
+    let weak = match closure {
+        Value::Function(_, ref map) | Value::NeedThis(box Value::Function(_, ref map)) => {
+            Rc::downgrade(map)
+        }
+        _ => unreachable!(),
+    };
+    // Forget the closures that have been freed every time the table doubles.
+    if self_.closure_envs.len().is_power_of_two() {
+        self_
+            .closure_envs
+            .retain(|_, &mut (_, ref weak)| weak.upgrade().is_some());
+    }
+    let addr = object_addr(&closure).unwrap();
+    self_.closure_envs.insert(addr, (env, weak));
+    self_.stats.allocations += 1;
+    self_.state.stack.push(closure);
+}
+
+/// Takes an environment and pushes the object of the innermost with statement in it that has the
+/// property named by the operand, or undefined if none has. A with statement keeps its object in
+/// the `with` variable of an environment of its own, which closures made in its body capture.
+fn get_with_object(self_: &mut VM) {
+    self_.state.pc += 1; // get_with_object
+    get_int32!(self_, n, usize);
+    let mut env = self_.state.pop();
+    loop {
+        let (obj, parent) = match env {
+            Value::Object(ref map) => {
+                let map = map.borrow();
+                (map.get("with").cloned(), map.get("__proto__").cloned())
+            }
+            _ => break,
+        };
+        if let Some(obj) = obj {
+            let name = self_.const_table.string[n].clone();
+            if has_property(self_, &obj, name.as_str()) {
+                return self_.state.stack.push(obj);
+            }
+        }
+        match parent {
+            Some(parent) => env = parent,
+            None => break,
+        }
+    }
+    self_.state.stack.push(Value::Undefined)
+}
+
 fn get_arg_local(self_: &mut VM) {
     self_.state.pc += 1; // get_arg_local
     get_int32!(self_, n, usize);
@@ -806,7 +2816,7 @@ fn get_arg_local(self_: &mut VM) {
 fn set_arg_local(self_: &mut VM) {
     self_.state.pc += 1; // set_arg_local
     get_int32!(self_, n, usize);
-    let val = self_.state.stack.pop().unwrap();
+    let val = self_.state.pop();
     self_.state.stack[self_.state.bp + n] = val;
 }
 
@@ -821,11 +2831,32 @@ fn jmp(self_: &mut VM) {
     self_.state.pc += dst as isize;
 }
 
+/// Takes a value and jumps to the offset the table gives for it. The table holds `len` offsets
+/// for the integers from `min`, and any other value jumps to the default offset. The offsets are
+/// from the end of the table.
+fn jmp_table(self_: &mut VM) {
+    self_.state.pc += 1; // jmp_table
+    get_int32!(self_, min, i32);
+    get_int32!(self_, len, usize);
+    get_int32!(self_, default, i32);
+    let table = self_.state.pc;
+    let end = table + 4 * len as isize;
+    let dst = match self_.state.pop() {
+        Value::Number(n) if n.fract() == 0.0 && n >= min as f64 && n < min as f64 + len as f64 => {
+            self_.state.pc = table + 4 * (n as i64 - min as i64) as isize;
+            get_int32!(self_, dst, i32);
+            dst
+        }
+        _ => default,
+    };
+    self_.state.pc = end + dst as isize;
+}
+
 fn jmp_if_false(self_: &mut VM) {
     self_.state.pc += 1; // jmp_if_false
     get_int32!(self_, dst, i32);
-    let cond = self_.state.stack.pop().unwrap();
-    if let Value::Bool(false) = cond {
+    let cond = self_.state.pop();
+    if !to_boolean(&cond) {
         self_.state.pc += dst as isize
     }
 }
@@ -833,63 +2864,51 @@ fn jmp_if_false(self_: &mut VM) {
 fn call(self_: &mut VM) {
     self_.state.pc += 1; // Call
     get_int32!(self_, argc, usize);
-    let mut argc = argc;
+    let callee = self_.state.pop();
+    call_with_args_on_stack(self_, callee, argc);
+}
 
-    let mut this = None;
+/// Calls `callee` with the `argc` values on top of the stack, and leaves its result there.
+fn call_with_args_on_stack(self_: &mut VM, callee: Value, argc: usize) {
+    call_with_this(self_, callee, argc, None)
+}
 
-    let mut callee = self_.state.stack.pop().unwrap();
+/// Calls `callee` as call_with_args_on_stack does. If `this` is given, a method that uses `this`
+/// is called with it instead of the global object.
+fn call_with_this(self_: &mut VM, callee: Value, argc: usize, this: Option<Value>) {
+    let mut this = this;
+    let mut callee = callee;
 
     loop {
         match callee {
             Value::BuiltinFunction(x) => {
                 let mut args = vec![];
                 for _ in 0..argc {
-                    args.push(self_.state.stack.pop().unwrap());
+                    args.push(self_.state.pop());
                 }
                 args.reverse();
                 if let Some(this) = this {
                     args.insert(0, this)
                 }
-                unsafe { self_.builtin_functions[x](args, self_) };
-                break;
-            }
-            Value::Function(dst, _) => {
-                if let Some(this) = this {
-                    let pos = self_.state.stack.len() - argc;
-                    argc += 1;
-                    self_.state.stack.insert(pos, this);
-                }
-
-                if args_all_number(&self_.state.stack, argc) {
-                    if let Some(f) = unsafe {
-                        self_
-                            .jit
-                            .can_jit(&self_.insts, &self_.const_table, dst, argc)
-                    } {
-                        let mut args = vec![];
-                        for _ in 0..argc {
-                            args.push(self_.state.stack.pop().unwrap());
-                        }
-                        args.reverse();
-                        self_
-                            .state
-                            .stack
-                            .push(unsafe { self_.jit.run_llvm_func(dst, f, args) });
-                        break;
+                self_.stats.calls += 1;
+                match self_.builtin_functions.get(x).cloned() {
+                    Some(f) => unsafe { f(args, self_) },
+                    None => {
+                        self_.report_error("native function is not registered in this VM");
+                        self_.state.stack.push(Value::Undefined);
                     }
                 }
-
-                self_.state.history.push((0, 0, 0, self_.state.pc));
-                self_.state.pc = dst as isize;
-                self_.state.stack.push(Value::Number(argc as f64));
-                self_.do_run();
-                self_
-                    .jit
-                    .register_return_type(dst, self_.state.stack.last().unwrap());
+                break;
+            }
+            Value::Function(dst, map) => {
+                let env = self_.closure_env(&map);
+                call_function(self_, dst, argc, this.unwrap_or(Value::Undefined), env);
                 break;
             }
             Value::NeedThis(callee_) => {
-                this = Some(Value::Object(self_.global_objects.clone()));
+                if this.is_none() {
+                    this = Some(Value::Object(self_.global_objects.clone()));
+                }
                 callee = *callee_;
             }
             Value::WithThis(box callee_this) => {
@@ -897,12 +2916,107 @@ fn call(self_: &mut VM) {
                 callee = callee_this.0;
             }
             c => {
-                println!("Call: err: {:?}, pc = {}", c, self_.state.pc);
-                break;
+                let msg = format!("{} is not a function", describe_value(&c));
+                self_.fatal_error(ErrorKind::TypeError, msg.as_str())
+            }
+        }
+    }
+}
+
+/// Calls the method `name` of the value on top of the stack with the `argc` values under it. The
+/// method is called with the value as `this` without being boxed in a WithThis first.
+fn call_method(self_: &mut VM) {
+    self_.state.pc += 1; // call_method
+    get_int32!(self_, argc, usize);
+    get_int32!(self_, name_id, usize);
+    let parent = self_.state.pop();
+    let name = self_.const_table.string[name_id].clone();
+    // A method is called with `parent` as `this` if it uses `this`. Methods of primitives
+    // always do.
+    let primitive = |parent: &Value| match primitive_method(parent, &name) {
+        Some(id) => (Value::BuiltinFunction(id), true),
+        None => (Value::Undefined, false),
+    };
+    let (callee, bound) = match parent {
+        Value::Object(ref map) => match obj_find_val(&*map.borrow(), &name) {
+            Value::Undefined => primitive(&parent),
+            val => (val, false),
+        },
+        Value::Function(_, ref map) => (obj_find_val(&*map.borrow(), &name), false),
+        Value::Array(ref map) if name != "length" => match obj_find_val(&map.borrow().obj, &name) {
+            Value::Undefined => primitive(&parent),
+            val => (val, false),
+        },
+        Value::String(_) if name != "length" => primitive(&parent),
+        Value::Number(_) | Value::Bool(_) => primitive(&parent),
+        // Everything else is looked up as GET_MEMBER looks it up.
+        _ => {
+            push_member(self_, parent, Value::String(CString::new(name).unwrap()));
+            let callee = self_.state.pop();
+            return call_with_args_on_stack(self_, callee, argc);
+        }
+    };
+    // The method of an accessor property is what its getter returns.
+    let callee = match callee {
+        Value::Accessor(box (getter, _)) => call_accessor(self_, getter, parent.clone(), vec![]),
+        callee => callee,
+    };
+    match callee {
+        Value::NeedThis(box callee) => call_with_this(self_, callee, argc, Some(parent)),
+        callee if bound => call_with_this(self_, callee, argc, Some(parent)),
+        callee => call_with_args_on_stack(self_, callee, argc),
+    }
+}
+
+fn call_direct(self_: &mut VM) {
+    self_.state.pc += 1; // call_direct
+    get_int32!(self_, dst, usize);
+    get_int32!(self_, argc, usize);
+    call_function(self_, dst, argc, Value::Undefined, Value::Undefined);
+}
+
+/// Calls the function at `dst`, which closes over `env`, with the `argc` values on top of the
+/// stack as its arguments.
+fn call_function(self_: &mut VM, dst: usize, argc: usize, this: Value, env: Value) {
+    self_.stats.calls += 1;
+    // JIT-compiled code is not instrumented, and takes exactly as many arguments as the function
+    // has parameters.
+    if self_.coverage.is_none()
+        && argc == num_params_at(&self_.insts, dst)
+        && args_all_number(&self_.state.stack, argc)
+    {
+        if let Some(f) = unsafe {
+            self_
+                .jit
+                .can_jit(&self_.insts, &self_.const_table, dst, argc)
+        } {
+            let mut args = vec![];
+            for _ in 0..argc {
+                args.push(self_.state.pop());
             }
+            args.reverse();
+            self_
+                .state
+                .stack
+                .push(unsafe { self_.jit.run_llvm_func(dst, f, args) });
+            return;
         }
     }
 
+    let pos = self_.state.stack.len() - argc;
+    self_.state.stack.insert(pos, this);
+    self_.push_frame(dst, None, env);
+    self_.state.pc = dst as isize;
+    self_.state.stack.push(Value::Number(argc as f64));
+
+    fn num_params_at(insts: &ByteCode, dst: usize) -> usize {
+        // CREATE_CONTEXT num_local_var num_params
+        insts[dst + 5] as usize
+            + ((insts[dst + 6] as usize) << 8)
+            + ((insts[dst + 7] as usize) << 16)
+            + ((insts[dst + 8] as usize) << 24)
+    }
+
     fn args_all_number(stack: &Vec<Value>, argc: usize) -> bool {
         let stack_len = stack.len();
         stack[stack_len - argc..stack_len].iter().all(|v| match v {
@@ -912,26 +3026,84 @@ fn call(self_: &mut VM) {
     }
 }
 
+fn pop(self_: &mut VM) {
+    self_.state.pc += 1; // pop
+    self_.state.pop();
+}
+
 fn return_(self_: &mut VM) {
     let len = self_.state.stack.len();
-    if let Some((bp, lp, sp, return_pc)) = self_.state.history.pop() {
-        self_.state.stack.drain(sp..len - 1);
-        self_.state.pc = return_pc;
-        self_.state.bp = bp;
-        self_.state.lp = lp;
-    } else {
-        unreachable!()
+    let frame = self_.state.history.pop().unwrap();
+    self_.state.stack.drain(frame.sp..len - 1);
+    self_.state.pc = frame.return_pc;
+    self_.state.bp = frame.bp;
+    self_.state.lp = frame.lp;
+    self_.state.argc = frame.argc;
+
+    let result = self_.state.stack.last_mut().unwrap();
+    match frame.new_this {
+        // `new` gives the new object, unless the constructor returns an object of its own.
+        Some(new_this) => match result {
+            &mut Value::Object(_)
+            | &mut Value::Array(_)
+            | &mut Value::Function(_, _)
+            | &mut Value::BuiltinFunction(_) => {}
+            others => *others = new_this,
+        },
+        None => self_.jit.register_return_type(frame.func_pos, result),
     }
 }
 
+fn new_promise(self_: &mut VM) {
+    self_.state.pc += 1; // new_promise
+    let promise = self_.new_promise();
+    self_.state.stack.push(promise);
+}
+
+/// Suspends the async function being run until the value on top of the stack is settled, and
+/// returns its promise to the caller.
+fn await_(self_: &mut VM) {
+    self_.state.pc += 1; // await
+    get_int32!(self_, promise_id, usize);
+    let val = self_.state.pop();
+    let promise = self_.state.stack[self_.state.lp + promise_id].clone();
+
+    let frame = self_.state.history.pop().unwrap();
+    let func = SuspendedFunction {
+        stack: self_.state.stack.drain(frame.sp..).collect(),
+        pc: self_.state.pc,
+        bp: self_.state.bp - frame.sp,
+        lp: self_.state.lp - frame.sp,
+        argc: self_.state.argc,
+        func_pos: frame.func_pos,
+        env: frame.env,
+        promise: promise.clone(),
+    };
+    self_.state.pc = frame.return_pc;
+    self_.state.bp = frame.bp;
+    self_.state.lp = frame.lp;
+    self_.state.argc = frame.argc;
+    self_.state.stack.push(promise);
+    self_.await_value(func, val);
+}
+
+fn resolve_promise(self_: &mut VM) {
+    self_.state.pc += 1; // resolve_promise
+    let promise = self_.state.pop();
+    let val = self_.state.pop();
+    self_.resolve_promise(&promise, val);
+    self_.state.stack.push(promise);
+}
+
 fn assign_func_rest_param(self_: &mut VM) {
     self_.state.pc += 1; // assign_func_rest_param
     get_int32!(self_, num_func_param, usize);
     get_int32!(self_, dst_var_id, usize);
     let mut rest_params = vec![];
-    for i in num_func_param..(self_.state.lp - self_.state.bp) {
+    for i in num_func_param..self_.state.argc {
         rest_params.push(self_.state.stack[self_.state.bp + i].clone());
     }
+    self_.stats.allocations += 1;
     self_.state.stack[self_.state.lp + dst_var_id] =
         Value::Array(Rc::new(RefCell::new(ArrayValue::new(rest_params))));
 }
@@ -1029,3 +3201,126 @@ fn assign_func_rest_param(self_: &mut VM) {
 //         RETURN, // Return
 //     ]);
 // }
+
+#[test]
+fn show_values() {
+    let mut map = PropertyMap::new();
+    map.insert("b".to_string(), Value::Bool(true));
+    map.insert("a".to_string(), Value::Number(1.5));
+    let array = Value::Array(Rc::new(RefCell::new(ArrayValue::new(vec![
+        Value::Object(Rc::new(RefCell::new(map))),
+        Value::String(CString::new("x").unwrap()),
+        Value::Undefined,
+        Value::Number(1e21),
+    ]))));
+    assert_eq!(array.to_string(), "[object Object],x,,1e+21");
+    assert_eq!(array.inspect(), "[ { b: true, a: 1.5 }, 'x', undefined, 1e+21 ]");
+    assert_eq!(Value::Number(-0.0).to_string(), "0");
+    assert_eq!(Value::Number(1.5e-7).to_string(), "1.5e-7");
+    assert_eq!(Value::Number(-1.0 / 0.0).to_string(), "-Infinity");
+}
+
+#[test]
+fn quote_strings() {
+    for &(s, quoted) in &[
+        ("a\nb", r"'a\nb'"),
+        ("it's", r#""it's""#),
+        ("say \"hi\", it's", r#"`say "hi", it's`"#),
+        ("`\"'", r#"'`"\''"#),
+        ("\t\u{1}\u{7f}\\é", r"'\t\x01\x7F\\é'"),
+    ] {
+        assert_eq!(quote_string(s, MAX_QUOTED_STRING_LEN), quoted);
+    }
+    assert_eq!(quote_string("abcde", 3), "'abc'... 2 more characters");
+    assert_eq!(quote_string("abcd", 3), "'abc'... 1 more character");
+    assert_eq!(quote_string("abc", 3), "'abc'");
+}
+
+#[test]
+fn embedder_data() {
+    struct Counter(usize);
+
+    unsafe fn count(_args: Vec<Value>, vm: &mut VM) {
+        let counter = vm.get_embedder_data_mut::<Counter>().unwrap();
+        counter.0 += 1;
+        let n = counter.0 as f64;
+        vm.state.stack.push(Value::Number(n))
+    }
+
+    let mut vm = VM::new();
+    assert!(vm.get_embedder_data::<Counter>().is_none());
+    vm.set_embedder_data(Counter(10));
+    vm.builtin_functions.push(count);
+    let count = Value::BuiltinFunction(vm.builtin_functions.len() - 1);
+    vm.call_value(count.clone(), vec![]);
+    assert_eq!(vm.call_value(count, vec![]), Value::Number(12.0));
+    assert_eq!(vm.get_embedder_data::<Counter>().unwrap().0, 12);
+
+    vm.set_embedder_data(Counter(0));
+    assert_eq!(vm.get_embedder_data::<Counter>().unwrap().0, 0);
+    assert!(vm.get_embedder_data::<String>().is_none());
+}
+
+#[test]
+fn recognize_array_indices() {
+    for &(key, index) in &[("0", 0), ("7", 7), ("10", 10), ("4294967294", 4294967294)] {
+        assert_eq!(array_index(key), Some(index));
+    }
+    for key in &["", "00", "01", "-1", "+1", "1.0", "1e3", " 1", "4294967295", "10000000000"] {
+        assert_eq!(array_index(key), None);
+    }
+}
+
+#[test]
+fn enumerate_keys_in_order() {
+    let mut map = PropertyMap::new();
+    for key in &["b", "10", "__proto__", "a", "2", "01", "4294967295", "0", "-1"] {
+        map.insert(key.to_string(), Value::Undefined);
+    }
+    map.remove("a");
+    map.insert("a".to_string(), Value::Undefined);
+    assert_eq!(
+        own_keys(&map),
+        vec!["0", "2", "10", "b", "01", "4294967295", "-1", "a"]
+    );
+}
+
+#[test]
+fn convert_strings_to_numbers() {
+    for &(s, n) in &[
+        ("", 0.0),
+        (" 12\n", 12.0),
+        ("-1.5e3", -1500.0),
+        (".5", 0.5),
+        ("0x1F", 31.0),
+        ("0b101", 5.0),
+        ("-Infinity", ::std::f64::NEG_INFINITY),
+    ] {
+        assert_eq!(string_to_number(s), n, "{:?}", s);
+    }
+    for s in &["abc", "1_000", "inf", "NaN", "--Infinity", "0x", "-0x10", "+"] {
+        assert!(string_to_number(s).is_nan(), "{:?}", s);
+    }
+}
+
+#[test]
+fn convert_values_to_booleans() {
+    for val in &[
+        Value::Undefined,
+        Value::Bool(false),
+        Value::Number(0.0),
+        Value::Number(-0.0),
+        Value::Number(::std::f64::NAN),
+        Value::String(CString::new("").unwrap()),
+    ] {
+        assert!(!to_boolean(val), "{:?}", val);
+    }
+    for val in &[
+        Value::Bool(true),
+        Value::Number(-1.0),
+        Value::String(CString::new("0").unwrap()),
+        Value::Object(Rc::new(RefCell::new(PropertyMap::new()))),
+    ] {
+        assert!(to_boolean(val), "{:?}", val);
+    }
+}
@@ -1,22 +1,23 @@
 use builtin;
-use bytecode_gen::{ByteCode, ByteCodeGen};
+use bytecode_gen::{stack_effect, ByteCode, ByteCodeGen};
+use error::{CodegenError, Error, Span};
 use id::{Id, IdGen};
 use node::{
-    BinOp, FormalParameters, FunctionDeclNode, Node, NodeBase, PropertyDefinition, UnaryOp,
+    BinOp, FormalParameters, FunctionDeclNode, LexicalKind, Node, NodeBase, PropertyDefinition,
+    UnaryOp,
 };
 use std::collections::HashSet;
-use vm::Value;
+use vm::{ArrayValue, ObjectTemplate, PropertyKind, PropertyMap, Value, READ_ONLY_GLOBALS};
 use vm::{
-    new_value_function, PUSH_INT32, PUSH_INT8, ADD, ASG_FREST_PARAM, CALL, CONSTRUCT, CREATE_ARRAY,
-    CREATE_CONTEXT, CREATE_OBJECT, DIV, END, EQ, GE, GET_ARG_LOCAL, GET_GLOBAL, GET_LOCAL,
-    GET_MEMBER, GT, JMP, JMP_IF_FALSE, LE, LT, MUL, NE, NEG, PUSH_ARGUMENTS, PUSH_CONST,
-    PUSH_FALSE, PUSH_THIS, PUSH_TRUE, REM, RETURN, SEQ, SET_ARG_LOCAL, SET_GLOBAL, SET_LOCAL,
-    SET_MEMBER, SNE, SUB,
+    inst_len, new_object_prototype, new_value_function, CALL_DIRECT, GET_GLOBAL,
+    GET_GLOBAL_OR_UNDEFINED, PUSH_CONST, RETURN,
 };
 
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::ffi::CString;
+use std::mem;
+use std::panic;
 use std::rc::Rc;
 
 #[derive(Clone, Debug, PartialEq)]
@@ -50,6 +51,9 @@ pub struct FunctionInfo {
     pub name: String,
     pub use_this: bool,
     pub insts: ByteCode,
+    pub source_map: SourceMap,
+    pub vars: Vec<Var>,
+    pub span: Span,
     pub info: FunctionInfoForJIT,
 }
 
@@ -58,31 +62,96 @@ impl FunctionInfo {
         name: String,
         use_this: bool,
         insts: ByteCode,
+        source_map: SourceMap,
+        vars: Vec<Var>,
+        span: Span,
         info: FunctionInfoForJIT,
     ) -> FunctionInfo {
         FunctionInfo {
             name: name,
             use_this: use_this,
             insts: insts,
+            source_map: source_map,
+            vars: vars,
+            span: span,
             info: info,
         }
     }
 }
 
+/// Pairs of (instruction address, position in source code), sorted by address.
+pub type SourceMap = Vec<(usize, usize)>;
+
+/// Pairs of (address of a function, its source code).
+pub type FunctionSpans = Vec<(usize, Span)>;
+
+/// A variable of a function: (name, is_arg_var, id).
+pub type Var = (String, bool, usize);
+
+/// The instructions of the script or of a function, and the names of its variables, so that the
+/// debugger can find them in a frame.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scope {
+    pub name: String,
+    pub start: usize, // address of the first instruction
+    pub end: usize,   // address next to the last instruction
+    pub vars: Vec<Var>,
+}
+
+impl Scope {
+    pub fn new(name: String, start: usize, end: usize, vars: Vec<Var>) -> Scope {
+        Scope {
+            name: name,
+            start: start,
+            end: end,
+            vars: vars,
+        }
+    }
+}
+
+fn vars_of(varmap: &HashMap<String, (bool, usize)>) -> Vec<Var> {
+    let mut vars = varmap
+        .iter()
+        .map(|(name, &(is_arg, id))| (name.clone(), is_arg, id))
+        .collect::<Vec<Var>>();
+    vars.sort_by(|a, b| (a.1, a.2).cmp(&(b.1, b.2)));
+    vars
+}
+
+/// What break and continue statements can jump out of.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LabelsKind {
+    Loop,
+    /// A for-in or a for-of loop, which keeps the iterated value and the position of its next
+    /// element on the stack.
+    ForEachLoop,
+    Switch,
+    /// Any other labelled statement, which only a labelled break jumps out of.
+    Block,
+}
+
 #[derive(Clone, Debug)]
 pub struct Labels {
+    kind: LabelsKind,
+    names: Vec<String>,
     continue_jmp_list: Vec<isize>,
     break_jmp_list: Vec<isize>,
 }
 
 impl Labels {
-    pub fn new() -> Labels {
+    pub fn new(kind: LabelsKind, names: Vec<String>) -> Labels {
         Labels {
+            kind: kind,
+            names: names,
             continue_jmp_list: vec![],
             break_jmp_list: vec![],
         }
     }
 
+    fn is_loop(&self) -> bool {
+        self.kind == LabelsKind::Loop || self.kind == LabelsKind::ForEachLoop
+    }
+
     fn replace_break_jmps(
         &mut self,
         bytecode_gen: &mut ByteCodeGen,
@@ -114,57 +183,173 @@ impl Labels {
     }
 }
 
+/// Whether the declaration of a variable declared with let or const has run, where code is being
+/// generated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Initialized {
+    No,
+    Yes,
+    /// Not known until run time, since a case of a switch statement can be jumped to past the
+    /// declaration.
+    Unknown,
+}
+
+/// Where a variable declared with let or const is kept.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Storage {
+    Local(Id),
+    /// The global named after the variable, since closures use it. Another global tells them
+    /// whether it has been initialized.
+    Global,
+    /// A property of the environment object in the local, since closures made in a loop use it.
+    /// Each iteration has its own environment, which the closures made in it capture.
+    Env(Id),
+}
+
+/// A variable declared with let or const, which cannot be used before its declaration runs.
+#[derive(Clone, Copy, Debug)]
+pub struct LexicalVar {
+    storage: Storage,
+    kind: LexicalKind,
+    initialized: Initialized,
+    flag: Option<Id>, // In a switch statement, the local telling whether it is initialized
+}
+
 #[derive(Clone, Debug)]
 pub struct VMCodeGen {
-    pub global_varmap: HashMap<String, Value>, // usize will be replaced with an appropriate type
+    pub global_varmap: PropertyMap, // usize will be replaced with an appropriate type
     pub local_varmap: Vec<HashMap<String, (bool, usize)>>, // hashmap<name, (is_arg_var, id)>
+    pub lexical_scopes: Vec<HashMap<String, LexicalVar>>, // let and const of the enclosing blocks
+    pub global_consts: HashMap<String, Node>, // Constants at the top level and their literals
+    pub captured_lexicals: HashMap<String, LexicalKind>, // let and const that closures use
+    pub loop_lexicals: HashSet<String>, // Those of captured_lexicals declared in loops
+    pub closures: HashSet<String>, // Functions using loop_lexicals or declared in with statements
     pub functions: HashMap<String, FunctionInfo>,
     pub local_var_stack_addr: IdGen,
     pub arguemnt_var_addr: IdGen,
     pub bytecode_gen: ByteCodeGen,
     pub labels: Vec<Labels>,
+    pub pending_labels: Vec<String>, // Labels of the statement about to be run
+    pub popped_before_jumps: usize,  // Values popped by jumps out of for-in and for-of loops
+    pub function_use_this: HashMap<String, bool>,
+    pub source_map: SourceMap,
+    pub function_spans: FunctionSpans,
+    pub scopes: Vec<Scope>,
+    pub incremental: bool, // Compiling more code for a VM that has run code before
+    pub async_promise: Option<Id>, // The local holding the promise of the async function
+    pub with_objects: Vec<Id>,      // The locals holding the objects of enclosing with statements
+    pub in_with: bool, // Compiling a function declared in a with statement, which it looks up
 }
 
 impl VMCodeGen {
     pub fn new() -> VMCodeGen {
         VMCodeGen {
-            global_varmap: HashMap::new(),
+            global_varmap: PropertyMap::new(),
             local_varmap: vec![HashMap::new()],
+            lexical_scopes: vec![],
+            global_consts: HashMap::new(),
+            captured_lexicals: HashMap::new(),
+            loop_lexicals: HashSet::new(),
+            closures: HashSet::new(),
             functions: HashMap::new(),
             local_var_stack_addr: IdGen::new(),
             arguemnt_var_addr: IdGen::new(),
             bytecode_gen: ByteCodeGen::new(),
-            labels: vec![Labels::new()],
+            labels: vec![],
+            pending_labels: vec![],
+            popped_before_jumps: 0,
+            function_use_this: HashMap::new(),
+            source_map: vec![],
+            function_spans: vec![],
+            scopes: vec![],
+            incremental: false,
+            async_promise: None,
+            with_objects: vec![],
+            in_with: false,
         }
     }
 }
 
 impl VMCodeGen {
+    /// Compiles the program `node` into `insts`. Fails if the program uses what cannot be
+    /// compiled yet.
     pub fn compile(
         &mut self,
         node: &Node,
         insts: &mut ByteCode,
         func_addr_in_bytecode_and_its_entity: &mut HashMap<usize, FunctionInfoForJIT>,
+    ) -> Result<(), Error> {
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            self.compile_program(node, insts, func_addr_in_bytecode_and_its_entity)
+        }));
+        match result {
+            Ok(()) => Ok(()),
+            Err(payload) => match payload.downcast::<CodegenError>() {
+                Ok(e) => Err(Error::Codegen(*e)),
+                Err(payload) => panic::resume_unwind(payload),
+            },
+        }
+    }
+
+    fn compile_program(
+        &mut self,
+        node: &Node,
+        insts: &mut ByteCode,
+        func_addr_in_bytecode_and_its_entity: &mut HashMap<usize, FunctionInfoForJIT>,
     ) {
-        let pos = insts.len();
-        self.bytecode_gen.gen_create_context(0, insts);
+        // Code compiled later may redefine the functions, so calls look them up.
+        if !self.incremental {
+            self.collect_function_decls(node);
+        }
 
-        self.run_arg_var_decl(&"this".to_string(), &None, insts);
+        let mut free_variables = vec![];
+        self.collect_captured_lexicals(node, false, &mut free_variables);
+        for (name, fv) in free_variables {
+            if fv.iter().any(|name| self.loop_lexicals.contains(name)) {
+                self.closures.insert(name);
+            }
+        }
 
-        self.run(node, insts);
+        let pos = insts.len();
+        self.bytecode_gen.gen_create_context(0, 0, insts);
+
+        // The completion value of the program is the value of its last expression statement.
+        let completion_var_id = self.local_var_stack_addr.gen_id();
+
+        match node.base {
+            NodeBase::StatementList(ref nodes) => {
+                self.collect_global_consts(nodes);
+                self.push_lexical_scope(nodes.iter(), false, insts);
+                for node in nodes {
+                    self.run(node, insts);
+                    if leaves_value(node) {
+                        self.bytecode_gen
+                            .gen_set_local(completion_var_id as u32, insts);
+                    }
+                }
+                self.lexical_scopes.pop();
+            }
+            _ => unreachable!(),
+        }
 
         self.bytecode_gen.replace_int32(
             self.local_var_stack_addr.get_cur_id() as i32,
             &mut insts[pos + 1..pos + 5],
         );
 
+        self.bytecode_gen
+            .gen_get_local(completion_var_id as u32, insts);
         self.bytecode_gen.gen_end(insts);
 
-        let mut function_value_list = HashMap::new();
+        let vars = vars_of(&self.local_varmap[0]);
+        self.scopes
+            .push(Scope::new("(toplevel)".to_string(), pos, insts.len(), vars));
+
+        let mut function_value_list = PropertyMap::new();
 
         {
             function_value_list.insert("console".to_string(), {
-                let mut map = HashMap::new();
+                let mut map = PropertyMap::new();
                 map.insert(
                     "log".to_string(),
                     Value::BuiltinFunction(builtin::CONSOLE_LOG),
@@ -173,20 +358,24 @@ impl VMCodeGen {
             });
 
             function_value_list.insert("process".to_string(), {
-                let mut map = HashMap::new();
+                let mut map = PropertyMap::new();
                 map.insert("stdout".to_string(), {
-                    let mut map = HashMap::new();
+                    let mut map = PropertyMap::new();
                     map.insert(
                         "write".to_string(),
                         Value::BuiltinFunction(builtin::PROCESS_STDOUT_WRITE),
                     );
                     Value::Object(Rc::new(RefCell::new(map)))
                 });
+                map.insert(
+                    "exit".to_string(),
+                    Value::BuiltinFunction(builtin::PROCESS_EXIT),
+                );
                 Value::Object(Rc::new(RefCell::new(map)))
             });
 
             function_value_list.insert("Math".to_string(), {
-                let mut map = HashMap::new();
+                let mut map = PropertyMap::new();
                 map.insert(
                     "floor".to_string(),
                     Value::BuiltinFunction(builtin::MATH_FLOOR),
@@ -198,6 +387,38 @@ impl VMCodeGen {
                 map.insert("pow".to_string(), Value::BuiltinFunction(builtin::MATH_POW));
                 Value::Object(Rc::new(RefCell::new(map)))
             });
+
+            function_value_list.insert("Array".to_string(), {
+                let mut map = PropertyMap::new();
+                map.insert("from".to_string(), Value::BuiltinFunction(builtin::ARRAY_FROM));
+                Value::Object(Rc::new(RefCell::new(map)))
+            });
+
+            function_value_list.insert("Object".to_string(), {
+                let mut map = PropertyMap::new();
+                map.insert("keys".to_string(), Value::BuiltinFunction(builtin::OBJECT_KEYS));
+                map.insert("prototype".to_string(), new_object_prototype());
+                map.insert("freeze".to_string(), Value::BuiltinFunction(builtin::OBJECT_FREEZE));
+                map.insert("seal".to_string(), Value::BuiltinFunction(builtin::OBJECT_SEAL));
+                map.insert(
+                    "isFrozen".to_string(),
+                    Value::BuiltinFunction(builtin::OBJECT_IS_FROZEN),
+                );
+                map.insert(
+                    "isSealed".to_string(),
+                    Value::BuiltinFunction(builtin::OBJECT_IS_SEALED),
+                );
+                Value::Object(Rc::new(RefCell::new(map)))
+            });
+        }
+
+        // The global object shares the builtin objects with the constant table so that properties
+        // set at runtime (e.g. process.argv) are seen through either. A VM that has run code
+        // before already has its own.
+        if !self.incremental {
+            for (name, val) in function_value_list.iter() {
+                self.global_varmap.insert(name, val.clone());
+            }
         }
 
         for (
@@ -206,11 +427,23 @@ impl VMCodeGen {
                 name,
                 use_this,
                 insts: func_insts,
+                source_map,
+                vars,
+                span,
                 info,
             },
         ) in &self.functions
         {
             let pos = insts.len();
+            self.scopes.push(Scope::new(
+                name.clone(),
+                pos,
+                pos + func_insts.len(),
+                vars.clone(),
+            ));
+            self.source_map
+                .extend(source_map.iter().map(|&(pc, src_pos)| (pc + pos, src_pos)));
+            self.function_spans.push((pos, *span));
             let mut val;
             if *use_this {
                 val = Value::NeedThis(Box::new(new_value_function(pos)));
@@ -227,63 +460,190 @@ impl VMCodeGen {
             insts.append(&mut func_insts);
         }
 
+        // Globals are looked up every time in incremental code, since code compiled later may
+        // assign them.
+        if self.incremental {
+            return;
+        }
+
+        // Every reference to the same known global shares one constant table entry.
+        let mut function_const_id = HashMap::new();
+
         let mut i = 0;
         while i < insts.len() {
             match insts[i] {
-                ASG_FREST_PARAM => i += 9,
-                CREATE_CONTEXT => i += 5,
-                CONSTRUCT | CREATE_OBJECT | PUSH_CONST | PUSH_INT32 | SET_GLOBAL | GET_LOCAL
-                | SET_ARG_LOCAL | GET_ARG_LOCAL | CREATE_ARRAY | SET_LOCAL | JMP_IF_FALSE | JMP
-                | CALL => i += 5,
-                PUSH_INT8 => i += 2,
-                PUSH_FALSE | END | PUSH_TRUE | PUSH_THIS | ADD | SUB | MUL | DIV | REM | LT
-                | PUSH_ARGUMENTS | NEG | GT | LE | GE | EQ | NE | GET_MEMBER | RETURN | SNE
-                | SEQ | SET_MEMBER => i += 1,
-                GET_GLOBAL => {
+                CALL_DIRECT => {
                     let id = insts[i + 1] as i32
                         + ((insts[i + 2] as i32) << 8)
                         + ((insts[i + 3] as i32) << 16)
                         + ((insts[i + 4] as i32) << 24);
-                    if let Some(val) = function_value_list
-                        .get(self.bytecode_gen.const_table.string[id as usize].as_str())
-                    {
-                        match val {
-                            Value::NeedThis(callee) => {
-                                insts[i] = PUSH_CONST;
-                                let id = self.bytecode_gen.const_table.value.len();
-                                self.bytecode_gen
-                                    .const_table
-                                    .value
-                                    .push(Value::NeedThis(callee.clone()));
-                                self.bytecode_gen
-                                    .replace_int32(id as i32, &mut insts[i + 1..i + 5]);
-                            }
-                            _ => {
-                                insts[i] = PUSH_CONST;
-                                let id = self.bytecode_gen.const_table.value.len();
-                                self.bytecode_gen.const_table.value.push(val.clone());
-                                self.bytecode_gen
-                                    .replace_int32(id as i32, &mut insts[i + 1..i + 5]);
-                            }
-                        }
+                    let name = self.bytecode_gen.const_table.string[id as usize].as_str();
+                    if let Some(&Value::Function(pos, _)) = function_value_list.get(name) {
+                        self.bytecode_gen
+                            .replace_int32(pos as i32, &mut insts[i + 1..i + 5]);
+                    } else {
+                        unreachable!()
+                    }
+                }
+                GET_GLOBAL | GET_GLOBAL_OR_UNDEFINED => {
+                    let id = insts[i + 1] as i32
+                        + ((insts[i + 2] as i32) << 8)
+                        + ((insts[i + 3] as i32) << 16)
+                        + ((insts[i + 4] as i32) << 24);
+                    let name = self.bytecode_gen.const_table.string[id as usize].clone();
+                    if let Some(val) = function_value_list.get(name.as_str()) {
+                        let id = *function_const_id.entry(name).or_insert_with(|| {
+                            let id = self.bytecode_gen.const_table.value.len();
+                            self.bytecode_gen.const_table.value.push(val.clone());
+                            id
+                        });
+                        insts[i] = PUSH_CONST;
+                        self.bytecode_gen
+                            .replace_int32(id as i32, &mut insts[i + 1..i + 5]);
                     }
-                    i += 5;
                 }
-                _ => unreachable!(),
+                _ => {}
+            }
+            i += inst_len(&insts, i);
+        }
+    }
+
+    fn record_source_pos(&mut self, node: &Node, pc: usize) {
+        match node.base {
+            NodeBase::StatementList(_) | NodeBase::FunctionDecl(_) | NodeBase::Nope => return,
+            _ => {}
+        }
+        // The outermost node starting at an address wins.
+        match self.source_map.last() {
+            Some(&(last_pc, _)) if last_pc == pc => {}
+            _ => self.source_map.push((pc, node.pos)),
+        }
+    }
+
+    /// Finds the constants at the top level initialized with literals. Functions use the literals
+    /// instead of looking the constants up, even before their declarations have run.
+    fn collect_global_consts(&mut self, nodes: &[Node]) {
+        for node in nodes {
+            if let NodeBase::LexicalDecl(ref name, Some(ref init), LexicalKind::Const) = node.base {
+                if init.is_literal() {
+                    self.global_consts.insert(name.clone(), (**init).clone());
+                }
+            }
+        }
+    }
+
+    /// Finds the variables declared with let or const that closures use, which the free variable
+    /// solver has renamed. Closures are compiled apart from the functions declaring them, so they
+    /// look them up here. The free variables of every function are added to `free_variables`.
+    fn collect_captured_lexicals(
+        &mut self,
+        node: &Node,
+        in_loop: bool,
+        free_variables: &mut Vec<(String, HashSet<String>)>,
+    ) {
+        match node.base {
+            NodeBase::LexicalDecl(ref name, _, kind) if name.contains('.') => {
+                self.captured_lexicals.insert(name.clone(), kind);
+                if in_loop {
+                    self.loop_lexicals.insert(name.clone());
+                }
+            }
+            NodeBase::StatementList(ref nodes) => for node in nodes {
+                self.collect_captured_lexicals(node, in_loop, free_variables)
+            },
+            NodeBase::FunctionDecl(FunctionDeclNode {
+                ref name,
+                ref mangled_name,
+                ref fv,
+                ref body,
+                in_with,
+                ..
+            }) => {
+                let name = mangled_name.as_ref().unwrap_or(name).clone();
+                if in_with {
+                    self.closures.insert(name.clone());
+                }
+                free_variables.push((name, fv.clone()));
+                self.collect_captured_lexicals(body, false, free_variables)
             }
+            NodeBase::If(_, ref then_, ref else_) => {
+                self.collect_captured_lexicals(then_, in_loop, free_variables);
+                self.collect_captured_lexicals(else_, in_loop, free_variables);
+            }
+            NodeBase::For(ref init, _, _, ref body) => {
+                self.collect_captured_lexicals(init, true, free_variables);
+                self.collect_captured_lexicals(body, true, free_variables);
+            }
+            NodeBase::While(_, ref body)
+            | NodeBase::DoWhile(ref body, _)
+            | NodeBase::ForIn(_, _, ref body)
+            | NodeBase::ForOf(_, _, ref body) => {
+                self.collect_captured_lexicals(body, true, free_variables)
+            }
+            NodeBase::Label(_, ref body) | NodeBase::With(_, ref body) => {
+                self.collect_captured_lexicals(body, in_loop, free_variables)
+            }
+            NodeBase::Switch(_, ref cases) => for &(_, ref body) in cases {
+                self.collect_captured_lexicals(body, in_loop, free_variables)
+            },
+            _ => {}
+        }
+    }
+
+    /// Records every function declaration so that calls to them can be emitted as CALL_DIRECT.
+    fn collect_function_decls(&mut self, node: &Node) {
+        match node.base {
+            NodeBase::StatementList(ref nodes) => {
+                for node in nodes {
+                    self.collect_function_decls(node)
+                }
+            }
+            NodeBase::FunctionDecl(FunctionDeclNode {
+                ref name,
+                ref mangled_name,
+                use_this,
+                ref body,
+                ..
+            }) => {
+                let name = mangled_name.as_ref().unwrap_or(name).clone();
+                // Functions sharing a name are never called directly.
+                let use_this = use_this || self.function_use_this.contains_key(name.as_str());
+                self.function_use_this.insert(name, use_this);
+                self.collect_function_decls(&*body);
+            }
+            NodeBase::If(_, ref then_, ref else_) => {
+                self.collect_function_decls(&*then_);
+                self.collect_function_decls(&*else_);
+            }
+            NodeBase::While(_, ref body)
+            | NodeBase::DoWhile(ref body, _)
+            | NodeBase::For(_, _, _, ref body)
+            | NodeBase::ForIn(_, _, ref body)
+            | NodeBase::ForOf(_, _, ref body)
+            | NodeBase::Label(_, ref body)
+            | NodeBase::With(_, ref body) => self.collect_function_decls(&*body),
+            NodeBase::Switch(_, ref cases) => for &(_, ref body) in cases {
+                self.collect_function_decls(body)
+            },
+            _ => {}
         }
     }
 
     fn run(&mut self, node: &Node, insts: &mut ByteCode) {
+        self.record_source_pos(node, insts.len());
+
         match &node.base {
             &NodeBase::StatementList(ref node_list) => self.run_statement_list(node_list, insts),
             &NodeBase::FunctionDecl(FunctionDeclNode {
                 ref name,
                 ref mangled_name,
                 ref use_this,
-                ref fv,
                 ref params,
                 ref body,
+                span,
+                is_async,
+                in_with,
+                ..
             }) => self.run_function_decl(
                 if let Some(ref mangled_name) = mangled_name {
                     mangled_name
@@ -291,32 +651,61 @@ impl VMCodeGen {
                     name
                 },
                 *use_this,
-                fv,
                 params,
                 &*body,
+                span,
+                is_async,
+                in_with,
             ),
             &NodeBase::VarDecl(ref name, ref init) => {
                 self.run_var_decl(name, init, insts);
             }
+            &NodeBase::LexicalDecl(ref name, ref init, _) => {
+                self.run_lexical_decl(name, init, insts)
+            }
             &NodeBase::If(ref cond, ref then_, ref else_) => {
                 self.run_if(&*cond, &*then_, &*else_, insts)
             }
             &NodeBase::While(ref cond, ref body) => self.run_while(&*cond, &*body, insts),
+            &NodeBase::DoWhile(ref body, ref cond) => self.run_do_while(&*body, &*cond, insts),
+            &NodeBase::With(ref obj, ref body) => self.run_with(&*obj, &*body, insts),
+            &NodeBase::Switch(ref discriminant, ref cases) => {
+                self.run_switch(&*discriminant, cases, insts)
+            }
             &NodeBase::For(ref init, ref cond, ref step, ref body) => {
                 self.run_for(&*init, &*cond, &*step, &*body, insts)
             }
-            &NodeBase::Assign(ref dst, ref src) => self.run_assign(&*dst, &*src, insts),
+            &NodeBase::ForIn(ref var, ref obj, ref body) => {
+                self.run_for_in(&*var, &*obj, &*body, insts)
+            }
+            &NodeBase::ForOf(ref var, ref iterable, ref body) => {
+                self.run_for_of(&*var, &*iterable, &*body, insts)
+            }
+            &NodeBase::Assign(ref dst, ref src) => self.run_assign(&*dst, &*src, false, insts),
+            &NodeBase::CompoundAssign(ref dst, ref src, ref op) => {
+                self.run_compound_assign(&*dst, &*src, op, false, insts)
+            }
             &NodeBase::UnaryOp(ref expr, ref op) => self.run_unary_op(&*expr, op, insts),
+            &NodeBase::TernaryOp(ref cond, ref then_, ref else_) => {
+                self.run_ternary_op(&*cond, &*then_, &*else_, insts)
+            }
             &NodeBase::BinaryOp(ref lhs, ref rhs, ref op) => {
                 self.run_binary_op(&*lhs, &*rhs, op, insts)
             }
             &NodeBase::Call(ref callee, ref args) => self.run_call(&*callee, args, insts),
+            &NodeBase::TaggedTemplate(ref tag, ref strings, ref substitutions) => {
+                self.run_tagged_template(&*tag, strings, substitutions, node.pos, insts)
+            }
             &NodeBase::Member(ref parent, ref member) => self.run_member(&*parent, member, insts),
             &NodeBase::Index(ref parent, ref idx) => self.run_index(&*parent, &*idx, insts),
             &NodeBase::Return(ref val) => self.run_return(val, insts),
-            &NodeBase::Break => self.run_break(insts),
-            &NodeBase::Continue => self.run_continue(insts),
+            &NodeBase::Break(ref label) => self.run_break(label, node.pos, insts),
+            &NodeBase::Continue(ref label) => self.run_continue(label, node.pos, insts),
+            &NodeBase::Label(ref name, ref body) => self.run_label(name, body, node.pos, insts),
             &NodeBase::New(ref expr) => self.run_new_expr(&*expr, insts),
+            &NodeBase::Class(ref constructor, ref heritage, ref methods) => {
+                self.run_class(&*constructor, heritage, methods, insts)
+            }
             &NodeBase::Object(ref properties) => self.run_object_literal(properties, insts),
             &NodeBase::Array(ref properties) => self.run_array_literal(properties, insts),
             &NodeBase::Identifier(ref name) => self.run_identifier(name, insts),
@@ -324,7 +713,7 @@ impl VMCodeGen {
             &NodeBase::Arguments => self.bytecode_gen.gen_push_arguments(insts),
             &NodeBase::String(ref s) => self
                 .bytecode_gen
-                .gen_push_const(Value::String(CString::new(s.as_str()).unwrap()), insts),
+                .gen_push_const(new_string(s, node.pos), insts),
             &NodeBase::Number(n) if n - n.floor() == 0.0 => {
                 // When 'n' is an integer
                 if -128.0 < n && n < 127.0 {
@@ -335,6 +724,7 @@ impl VMCodeGen {
             }
             &NodeBase::Number(n) => self.bytecode_gen.gen_push_const(Value::Number(n), insts),
             &NodeBase::Boolean(b) => self.bytecode_gen.gen_push_bool(b, insts),
+            &NodeBase::Null => self.bytecode_gen.gen_push_const(Value::Null, insts),
             _ => {}
         }
     }
@@ -342,45 +732,177 @@ impl VMCodeGen {
 
 impl VMCodeGen {
     pub fn run_statement_list(&mut self, node_list: &Vec<Node>, insts: &mut ByteCode) {
+        self.push_lexical_scope(node_list.iter(), false, insts);
         for node in node_list {
-            self.run(node, insts)
+            self.run_statement(node, insts)
+        }
+        self.lexical_scopes.pop();
+    }
+
+    /// Runs `node` for its value.
+    pub fn run_expr(&mut self, node: &Node, insts: &mut ByteCode) {
+        match node.base {
+            NodeBase::Assign(ref dst, ref src) => {
+                self.record_source_pos(node, insts.len());
+                self.run_assign(dst, src, true, insts)
+            }
+            NodeBase::CompoundAssign(ref dst, ref src, ref op) => {
+                self.record_source_pos(node, insts.len());
+                self.run_compound_assign(dst, src, op, true, insts)
+            }
+            NodeBase::BinaryOp(ref lhs, ref rhs, BinOp::Comma) => {
+                self.record_source_pos(node, insts.len());
+                self.run_statement(lhs, insts);
+                self.run_expr(rhs, insts)
+            }
+            _ if !leaves_value(node) => {
+                unsupported("statements are not expressions", node.pos)
+            }
+            _ => self.run(node, insts),
+        }
+    }
+
+    /// Runs `node` as a statement, discarding the value of an expression statement.
+    pub fn run_statement(&mut self, node: &Node, insts: &mut ByteCode) {
+        let (start, popped) = (insts.len(), self.popped_before_jumps);
+        self.run(node, insts);
+        if leaves_value(node) {
+            self.bytecode_gen.gen_pop(insts);
         }
+        debug_assert_eq!(
+            stack_effect(&insts[start..], &self.bytecode_gen.const_table)
+                + (self.popped_before_jumps - popped) as isize,
+            0,
+            "the statement at {} leaves the stack unbalanced",
+            node.pos
+        );
+    }
+}
+
+/// Whether the code generated for `node` leaves exactly one value on the stack.
+pub fn leaves_value(node: &Node) -> bool {
+    match node.base {
+        NodeBase::BinaryOp(_, ref rhs, BinOp::Comma) => leaves_value(rhs),
+        NodeBase::Call(_, _)
+        | NodeBase::TaggedTemplate(_, _, _)
+        | NodeBase::New(_)
+        | NodeBase::Class(_, _, _)
+        | NodeBase::UnaryOp(_, _)
+        | NodeBase::BinaryOp(_, _, _)
+        | NodeBase::TernaryOp(_, _, _)
+        | NodeBase::Member(_, _)
+        | NodeBase::Index(_, _)
+        | NodeBase::Identifier(_)
+        | NodeBase::This
+        | NodeBase::Arguments
+        | NodeBase::String(_)
+        | NodeBase::Number(_)
+        | NodeBase::Boolean(_)
+        | NodeBase::Null
+        | NodeBase::Object(_)
+        | NodeBase::Array(_) => true,
+        _ => false,
+    }
+}
+
+/// Returns the statements of `node` if it is a statement list.
+fn statements_of(node: &Node) -> &[Node] {
+    match node.base {
+        NodeBase::StatementList(ref nodes) => nodes,
+        _ => &[],
+    }
+}
+
+fn is_spread(node: &Node) -> bool {
+    match node.base {
+        NodeBase::Spread(_) => true,
+        _ => false,
+    }
+}
+
+/// Returns the key of the property that the variable `name` is inside with statements.
+fn property_key(name: &str) -> Value {
+    Value::String(CString::new(unmangled(name)).unwrap())
+}
+
+/// Returns the name of the variable `name` in the source. Free variables are renamed to `name.N`.
+fn unmangled(name: &str) -> String {
+    name.split('.').next().unwrap().to_string()
+}
+
+/// Returns the name of the global telling whether the variable `name` declared with let or const,
+/// which closures use, has been initialized.
+fn initialized_flag(name: &str) -> String {
+    format!("{}.initialized", name)
+}
+
+/// Returns the value of the string literal `s` at `pos`.
+fn new_string(s: &str, pos: usize) -> Value {
+    match CString::new(s) {
+        Ok(s) => Value::String(s),
+        Err(_) => unsupported("strings containing NUL are not supported", pos),
+    }
+}
+
+/// Stops compiling at `pos` if the property `name` contains NUL, since Object.keys() and for-in
+/// make strings of the names.
+fn check_property_name(name: &str, pos: usize) {
+    if name.contains('\0') {
+        unsupported("property names containing NUL are not supported", pos)
     }
 }
 
+/// Stops compiling because the code at `pos` cannot be compiled yet. compile() catches the error.
+fn unsupported(msg: &str, pos: usize) -> ! {
+    panic::resume_unwind(Box::new(CodegenError {
+        message: msg.to_string(),
+        span: Span::new(pos, pos + 1),
+    }))
+}
+
 impl VMCodeGen {
     pub fn run_function_decl(
         &mut self,
         name: &String,
         use_this: bool,
-        fv: &HashSet<String>,
         params: &FormalParameters,
         body: &Node,
+        span: Span,
+        is_async: bool,
+        in_with: bool,
     ) {
-        assert_eq!(fv.len(), 0);
-
         let name = name.clone();
 
         self.local_varmap.push(HashMap::new());
         self.local_var_stack_addr.save();
         self.arguemnt_var_addr.save();
+        let outer_source_map = mem::replace(&mut self.source_map, vec![]);
+        let outer_labels = mem::replace(&mut self.labels, vec![]);
+        let outer_lexical_scopes = mem::replace(&mut self.lexical_scopes, vec![]);
+        let outer_async_promise = self.async_promise.take();
+        let outer_with_objects = mem::replace(&mut self.with_objects, vec![]);
+        let outer_in_with = mem::replace(&mut self.in_with, in_with);
 
         let mut func_insts = vec![];
 
-        self.bytecode_gen.gen_create_context(0, &mut func_insts);
-
-        if use_this {
-            self.run_arg_var_decl(&"this".to_string(), &None, &mut func_insts);
+        // The rest parameter is a local variable.
+        let num_params = params.iter().filter(|param| !param.is_rest_param).count();
+        self.bytecode_gen
+            .gen_create_context(0, num_params, &mut func_insts);
+
+        // An async function returns a promise, which it resolves when it returns.
+        if is_async {
+            let id = self.local_var_stack_addr.gen_id();
+            self.bytecode_gen.gen_new_promise(&mut func_insts);
+            self.bytecode_gen.gen_set_local(id as u32, &mut func_insts);
+            self.async_promise = Some(id);
         }
 
         for param in params {
             if param.is_rest_param {
                 let id = self.run_var_decl(&param.name, &None, &mut func_insts);
-                self.bytecode_gen.gen_assign_func_rest_param(
-                    if use_this { 1 } else { 0 } + params.len() - /*rest param itself->*/ 1,
-                    id,
-                    &mut func_insts,
-                );
+                self.bytecode_gen
+                    .gen_assign_func_rest_param(num_params, id, &mut func_insts);
             } else {
                 self.run_arg_var_decl(&param.name, &param.init, &mut func_insts);
             }
@@ -390,11 +912,7 @@ impl VMCodeGen {
 
         match func_insts.last() {
             Some(&RETURN) => {}
-            _ => {
-                self.bytecode_gen
-                    .gen_push_const(Value::Undefined, &mut func_insts);
-                self.bytecode_gen.gen_return(&mut func_insts);
-            }
+            _ => self.run_return(&None, &mut func_insts),
         }
 
         self.bytecode_gen.replace_int32(
@@ -404,7 +922,13 @@ impl VMCodeGen {
 
         self.local_var_stack_addr.restore();
         self.arguemnt_var_addr.restore();
-        self.local_varmap.pop();
+        let vars = vars_of(&self.local_varmap.pop().unwrap());
+        let source_map = mem::replace(&mut self.source_map, outer_source_map);
+        self.labels = outer_labels;
+        self.lexical_scopes = outer_lexical_scopes;
+        self.async_promise = outer_async_promise;
+        self.with_objects = outer_with_objects;
+        self.in_with = outer_in_with;
 
         self.functions.insert(
             name.clone(),
@@ -412,6 +936,9 @@ impl VMCodeGen {
                 name.clone(),
                 use_this,
                 func_insts,
+                source_map,
+                vars,
+                span,
                 FunctionInfoForJIT::new(name.clone(), use_this, params.clone(), body.clone()),
             ),
         );
@@ -419,44 +946,198 @@ impl VMCodeGen {
 
     pub fn run_return(&mut self, val: &Option<Box<Node>>, insts: &mut ByteCode) {
         if let &Some(ref val) = val {
-            self.run(&*val, insts)
+            self.run_expr(&*val, insts)
         } else {
             self.bytecode_gen.gen_push_const(Value::Undefined, insts);
         }
+        if let Some(id) = self.async_promise {
+            self.bytecode_gen.gen_get_local(id as u32, insts);
+            self.bytecode_gen.gen_resolve_promise(insts);
+        }
         self.bytecode_gen.gen_return(insts);
     }
 }
 
 impl VMCodeGen {
-    pub fn run_break(&mut self, insts: &mut ByteCode) {
-        let break_jmp_pos = insts.len() as isize;
-        self.bytecode_gen.gen_jmp(0, insts);
-        self.labels
-            .last_mut()
-            .unwrap()
-            .break_jmp_list
-            .push(break_jmp_pos);
+    /// Runs a labelled statement. Loops and switches take the labels in front of them when they
+    /// push their Labels; any other statement gets Labels that only a labelled break uses.
+    pub fn run_label(&mut self, name: &String, body: &Node, pos: usize, insts: &mut ByteCode) {
+        let declared = |labels: &Labels| labels.names.contains(name);
+        if self.labels.iter().any(declared) || self.pending_labels.contains(name) {
+            unsupported(format!("label '{}' has already been declared", name).as_str(), pos)
+        }
+        self.pending_labels.push(name.clone());
+        match body.base {
+            NodeBase::While(_, _)
+            | NodeBase::DoWhile(_, _)
+            | NodeBase::For(_, _, _, _)
+            | NodeBase::ForIn(_, _, _)
+            | NodeBase::ForOf(_, _, _)
+            | NodeBase::Switch(_, _)
+            | NodeBase::Label(_, _) => self.run_statement(body, insts),
+            _ => {
+                self.push_labels(LabelsKind::Block);
+                self.run_statement(body, insts);
+                let break_label_pos = insts.len() as isize;
+                self.labels.last_mut().unwrap().replace_break_jmps(
+                    &mut self.bytecode_gen,
+                    insts,
+                    break_label_pos,
+                );
+                self.labels.pop();
+            }
+        }
+    }
+
+    fn push_labels(&mut self, kind: LabelsKind) {
+        let names = mem::replace(&mut self.pending_labels, vec![]);
+        self.labels.push(Labels::new(kind, names));
+    }
+
+    /// Returns the index in `self.labels` of the statement labelled `name`.
+    fn find_label(&self, name: &String, pos: usize) -> usize {
+        match self.labels.iter().rposition(|labels| labels.names.contains(name)) {
+            Some(i) => i,
+            None => unsupported(format!("undefined label '{}'", name).as_str(), pos),
+        }
+    }
+
+    pub fn run_break(&mut self, label: &Option<String>, pos: usize, insts: &mut ByteCode) {
+        let target = match *label {
+            Some(ref name) => self.find_label(name, pos),
+            None => match self
+                .labels
+                .iter()
+                .rposition(|labels| labels.kind != LabelsKind::Block)
+            {
+                Some(i) => i,
+                None => unsupported("illegal break statement", pos),
+            },
+        };
+        let break_jmp_pos = self.gen_jmp_out_of(target, insts);
+        self.labels[target].break_jmp_list.push(break_jmp_pos);
+    }
+
+    pub fn run_continue(&mut self, label: &Option<String>, pos: usize, insts: &mut ByteCode) {
+        let target = match *label {
+            Some(ref name) => {
+                let i = self.find_label(name, pos);
+                if !self.labels[i].is_loop() {
+                    let msg = format!(
+                        "illegal continue statement: '{}' does not denote an iteration statement",
+                        name
+                    );
+                    unsupported(msg.as_str(), pos)
+                }
+                i
+            }
+            None => match self.labels.iter().rposition(Labels::is_loop) {
+                Some(i) => i,
+                None => unsupported("illegal continue statement", pos),
+            },
+        };
+        let continue_jmp_pos = self.gen_jmp_out_of(target, insts);
+        self.labels[target].continue_jmp_list.push(continue_jmp_pos);
     }
 
-    pub fn run_continue(&mut self, insts: &mut ByteCode) {
-        let continue_jmp_pos = insts.len() as isize;
+    /// Generates a jump whose destination is replaced later, out of the statements inside
+    /// `self.labels[target]`. The values of the for-in and for-of loops it leaves are popped
+    /// first.
+    fn gen_jmp_out_of(&mut self, target: usize, insts: &mut ByteCode) -> isize {
+        for labels in &self.labels[target + 1..] {
+            if labels.kind == LabelsKind::ForEachLoop {
+                self.bytecode_gen.gen_pop(insts);
+                self.bytecode_gen.gen_pop(insts);
+                self.popped_before_jumps += 2;
+            }
+        }
+        let jmp_pos = insts.len() as isize;
         self.bytecode_gen.gen_jmp(0, insts);
-        self.labels
-            .last_mut()
-            .unwrap()
-            .continue_jmp_list
-            .push(continue_jmp_pos);
+        jmp_pos
     }
 }
 
 impl VMCodeGen {
+    /// Runs a class, which leaves its constructor. The methods are installed on the prototype of
+    /// the constructor, and static ones on the constructor itself. A derived class inherits
+    /// through `__proto__` links from the superclass to the constructor, and from the prototype
+    /// of the superclass to the prototype.
+    pub fn run_class(
+        &mut self,
+        constructor: &Node,
+        heritage: &Option<Box<Node>>,
+        methods: &Vec<(String, Node, bool)>,
+        insts: &mut ByteCode,
+    ) {
+        let prototype = new_string("prototype", constructor.pos);
+        let proto = new_string("__proto__", constructor.pos);
+        let superclass = heritage.as_ref().map(|heritage| {
+            self.run_expr(heritage, insts);
+            let id = self.local_var_stack_addr.gen_id() as u32;
+            self.bytecode_gen.gen_set_local(id, insts);
+            id
+        });
+
+        self.run_expr(constructor, insts);
+        let id = self.local_var_stack_addr.gen_id() as u32;
+        self.bytecode_gen.gen_set_local(id, insts);
+
+        if let Some(superclass) = superclass {
+            self.bytecode_gen.gen_get_local(superclass, insts);
+            self.bytecode_gen.gen_get_local(id, insts);
+            self.bytecode_gen.gen_push_const(proto.clone(), insts);
+            self.bytecode_gen.gen_set_member(insts);
+
+            self.bytecode_gen.gen_get_local(superclass, insts);
+            self.bytecode_gen.gen_push_const(prototype.clone(), insts);
+            self.bytecode_gen.gen_get_member(insts);
+            self.bytecode_gen.gen_get_local(id, insts);
+            self.bytecode_gen.gen_push_const(prototype.clone(), insts);
+            self.bytecode_gen.gen_get_member(insts);
+            self.bytecode_gen.gen_push_const(proto, insts);
+            self.bytecode_gen.gen_set_member(insts);
+        }
+
+        // The prototype refers back to the constructor.
+        self.bytecode_gen.gen_get_local(id, insts);
+        self.bytecode_gen.gen_get_local(id, insts);
+        self.bytecode_gen.gen_push_const(prototype.clone(), insts);
+        self.bytecode_gen.gen_get_member(insts);
+        let name = new_string("constructor", constructor.pos);
+        self.bytecode_gen.gen_push_const(name, insts);
+        self.bytecode_gen.gen_set_member(insts);
+
+        for &(ref name, ref method, is_static) in methods {
+            self.run_expr(method, insts);
+            self.bytecode_gen.gen_get_local(id, insts);
+            if !is_static {
+                self.bytecode_gen.gen_push_const(prototype.clone(), insts);
+                self.bytecode_gen.gen_get_member(insts);
+            }
+            let name = new_string(name, method.pos);
+            self.bytecode_gen.gen_push_const(name, insts);
+            self.bytecode_gen.gen_set_member(insts);
+        }
+        self.bytecode_gen.gen_get_local(id, insts);
+    }
+
     pub fn run_new_expr(&mut self, expr: &Node, insts: &mut ByteCode) {
-        self.run(expr, insts);
-        let len = insts.len();
-        if insts[len - 1 - 4] == CALL {
-            insts[len - 1 - 4] = CONSTRUCT;
+        if let NodeBase::Call(ref callee, ref args) = expr.base {
+            if args.iter().any(is_spread) {
+                self.run_array_literal(args, insts);
+                self.run_expr(&*callee, insts);
+                self.bytecode_gen.gen_construct_spread(insts);
+                return;
+            }
+            for arg in args {
+                self.run_expr(arg, insts);
+            }
+            self.run_expr(&*callee, insts);
+            self.bytecode_gen.gen_constract(args.len(), insts);
         } else {
-            unreachable!()
+            // new F is new F()
+            self.run_expr(expr, insts);
+            self.bytecode_gen.gen_constract(0, insts);
         }
     }
 }
@@ -476,13 +1157,154 @@ impl VMCodeGen {
             .insert(name.clone(), (false, id));
 
         if let &Some(ref init) = init {
-            self.run(&*init, insts);
+            self.run_expr(&*init, insts);
             self.bytecode_gen.gen_set_local(id as u32, insts);
         }
 
         id
     }
 
+    /// Starts the scope of the variables that `nodes` declare with let or const. In a switch
+    /// statement, each of them gets a local telling whether it has been initialized, since its
+    /// declaration may be jumped over. Those that closures made in a loop use are kept in a new
+    /// environment, which inherits the enclosing one.
+    fn push_lexical_scope<'a, I: Iterator<Item = &'a Node>>(
+        &mut self,
+        nodes: I,
+        in_switch: bool,
+        insts: &mut ByteCode,
+    ) {
+        let mut scope = HashMap::new();
+        let mut env = None;
+        for node in nodes {
+            if let NodeBase::LexicalDecl(ref name, _, kind) = node.base {
+                if scope.contains_key(name) {
+                    let msg = format!("identifier '{}' has already been declared", name);
+                    unsupported(msg.as_str(), node.pos)
+                }
+                let flag = if in_switch {
+                    let flag = self.local_var_stack_addr.gen_id();
+                    self.bytecode_gen.gen_push_bool(false, insts);
+                    self.bytecode_gen.gen_set_local(flag as u32, insts);
+                    Some(flag)
+                } else {
+                    None
+                };
+                let storage = if self.loop_lexicals.contains(name) {
+                    if env.is_none() {
+                        let id = self.local_var_stack_addr.gen_id();
+                        self.gen_current_env(insts);
+                        self.bytecode_gen.gen_new_env(insts);
+                        self.bytecode_gen.gen_set_local(id as u32, insts);
+                        env = Some(id);
+                    }
+                    Storage::Env(env.unwrap())
+                } else if self.captured_lexicals.contains_key(name) {
+                    self.bytecode_gen.gen_push_bool(false, insts);
+                    self.bytecode_gen
+                        .gen_set_global(initialized_flag(name), insts);
+                    Storage::Global
+                } else {
+                    Storage::Local(self.local_var_stack_addr.gen_id())
+                };
+                let var = LexicalVar {
+                    storage: storage,
+                    kind: kind,
+                    initialized: Initialized::No,
+                    flag: flag,
+                };
+                scope.insert(name.clone(), var);
+            }
+        }
+        self.lexical_scopes.push(scope);
+    }
+
+    /// Pushes the innermost environment, or the one that the function closes over if no enclosing
+    /// block has one.
+    fn gen_current_env(&mut self, insts: &mut ByteCode) {
+        match self.lexical_scopes.iter().rev().filter_map(scope_env).next() {
+            Some(env) => self.bytecode_gen.gen_get_local(env as u32, insts),
+            None => self.bytecode_gen.gen_get_env(insts),
+        }
+    }
+
+    /// Returns the innermost variable named `name` declared with let or const.
+    fn lexical_var(&self, name: &str) -> Option<LexicalVar> {
+        self.lexical_scopes
+            .iter()
+            .rev()
+            .filter_map(|scope| scope.get(name))
+            .next()
+            .cloned()
+    }
+
+    pub fn run_lexical_decl(
+        &mut self,
+        name: &String,
+        init: &Option<Box<Node>>,
+        insts: &mut ByteCode,
+    ) {
+        // The variable is still uninitialized while the initializer runs.
+        match init {
+            &Some(ref init) => self.run_expr(&*init, insts),
+            &None => self.bytecode_gen.gen_push_const(Value::Undefined, insts),
+        }
+        let var = self
+            .lexical_scopes
+            .last_mut()
+            .and_then(|scope| scope.get_mut(name.as_str()))
+            .unwrap();
+        var.initialized = Initialized::Yes;
+        match var.storage {
+            Storage::Local(id) => self.bytecode_gen.gen_set_local(id as u32, insts),
+            Storage::Global => {
+                self.bytecode_gen.gen_set_global(name.clone(), insts);
+                self.bytecode_gen.gen_push_bool(true, insts);
+                self.bytecode_gen
+                    .gen_set_global(initialized_flag(name), insts);
+            }
+            // The variable is added to the environment, which it is missing from until now.
+            Storage::Env(env) => {
+                self.bytecode_gen.gen_get_local(env as u32, insts);
+                self.bytecode_gen
+                    .gen_push_const(Value::String(CString::new(name.as_str()).unwrap()), insts);
+                self.bytecode_gen.gen_set_member(insts);
+            }
+        }
+        if let Some(flag) = var.flag {
+            self.bytecode_gen.gen_push_bool(true, insts);
+            self.bytecode_gen.gen_set_local(flag as u32, insts);
+        }
+    }
+
+    /// Stops at run time if the variable `name` declared with let or const is used before its
+    /// declaration has run.
+    fn gen_check_initialized(&mut self, name: &String, var: LexicalVar, insts: &mut ByteCode) {
+        match var.initialized {
+            Initialized::Yes => return,
+            Initialized::No => self.bytecode_gen.gen_push_bool(false, insts),
+            Initialized::Unknown => self
+                .bytecode_gen
+                .gen_get_local(var.flag.unwrap() as u32, insts),
+        }
+        self.bytecode_gen.gen_check_initialized(unmangled(name), insts);
+    }
+
+    /// Stops at run time if the variable `name` declared with let or const in another function is
+    /// used before its declaration has run.
+    fn gen_check_captured_initialized(&mut self, name: &String, insts: &mut ByteCode) {
+        // A variable kept in an environment is missing from it until then.
+        if self.loop_lexicals.contains(name) {
+            self.gen_current_env(insts);
+            self.bytecode_gen.gen_get_env_var(name.clone(), insts);
+            self.bytecode_gen.gen_pop(insts);
+            return;
+        }
+        self.bytecode_gen
+            .gen_get_global_or_undefined(initialized_flag(name), insts);
+        self.bytecode_gen.gen_check_initialized(unmangled(name), insts);
+    }
+
     pub fn run_arg_var_decl(&mut self, name: &String, init: &Option<Node>, insts: &mut ByteCode) {
         let id = self.arguemnt_var_addr.gen_id();
 
@@ -492,7 +1314,7 @@ impl VMCodeGen {
             .insert(name.clone(), (true, id));
 
         if let &Some(ref init) = init {
-            self.run(init, insts);
+            self.run_expr(init, insts);
             self.bytecode_gen.gen_set_local(id as u32, insts);
         }
     }
@@ -500,12 +1322,12 @@ impl VMCodeGen {
 
 impl VMCodeGen {
     pub fn run_if(&mut self, cond: &Node, then_: &Node, else_: &Node, insts: &mut ByteCode) {
-        self.run(cond, insts);
+        self.run_expr(cond, insts);
 
         let cond_pos = insts.len() as isize;
         self.bytecode_gen.gen_jmp_if_false(0, insts);
 
-        self.run(then_, insts);
+        self.run_statement(then_, insts);
 
         if else_.base == NodeBase::Nope {
             let pos = insts.len() as isize;
@@ -523,7 +1345,7 @@ impl VMCodeGen {
                 &mut insts[cond_pos as usize + 1..cond_pos as usize + 5],
             );
 
-            self.run(else_, insts);
+            self.run_statement(else_, insts);
 
             let pos = insts.len() as isize;
             self.bytecode_gen.replace_int32(
@@ -535,14 +1357,14 @@ impl VMCodeGen {
 
     pub fn run_while(&mut self, cond: &Node, body: &Node, insts: &mut ByteCode) {
         let pos1 = insts.len() as isize;
-        self.labels.push(Labels::new());
+        self.push_labels(LabelsKind::Loop);
 
-        self.run(cond, insts);
+        self.run_expr(cond, insts);
 
         let cond_pos = insts.len() as isize;
         self.bytecode_gen.gen_jmp_if_false(0, insts);
 
-        self.run(body, insts);
+        self.run_statement(body, insts);
 
         let loop_pos = insts.len() as isize;
         self.bytecode_gen
@@ -567,25 +1389,98 @@ impl VMCodeGen {
         );
     }
 
-    pub fn run_for(
-        &mut self,
-        init: &Node,
-        cond: &Node,
-        step: &Node,
-        body: &Node,
+    /// Runs `with (obj) body`. The object is kept in a hidden local, and the names in the body
+    /// are looked up in it before the variables. It is also the `with` variable of a new
+    /// environment, which the closures made in the body capture to look names up in it.
+    pub fn run_with(&mut self, obj: &Node, body: &Node, insts: &mut ByteCode) {
+        self.run_expr(obj, insts);
+        let id = self.local_var_stack_addr.gen_id();
+        self.bytecode_gen.gen_set_local(id as u32, insts);
+
+        let env = self.local_var_stack_addr.gen_id();
+        self.gen_current_env(insts);
+        self.bytecode_gen.gen_new_env(insts);
+        self.bytecode_gen.gen_set_local(env as u32, insts);
+        self.bytecode_gen.gen_get_local(id as u32, insts);
+        self.bytecode_gen.gen_get_local(env as u32, insts);
+        self.bytecode_gen
+            .gen_push_const(property_key("with"), insts);
+        self.bytecode_gen.gen_set_member(insts);
+        // `with` is a reserved word, so no variable has its name.
+        let mut scope = HashMap::new();
+        let var = LexicalVar {
+            storage: Storage::Env(env),
+            kind: LexicalKind::Const,
+            initialized: Initialized::Yes,
+            flag: None,
+        };
+        scope.insert("with".to_string(), var);
+        self.lexical_scopes.push(scope);
+
+        self.with_objects.push(id);
+        self.run_statement(body, insts);
+        self.with_objects.pop();
+        self.lexical_scopes.pop();
+    }
+
+    /// Runs a do-while loop. The body comes first, and the loop ends with a jump back to it as
+    /// other loops do, where the JIT finds the loop.
+    pub fn run_do_while(&mut self, body: &Node, cond: &Node, insts: &mut ByteCode) {
+        let pos = insts.len() as isize;
+        self.push_labels(LabelsKind::Loop);
+
+        self.run_statement(body, insts);
+
+        let continue_label_pos = insts.len() as isize;
+        self.labels.last_mut().unwrap().replace_continue_jmps(
+            &mut self.bytecode_gen,
+            insts,
+            continue_label_pos,
+        );
+        self.run_expr(cond, insts);
+        // Jumps over the jump back when the condition is false.
+        self.bytecode_gen.gen_jmp_if_false(5, insts);
+
+        let loop_pos = insts.len() as isize;
+        self.bytecode_gen
+            .gen_jmp((pos - loop_pos) as i32 - 5, insts);
+
+        let break_label_pos = insts.len() as isize;
+        self.labels.last_mut().unwrap().replace_break_jmps(
+            &mut self.bytecode_gen,
+            insts,
+            break_label_pos,
+        );
+        self.labels.pop();
+    }
+
+    pub fn run_for(
+        &mut self,
+        init: &Node,
+        cond: &Node,
+        step: &Node,
+        body: &Node,
         insts: &mut ByteCode,
     ) {
-        self.run(init, insts);
+        // The variables declared with let or const in the head belong to the whole loop.
+        self.push_lexical_scope(statements_of(init).iter(), false, insts);
+        match init.base {
+            NodeBase::StatementList(ref nodes) => for node in nodes {
+                self.run_statement(node, insts)
+            },
+            _ => self.run_statement(init, insts),
+        }
+        self.gen_next_iteration_env(insts);
 
         let pos = insts.len() as isize;
-        self.labels.push(Labels::new());
+        self.push_labels(LabelsKind::Loop);
 
-        self.run(cond, insts);
+        self.run_expr(cond, insts);
 
         let cond_pos = insts.len() as isize;
         self.bytecode_gen.gen_jmp_if_false(0, insts);
 
-        self.run(body, insts);
+        self.run_statement(body, insts);
 
         let continue_label_pos = insts.len() as isize;
         self.labels.last_mut().unwrap().replace_continue_jmps(
@@ -593,7 +1488,8 @@ impl VMCodeGen {
             insts,
             continue_label_pos,
         );
-        self.run(step, insts);
+        self.gen_next_iteration_env(insts);
+        self.run_statement(step, insts);
 
         let loop_pos = insts.len() as isize;
         self.bytecode_gen
@@ -612,21 +1508,353 @@ impl VMCodeGen {
             (pos - cond_pos) as i32 - 5,
             &mut insts[cond_pos as usize + 1..cond_pos as usize + 5],
         );
+        self.lexical_scopes.pop();
+    }
+
+    /// Gives the next iteration of a for loop a copy of the environment of the variables declared
+    /// in its head, if closures use them, so that the closures made so far keep the values that
+    /// they had.
+    fn gen_next_iteration_env(&mut self, insts: &mut ByteCode) {
+        if let Some(env) = scope_env(self.lexical_scopes.last().unwrap()) {
+            self.bytecode_gen.gen_get_local(env as u32, insts);
+            self.bytecode_gen.gen_copy_env(insts);
+            self.bytecode_gen.gen_set_local(env as u32, insts);
+        }
+    }
+}
+
+/// Returns the local holding the environment of the variables of `scope`, if it has one.
+fn scope_env(scope: &HashMap<String, LexicalVar>) -> Option<Id> {
+    scope
+        .values()
+        .filter_map(|var| match var.storage {
+            Storage::Env(env) => Some(env),
+            _ => None,
+        })
+        .next()
+}
+
+impl VMCodeGen {
+    /// Runs a switch statement. If the labels are consecutive integers, the case to start at is
+    /// found with a jump table. Otherwise the discriminant is compared with each label in turn.
+    pub fn run_switch(
+        &mut self,
+        discriminant: &Node,
+        cases: &Vec<(Option<Node>, Node)>,
+        insts: &mut ByteCode,
+    ) {
+        // The offsets to replace, each with where it is, where it is from and the case it jumps
+        // to. None is the default clause, or the end if there is none.
+        let mut jmps: Vec<(usize, usize, Option<usize>)> = vec![];
+
+        self.run_expr(discriminant, insts);
+        // The cases share one scope, in which the variables declared with let or const are run
+        // from the case jumped to.
+        let decls = cases.iter().flat_map(|case| statements_of(&case.1));
+        self.push_lexical_scope(decls, true, insts);
+        match dense_int_labels(cases) {
+            Some(min) => {
+                let len = cases.iter().filter(|case| case.0.is_some()).count();
+                let pos = insts.len();
+                self.bytecode_gen.gen_jmp_table(min, len, insts);
+                let end = insts.len();
+                jmps.push((pos + 9, end, None));
+                for (i, case) in cases.iter().enumerate() {
+                    if let Some(ref label) = case.0 {
+                        let n = int_label(label).unwrap() - min;
+                        jmps.push((pos + 13 + 4 * n as usize, end, Some(i)));
+                    }
+                }
+            }
+            None => {
+                // The discriminant is kept in a local that has no name.
+                let id = self.local_var_stack_addr.gen_id();
+                self.bytecode_gen.gen_set_local(id as u32, insts);
+                for (i, case) in cases.iter().enumerate() {
+                    if let Some(ref label) = case.0 {
+                        self.bytecode_gen.gen_get_local(id as u32, insts);
+                        self.run_expr(label, insts);
+                        self.bytecode_gen.gen_sne(insts);
+                        let pos = insts.len();
+                        self.bytecode_gen.gen_jmp_if_false(0, insts);
+                        jmps.push((pos + 1, pos + 5, Some(i)));
+                    }
+                }
+                let pos = insts.len();
+                self.bytecode_gen.gen_jmp(0, insts);
+                jmps.push((pos + 1, pos + 5, None));
+            }
+        }
+
+        // The bodies follow one another, so a case without a break falls through to the next.
+        self.push_labels(LabelsKind::Switch);
+        let mut body_pos = vec![];
+        for &(_, ref body) in cases {
+            // The declarations run so far may have been jumped over.
+            if !body_pos.is_empty() {
+                for var in self.lexical_scopes.last_mut().unwrap().values_mut() {
+                    if var.initialized == Initialized::Yes {
+                        var.initialized = Initialized::Unknown
+                    }
+                }
+            }
+            body_pos.push(insts.len());
+            for node in statements_of(body) {
+                self.run_statement(node, insts)
+            }
+        }
+        self.lexical_scopes.pop();
+
+        let break_label_pos = insts.len() as isize;
+        self.labels.last_mut().unwrap().replace_break_jmps(
+            &mut self.bytecode_gen,
+            insts,
+            break_label_pos,
+        );
+        self.labels.pop();
+
+        let default_pos = cases
+            .iter()
+            .position(|case| case.0.is_none())
+            .map_or(insts.len(), |i| body_pos[i]);
+        for (pos, from, case) in jmps {
+            let dst = case.map_or(default_pos, |i| body_pos[i]);
+            self.bytecode_gen
+                .replace_int32(dst as i32 - from as i32, &mut insts[pos..pos + 4]);
+        }
+    }
+}
+
+/// Returns the smallest label if the labels of `cases` are distinct consecutive integers, and
+/// there are enough of them for a jump table to be worth it.
+fn dense_int_labels(cases: &Vec<(Option<Node>, Node)>) -> Option<i32> {
+    let mut labels = vec![];
+    for case in cases {
+        if let Some(ref label) = case.0 {
+            labels.push(int_label(label)?);
+        }
+    }
+    let len = labels.len();
+    labels.sort();
+    labels.dedup();
+    if len < 3 || labels.len() < len || labels[len - 1] - labels[0] != len as i32 - 1 {
+        return None;
+    }
+    Some(labels[0])
+}
+
+/// Returns the value of `label` if it is a small integer literal.
+fn int_label(label: &Node) -> Option<i32> {
+    let n = match label.base {
+        NodeBase::Number(n) => n,
+        NodeBase::UnaryOp(ref expr, UnaryOp::Minus) => match expr.base {
+            NodeBase::Number(n) => -n,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    if n.fract() == 0.0 && n.abs() < (1 << 30) as f64 {
+        Some(n as i32)
+    } else {
+        None
+    }
+}
+
+impl VMCodeGen {
+    /// Runs a for-in loop, which iterates a snapshot of the keys of `obj`.
+    pub fn run_for_in(&mut self, var: &Node, obj: &Node, body: &Node, insts: &mut ByteCode) {
+        let id = self.run_for_each_var(var, insts);
+        self.run_expr(obj, insts);
+        self.bytecode_gen.gen_get_key_iterator(insts);
+        self.run_for_each_body(id, body, insts);
+    }
+
+    /// Runs a for-of loop, which iterates the elements of `iterable`.
+    pub fn run_for_of(&mut self, var: &Node, iterable: &Node, body: &Node, insts: &mut ByteCode) {
+        let id = self.run_for_each_var(var, insts);
+        self.run_expr(iterable, insts);
+        self.bytecode_gen.gen_get_iterator(insts);
+        self.run_for_each_body(id, body, insts);
+    }
+
+    fn run_for_each_var(&mut self, var: &Node, insts: &mut ByteCode) -> Id {
+        match var.base {
+            NodeBase::VarDecl(ref name, _) => self.run_var_decl(name, &None, insts),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Runs the loop of a for-in or a for-of statement, which puts each element in the local
+    /// `id`. The iterated value and the position of its next element stay on the stack while the
+    /// loop runs, so breaking out of it jumps to where they are popped.
+    fn run_for_each_body(&mut self, id: Id, body: &Node, insts: &mut ByteCode) {
+        let pos = insts.len() as isize;
+        self.push_labels(LabelsKind::ForEachLoop);
+
+        self.bytecode_gen.gen_iter_next(0, insts);
+        self.bytecode_gen.gen_set_local(id as u32, insts);
+
+        self.run_statement(body, insts);
+
+        let loop_pos = insts.len() as isize;
+        self.bytecode_gen
+            .gen_jmp((pos - loop_pos) as i32 - 5, insts);
+
+        let break_label_pos = insts.len() as isize;
+        self.labels.last_mut().unwrap().replace_break_jmps(
+            &mut self.bytecode_gen,
+            insts,
+            break_label_pos,
+        );
+        self.labels
+            .last_mut()
+            .unwrap()
+            .replace_continue_jmps(&mut self.bytecode_gen, insts, pos);
+        self.labels.pop();
+
+        self.bytecode_gen.replace_int32(
+            (break_label_pos - pos) as i32 - 5,
+            &mut insts[pos as usize + 1..pos as usize + 5],
+        );
+
+        self.bytecode_gen.gen_pop(insts);
+        self.bytecode_gen.gen_pop(insts);
     }
 }
 
 impl VMCodeGen {
     pub fn run_unary_op(&mut self, expr: &Node, op: &UnaryOp, insts: &mut ByteCode) {
-        self.run(expr, insts);
+        match (op, &expr.base) {
+            (&UnaryOp::Delete, _) => return self.run_delete(expr, insts),
+            // typeof of an undeclared variable is "undefined" instead of a ReferenceError.
+            (&UnaryOp::Typeof, &NodeBase::Identifier(ref name))
+                if !self.is_local(name)
+                    && !self.global_consts.contains_key(name)
+                    && !self.captured_lexicals.contains_key(name) =>
+            {
+                self.gen_with_lookup(name, insts, |codegen, insts| {
+                    codegen
+                        .bytecode_gen
+                        .gen_get_global_or_undefined(name.clone(), insts)
+                })
+            }
+            _ => self.run_expr(expr, insts),
+        }
         match op {
             &UnaryOp::Minus => self.bytecode_gen.gen_neg(insts),
-            _ => unimplemented!(),
+            &UnaryOp::Not => self.bytecode_gen.gen_not(insts),
+            &UnaryOp::Typeof => self.bytecode_gen.gen_typeof(insts),
+            &UnaryOp::Await => {
+                let id = self.async_promise.unwrap();
+                self.bytecode_gen.gen_await(id as u32, insts)
+            }
+            _ => unsupported("unsupported unary operator", expr.pos),
+        }
+    }
+
+    /// Runs `delete expr`. Only properties can be removed: deleting a variable gives false, and
+    /// deleting any other value gives true once it has been run.
+    fn run_delete(&mut self, expr: &Node, insts: &mut ByteCode) {
+        match expr.base {
+            NodeBase::Member(ref parent, ref member) => {
+                self.run_expr(parent, insts);
+                self.bytecode_gen
+                    .gen_push_const(Value::String(CString::new(member.as_str()).unwrap()), insts);
+                self.bytecode_gen.gen_delete_member(insts)
+            }
+            NodeBase::Index(ref parent, ref idx) => {
+                self.run_expr(parent, insts);
+                self.run_expr(idx, insts);
+                self.bytecode_gen.gen_delete_member(insts)
+            }
+            // TODO: Global variables made by assigning to them can be deleted.
+            NodeBase::Identifier(_) => self.bytecode_gen.gen_push_bool(false, insts),
+            _ => {
+                self.run_statement(expr, insts);
+                self.bytecode_gen.gen_push_bool(true, insts)
+            }
         }
     }
 
     pub fn run_binary_op(&mut self, lhs: &Node, rhs: &Node, op: &BinOp, insts: &mut ByteCode) {
-        self.run(lhs, insts);
-        self.run(rhs, insts);
+        if let &BinOp::Comma = op {
+            self.run_statement(lhs, insts);
+            self.run(rhs, insts);
+            return;
+        }
+        if let &BinOp::LAnd | &BinOp::LOr = op {
+            return self.run_logical_op(lhs, rhs, op, insts);
+        }
+        self.run_expr(lhs, insts);
+        self.run_expr(rhs, insts);
+        self.gen_binary_op(op, lhs.pos, insts);
+    }
+
+    /// Runs `lhs && rhs` or `lhs || rhs`. `rhs` is run only if `lhs` does not decide the value.
+    /// Both branches leave their value in a hidden local, so that the stack is the same where
+    /// they join.
+    fn run_logical_op(&mut self, lhs: &Node, rhs: &Node, op: &BinOp, insts: &mut ByteCode) {
+        let id = self.local_var_stack_addr.gen_id();
+        self.run_expr(lhs, insts);
+        self.bytecode_gen.gen_set_local(id as u32, insts);
+        self.bytecode_gen.gen_get_local(id as u32, insts);
+        if let &BinOp::LOr = op {
+            self.bytecode_gen.gen_not(insts);
+        }
+
+        let cond_pos = insts.len() as isize;
+        self.bytecode_gen.gen_jmp_if_false(0, insts);
+
+        self.run_expr(rhs, insts);
+        self.bytecode_gen.gen_set_local(id as u32, insts);
+
+        let pos = insts.len() as isize;
+        self.bytecode_gen.replace_int32(
+            (pos - cond_pos) as i32 - 5,
+            &mut insts[cond_pos as usize + 1..cond_pos as usize + 5],
+        );
+        self.bytecode_gen.gen_get_local(id as u32, insts);
+    }
+
+    /// Runs `cond ? then_ : else_`, joining the branches through a hidden local like
+    /// `run_logical_op`.
+    pub fn run_ternary_op(
+        &mut self,
+        cond: &Node,
+        then_: &Node,
+        else_: &Node,
+        insts: &mut ByteCode,
+    ) {
+        let id = self.local_var_stack_addr.gen_id();
+        self.run_expr(cond, insts);
+
+        let cond_pos = insts.len() as isize;
+        self.bytecode_gen.gen_jmp_if_false(0, insts);
+
+        self.run_expr(then_, insts);
+        self.bytecode_gen.gen_set_local(id as u32, insts);
+
+        let then_end_pos = insts.len() as isize;
+        self.bytecode_gen.gen_jmp(0, insts);
+
+        let pos = insts.len() as isize;
+        self.bytecode_gen.replace_int32(
+            (pos - cond_pos) as i32 - 5,
+            &mut insts[cond_pos as usize + 1..cond_pos as usize + 5],
+        );
+
+        self.run_expr(else_, insts);
+        self.bytecode_gen.gen_set_local(id as u32, insts);
+
+        let pos = insts.len() as isize;
+        self.bytecode_gen.replace_int32(
+            (pos - then_end_pos) as i32 - 5,
+            &mut insts[then_end_pos as usize + 1..then_end_pos as usize + 5],
+        );
+        self.bytecode_gen.gen_get_local(id as u32, insts);
+    }
+
+    fn gen_binary_op(&mut self, op: &BinOp, pos: usize, insts: &mut ByteCode) {
         match op {
             &BinOp::Add => self.bytecode_gen.gen_add(insts),
             &BinOp::Sub => self.bytecode_gen.gen_sub(insts),
@@ -641,73 +1869,403 @@ impl VMCodeGen {
             &BinOp::Gt => self.bytecode_gen.gen_gt(insts),
             &BinOp::Le => self.bytecode_gen.gen_le(insts),
             &BinOp::Ge => self.bytecode_gen.gen_ge(insts),
-            _ => {}
+            &BinOp::In => self.bytecode_gen.gen_in(insts),
+            &BinOp::InstanceOf => self.bytecode_gen.gen_instance_of(insts),
+            _ => unsupported("unsupported binary operator", pos),
         }
     }
 
-    pub fn run_assign(&mut self, dst: &Node, src: &Node, insts: &mut ByteCode) {
-        self.run(src, insts);
-
-        match dst.base {
+    /// Runs `dst = src`. With `leave_value`, the assigned value is left on the stack so that
+    /// assignments can be chained.
+    pub fn run_assign(&mut self, dst: &Node, src: &Node, leave_value: bool, insts: &mut ByteCode) {
+        let val = match dst.base {
+            NodeBase::Identifier(ref name) if self.in_with_scope(name) => {
+                self.run_expr(src, insts);
+                self.run_assign_in_with(name, leave_value, insts);
+                None
+            }
             NodeBase::Identifier(ref name) => {
-                if let Some((is_arg, p)) = self.local_varmap.last().unwrap().get(name.as_str()) {
-                    if *is_arg {
-                        self.bytecode_gen.gen_set_arg_local(*p as u32, insts);
-                    } else {
-                        self.bytecode_gen.gen_set_local(*p as u32, insts);
-                    }
-                } else {
-                    self.bytecode_gen.gen_set_global(name.clone(), insts);
+                self.run_expr(src, insts);
+                let val = self.keep_value(leave_value, insts);
+                self.gen_set_variable(name, insts);
+                val
+            }
+            NodeBase::Member(_, _) | NodeBase::Index(_, _) => {
+                let (parent, key) = self.run_assign_target(dst, is_simple(src), insts);
+                self.run_expr(src, insts);
+                let val = self.keep_value(leave_value, insts);
+                self.push_operand(&parent, insts);
+                self.push_operand(&key, insts);
+                self.bytecode_gen.gen_set_member(insts);
+                val
+            }
+            _ => unsupported("unsupported assignment target", dst.pos),
+        };
+        if let Some(id) = val {
+            self.bytecode_gen.gen_get_local(id as u32, insts);
+        }
+    }
+
+    /// Assigns the value on top of the stack to the variable `name`.
+    fn gen_set_variable(&mut self, name: &String, insts: &mut ByteCode) {
+        if let Some(var) = self.lexical_var(name) {
+            self.gen_check_initialized(name, var, insts);
+            match (var.kind, var.storage) {
+                (LexicalKind::Const, _) => {
+                    self.bytecode_gen.gen_assign_to_const(unmangled(name), insts)
+                }
+                (_, Storage::Local(id)) => self.bytecode_gen.gen_set_local(id as u32, insts),
+                (_, Storage::Global) => self.bytecode_gen.gen_set_global(name.clone(), insts),
+                (_, Storage::Env(env)) => {
+                    self.bytecode_gen.gen_get_local(env as u32, insts);
+                    self.bytecode_gen.gen_set_env_var(name.clone(), insts)
                 }
             }
+        } else if let Some((is_arg, p)) = self.local_varmap.last().unwrap().get(name.as_str()) {
+            if *is_arg {
+                self.bytecode_gen.gen_set_arg_local(*p as u32, insts);
+            } else {
+                self.bytecode_gen.gen_set_local(*p as u32, insts);
+            }
+        } else if self.global_consts.contains_key(name) {
+            self.bytecode_gen.gen_assign_to_const(unmangled(name), insts);
+        } else if let Some(&kind) = self.captured_lexicals.get(name) {
+            match (kind, self.loop_lexicals.contains(name)) {
+                // SET_ENV_VAR checks that the variable has been initialized itself.
+                (LexicalKind::Let, true) => {
+                    self.gen_current_env(insts);
+                    self.bytecode_gen.gen_set_env_var(name.clone(), insts)
+                }
+                (LexicalKind::Let, false) => {
+                    self.gen_check_captured_initialized(name, insts);
+                    self.bytecode_gen.gen_set_global(name.clone(), insts)
+                }
+                (LexicalKind::Const, _) => {
+                    self.gen_check_captured_initialized(name, insts);
+                    self.bytecode_gen.gen_assign_to_const(unmangled(name), insts)
+                }
+            }
+        } else if READ_ONLY_GLOBALS.contains(&name.as_str()) {
+            self.bytecode_gen.gen_pop(insts);
+        } else {
+            self.bytecode_gen.gen_set_global(name.clone(), insts);
+        }
+    }
+
+    /// Assigns the value on top of the stack to `name` inside with statements: to the property
+    /// of the innermost with object that has it, or else to the variable.
+    fn run_assign_in_with(&mut self, name: &String, leave_value: bool, insts: &mut ByteCode) {
+        let val = self.local_var_stack_addr.gen_id();
+        self.bytecode_gen.gen_set_local(val as u32, insts);
+        let found = |codegen: &mut VMCodeGen, obj: Id, insts: &mut ByteCode| {
+            codegen.bytecode_gen.gen_get_local(val as u32, insts);
+            codegen.bytecode_gen.gen_get_local(obj as u32, insts);
+            codegen
+                .bytecode_gen
+                .gen_push_const(property_key(name), insts);
+            codegen.bytecode_gen.gen_set_member(insts);
+            codegen.bytecode_gen.gen_get_local(val as u32, insts);
+        };
+        let not_found = |codegen: &mut VMCodeGen, insts: &mut ByteCode| {
+            codegen.bytecode_gen.gen_get_local(val as u32, insts);
+            codegen.gen_set_variable(name, insts);
+            codegen.bytecode_gen.gen_get_local(val as u32, insts);
+        };
+        self.gen_with_objects_lookup(name, found, not_found, insts);
+        if !leave_value {
+            self.bytecode_gen.gen_pop(insts);
+        }
+    }
+
+    /// Runs `dst op= src`, where `dst` is a member or an index.
+    pub fn run_compound_assign(
+        &mut self,
+        dst: &Node,
+        src: &Node,
+        op: &BinOp,
+        leave_value: bool,
+        insts: &mut ByteCode,
+    ) {
+        let (parent, key) = self.run_assign_target(dst, is_simple(src), insts);
+        self.push_operand(&parent, insts);
+        self.push_operand(&key, insts);
+        self.bytecode_gen.gen_get_member(insts);
+        self.run_expr(src, insts);
+        self.gen_binary_op(op, dst.pos, insts);
+        let val = self.keep_value(leave_value, insts);
+        self.push_operand(&parent, insts);
+        self.push_operand(&key, insts);
+        self.bytecode_gen.gen_set_member(insts);
+        if let Some(id) = val {
+            self.bytecode_gen.gen_get_local(id as u32, insts);
+        }
+    }
+
+    /// Evaluates the object and the key of the member or index `dst`. Unless both they and the
+    /// value are simple, they are evaluated now, once, into hidden locals.
+    fn run_assign_target<'a>(
+        &mut self,
+        dst: &'a Node,
+        simple_src: bool,
+        insts: &mut ByteCode,
+    ) -> (Operand<'a>, Operand<'a>) {
+        let mut operand = |node: &'a Node, insts: &mut ByteCode| {
+            if simple_src && is_simple(node) {
+                return Operand::Node(node);
+            }
+            self.run_expr(node, insts);
+            let id = self.local_var_stack_addr.gen_id();
+            self.bytecode_gen.gen_set_local(id as u32, insts);
+            Operand::Local(id)
+        };
+        match dst.base {
             NodeBase::Member(ref parent, ref member) => {
-                self.run(&*parent, insts);
-                self.bytecode_gen
-                    .gen_push_const(Value::String(CString::new(member.as_str()).unwrap()), insts);
-                self.bytecode_gen.gen_set_member(insts);
+                (operand(parent, insts), Operand::Name(member.as_str()))
             }
             NodeBase::Index(ref parent, ref idx) => {
-                self.run(&*parent, insts);
-                self.run(&*idx, insts);
-                self.bytecode_gen.gen_set_member(insts);
+                let parent = operand(parent, insts);
+                (parent, operand(idx, insts))
             }
-            _ => unimplemented!(),
+            _ => unsupported("unsupported assignment target", dst.pos),
         }
     }
+
+    fn push_operand(&mut self, operand: &Operand, insts: &mut ByteCode) {
+        match *operand {
+            Operand::Node(node) => self.run_expr(node, insts),
+            Operand::Name(name) => self
+                .bytecode_gen
+                .gen_push_const(Value::String(CString::new(name).unwrap()), insts),
+            Operand::Local(id) => self.bytecode_gen.gen_get_local(id as u32, insts),
+        }
+    }
+
+    /// Copies the value on top of the stack into a hidden local if `leave_value`.
+    fn keep_value(&mut self, leave_value: bool, insts: &mut ByteCode) -> Option<Id> {
+        if !leave_value {
+            return None;
+        }
+        let id = self.local_var_stack_addr.gen_id();
+        self.bytecode_gen.gen_set_local(id as u32, insts);
+        self.bytecode_gen.gen_get_local(id as u32, insts);
+        Some(id)
+    }
+}
+
+/// The object or the key of an assignment target.
+enum Operand<'a> {
+    /// Evaluated again wherever it is needed.
+    Node(&'a Node),
+    Name(&'a str),
+    /// Already evaluated into a hidden local.
+    Local(Id),
+}
+
+/// Whether evaluating `node` again gives the same value, as long as nothing is assigned in
+/// between.
+fn is_simple(node: &Node) -> bool {
+    match node.base {
+        NodeBase::Member(ref parent, _) => is_simple(parent),
+        NodeBase::Index(ref parent, ref idx) => is_simple(parent) && is_simple(idx),
+        NodeBase::UnaryOp(ref expr, UnaryOp::Minus)
+        | NodeBase::UnaryOp(ref expr, UnaryOp::Not) => is_simple(expr),
+        NodeBase::BinaryOp(ref lhs, ref rhs, _) => is_simple(lhs) && is_simple(rhs),
+        NodeBase::Identifier(_)
+        | NodeBase::This
+        | NodeBase::Arguments
+        | NodeBase::String(_)
+        | NodeBase::Number(_)
+        | NodeBase::Boolean(_)
+        | NodeBase::Null => true,
+        _ => false,
+    }
 }
 
 impl VMCodeGen {
     pub fn run_call(&mut self, callee: &Node, args: &Vec<Node>, insts: &mut ByteCode) {
+        // The arguments are collected into an array if any of them is spread. A method callee is
+        // looked up as a member, which binds this.
+        if args.iter().any(is_spread) {
+            self.run_array_literal(args, insts);
+            self.run_expr(callee, insts);
+            self.bytecode_gen.gen_call_spread(insts);
+            return;
+        }
+
         for arg in args {
-            self.run(arg, insts);
+            self.run_expr(arg, insts);
         }
+        self.run_callee(callee, args.len(), insts);
+    }
+
+    /// Calls `callee` with the `argc` arguments on the stack.
+    fn run_callee(&mut self, callee: &Node, argc: usize, insts: &mut ByteCode) {
+        if let NodeBase::Identifier(ref name) = callee.base {
+            // A with object may have a method of the same name.
+            if !self.is_local(name)
+                && !self.in_with_scope(name)
+                && !self.closures.contains(name)
+                && self.function_use_this.get(name.as_str()) == Some(&false)
+            {
+                let id = self.bytecode_gen.intern_string(name.clone());
+                self.bytecode_gen.gen_call_direct(id as i32, argc as u32, insts);
+                return;
+            }
+        }
+
+        // obj.m(...) looks up m and calls it with obj as this in one instruction.
+        if let NodeBase::Member(ref parent, ref name) = callee.base {
+            self.run_expr(parent, insts);
+            let id = self.bytecode_gen.intern_string(name.clone());
+            self.bytecode_gen.gen_call_method(argc as u32, id, insts);
+            return;
+        }
+
+        self.run_expr(callee, insts);
+
+        self.bytecode_gen.gen_call(argc as u32, insts);
+    }
 
-        self.run(callee, insts);
+    /// Calls the tag of a tagged template with the template object and the substitutions. The
+    /// template object is the array of the cooked strings, whose `raw` property is the array of
+    /// the raw strings. It is a constant, so every evaluation of the template passes the same
+    /// object, as in other engines.
+    ///
+    /// TODO: Freeze the template object.
+    pub fn run_tagged_template(
+        &mut self,
+        tag: &Node,
+        strings: &Vec<(Option<String>, String)>,
+        substitutions: &Vec<Node>,
+        pos: usize,
+        insts: &mut ByteCode,
+    ) {
+        let new_array = |elems| Value::Array(Rc::new(RefCell::new(ArrayValue::new(elems))));
+        let cooked = strings
+            .iter()
+            .map(|(cooked, _)| match cooked {
+                Some(cooked) => new_string(cooked, pos),
+                None => Value::Undefined,
+            }).collect();
+        let raw = strings.iter().map(|(_, raw)| new_string(raw, pos)).collect();
+        let template = new_array(cooked);
+        if let Value::Array(ref arr) = template {
+            arr.borrow_mut().obj.insert("raw".to_string(), new_array(raw));
+        }
+        self.bytecode_gen.gen_push_const(template, insts);
 
-        self.bytecode_gen.gen_call(args.len() as u32, insts);
+        for substitution in substitutions {
+            self.run_expr(substitution, insts);
+        }
+        self.run_callee(tag, substitutions.len() + 1, insts);
     }
 }
 
 impl VMCodeGen {
     fn run_object_literal(&mut self, properties: &Vec<PropertyDefinition>, insts: &mut ByteCode) {
-        for property in properties {
+        // The properties between two spreads are created as an object of their own, which is
+        // then spread into the literal, so that each run of properties still has a template.
+        let mut runs = properties.split(|property| match property {
+            PropertyDefinition::Spread(_) => true,
+            _ => false,
+        });
+        self.run_object_properties(runs.next().unwrap(), insts);
+
+        let spreads = properties.iter().filter_map(|property| match property {
+            PropertyDefinition::Spread(node) => Some(node),
+            _ => None,
+        });
+        for (spread, run) in spreads.zip(runs) {
+            self.run_expr(spread, insts);
+            self.bytecode_gen.gen_object_spread(insts);
+            if !run.is_empty() {
+                self.run_object_properties(run, insts);
+                self.bytecode_gen.gen_object_spread(insts);
+            }
+        }
+    }
+
+    fn run_object_properties(&mut self, properties: &[PropertyDefinition], insts: &mut ByteCode) {
+        let mut template = ObjectTemplate::new();
+
+        for (i, property) in properties.iter().enumerate() {
             match property {
-                PropertyDefinition::IdentifierReference(_) => unimplemented!(),
+                PropertyDefinition::IdentifierReference(_) | PropertyDefinition::Spread(_) => {
+                    unreachable!()
+                }
+                PropertyDefinition::Getter(name, node) | PropertyDefinition::Setter(name, node) => {
+                    check_property_name(name, node.pos);
+                    self.run_expr(&node, insts);
+                    template.props.get_or_insert_undefined(name.clone());
+                    let kind = match property {
+                        PropertyDefinition::Getter(_, _) => PropertyKind::Getter,
+                        _ => PropertyKind::Setter,
+                    };
+                    template.dynamic_keys.push((name.clone(), kind));
+                }
                 PropertyDefinition::Property(name, node) => {
-                    self.run(&node, insts);
-                    self.bytecode_gen
-                        .gen_push_const(Value::String(CString::new(name.as_str()).unwrap()), insts);
+                    check_property_name(name, node.pos);
+                    // Dynamic values are set after the constants, so every definition of a
+                    // redefined property is dynamic for the last one to win.
+                    let redefined = properties.iter().enumerate().any(|(j, p)| match p {
+                        PropertyDefinition::Property(name_, _)
+                        | PropertyDefinition::Getter(name_, _)
+                        | PropertyDefinition::Setter(name_, _) => i != j && name == name_,
+                        _ => false,
+                    });
+                    let constant = match node.base {
+                        NodeBase::String(ref s) => Some(new_string(s, node.pos)),
+                        NodeBase::Number(n) => Some(Value::Number(n)),
+                        NodeBase::Boolean(b) => Some(Value::Bool(b)),
+                        NodeBase::Null => Some(Value::Null),
+                        _ => None,
+                    };
+                    match constant {
+                        Some(val) if !redefined => {
+                            template.props.insert(name.clone(), val);
+                        }
+                        _ => {
+                            self.run_expr(&node, insts);
+                            // Keeps the place of the property, so that properties are in the
+                            // order they were written.
+                            template.props.get_or_insert_undefined(name.clone());
+                            template.dynamic_keys.push((name.clone(), PropertyKind::Value));
+                        }
+                    }
                 }
             }
         }
 
-        self.bytecode_gen
-            .gen_create_object(properties.len() as usize, insts);
+        let id = self.bytecode_gen.add_object_template(template);
+        self.bytecode_gen.gen_create_object(id, insts);
     }
 
     fn run_array_literal(&mut self, elems: &Vec<Node>, insts: &mut ByteCode) {
+        // As in object literals, the elements between two spreads are created as an array of
+        // their own, which is then spread into the literal.
+        let mut runs = elems.split(is_spread);
+        self.run_array_elements(runs.next().unwrap(), insts);
+
+        let spreads = elems.iter().filter_map(|elem| match elem.base {
+            NodeBase::Spread(ref expr) => Some(expr),
+            _ => None,
+        });
+        for (spread, run) in spreads.zip(runs) {
+            self.run_expr(spread, insts);
+            self.bytecode_gen.gen_array_spread(insts);
+            if !run.is_empty() {
+                self.run_array_elements(run, insts);
+                self.bytecode_gen.gen_array_spread(insts);
+            }
+        }
+    }
+
+    fn run_array_elements(&mut self, elems: &[Node], insts: &mut ByteCode) {
         for elem in elems.iter().rev() {
-            self.run(elem, insts);
+            match elem.base {
+                NodeBase::Nope => self.bytecode_gen.gen_push_const(Value::Undefined, insts),
+                _ => self.run_expr(elem, insts),
+            }
         }
 
         self.bytecode_gen
@@ -717,7 +2275,7 @@ impl VMCodeGen {
 
 impl VMCodeGen {
     fn run_member(&mut self, parent: &Node, member: &String, insts: &mut ByteCode) {
-        self.run(parent, insts);
+        self.run_expr(parent, insts);
 
         self.bytecode_gen
             .gen_push_const(Value::String(CString::new(member.as_str()).unwrap()), insts);
@@ -725,19 +2283,141 @@ impl VMCodeGen {
     }
 
     fn run_index(&mut self, parent: &Node, idx: &Node, insts: &mut ByteCode) {
-        self.run(parent, insts);
+        self.run_expr(parent, insts);
 
-        self.run(idx, insts);
+        self.run_expr(idx, insts);
         self.bytecode_gen.gen_get_member(insts);
     }
 
+    fn is_local(&self, name: &str) -> bool {
+        self.lexical_var(name).is_some() || self.local_varmap.last().unwrap().contains_key(name)
+    }
+
+    /// Returns whether a with object may have the property `name` where it is used: inside the
+    /// body of a with statement, or where a function declared in one uses an outer variable.
+    fn in_with_scope(&self, name: &str) -> bool {
+        // Functions made from function expressions are found under hidden names.
+        !name.starts_with("anonymous.")
+            && (!self.with_objects.is_empty() || self.in_with && !self.is_local(name))
+    }
+
     fn run_identifier(&mut self, name: &String, insts: &mut ByteCode) {
-        if let Some((is_arg, p)) = self.local_varmap.last().unwrap().get(name.as_str()) {
+        if self.in_with_scope(name) {
+            return self.gen_with_lookup(name, insts, |codegen, insts| {
+                codegen.run_variable(name, insts)
+            });
+        }
+        self.run_variable(name, insts)
+    }
+
+    /// Pushes `name` inside with statements: the property of the innermost with object that has
+    /// it, or else what `not_found` pushes.
+    fn gen_with_lookup<F>(&mut self, name: &String, insts: &mut ByteCode, not_found: F)
+    where
+        F: FnOnce(&mut VMCodeGen, &mut ByteCode),
+    {
+        let found = |codegen: &mut VMCodeGen, obj: Id, insts: &mut ByteCode| {
+            codegen.bytecode_gen.gen_get_local(obj as u32, insts);
+            codegen
+                .bytecode_gen
+                .gen_push_const(property_key(name), insts);
+            codegen.bytecode_gen.gen_get_member(insts);
+        };
+        self.gen_with_objects_lookup(name, found, not_found, insts)
+    }
+
+    /// Runs `found` with the innermost with object that has the property `name`, or `not_found`
+    /// if none has it. Each leaves one value, which is joined through a hidden local like
+    /// `run_logical_op` does.
+    fn gen_with_objects_lookup<F, G>(
+        &mut self,
+        name: &String,
+        found: F,
+        not_found: G,
+        insts: &mut ByteCode,
+    ) where
+        F: Fn(&mut VMCodeGen, Id, &mut ByteCode),
+        G: FnOnce(&mut VMCodeGen, &mut ByteCode),
+    {
+        let result = self.local_var_stack_addr.gen_id();
+        let mut end_jmps = vec![];
+        let mut objs: Vec<(Id, bool)> = self
+            .with_objects
+            .iter()
+            .rev()
+            .map(|&obj| (obj, false))
+            .collect();
+        // The with statements around the function are looked up at run time, in the environment
+        // it closes over.
+        if self.in_with && !self.is_local(name) {
+            objs.push((self.local_var_stack_addr.gen_id(), true));
+        }
+        for (obj, outer) in objs {
+            if outer {
+                self.bytecode_gen.gen_get_env(insts);
+                self.bytecode_gen.gen_get_with_object(unmangled(name), insts);
+                self.bytecode_gen.gen_set_local(obj as u32, insts);
+                self.bytecode_gen.gen_get_local(obj as u32, insts);
+            } else {
+                self.bytecode_gen
+                    .gen_push_const(property_key(name), insts);
+                self.bytecode_gen.gen_get_local(obj as u32, insts);
+                self.bytecode_gen.gen_in(insts);
+            }
+            let cond_pos = insts.len();
+            self.bytecode_gen.gen_jmp_if_false(0, insts);
+            found(self, obj, insts);
+            self.bytecode_gen.gen_set_local(result as u32, insts);
+            end_jmps.push(insts.len());
+            self.bytecode_gen.gen_jmp(0, insts);
+            let pos = insts.len();
+            self.bytecode_gen.replace_int32(
+                (pos - cond_pos) as i32 - 5,
+                &mut insts[cond_pos + 1..cond_pos + 5],
+            );
+        }
+        not_found(self, insts);
+        self.bytecode_gen.gen_set_local(result as u32, insts);
+        let pos = insts.len();
+        for jmp_pos in end_jmps {
+            self.bytecode_gen
+                .replace_int32((pos - jmp_pos) as i32 - 5, &mut insts[jmp_pos + 1..jmp_pos + 5]);
+        }
+        self.bytecode_gen.gen_get_local(result as u32, insts);
+    }
+
+    /// Pushes the variable `name`, as if no with object had the property.
+    fn run_variable(&mut self, name: &String, insts: &mut ByteCode) {
+        if let Some(var) = self.lexical_var(name) {
+            self.gen_check_initialized(name, var, insts);
+            match (var.storage, self.global_consts.get(name).cloned()) {
+                (Storage::Local(id), _) => self.bytecode_gen.gen_get_local(id as u32, insts),
+                (Storage::Global, Some(literal)) => self.run_expr(&literal, insts),
+                (Storage::Global, None) => self.bytecode_gen.gen_get_global(name.clone(), insts),
+                (Storage::Env(env), _) => {
+                    self.bytecode_gen.gen_get_local(env as u32, insts);
+                    self.bytecode_gen.gen_get_env_var(name.clone(), insts)
+                }
+            }
+        } else if let Some((is_arg, p)) = self.local_varmap.last().unwrap().get(name.as_str()) {
             if *is_arg {
                 self.bytecode_gen.gen_get_arg_local(*p as u32, insts);
             } else {
                 self.bytecode_gen.gen_get_local(*p as u32, insts);
             }
+        } else if let Some(literal) = self.global_consts.get(name).cloned() {
+            self.run_expr(&literal, insts);
+        } else if self.loop_lexicals.contains(name) {
+            self.gen_current_env(insts);
+            self.bytecode_gen.gen_get_env_var(name.clone(), insts);
+        } else if self.captured_lexicals.contains_key(name) {
+            self.gen_check_captured_initialized(name, insts);
+            self.bytecode_gen.gen_get_global(name.clone(), insts);
+        } else if self.closures.contains(name) {
+            // A closure made in a loop captures the environment of the iteration.
+            self.bytecode_gen.gen_get_global(name.clone(), insts);
+            self.gen_current_env(insts);
+            self.bytecode_gen.gen_make_closure(insts);
         } else {
             self.bytecode_gen.gen_get_global(name.clone(), insts);
         }